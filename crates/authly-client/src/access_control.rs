@@ -1,18 +1,24 @@
 //! Access control functionality.
 
-use std::{future::Future, pin::Pin, sync::Arc};
+use std::{collections::BTreeSet, future::Future, pin::Pin, sync::Arc};
 
+use anyhow::anyhow;
 use authly_common::{
-    id::{AttrId, EntityId, Id128DynamicArrayConv},
+    id::{AttrId, EntityId, Id128DynamicArrayConv, PolicyId},
+    policy::{
+        code::PolicyValue,
+        engine::{AccessControlParams, Combinator, NoOpPolicyTracer, PolicyEngine},
+    },
     proto::service::{self as proto},
     service::{NamespacePropertyMapping, NamespacedPropertyAttribute},
 };
 use fnv::FnvHashSet;
 use http::header::AUTHORIZATION;
+use serde::Serialize;
 use tonic::Request;
 use tracing::debug;
 
-use crate::{error, id_codec_error, token::AccessToken, Client, Error};
+use crate::{error, id_codec_error, metrics, token::AccessToken, Client, Error};
 
 /// Trait for initiating an access control request
 pub trait AccessControl {
@@ -24,19 +30,52 @@ pub trait AccessControl {
         &self,
         builder: AccessControlRequestBuilder<'_>,
     ) -> Pin<Box<dyn Future<Output = Result<bool, Error>> + Send + '_>>;
+
+    /// Enforce the access control request, failing with [Error::AccessDenied] when denied.
+    ///
+    /// Implementations may fail with [Error::AccessDeniedReason] instead, when the reason for the denial is known.
+    fn enforce(
+        &self,
+        builder: AccessControlRequestBuilder<'_>,
+    ) -> Pin<Box<dyn Future<Output = Result<(), Error>> + Send + '_>> {
+        let evaluation = self.evaluate(builder);
+
+        Box::pin(async move {
+            if evaluation.await? {
+                Ok(())
+            } else {
+                Err(Error::AccessDenied)
+            }
+        })
+    }
+
+    /// Get the policy engine used for local access control evaluation, if available.
+    fn local_policy_engine(&self) -> Option<Arc<PolicyEngine>> {
+        None
+    }
+
+    /// Decode and validate an access token, e.g. one passed in an `Authorization` header.
+    ///
+    /// The default implementation rejects all access tokens.
+    fn decode_access_token(&self, access_token: String) -> Result<Arc<AccessToken>, Error> {
+        let _ = access_token;
+        Err(Error::InvalidAccessToken(anyhow!(
+            "access token decoding is not supported"
+        )))
+    }
 }
 
 /// A builder for making an access control request.
 ///
-// TODO: Include peer service(s) in the access control request.
-// For that to work locally, there are two options:
-// 1. The service verifies each incoming peer with a call to authly, to retrieve entity attributes.
-// 2. The service is conscious about its mesh, and is allowed to keep an in-memory map of incoming service entity attributes.
+// Peer service(s) can be included in the access control request in two ways:
+// 1. By entity ID, making Authly look up the peer's entity attributes.
+// 2. By subject attributes, when the service is conscious about its mesh and keeps an in-memory map of incoming service entity attributes.
 pub struct AccessControlRequestBuilder<'c> {
     access_control: &'c (dyn AccessControl + Send + Sync),
     property_mapping: Arc<NamespacePropertyMapping>,
     access_token: Option<Arc<AccessToken>>,
     resource_attributes: FnvHashSet<AttrId>,
+    subject_attributes: FnvHashSet<AttrId>,
     peer_entity_ids: FnvHashSet<EntityId>,
 }
 
@@ -51,6 +90,7 @@ impl<'c> AccessControlRequestBuilder<'c> {
             property_mapping,
             access_token: None,
             resource_attributes: Default::default(),
+            subject_attributes: Default::default(),
             peer_entity_ids: Default::default(),
         }
     }
@@ -80,7 +120,36 @@ impl<'c> AccessControlRequestBuilder<'c> {
         mut self,
         attr: impl NamespacedPropertyAttribute,
     ) -> Result<Self, Error> {
-        let attr_id = self.property_mapping.attribute_id(&attr).ok_or_else(|| {
+        let attr_id = self.lookup_attribute(&attr)?;
+        self.resource_attributes.insert(attr_id);
+        Ok(self)
+    }
+
+    /// Define a resource attribute by its [AttrId], to be included in the access control request.
+    ///
+    /// This is useful when the label has already been translated, e.g. using [NamespacePropertyMapping::translate],
+    /// and does not depend on the current property mapping of the service.
+    pub fn resource_attribute_id(mut self, attr_id: AttrId) -> Self {
+        self.resource_attributes.insert(attr_id);
+        self
+    }
+
+    /// Define a labelled subject attribute to be included in the access control request.
+    ///
+    /// This is used for subject attributes resolved by the service itself,
+    /// e.g. from a peer service certificate combined with a local cache.
+    /// The attributes are sent to Authly as peer entity attributes.
+    pub fn subject_attribute(
+        mut self,
+        attr: impl NamespacedPropertyAttribute,
+    ) -> Result<Self, Error> {
+        let attr_id = self.lookup_attribute(&attr)?;
+        self.subject_attributes.insert(attr_id);
+        Ok(self)
+    }
+
+    fn lookup_attribute(&self, attr: &impl NamespacedPropertyAttribute) -> Result<AttrId, Error> {
+        self.property_mapping.attribute_id(attr).ok_or_else(|| {
             debug!(
                 "invalid namespace/property/attribute label: {}/{}/{}",
                 attr.namespace(),
@@ -88,10 +157,7 @@ impl<'c> AccessControlRequestBuilder<'c> {
                 attr.attribute(),
             );
             Error::InvalidPropertyAttributeLabel
-        })?;
-
-        self.resource_attributes.insert(attr_id);
-        Ok(self)
+        })
     }
 
     /// Include an access token in the request.
@@ -113,13 +179,24 @@ impl<'c> AccessControlRequestBuilder<'c> {
         self.resource_attributes.iter().copied()
     }
 
+    /// Get an iterator over the current subject attributes.
+    pub fn subject_attributes(&self) -> impl Iterator<Item = AttrId> + use<'_> {
+        self.subject_attributes.iter().copied()
+    }
+
+    /// Get an iterator over the current peer entity IDs.
+    pub fn peer_entity_ids(&self) -> impl Iterator<Item = EntityId> + use<'_> {
+        self.peer_entity_ids.iter().copied()
+    }
+
+    /// Get the access token included in the request, if any.
+    pub fn get_access_token(&self) -> Option<&Arc<AccessToken>> {
+        self.access_token.as_ref()
+    }
+
     /// Enforce the access control request.
     pub async fn enforce(self) -> Result<(), Error> {
-        if self.access_control.evaluate(self).await? {
-            Ok(())
-        } else {
-            Err(Error::AccessDenied)
-        }
+        self.access_control.enforce(self).await
     }
 
     /// Evaluate the access control request.
@@ -128,6 +205,194 @@ impl<'c> AccessControlRequestBuilder<'c> {
     pub async fn evaluate(self) -> Result<bool, Error> {
         self.access_control.evaluate(self).await
     }
+
+    /// Evaluate the access control request locally, using policies downloaded from Authly.
+    ///
+    /// The subject attributes are taken from the access token claims, together with any explicit subject attributes.
+    /// The request is evaluated remotely, as with [Self::evaluate], when local evaluation is not possible:
+    /// when Authly has not provided any policies, when the provided policies are not supported by this client,
+    /// when the request includes peer entities
+    /// (whose attributes are not known locally), or when a policy fails to evaluate.
+    ///
+    /// The local policies are only as fresh as the last configuration reload.
+    /// They are replaced when Authly signals that the service should reload its cache,
+    /// so policy changes made in Authly may take effect with a small delay.
+    pub async fn evaluate_local(self) -> Result<bool, Error> {
+        let Some(engine) = self.access_control.local_policy_engine() else {
+            return self.evaluate().await;
+        };
+        if !self.peer_entity_ids.is_empty() {
+            return self.evaluate().await;
+        }
+
+        let mut params = match &self.access_token {
+            Some(access_token) => AccessControlParams::from(access_token.as_ref()),
+            None => AccessControlParams::default(),
+        };
+        params
+            .subject_attrs
+            .extend(self.subject_attributes.iter().copied());
+        params.resource_attrs = self.resource_attributes.clone();
+
+        match engine.eval(&params, &mut NoOpPolicyTracer) {
+            Ok(value) => Ok(value.is_allow()),
+            Err(err) => {
+                debug!(?err, "local policy evaluation failed, evaluating remotely");
+                self.evaluate().await
+            }
+        }
+    }
+
+    /// Evaluate the access control request, producing an [AuditRecord] that describes the decision.
+    ///
+    /// The request is evaluated like with [Self::enforce], so the record includes the reason for a denial, when known.
+    /// Use [AuditRecord::enforce] to enforce the decision after recording it.
+    pub async fn evaluate_detailed(self) -> Result<AuditRecord, Error> {
+        let subject_entity_id = self
+            .access_token
+            .as_ref()
+            .map(|token| token.claims.authly.entity_id);
+        let mut peer_entity_ids: Vec<EntityId> = self.peer_entity_ids.iter().copied().collect();
+        peer_entity_ids.sort();
+        let resource_attributes =
+            attribute_labels(&self.property_mapping, &self.resource_attributes);
+
+        let (decision, reason) = match self.access_control.enforce(self).await {
+            Ok(()) => (PolicyValue::Allow, None),
+            Err(Error::AccessDenied) => (PolicyValue::Deny, None),
+            Err(Error::AccessDeniedReason(reason)) => (PolicyValue::Deny, Some(reason)),
+            Err(err) => return Err(err),
+        };
+
+        Ok(AuditRecord {
+            timestamp: time::OffsetDateTime::now_utc().unix_timestamp(),
+            decision,
+            reason,
+            subject_entity_id,
+            peer_entity_ids,
+            resource_attributes,
+        })
+    }
+}
+
+impl AccessControlRequestBuilder<'_> {
+    /// Convert into a protobuf request, along with the access token to authorize it.
+    pub(crate) fn into_proto(self) -> (proto::AccessControlRequest, Option<Arc<AccessToken>>) {
+        let request = proto::AccessControlRequest {
+            resource_attributes: self
+                .resource_attributes
+                .into_iter()
+                .map(|attr| attr.to_array_dynamic().to_vec().into())
+                .collect(),
+            peer_entity_attributes: self
+                .subject_attributes
+                .into_iter()
+                .map(|attr| attr.to_array_dynamic().to_vec().into())
+                .collect(),
+            peer_entity_ids: self
+                .peer_entity_ids
+                .into_iter()
+                .map(|eid| eid.to_array_dynamic().to_vec().into())
+                .collect(),
+        };
+
+        (request, self.access_token)
+    }
+}
+
+impl Client {
+    /// Make a remote access control request to Authly.
+    async fn access_control(
+        &self,
+        request: proto::AccessControlRequest,
+        access_token: Option<Arc<AccessToken>>,
+    ) -> Result<proto::AccessControlResponse, Error> {
+        Ok(self
+            .state
+            .retry_policy
+            .retry(|| async {
+                let mut request = Request::new(request.clone());
+                if let Some(access_token) = &access_token {
+                    append_bearer(&mut request, access_token)?;
+                }
+
+                metrics::observe_rpc(
+                    &*self.state.metrics,
+                    "access_control",
+                    self.current_service().access_control(request),
+                )
+                .await
+            })
+            .await?
+            .into_inner())
+    }
+}
+
+pub(crate) fn append_bearer<T>(
+    request: &mut Request<T>,
+    access_token: &AccessToken,
+) -> Result<(), Error> {
+    request.metadata_mut().append(
+        AUTHORIZATION.as_str(),
+        format!("Bearer {}", access_token.token)
+            .parse()
+            .map_err(error::unclassified)?,
+    );
+    Ok(())
+}
+
+/// A record of an access control decision, suitable for audit logging.
+#[derive(Clone, Serialize, Debug)]
+pub struct AuditRecord {
+    /// The time of the decision, as seconds since the unix epoch.
+    pub timestamp: i64,
+
+    /// The outcome of the access control evaluation.
+    pub decision: PolicyValue,
+
+    /// The reason for the decision given by Authly, if any.
+    pub reason: Option<String>,
+
+    /// The entity of the access token included in the request, if any.
+    pub subject_entity_id: Option<EntityId>,
+
+    /// The peer entities included in the request.
+    pub peer_entity_ids: Vec<EntityId>,
+
+    /// The resource attributes of the request, as namespace/property/attribute label triples.
+    pub resource_attributes: Vec<(String, String, String)>,
+}
+
+impl AuditRecord {
+    /// Enforce the recorded decision, failing like [AccessControlRequestBuilder::enforce] when access was denied.
+    pub fn enforce(&self) -> Result<(), Error> {
+        match (self.decision, &self.reason) {
+            (PolicyValue::Allow, _) => Ok(()),
+            (PolicyValue::Deny, Some(reason)) => Err(Error::AccessDeniedReason(reason.clone())),
+            (PolicyValue::Deny, None) => Err(Error::AccessDenied),
+        }
+    }
+}
+
+/// Find the sorted label triples of the given attributes.
+pub(crate) fn attribute_labels(
+    property_mapping: &NamespacePropertyMapping,
+    attributes: &FnvHashSet<AttrId>,
+) -> Vec<(String, String, String)> {
+    let mut labels: Vec<_> = attributes
+        .iter()
+        .filter_map(|attr_id| property_mapping.reverse(*attr_id))
+        .map(|(namespace, property, attribute)| {
+            (
+                namespace.to_string(),
+                property.to_string(),
+                attribute.to_string(),
+            )
+        })
+        .collect();
+
+    labels.sort();
+    labels
 }
 
 pub(crate) fn get_resource_property_mapping(
@@ -153,6 +418,71 @@ pub(crate) fn get_resource_property_mapping(
     Ok(Arc::new(property_mapping))
 }
 
+pub(crate) fn get_policy_engine(policy_set: proto::PolicySet) -> Result<Arc<PolicyEngine>, Error> {
+    let mut engine = PolicyEngine::default();
+
+    for policy in policy_set.policies {
+        let class = match proto::AccessControlValue::try_from(policy.class) {
+            Ok(proto::AccessControlValue::Allow) => PolicyValue::Allow,
+            Ok(proto::AccessControlValue::Deny) => PolicyValue::Deny,
+            Err(_) => {
+                return Err(Error::Codec(anyhow!(
+                    "unknown policy class {}",
+                    policy.class
+                )))
+            }
+        };
+
+        let policy_id = PolicyId::try_from_bytes_dynamic(&policy.id).ok_or_else(id_codec_error)?;
+
+        // a policy set with a policy that cannot be evaluated is rejected as a whole,
+        // since skipping e.g. a deny policy could allow access
+        engine
+            .add_policy_verified(policy_id, class, policy.bytecode.to_vec())
+            .map_err(|err| Error::Codec(anyhow!("invalid policy {policy_id}: {err:?}")))?;
+    }
+
+    for trigger in policy_set.triggers {
+        let attr_matcher = trigger
+            .attr_matcher
+            .iter()
+            .map(|bytes| AttrId::try_from_bytes_dynamic(bytes).ok_or_else(id_codec_error))
+            .collect::<Result<BTreeSet<_>, _>>()?;
+        let policy_ids = trigger
+            .policy_ids
+            .iter()
+            .map(|bytes| PolicyId::try_from_bytes_dynamic(bytes).ok_or_else(id_codec_error))
+            .collect::<Result<BTreeSet<_>, _>>()?;
+
+        let excluded_attrs = trigger
+            .excluded_attrs
+            .iter()
+            .map(|bytes| AttrId::try_from_bytes_dynamic(bytes).ok_or_else(id_codec_error))
+            .collect::<Result<BTreeSet<_>, _>>()?;
+
+        if excluded_attrs.is_empty() {
+            engine.add_trigger_with_combinator(
+                attr_matcher,
+                policy_ids,
+                if trigger.conjunctive {
+                    Combinator::Conjunctive
+                } else {
+                    Combinator::Disjunctive
+                },
+            );
+        } else if trigger.conjunctive {
+            // ignoring either the exclusions or the combinator would change the outcome
+            return Err(Error::Codec(anyhow!(
+                "conjunctive trigger with excluded attributes"
+            )));
+        } else {
+            engine.add_trigger_excluding(attr_matcher, excluded_attrs, policy_ids);
+        }
+    }
+
+    Ok(Arc::new(engine))
+}
+
 impl AccessControl for Client {
     fn access_control_request(&self) -> AccessControlRequestBuilder<'_> {
         AccessControlRequestBuilder::new(
@@ -169,38 +499,460 @@ impl AccessControl for Client {
         &self,
         builder: AccessControlRequestBuilder<'_>,
     ) -> Pin<Box<dyn Future<Output = Result<bool, Error>> + Send + '_>> {
+        let (request, access_token) = builder.into_proto();
+
+        Box::pin(async move { Ok(self.access_control(request, access_token).await?.value > 0) })
+    }
+
+    fn enforce(
+        &self,
+        builder: AccessControlRequestBuilder<'_>,
+    ) -> Pin<Box<dyn Future<Output = Result<(), Error>> + Send + '_>> {
+        let (request, access_token) = builder.into_proto();
+
         Box::pin(async move {
-            let mut request = Request::new(proto::AccessControlRequest {
-                resource_attributes: builder
-                    .resource_attributes
-                    .into_iter()
-                    .map(|attr| attr.to_array_dynamic().to_vec().into())
-                    .collect(),
-                // Peer entity attributes are currently not known to the service:
-                peer_entity_attributes: vec![],
-                peer_entity_ids: builder
-                    .peer_entity_ids
-                    .into_iter()
-                    .map(|eid| eid.to_array_dynamic().to_vec().into())
-                    .collect(),
-            });
-            if let Some(access_token) = builder.access_token {
-                request.metadata_mut().append(
-                    AUTHORIZATION.as_str(),
-                    format!("Bearer {}", access_token.token)
-                        .parse()
-                        .map_err(error::unclassified)?,
-                );
+            let response = self.access_control(request, access_token).await?;
+
+            match (response.value > 0, response.denial_reason) {
+                (true, _) => Ok(()),
+                (false, Some(reason)) => Err(Error::AccessDeniedReason(reason)),
+                (false, None) => Err(Error::AccessDenied),
             }
+        })
+    }
 
-            let access_control_response = self
-                .current_service()
-                .access_control(request)
-                .await
-                .map_err(error::tonic)?
-                .into_inner();
+    fn local_policy_engine(&self) -> Option<Arc<PolicyEngine>> {
+        self.state.configuration.load().policy_engine.clone()
+    }
 
-            Ok(access_control_response.value > 0)
-        })
+    fn decode_access_token(&self, access_token: String) -> Result<Arc<AccessToken>, Error> {
+        Client::decode_access_token(self, access_token)
+    }
+}
+
+#[tokio::test]
+async fn test_resource_attribute_id() {
+    let client = crate::mock::client(crate::mock::MockAuthly::default().serve().await);
+
+    let mut property_mapping = NamespacePropertyMapping::default();
+    property_mapping
+        .namespace_mut("orders".to_string())
+        .property_mut("action".to_string())
+        .put("read".to_string(), AttrId::from_uint(1));
+
+    let builder = AccessControlRequestBuilder::new(&client, Arc::new(property_mapping))
+        .resource_attribute(("orders", "action", "read"))
+        .unwrap()
+        .resource_attribute_id(AttrId::from_uint(2));
+
+    let mut resource_attributes: Vec<AttrId> = builder.resource_attributes().collect();
+    resource_attributes.sort();
+    assert_eq!(
+        vec![AttrId::from_uint(1), AttrId::from_uint(2)],
+        resource_attributes
+    );
+
+    let (request, _) = builder.into_proto();
+    assert_eq!(2, request.resource_attributes.len());
+}
+
+#[tokio::test]
+async fn test_qualified_resource_attribute() {
+    let client = crate::mock::client(crate::mock::MockAuthly::default().serve().await);
+
+    let mut property_mapping = NamespacePropertyMapping::default();
+    property_mapping
+        .namespace_mut("orders".to_string())
+        .property_mut("action".to_string())
+        .put("read".to_string(), AttrId::from_uint(1));
+
+    let name: authly_common::property::QualifiedAttributeName =
+        "orders:action:read".parse().unwrap();
+    let builder = AccessControlRequestBuilder::new(&client, Arc::new(property_mapping))
+        .resource_attribute(name)
+        .unwrap();
+
+    assert_eq!(
+        vec![AttrId::from_uint(1)],
+        builder.resource_attributes().collect::<Vec<_>>()
+    );
+}
+
+#[tokio::test]
+async fn test_enforce_denial_reason() {
+    let allowed = crate::mock::client(crate::mock::MockAuthly::default().serve().await);
+    allowed.access_control_request().enforce().await.unwrap();
+
+    let denied = crate::mock::client(
+        crate::mock::MockAuthly {
+            denial_reason: Some("outside business hours".to_string()),
+            ..Default::default()
+        }
+        .serve()
+        .await,
+    );
+    assert!(!denied.access_control_request().evaluate().await.unwrap());
+
+    let err = denied.access_control_request().enforce().await.unwrap_err();
+    assert!(err.is_auth());
+    assert!(
+        matches!(&err, Error::AccessDeniedReason(reason) if reason == "outside business hours"),
+        "{err:?}"
+    );
+    assert_eq!("access denied: outside business hours", err.to_string());
+}
+
+/// A policy set allowing access to resources with the given attribute.
+#[cfg(test)]
+fn test_policy_set(attr: AttrId) -> proto::PolicySet {
+    use authly_common::policy::code::{to_bytecode, OpCode};
+
+    let policy_id = PolicyId::from_uint(1).to_array_dynamic().to_vec();
+    proto::PolicySet {
+        policies: vec![proto::Policy {
+            id: policy_id.clone().into(),
+            class: proto::AccessControlValue::Allow.into(),
+            bytecode: to_bytecode(&[OpCode::LoadTrue, OpCode::Return]).into(),
+        }],
+        triggers: vec![proto::PolicyTrigger {
+            attr_matcher: vec![attr.to_array_dynamic().to_vec().into()],
+            policy_ids: vec![policy_id.into()],
+            conjunctive: false,
+            excluded_attrs: vec![],
+        }],
+    }
+}
+
+#[cfg(test)]
+fn reload_cache_message() -> proto::ServiceMessage {
+    proto::ServiceMessage {
+        service_message_kind: Some(proto::service_message::ServiceMessageKind::ReloadCache(
+            proto::Empty {},
+        )),
+    }
+}
+
+#[test]
+fn test_policy_engine_rejects_invalid_policy() {
+    use authly_common::{
+        id::{kind::Kind, EntityId},
+        policy::code::{to_bytecode, OpCode},
+    };
+
+    assert!(get_policy_engine(test_policy_set(AttrId::from_uint(1))).is_ok());
+
+    // a constant entity ID with a kind that is not an entity kind
+    let mut bytecode = to_bytecode(&[
+        OpCode::LoadConstEntityId(EntityId::from_uint(Kind::Group, 1)),
+        OpCode::LoadSubjectIdSet,
+        OpCode::IdSetContains,
+        OpCode::Return,
+    ]);
+    bytecode[1] = Kind::Attribute.into();
+
+    let mut policy_set = test_policy_set(AttrId::from_uint(1));
+    policy_set.policies[0].bytecode = bytecode.into();
+    assert!(matches!(
+        get_policy_engine(policy_set),
+        Err(Error::Codec(_))
+    ));
+
+    // a policy class this client does not know
+    let mut policy_set = test_policy_set(AttrId::from_uint(1));
+    policy_set.policies[0].class = 42;
+    assert!(matches!(
+        get_policy_engine(policy_set),
+        Err(Error::Codec(_))
+    ));
+}
+
+#[tokio::test]
+async fn test_evaluate_local() {
+    use futures_util::StreamExt;
+
+    let (messages_tx, _) = tokio::sync::broadcast::channel(1);
+    let access_control_requests = Arc::<std::sync::Mutex<Vec<_>>>::default();
+    let mock = crate::mock::MockAuthly {
+        messages: Some(messages_tx.clone()),
+        configuration: Arc::new(std::sync::Mutex::new(proto::ServiceConfiguration {
+            policy_set: Some(test_policy_set(AttrId::from_uint(1))),
+            ..Default::default()
+        })),
+        // remote evaluation always denies
+        denial_reason: Some("remote".to_string()),
+        access_control_requests: access_control_requests.clone(),
+        ..Default::default()
+    };
+    let client = crate::mock::client_with_worker(mock.serve().await, Default::default()).await;
+    let mut configuration_stream = client.configuration_stream();
+    configuration_stream.next().await.unwrap();
+
+    messages_tx.send(reload_cache_message()).unwrap();
+    configuration_stream.next().await.unwrap();
+
+    for (attr, expected) in [(1, true), (2, false)] {
+        let allowed = client
+            .access_control_request()
+            .resource_attribute_id(AttrId::from_uint(attr))
+            .evaluate_local()
+            .await
+            .unwrap();
+        assert_eq!(expected, allowed, "{attr}");
+    }
+
+    // evaluated without asking Authly
+    assert!(access_control_requests.lock().unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn test_evaluate_local_excluded_attributes() {
+    use authly_common::policy::code::{to_bytecode, OpCode};
+    use futures_util::StreamExt;
+
+    // Authly allows attribute 1, unless attribute 2 is also present
+    let mut authly_engine = PolicyEngine::default();
+    authly_engine.add_policy(
+        PolicyId::from_uint(1),
+        PolicyValue::Allow,
+        to_bytecode(&[OpCode::LoadTrue, OpCode::Return]),
+    );
+    authly_engine.add_trigger_excluding(
+        [AttrId::from_uint(1)],
+        [AttrId::from_uint(2)],
+        [PolicyId::from_uint(1)],
+    );
+
+    let mut policy_set = test_policy_set(AttrId::from_uint(1));
+    policy_set.triggers[0].excluded_attrs =
+        vec![AttrId::from_uint(2).to_array_dynamic().to_vec().into()];
+
+    let (messages_tx, _) = tokio::sync::broadcast::channel(1);
+    let mock = crate::mock::MockAuthly {
+        messages: Some(messages_tx.clone()),
+        configuration: Arc::new(std::sync::Mutex::new(proto::ServiceConfiguration {
+            policy_set: Some(policy_set),
+            ..Default::default()
+        })),
+        policy_engine: Some(Arc::new(authly_engine)),
+        ..Default::default()
+    };
+    let client = crate::mock::client_with_worker(mock.serve().await, Default::default()).await;
+    let mut configuration_stream = client.configuration_stream();
+    configuration_stream.next().await.unwrap();
+
+    messages_tx.send(reload_cache_message()).unwrap();
+    configuration_stream.next().await.unwrap();
+
+    for attrs in [vec![1], vec![1, 2], vec![2]] {
+        let request = || {
+            attrs
+                .iter()
+                .fold(client.access_control_request(), |request, attr| {
+                    request.resource_attribute_id(AttrId::from_uint(*attr))
+                })
+        };
+
+        let remote = request().evaluate().await.unwrap();
+        let local = request().evaluate_local().await.unwrap();
+        assert_eq!(remote, local, "{attrs:?}");
+        assert_eq!(attrs == [1], local, "{attrs:?}");
+    }
+
+    let mut policy_set = test_policy_set(AttrId::from_uint(1));
+    policy_set.triggers[0].conjunctive = true;
+    policy_set.triggers[0].excluded_attrs =
+        vec![AttrId::from_uint(2).to_array_dynamic().to_vec().into()];
+    assert!(matches!(
+        get_policy_engine(policy_set),
+        Err(Error::Codec(_))
+    ));
+}
+
+#[tokio::test]
+async fn test_evaluate_local_remote_fallback() {
+    use futures_util::StreamExt;
+
+    let (messages_tx, _) = tokio::sync::broadcast::channel(1);
+    let access_control_requests = Arc::<std::sync::Mutex<Vec<_>>>::default();
+    let mock = crate::mock::MockAuthly {
+        messages: Some(messages_tx.clone()),
+        // remote evaluation always denies
+        denial_reason: Some("remote".to_string()),
+        access_control_requests: access_control_requests.clone(),
+        ..Default::default()
+    };
+    let configuration = mock.configuration.clone();
+    let client = crate::mock::client_with_worker(mock.serve().await, Default::default()).await;
+    let mut configuration_stream = client.configuration_stream();
+    configuration_stream.next().await.unwrap();
+
+    let evaluate_local = || {
+        client
+            .access_control_request()
+            .resource_attribute_id(AttrId::from_uint(1))
+            .evaluate_local()
+    };
+
+    // no policy set cached, evaluated remotely
+    assert!(!evaluate_local().await.unwrap());
+    assert_eq!(1, access_control_requests.lock().unwrap().len());
+
+    configuration.lock().unwrap().policy_set = Some(test_policy_set(AttrId::from_uint(1)));
+    messages_tx.send(reload_cache_message()).unwrap();
+    configuration_stream.next().await.unwrap();
+
+    assert!(evaluate_local().await.unwrap());
+    assert_eq!(1, access_control_requests.lock().unwrap().len());
+
+    // the cached policy set is invalidated on reload
+    configuration.lock().unwrap().policy_set = None;
+    messages_tx.send(reload_cache_message()).unwrap();
+    configuration_stream.next().await.unwrap();
+
+    assert!(!evaluate_local().await.unwrap());
+    assert_eq!(2, access_control_requests.lock().unwrap().len());
+}
+
+#[tokio::test]
+async fn test_evaluate_local_unsupported_policy() {
+    use authly_common::policy::code::{to_bytecode, OpCode};
+    use futures_util::StreamExt;
+
+    // an opcode introduced by a newer Authly
+    let mut bytecode = to_bytecode(&[OpCode::LoadTrue, OpCode::Return]);
+    bytecode.insert(0, 0xff);
+
+    let mut policy_set = test_policy_set(AttrId::from_uint(1));
+    policy_set.policies[0].bytecode = bytecode.into();
+
+    let (messages_tx, _) = tokio::sync::broadcast::channel(1);
+    let access_control_requests = Arc::<std::sync::Mutex<Vec<_>>>::default();
+    let mock = crate::mock::MockAuthly {
+        messages: Some(messages_tx.clone()),
+        configuration: Arc::new(std::sync::Mutex::new(proto::ServiceConfiguration {
+            policy_set: Some(policy_set),
+            ..Default::default()
+        })),
+        // remote evaluation always denies
+        denial_reason: Some("remote".to_string()),
+        access_control_requests: access_control_requests.clone(),
+        ..Default::default()
+    };
+    let client = crate::mock::client_with_worker(mock.serve().await, Default::default()).await;
+    let mut configuration_stream = client.configuration_stream();
+    configuration_stream.next().await.unwrap();
+
+    // the configuration is still reloaded, without the policies
+    messages_tx.send(reload_cache_message()).unwrap();
+    configuration_stream.next().await.unwrap();
+    assert!(client.local_policy_engine().is_none());
+
+    let allowed = client
+        .access_control_request()
+        .resource_attribute_id(AttrId::from_uint(1))
+        .evaluate_local()
+        .await
+        .unwrap();
+    assert!(!allowed);
+    assert_eq!(1, access_control_requests.lock().unwrap().len());
+}
+
+#[tokio::test]
+async fn test_audit_record() {
+    use authly_common::id::ServiceId;
+
+    let client = crate::mock::client(
+        crate::mock::MockAuthly {
+            allowed_resource_attributes: Some(vec![AttrId::from_uint(1)]),
+            ..Default::default()
+        }
+        .serve()
+        .await,
+    );
+    crate::mock::use_visibility_property_mapping(&client);
+
+    for (visibility, decision) in [("public", "Allow"), ("private", "Deny")] {
+        let record = client
+            .access_control_request()
+            .access_token(crate::token::test_access_token(0))
+            .peer_entity_id(ServiceId::from_uint(0xf001).upcast())
+            .resource_attribute(("docs", "visibility", visibility))
+            .unwrap()
+            .evaluate_detailed()
+            .await
+            .unwrap();
+
+        let mut json = serde_json::to_value(&record).unwrap();
+        assert!(json["timestamp"].as_i64().unwrap() > 0);
+        json.as_object_mut().unwrap().remove("timestamp");
+        assert_eq!(
+            serde_json::json!({
+                "decision": decision,
+                "reason": null,
+                "subject_entity_id": "p.1c2d3e4f5a6b7c8d9e0f1a2b3c4d5e6f",
+                "peer_entity_ids": ["s.0000000000000000000000000000f001"],
+                "resource_attributes": [["docs", "visibility", visibility]],
+            }),
+            json
+        );
+        assert_eq!(visibility == "public", record.enforce().is_ok());
     }
 }
+
+#[tokio::test]
+async fn test_audit_record_reason() {
+    let client = crate::mock::client(
+        crate::mock::MockAuthly {
+            denial_reason: Some("not the owner".to_string()),
+            ..Default::default()
+        }
+        .serve()
+        .await,
+    );
+
+    let record = client
+        .access_control_request()
+        .evaluate_detailed()
+        .await
+        .unwrap();
+
+    assert_eq!(PolicyValue::Deny, record.decision);
+    assert_eq!(Some("not the owner"), record.reason.as_deref());
+    assert_eq!(
+        "not the owner",
+        serde_json::to_value(&record).unwrap()["reason"]
+    );
+    assert!(matches!(
+        record.enforce(),
+        Err(Error::AccessDeniedReason(reason)) if reason == "not the owner"
+    ));
+}
+
+#[tokio::test]
+async fn test_subject_attribute() {
+    let access_control_requests = Arc::<std::sync::Mutex<Vec<_>>>::default();
+    let client = crate::mock::client(
+        crate::mock::MockAuthly {
+            access_control_requests: access_control_requests.clone(),
+            ..Default::default()
+        }
+        .serve()
+        .await,
+    );
+    crate::mock::use_visibility_property_mapping(&client);
+
+    client
+        .access_control_request()
+        .subject_attribute(("docs", "visibility", "private"))
+        .unwrap()
+        .resource_attribute(("docs", "visibility", "public"))
+        .unwrap()
+        .evaluate()
+        .await
+        .unwrap();
+
+    let requests = access_control_requests.lock().unwrap();
+    let to_bytes = |attr: u128| AttrId::from_uint(attr).to_array_dynamic().to_vec();
+    assert_eq!(vec![to_bytes(2)], requests[0].peer_entity_attributes);
+    assert_eq!(vec![to_bytes(1)], requests[0].resource_attributes);
+}
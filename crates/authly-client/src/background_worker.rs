@@ -1,27 +1,44 @@
 use std::{sync::Arc, time::Duration};
 
 use authly_common::proto::service::{self as proto};
+use tokio::{task::JoinHandle, time::Instant};
 use tonic::Streaming;
 
 use crate::{
-    connection::{make_connection, ConnectionParams},
-    error, get_configuration, ClientState, Error,
+    connection::{make_connection, Connection, ConnectionParams, ConnectionStatus},
+    get_configuration, metrics, ClientState, Error, RetiredDecodingKey,
 };
 
+/// The interval between attempts to re-open a dropped message stream.
+const STREAM_REOPEN_INTERVAL: Duration = Duration::from_millis(250);
+
 pub struct WorkerSenders {
     pub reconfigured_tx: tokio::sync::watch::Sender<Arc<ConnectionParams>>,
     pub metadata_invalidated_tx: tokio::sync::watch::Sender<()>,
+    pub status_tx: tokio::sync::watch::Sender<ConnectionStatus>,
+}
+
+impl WorkerSenders {
+    /// Publish the connection status, if it changed.
+    fn set_status(&self, status: ConnectionStatus) {
+        self.status_tx.send_if_modified(|current| {
+            let modified = *current != status;
+            *current = status;
+            modified
+        });
+    }
 }
 
 pub async fn spawn_background_worker(
     state: Arc<ClientState>,
     senders: WorkerSenders,
     closed_rx: tokio::sync::watch::Receiver<()>,
-) -> Result<(), Error> {
+) -> Result<JoinHandle<()>, Error> {
     let msg_stream = init_message_stream(&state).await?;
-    tokio::spawn(background_worker(state, senders, closed_rx, msg_stream));
 
-    Ok(())
+    Ok(tokio::spawn(background_worker(
+        state, senders, closed_rx, msg_stream,
+    )))
 }
 
 async fn background_worker(
@@ -31,10 +48,12 @@ async fn background_worker(
     mut msg_stream: Streaming<proto::ServiceMessage>,
 ) {
     loop {
+        // message handling is cancelled on close, so reconnect delays don't hold up the shutdown
         tokio::select! {
-            msg_result = msg_stream.message() => {
+            _ = async {
+                let msg_result = msg_stream.message().await;
                 handle_message_result(&state, msg_result, &mut msg_stream, &senders).await;
-            }
+            } => {}
             _ = closed_rx.changed() => {
                 tracing::info!("Authly channel closed");
                 return;
@@ -55,13 +74,47 @@ async fn handle_message_result(
                 handle_message_kind(state, kind, msg_stream, senders).await;
             }
         }
-        Ok(None) => {
-            reconfigure_loop(state, msg_stream, senders).await;
+        Ok(None) | Err(_) => {
+            senders.set_status(ConnectionStatus::Reconnecting);
+
+            if reopen_message_stream(state, msg_stream).await {
+                senders.set_status(ConnectionStatus::Connected);
+
+                // messages may have been lost while the stream was down
+                reload_local_cache(state, senders).await;
+            } else {
+                // the current Authly host may have gone away
+                reconfigure_loop(state, msg_stream, senders, true).await;
+            }
         }
-        Err(_error) => {
-            reconfigure_loop(state, msg_stream, senders).await;
+    }
+}
+
+/// Try to re-open the message stream on the current connection within the reconnect grace period.
+///
+/// Returns whether the stream was successfully re-opened.
+async fn reopen_message_stream(
+    state: &ClientState,
+    msg_stream: &mut Streaming<proto::ServiceMessage>,
+) -> bool {
+    let deadline = Instant::now() + state.reconnect_policy.grace_period;
+
+    while Instant::now() < deadline {
+        match init_message_stream(state).await {
+            Ok(stream) => {
+                *msg_stream = stream;
+                return true;
+            }
+            Err(err) => {
+                tracing::debug!(?err, "could not re-open message stream");
+
+                let remaining = deadline.saturating_duration_since(Instant::now());
+                tokio::time::sleep(remaining.min(STREAM_REOPEN_INTERVAL)).await;
+            }
         }
     }
+
+    false
 }
 
 async fn handle_message_kind(
@@ -74,35 +127,45 @@ async fn handle_message_kind(
 
     match msg_kind {
         proto::service_message::ServiceMessageKind::ReloadCa(_) => {
-            reconfigure_loop(state, msg_stream, senders).await;
+            reconfigure_loop(state, msg_stream, senders, false).await;
         }
         proto::service_message::ServiceMessageKind::ReloadCache(_) => {
             reload_local_cache(state, senders).await;
         }
         proto::service_message::ServiceMessageKind::Ping(_) => {
-            let _result = state
-                .conn
-                .load()
-                .authly_service
-                .clone()
-                .pong(tonic::Request::new(proto::Empty {}))
-                .await;
+            let mut service = state.conn.load().authly_service.clone();
+            let _result = metrics::observe_rpc(
+                &*state.metrics,
+                "pong",
+                service.pong(tonic::Request::new(proto::Empty {})),
+            )
+            .await;
         }
     }
 }
 
+/// Reconfigure the connection to Authly until it succeeds.
+///
+/// With `rotate_url`, the new connection starts out at the next Authly host instead of the current one.
 async fn reconfigure_loop(
     state: &ClientState,
     msg_stream: &mut Streaming<proto::ServiceMessage>,
     senders: &WorkerSenders,
+    rotate_url: bool,
 ) {
+    senders.set_status(ConnectionStatus::Reconnecting);
+
     loop {
-        match try_reconfigure(state, msg_stream, senders).await {
-            Ok(()) => return,
+        match try_reconfigure(state, msg_stream, senders, rotate_url).await {
+            Ok(()) => {
+                senders.set_status(ConnectionStatus::Connected);
+                return;
+            }
             Err(err) => {
                 tracing::error!(?err, "background reconfigure error");
+                senders.set_status(ConnectionStatus::Failed);
 
-                tokio::time::sleep(Duration::from_secs(10)).await;
+                tokio::time::sleep(state.reconnect_policy.retry_delay).await;
             }
         }
     }
@@ -112,11 +175,11 @@ async fn try_reconfigure(
     state: &ClientState,
     msg_stream: &mut Streaming<proto::ServiceMessage>,
     senders: &WorkerSenders,
+    rotate_url: bool,
 ) -> Result<(), Error> {
     let params = state.reconfigure.new_connection_params().await?;
-    let connection = Arc::new(make_connection(params.clone()).await?);
-
-    state.conn.store(connection.clone());
+    let first_url = state.conn.load().url_index + usize::from(rotate_url);
+    swap_connection(state, make_connection(params.clone(), first_url).await?);
 
     *msg_stream = init_message_stream(state).await?;
     reload_local_cache(state, senders).await;
@@ -128,22 +191,45 @@ async fn try_reconfigure(
     Ok(())
 }
 
+/// Switch the client over to a new connection.
+///
+/// If the Authly CA changed, the JWT decoding key of the previous CA is retired.
+pub(crate) fn swap_connection(state: &ClientState, connection: Connection) {
+    let params = connection.params.clone();
+    let previous_connection = state.conn.swap(Arc::new(connection));
+    if previous_connection.params.authly_local_ca != params.authly_local_ca {
+        // keep accepting tokens signed by the previous CA for a while
+        state
+            .retired_jwt_decoding_key
+            .store(Some(Arc::new(RetiredDecodingKey {
+                key: previous_connection.params.jwt_decoding_key.clone(),
+                algorithm: previous_connection.params.jwt_algorithm,
+                retired_at: Instant::now(),
+            })));
+    }
+}
+
 async fn init_message_stream(
     state: &ClientState,
 ) -> Result<Streaming<proto::ServiceMessage>, Error> {
     let mut current_service = state.conn.load().authly_service.clone();
-    let response = current_service
-        .messages(tonic::Request::new(proto::Empty {}))
-        .await
-        .map_err(error::tonic)?;
+    let response = metrics::observe_rpc(
+        &*state.metrics,
+        "messages",
+        current_service.messages(tonic::Request::new(proto::Empty {})),
+    )
+    .await?;
 
     Ok(response.into_inner())
 }
 
 async fn reload_local_cache(state: &ClientState, senders: &WorkerSenders) {
-    match get_configuration(state.conn.load().authly_service.clone()).await {
+    match get_configuration(state.conn.load().authly_service.clone(), &*state.metrics).await {
         Ok(configuration) => {
             state.configuration.store(Arc::new(configuration));
+            if let Some(access_token_cache) = &state.access_token_cache {
+                access_token_cache.clear();
+            }
             if let Err(err) = senders.metadata_invalidated_tx.send(()) {
                 tracing::error!(?err, "Could not publish metadata invalidated");
             }
@@ -1,9 +1,11 @@
 //! Code related to the connection to Authly.
 
-use std::{borrow::Cow, sync::Arc};
+use std::{borrow::Cow, future::Future, path::PathBuf, sync::Arc, time::Duration};
 
+use anyhow::anyhow;
 use authly_common::{id::ServiceId, proto::service::authly_service_client::AuthlyServiceClient};
-use tonic::transport::Endpoint;
+use tonic::transport::{Channel, Endpoint};
+use tracing::debug;
 
 use crate::{
     builder::{ConnectionParamsBuilder, Inference},
@@ -12,17 +14,40 @@ use crate::{
     Error,
 };
 
+#[cfg(feature = "rustls_023")]
+mod tls;
+
+#[cfg(feature = "tonic_014")]
+pub(crate) use tls::peer_channel;
+
 /// The parameters used to establish a connection to Authly.
 #[derive(Clone)]
 pub struct ConnectionParams {
     pub(crate) inference: Inference,
-    pub(crate) url: Cow<'static, str>,
+    pub(crate) urls: Vec<Cow<'static, str>>,
     pub(crate) authly_local_ca: Vec<u8>,
     pub(crate) identity: Identity,
     pub(crate) entity_id: ServiceId,
     pub(crate) jwt_decoding_key: jsonwebtoken::DecodingKey,
+    pub(crate) jwt_algorithm: jsonwebtoken::Algorithm,
+    pub(crate) token_validation: Option<Arc<jsonwebtoken::Validation>>,
+    pub(crate) min_tls_version: TlsVersion,
+    #[cfg_attr(not(feature = "rustls_023"), allow(unused))]
+    pub(crate) tls_keylog: Option<PathBuf>,
+    pub(crate) request_timeout: Duration,
+    pub(crate) connect_timeout: Duration,
+    pub(crate) tcp_keepalive: Duration,
 }
 
+/// The default deadline of requests made to Authly.
+pub(crate) const DEFAULT_REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// The default deadline of establishing a connection.
+pub(crate) const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The default idle time of connections before TCP keepalive probes are sent.
+pub(crate) const DEFAULT_TCP_KEEPALIVE: Duration = Duration::from_secs(60);
+
 impl ConnectionParams {
     /// Gets the current Authly root CA.
     pub fn ca_pem(&self) -> &[u8] {
@@ -33,24 +58,167 @@ impl ConnectionParams {
     pub fn identity(&self) -> &Identity {
         &self.identity
     }
+
+    /// Gets the minimum TLS protocol version to use for connections.
+    pub fn min_tls_version(&self) -> TlsVersion {
+        self.min_tls_version
+    }
+
+    /// Gets the deadline of each request made to Authly.
+    pub fn request_timeout(&self) -> Duration {
+        self.request_timeout
+    }
+
+    /// Gets the deadline of establishing a connection.
+    pub fn connect_timeout(&self) -> Duration {
+        self.connect_timeout
+    }
+
+    /// Gets the idle time of connections before TCP keepalive probes are sent.
+    pub fn tcp_keepalive(&self) -> Duration {
+        self.tcp_keepalive
+    }
+}
+
+/// A TLS protocol version.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default, Debug)]
+pub enum TlsVersion {
+    /// TLS 1.2
+    #[default]
+    Tls12,
+    /// TLS 1.3
+    Tls13,
+}
+
+/// The status of the client's connection to Authly.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ConnectionStatus {
+    /// The client is connected to Authly.
+    Connected,
+    /// The connection to Authly was lost, and the client is trying to reconnect.
+    Reconnecting,
+    /// The last attempt to reconnect to Authly failed. The client keeps retrying according to its [ReconnectPolicy].
+    Failed,
+}
+
+/// Policy for how the client recovers after losing its connection to Authly.
+#[derive(Clone, Debug)]
+pub struct ReconnectPolicy {
+    /// For how long the client tries to re-open a dropped message stream before doing a full reconnect.
+    ///
+    /// A zero duration means that a full reconnect starts immediately.
+    pub grace_period: Duration,
+
+    /// How long to wait between failed reconnection attempts.
+    pub retry_delay: Duration,
+}
+
+impl Default for ReconnectPolicy {
+    fn default() -> Self {
+        Self {
+            grace_period: Duration::from_secs(2),
+            retry_delay: Duration::from_secs(10),
+        }
+    }
+}
+
+/// Policy for retrying requests to Authly that failed with a transient error.
+///
+/// Only idempotent requests are retried, and only when [Error::is_retryable].
+#[derive(Clone, Debug)]
+pub struct RetryPolicy {
+    /// The maximum number of attempts, including the first one.
+    ///
+    /// A value of `1` disables retrying.
+    pub max_attempts: u32,
+
+    /// The delay before the first retry, which doubles for each subsequent retry.
+    pub base_delay: Duration,
+
+    /// The maximum delay before a retry.
+    pub max_delay: Duration,
+
+    /// Whether to randomly shorten each delay by up to a half, to spread out retries from many clients.
+    pub jitter: bool,
+}
+
+impl RetryPolicy {
+    /// A policy that never retries.
+    pub fn never() -> Self {
+        Self {
+            max_attempts: 1,
+            ..Default::default()
+        }
+    }
+
+    /// The delay before the given retry, counting from zero.
+    pub(crate) fn delay(&self, retry: u32) -> Duration {
+        let delay = self
+            .base_delay
+            .saturating_mul(2u32.saturating_pow(retry))
+            .min(self.max_delay);
+
+        if self.jitter {
+            delay.mul_f64(rand::random_range(0.5..=1.0))
+        } else {
+            delay
+        }
+    }
+
+    /// Run the operation, retrying it according to this policy.
+    pub(crate) async fn retry<T, F, Fut>(&self, mut operation: F) -> Result<T, Error>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T, Error>>,
+    {
+        let mut retry = 0;
+
+        loop {
+            match operation().await {
+                Err(err) if err.is_retryable() && retry + 1 < self.max_attempts => {
+                    let delay = self.delay(retry);
+                    debug!(?err, ?delay, "retrying request");
+
+                    tokio::time::sleep(delay).await;
+                    retry += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 3,
+            base_delay: Duration::from_millis(100),
+            max_delay: Duration::from_secs(2),
+            jitter: true,
+        }
+    }
 }
 
 pub(crate) struct Connection {
     pub authly_service: AuthlyServiceClient<tonic::transport::Channel>,
     pub params: Arc<ConnectionParams>,
+    /// The index of the connected URL within [ConnectionParams::urls]
+    pub url_index: usize,
 }
 
 #[derive(Clone)]
 pub(crate) enum ReconfigureStrategy {
-    ReInfer { url: Cow<'static, str> },
+    ReInfer {
+        template: Box<ConnectionParamsBuilder>,
+    },
     Params(Arc<ConnectionParams>),
 }
 
 impl ReconfigureStrategy {
     pub(crate) async fn new_connection_params(&self) -> Result<Arc<ConnectionParams>, Error> {
         match self {
-            Self::ReInfer { url } => {
-                let mut params_builder = ConnectionParamsBuilder::new(url.clone());
+            Self::ReInfer { template } => {
+                let mut params_builder = ConnectionParamsBuilder::clone(template);
                 params_builder.infer().await?;
                 Ok(params_builder.try_into_connection_params()?)
             }
@@ -59,7 +227,58 @@ impl ReconfigureStrategy {
     }
 }
 
-pub(crate) async fn make_connection(params: Arc<ConnectionParams>) -> Result<Connection, Error> {
+/// Connect to the first reachable Authly URL, trying them in order starting at `first_url`.
+pub(crate) async fn make_connection(
+    params: Arc<ConnectionParams>,
+    first_url: usize,
+) -> Result<Connection, Error> {
+    let (channel, url_index) =
+        connect_failover(&params.urls, first_url, |url| connect_url(&params, url)).await?;
+
+    let authly_service = AuthlyServiceClient::new(channel);
+
+    Ok(Connection {
+        authly_service,
+        params,
+        url_index,
+    })
+}
+
+/// Try connecting to each URL in turn, starting at `first` and wrapping around.
+///
+/// Returns the channel and the index of the URL that was connected to, or the last connection error.
+async fn connect_failover<'u, F, Fut>(
+    urls: &'u [Cow<'static, str>],
+    first: usize,
+    mut connect: F,
+) -> Result<(Channel, usize), Error>
+where
+    F: FnMut(&'u str) -> Fut,
+    Fut: Future<Output = Result<Channel, Error>>,
+{
+    let mut last_err = Error::Network(anyhow!("no Authly URL configured"));
+
+    for offset in 0..urls.len() {
+        let index = (first + offset) % urls.len();
+
+        match connect(&urls[index]).await {
+            Ok(channel) => return Ok((channel, index)),
+            Err(err) => {
+                debug!(?err, url = %urls[index], "could not connect to Authly");
+                last_err = err;
+            }
+        }
+    }
+
+    Err(last_err)
+}
+
+async fn connect_url(params: &ConnectionParams, url: &str) -> Result<Channel, Error> {
+    #[cfg(feature = "rustls_023")]
+    if params.min_tls_version > TlsVersion::default() || params.tls_keylog.is_some() {
+        return tls::connect_with_rustls(params, url).await;
+    }
+
     let tls_config = tonic::transport::ClientTlsConfig::new()
         .ca_certificate(tonic::transport::Certificate::from_pem(
             &params.authly_local_ca,
@@ -69,18 +288,247 @@ pub(crate) async fn make_connection(params: Arc<ConnectionParams>) -> Result<Con
             params.identity.key_pem.clone(),
         ));
 
-    let endpoint = match &params.url {
-        Cow::Borrowed(url) => Endpoint::from_static(url),
-        Cow::Owned(url) => Endpoint::from_shared(url.clone()).map_err(error::network)?,
+    let endpoint = Endpoint::from_shared(url.to_string())
+        .map_err(error::network)?
+        .tls_config(tls_config)
+        .map_err(error::network)?
+        .timeout(params.request_timeout)
+        .connect_timeout(params.connect_timeout)
+        .tcp_keepalive(Some(params.tcp_keepalive));
+
+    // tonic only applies the connect timeout to the TCP connection, not to the TLS handshake
+    tokio::time::timeout(params.connect_timeout, endpoint.connect())
+        .await
+        .map_err(|elapsed| Error::Network(elapsed.into()))?
+        .map_err(error::network)
+}
+
+/// A self-signed certificate identifying a service, and its key pair.
+#[cfg(test)]
+fn self_signed_service_cert() -> (rcgen::Certificate, rcgen::KeyPair) {
+    use rcgen::{CertificateParams, DnType, KeyPair};
+
+    let key_pair = KeyPair::generate().unwrap();
+    let mut cert_params = CertificateParams::new(vec![]).unwrap();
+    cert_params.distinguished_name.push(
+        DnType::CustomDnType(authly_common::certificate::oid::ENTITY_UNIQUE_IDENTIFIER.to_vec()),
+        ServiceId::from_uint(0xf001).to_string(),
+    );
+    let cert = cert_params.self_signed(&key_pair).unwrap();
+
+    (cert, key_pair)
+}
+
+#[tokio::test]
+async fn test_request_timeout() {
+    use authly_common::proto::service as proto;
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    // accept connections, but never respond
+    tokio::spawn(async move {
+        let mut connections = vec![];
+        while let Ok((stream, _)) = listener.accept().await {
+            connections.push(stream);
+        }
+    });
+
+    let (cert, key_pair) = self_signed_service_cert();
+
+    let url = format!("http://{addr}");
+    let params = crate::Client::builder()
+        .with_url(url.clone())
+        .with_authly_local_ca_pem(cert.pem().into_bytes())
+        .unwrap()
+        .with_identity(
+            Identity::from_pem(format!("{}{}", cert.pem(), key_pair.serialize_pem())).unwrap(),
+        )
+        .with_request_timeout(Duration::from_millis(200))
+        .inner
+        .try_into_connection_params()
+        .unwrap();
+    assert_eq!(Duration::from_millis(200), params.request_timeout());
+
+    let channel = connect_url(&params, &url).await.unwrap();
+
+    let started = std::time::Instant::now();
+    let result = AuthlyServiceClient::new(channel)
+        .get_metadata(proto::Empty::default())
+        .await
+        .map_err(error::tonic);
+
+    assert!(matches!(result, Err(Error::Network(_))));
+    assert!(started.elapsed() < Duration::from_secs(5));
+}
+
+#[tokio::test]
+async fn test_retry_policy() {
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    let policy = RetryPolicy {
+        max_attempts: 3,
+        base_delay: Duration::from_millis(1),
+        max_delay: Duration::from_millis(10),
+        jitter: true,
+    };
+
+    // A flaky operation failing `failures` times before succeeding
+    let flaky = |attempts: &AtomicU32, failures: u32, code: tonic::Code| {
+        let attempt = attempts.fetch_add(1, Ordering::SeqCst);
+        async move {
+            if attempt < failures {
+                Err(error::tonic(tonic::Status::new(code, "flaky")))
+            } else {
+                Ok(attempt)
+            }
+        }
+    };
+
+    let attempts = AtomicU32::new(0);
+    let result = policy
+        .retry(|| flaky(&attempts, 2, tonic::Code::Unavailable))
+        .await;
+    assert_eq!(2, result.unwrap());
+    assert_eq!(3, attempts.load(Ordering::SeqCst));
+
+    let attempts = AtomicU32::new(0);
+    let result = policy
+        .retry(|| flaky(&attempts, 3, tonic::Code::ResourceExhausted))
+        .await;
+    assert!(matches!(result, Err(Error::Network(_))));
+    assert_eq!(3, attempts.load(Ordering::SeqCst));
+
+    let attempts = AtomicU32::new(0);
+    let result = policy
+        .retry(|| flaky(&attempts, 2, tonic::Code::PermissionDenied))
+        .await;
+    assert!(matches!(result, Err(Error::Unauthorized(_))));
+    assert_eq!(1, attempts.load(Ordering::SeqCst));
+
+    let attempts = AtomicU32::new(0);
+    let result = RetryPolicy::never()
+        .retry(|| flaky(&attempts, 2, tonic::Code::Unavailable))
+        .await;
+    assert!(matches!(result, Err(Error::Network(_))));
+    assert_eq!(1, attempts.load(Ordering::SeqCst));
+}
+
+#[tokio::test]
+async fn test_connect_failover() {
+    // a port that refuses connections
+    let refused_addr = {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        listener.local_addr().unwrap()
+    };
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        let mut connections = vec![];
+        while let Ok((stream, _)) = listener.accept().await {
+            connections.push(stream);
+        }
+    });
+
+    let urls: Vec<Cow<'static, str>> = vec![
+        format!("http://{refused_addr}").into(),
+        format!("http://{addr}").into(),
+    ];
+    let connect = |url: &str| {
+        let endpoint = Endpoint::from_shared(url.to_string()).unwrap();
+        async move { endpoint.connect().await.map_err(error::unclassified) }
+    };
+
+    let (_, index) = connect_failover(&urls, 0, connect).await.unwrap();
+    assert_eq!(1, index);
+
+    // rotating past the last URL wraps around to the working one
+    let (_, index) = connect_failover(&urls, 2, connect).await.unwrap();
+    assert_eq!(1, index);
+
+    let result = connect_failover(&urls[..1], 0, connect).await;
+    assert!(result.is_err());
+
+    let result = connect_failover(&[], 0, connect).await;
+    assert!(matches!(result, Err(Error::Network(_))));
+}
+
+#[tokio::test]
+async fn test_client_connect_failover() {
+    // a port that refuses connections
+    let refused_addr = {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        listener.local_addr().unwrap()
+    };
+    let addr = crate::mock::MockAuthly::default().serve().await;
+
+    let (cert, key_pair) = self_signed_service_cert();
+    let client = crate::Client::builder()
+        .with_urls(vec![
+            format!("http://{refused_addr}"),
+            format!("http://{addr}"),
+        ])
+        .with_authly_local_ca_pem(cert.pem().into_bytes())
+        .unwrap()
+        .with_identity(
+            Identity::from_pem(format!("{}{}", cert.pem(), key_pair.serialize_pem())).unwrap(),
+        )
+        .connect()
+        .await
+        .unwrap();
+
+    assert_eq!(1, client.state.conn.load().url_index);
+    client.health_check().await.unwrap();
+}
+
+#[tokio::test]
+async fn test_connect_error_kind() {
+    use crate::ErrorKind;
+
+    // a port that refuses connections
+    let refused_addr = {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        listener.local_addr().unwrap()
+    };
+    let (cert, key_pair) = self_signed_service_cert();
+
+    for url in ["not a url".to_string(), format!("https://{refused_addr}")] {
+        let mut params_builder = ConnectionParamsBuilder::new(url.clone().into());
+        params_builder.authly_local_ca = Some(cert.pem().into_bytes());
+        params_builder.identity = Some(
+            Identity::from_pem(format!("{}{}", cert.pem(), key_pair.serialize_pem())).unwrap(),
+        );
+        let params = params_builder.try_into_connection_params().unwrap();
+
+        let err = connect_url(&params, &url).await.err().unwrap();
+        assert_eq!(ErrorKind::Network, err.kind(), "{url}: {err:?}");
+        assert!(!err.is_auth(), "{url}: {err:?}");
     }
-    .tls_config(tls_config)
-    .map_err(error::network)?;
+}
 
-    let authly_service =
-        AuthlyServiceClient::new(endpoint.connect().await.map_err(error::unclassified)?);
+#[tokio::test]
+async fn test_connect_timeout() {
+    let (cert, key_pair) = self_signed_service_cert();
 
-    Ok(Connection {
-        authly_service,
-        params,
-    })
+    // a bound socket that never accepts, so the TLS handshake is never answered
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("https://{}", listener.local_addr().unwrap());
+
+    for min_tls_version in [TlsVersion::Tls12, TlsVersion::Tls13] {
+        let mut params_builder = ConnectionParamsBuilder::new(url.clone().into());
+        params_builder.authly_local_ca = Some(cert.pem().into_bytes());
+        params_builder.identity = Some(
+            Identity::from_pem(format!("{}{}", cert.pem(), key_pair.serialize_pem())).unwrap(),
+        );
+        params_builder.min_tls_version = min_tls_version;
+        params_builder.connect_timeout = Duration::from_millis(200);
+        let params = params_builder.try_into_connection_params().unwrap();
+
+        let started = std::time::Instant::now();
+        let result = connect_url(&params, &url).await;
+
+        assert!(result.is_err());
+        assert!(started.elapsed() < Duration::from_secs(5));
+    }
 }
@@ -0,0 +1,364 @@
+//! Connections using a custom rustls configuration, for TLS settings that tonic does not support.
+
+use std::{
+    fmt::Write as _,
+    future::Future,
+    io::{self, Write as _},
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use hyper_util::rt::TokioIo;
+use rustls::RootCertStore;
+use rustls_pki_types::{pem::PemObject, CertificateDer, ServerName};
+use tonic::transport::{Channel, Endpoint};
+use tracing::debug;
+
+use crate::{error, identity::Identity, Error};
+
+use super::{ConnectionParams, TlsVersion};
+
+impl TlsVersion {
+    /// The rustls protocol versions that are at least this version.
+    pub(crate) fn rustls_protocol_versions(
+        self,
+    ) -> &'static [&'static rustls::SupportedProtocolVersion] {
+        static TLS13_ONLY: &[&rustls::SupportedProtocolVersion] = &[&rustls::version::TLS13];
+
+        match self {
+            Self::Tls12 => rustls::ALL_VERSIONS,
+            Self::Tls13 => TLS13_ONLY,
+        }
+    }
+}
+
+/// The rustls client configuration used when connecting to Authly.
+fn rustls_client_config(
+    authly_local_ca: &[u8],
+    identity: &Identity,
+    min_tls_version: TlsVersion,
+) -> Result<rustls::ClientConfig, Error> {
+    let mut root_cert_store = RootCertStore::empty();
+    root_cert_store
+        .add(
+            CertificateDer::from_pem_slice(authly_local_ca)
+                .map_err(|_err| Error::AuthlyCA("unable to parse"))?,
+        )
+        .map_err(|_err| Error::AuthlyCA("unable to include in root cert store"))?;
+
+    let provider = rustls::crypto::CryptoProvider::get_default()
+        .cloned()
+        .unwrap_or_else(|| Arc::new(rustls::crypto::ring::default_provider()));
+
+    let mut tls_config = rustls::ClientConfig::builder_with_provider(provider)
+        .with_protocol_versions(min_tls_version.rustls_protocol_versions())
+        .map_err(|_| Error::Tls("unsupported protocol versions"))?
+        .with_root_certificates(root_cert_store)
+        .with_client_auth_cert(
+            vec![identity.cert_der.clone()],
+            identity.key_der.clone_key(),
+        )
+        .map_err(|_| Error::Tls("Unable to configure client"))?;
+    tls_config.alpn_protocols = vec![b"h2".to_vec()];
+
+    Ok(tls_config)
+}
+
+/// The rustls client configuration for the given connection parameters, including the TLS key log, if enabled.
+fn params_tls_config(params: &ConnectionParams) -> Result<rustls::ClientConfig, Error> {
+    let mut tls_config = rustls_client_config(
+        &params.authly_local_ca,
+        &params.identity,
+        params.min_tls_version,
+    )?;
+
+    if let Some(path) = &params.tls_keylog {
+        tls_config.key_log = Arc::new(KeyLogPath::open(path)?);
+    }
+
+    Ok(tls_config)
+}
+
+/// Connect using a custom rustls configuration.
+///
+/// tonic's TLS configuration does not support restricting protocol versions or logging keys,
+/// so the TLS handshake is instead performed by the connector.
+pub(super) async fn connect_with_rustls(
+    params: &ConnectionParams,
+    url: &str,
+) -> Result<Channel, Error> {
+    let tls_config = params_tls_config(params)?;
+
+    rustls_endpoint(url)?
+        .timeout(params.request_timeout)
+        .connect_timeout(params.connect_timeout)
+        .connect_with_connector(rustls_connector(tls_config, params.tcp_keepalive))
+        .await
+        .map_err(error::network)
+}
+
+/// Make a lazily connecting channel to a peer service in the Authly service mesh,
+/// authenticating with the Authly identity over mutual TLS.
+#[cfg(feature = "tonic_014")]
+pub(crate) fn peer_channel(params: &ConnectionParams, url: &str) -> Result<Channel, Error> {
+    Ok(rustls_endpoint(url)?
+        .connect_timeout(params.connect_timeout)
+        .connect_with_connector_lazy(rustls_connector(
+            params_tls_config(params)?,
+            params.tcp_keepalive,
+        )))
+}
+
+/// An endpoint for connecting with [rustls_connector].
+fn rustls_endpoint(url: &str) -> Result<Endpoint, Error> {
+    // tonic refuses `https` without its own TLS, which is bypassed here.
+    let mut url_parts = http::Uri::try_from(url)
+        .map_err(error::network)?
+        .into_parts();
+    url_parts.scheme = Some(http::uri::Scheme::HTTP);
+
+    Ok(Endpoint::from(
+        http::Uri::from_parts(url_parts).map_err(error::network)?,
+    ))
+}
+
+/// A connector performing the TLS handshake with the given rustls configuration.
+///
+/// The connect timeout is applied by the [Endpoint], but TCP keepalive has to be set up by the connector.
+fn rustls_connector(tls_config: rustls::ClientConfig, tcp_keepalive: Duration) -> RustlsConnector {
+    RustlsConnector {
+        tls: tokio_rustls::TlsConnector::from(Arc::new(tls_config)),
+        tcp_keepalive,
+    }
+}
+
+/// A [rustls::KeyLog] appending TLS secrets to a file, in NSS key log format.
+#[derive(Debug)]
+struct KeyLogPath(Mutex<std::fs::File>);
+
+impl KeyLogPath {
+    fn open(path: &std::path::Path) -> Result<Self, Error> {
+        let file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .map_err(|_| Error::Tls("unable to open TLS key log file"))?;
+
+        Ok(Self(Mutex::new(file)))
+    }
+}
+
+impl rustls::KeyLog for KeyLogPath {
+    fn log(&self, label: &str, client_random: &[u8], secret: &[u8]) {
+        let mut line = format!("{label} ");
+        for byte in client_random {
+            let _ = write!(line, "{byte:02x}");
+        }
+        line.push(' ');
+        for byte in secret {
+            let _ = write!(line, "{byte:02x}");
+        }
+        line.push('\n');
+
+        if let Err(err) = self.0.lock().unwrap().write_all(line.as_bytes()) {
+            debug!(?err, "could not write TLS key log");
+        }
+    }
+}
+
+#[derive(Clone)]
+struct RustlsConnector {
+    tls: tokio_rustls::TlsConnector,
+    tcp_keepalive: Duration,
+}
+
+impl tower::Service<http::Uri> for RustlsConnector {
+    type Response = TokioIo<tokio_rustls::client::TlsStream<tokio::net::TcpStream>>;
+    type Error = io::Error;
+    type Future = Pin<Box<dyn Future<Output = io::Result<Self::Response>> + Send>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, uri: http::Uri) -> Self::Future {
+        let tls_connector = self.tls.clone();
+        let tcp_keepalive = socket2::TcpKeepalive::new().with_time(self.tcp_keepalive);
+
+        Box::pin(async move {
+            let host = uri
+                .host()
+                .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "missing host"))?
+                .to_string();
+            let port = uri.port_u16().unwrap_or(443);
+
+            let tcp = tokio::net::TcpStream::connect((host.as_str(), port)).await?;
+            socket2::SockRef::from(&tcp).set_tcp_keepalive(&tcp_keepalive)?;
+            let server_name = ServerName::try_from(host)
+                .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+            let tls = tls_connector.connect(server_name, tcp).await?;
+
+            Ok(TokioIo::new(tls))
+        })
+    }
+}
+
+#[test]
+fn test_identity_client_config() {
+    use rustls_pki_types::PrivateKeyDer;
+
+    let (cert, key_pair) = super::self_signed_service_cert();
+
+    let from_pem =
+        Identity::from_pem(format!("{}{}", cert.pem(), key_pair.serialize_pem())).unwrap();
+    let from_der = Identity::from_der(
+        cert.der().clone(),
+        PrivateKeyDer::Pkcs8(key_pair.serialize_der().into()),
+    );
+
+    assert_eq!(from_pem.cert_der(), from_der.cert_der());
+    assert_eq!(
+        from_pem.key_der().secret_der(),
+        from_der.key_der().secret_der()
+    );
+    assert_eq!(from_pem.cert_pem(), from_der.cert_pem());
+    assert_eq!(from_pem.key_pem(), from_der.key_pem());
+
+    for identity in [from_pem, from_der] {
+        rustls_client_config(cert.pem().as_bytes(), &identity, TlsVersion::Tls13).unwrap();
+    }
+}
+
+/// A TLS server on `127.0.0.1` only supporting the given protocol versions,
+/// which completes handshakes and nothing more.
+///
+/// Returns the PEM of the CA that issued the server certificate, and the server address.
+#[cfg(test)]
+async fn tls_handshake_server(
+    protocol_versions: &[&'static rustls::SupportedProtocolVersion],
+) -> (String, std::net::SocketAddr) {
+    use rcgen::{BasicConstraints, CertificateParams, IsCa, Issuer, KeyPair};
+
+    let ca_key = KeyPair::generate().unwrap();
+    let mut ca_params = CertificateParams::new(vec![]).unwrap();
+    ca_params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+    let ca_cert = ca_params.self_signed(&ca_key).unwrap();
+    let issuer = Issuer::new(ca_params, ca_key);
+
+    let server_key = KeyPair::generate().unwrap();
+    let server_cert = CertificateParams::new(vec!["127.0.0.1".to_string()])
+        .unwrap()
+        .signed_by(&server_key, &issuer)
+        .unwrap();
+    let server_config = rustls::ServerConfig::builder_with_provider(Arc::new(
+        rustls::crypto::ring::default_provider(),
+    ))
+    .with_protocol_versions(protocol_versions)
+    .unwrap()
+    .with_no_client_auth()
+    .with_single_cert(
+        vec![server_cert.der().clone()],
+        rustls_pki_types::PrivateKeyDer::Pkcs8(server_key.serialize_der().into()),
+    )
+    .unwrap();
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let acceptor = tokio_rustls::TlsAcceptor::from(Arc::new(server_config));
+    tokio::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            let acceptor = acceptor.clone();
+            tokio::spawn(async move {
+                let _tls = acceptor.accept(stream).await;
+            });
+        }
+    });
+
+    (ca_cert.pem(), addr)
+}
+
+#[tokio::test]
+async fn test_min_tls_version() {
+    use tower::ServiceExt;
+
+    // a peer only supporting TLS 1.2
+    let (ca_pem, addr) = tls_handshake_server(&[&rustls::version::TLS12]).await;
+    let (cert, key_pair) = super::self_signed_service_cert();
+
+    for (min_tls_version, accepted) in [(TlsVersion::Tls12, true), (TlsVersion::Tls13, false)] {
+        let params = crate::Client::builder()
+            .with_url(format!("https://{addr}"))
+            .with_authly_local_ca_pem(ca_pem.clone().into_bytes())
+            .unwrap()
+            .with_identity(
+                Identity::from_pem(format!("{}{}", cert.pem(), key_pair.serialize_pem())).unwrap(),
+            )
+            .with_min_tls_version(min_tls_version)
+            .inner
+            .try_into_connection_params()
+            .unwrap();
+
+        let result = rustls_connector(
+            params_tls_config(&params).unwrap(),
+            super::DEFAULT_TCP_KEEPALIVE,
+        )
+        .oneshot(format!("http://{addr}").parse().unwrap())
+        .await;
+        assert_eq!(accepted, result.is_ok(), "{min_tls_version:?}");
+    }
+}
+
+#[cfg(feature = "tonic_014")]
+#[tokio::test]
+async fn test_peer_tls_config() {
+    let (cert, key_pair) = super::self_signed_service_cert();
+
+    let mut params_builder = crate::builder::ConnectionParamsBuilder::new("https://authly".into());
+    params_builder.authly_local_ca = Some(cert.pem().into_bytes());
+    params_builder.identity =
+        Some(Identity::from_pem(format!("{}{}", cert.pem(), key_pair.serialize_pem())).unwrap());
+    let params = params_builder.try_into_connection_params().unwrap();
+
+    let tls_config = params_tls_config(&params).unwrap();
+    assert!(tls_config.client_auth_cert_resolver.has_certs());
+    assert_eq!(vec![b"h2".to_vec()], tls_config.alpn_protocols);
+
+    peer_channel(&params, "https://peer.local:1234").unwrap();
+}
+
+#[cfg(feature = "dangerous-tls-keylog")]
+#[tokio::test]
+async fn test_tls_keylog() {
+    use tower::ServiceExt;
+
+    let (ca_pem, addr) = tls_handshake_server(rustls::DEFAULT_VERSIONS).await;
+    let (cert, key_pair) = super::self_signed_service_cert();
+
+    let keylog_path = std::env::temp_dir().join(format!("authly-keylog-{}", std::process::id()));
+    let mut params_builder = crate::builder::ConnectionParamsBuilder::new("https://authly".into());
+    params_builder.authly_local_ca = Some(ca_pem.into_bytes());
+    params_builder.identity =
+        Some(Identity::from_pem(format!("{}{}", cert.pem(), key_pair.serialize_pem())).unwrap());
+    params_builder.tls_keylog = Some(keylog_path.clone());
+    let params = params_builder.try_into_connection_params().unwrap();
+
+    rustls_connector(
+        params_tls_config(&params).unwrap(),
+        super::DEFAULT_TCP_KEEPALIVE,
+    )
+    .oneshot(format!("http://{addr}").parse().unwrap())
+    .await
+    .unwrap();
+
+    let keylog = std::fs::read_to_string(&keylog_path).unwrap();
+    std::fs::remove_file(&keylog_path).unwrap();
+
+    assert!(!keylog.is_empty());
+    for line in keylog.lines() {
+        let parts: Vec<&str> = line.split(' ').collect();
+        assert_eq!(3, parts.len(), "{line}");
+        assert_eq!(64, parts[1].len());
+    }
+}
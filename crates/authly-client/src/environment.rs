@@ -0,0 +1,62 @@
+//! Access to the environment the client runs in, used for inferring the client configuration.
+
+use std::path::Path;
+
+/// The environment the client is inferred from.
+///
+/// Configured with [ClientBuilder::with_environment](crate::ClientBuilder::with_environment).
+/// The default is [SystemEnvironment].
+pub trait Environment: Send + Sync {
+    /// Read the contents of a file.
+    fn read_file(&self, path: &Path) -> std::io::Result<Vec<u8>>;
+
+    /// Check whether a file exists.
+    fn file_exists(&self, path: &Path) -> bool;
+
+    /// Read an environment variable, if set and valid unicode.
+    fn var(&self, key: &str) -> Option<String>;
+}
+
+/// The [Environment] of the running process, backed by the file system and process environment variables.
+#[derive(Clone, Copy, Default, Debug)]
+pub struct SystemEnvironment;
+
+impl Environment for SystemEnvironment {
+    fn read_file(&self, path: &Path) -> std::io::Result<Vec<u8>> {
+        std::fs::read(path)
+    }
+
+    fn file_exists(&self, path: &Path) -> bool {
+        std::fs::exists(path).unwrap_or(false)
+    }
+
+    fn var(&self, key: &str) -> Option<String> {
+        std::env::var(key).ok()
+    }
+}
+
+/// An in-memory [Environment] for tests.
+#[cfg(test)]
+#[derive(Default)]
+pub(crate) struct FakeEnvironment {
+    pub files: std::collections::HashMap<std::path::PathBuf, Vec<u8>>,
+    pub vars: std::collections::HashMap<String, String>,
+}
+
+#[cfg(test)]
+impl Environment for FakeEnvironment {
+    fn read_file(&self, path: &Path) -> std::io::Result<Vec<u8>> {
+        self.files
+            .get(path)
+            .cloned()
+            .ok_or_else(|| std::io::ErrorKind::NotFound.into())
+    }
+
+    fn file_exists(&self, path: &Path) -> bool {
+        self.files.contains_key(path)
+    }
+
+    fn var(&self, key: &str) -> Option<String> {
+        self.vars.get(key).cloned()
+    }
+}
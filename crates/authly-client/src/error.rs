@@ -30,9 +30,9 @@ pub enum Error {
     )]
     EnvironmentNotInferrable,
 
-    /// Invalid Common Name in certificate signing request.
-    #[error("invalid X509 alt names")]
-    InvalidAltNames,
+    /// Invalid subject alternative names in certificate signing request.
+    #[error("invalid X509 alt names: {0}")]
+    InvalidAltNames(anyhow::Error),
 
     /// A party was not authenticated or an operation was forbidden.
     #[error("unauthorized: {0}")]
@@ -58,11 +58,81 @@ pub enum Error {
     #[error("access denied")]
     AccessDenied,
 
+    /// Access control enforcement has resulted in "deny", for a reason given by Authly.
+    #[error("access denied: {0}")]
+    AccessDeniedReason(String),
+
     /// Other type of unclassified error.
     #[error("unclassified error: {0}")]
     Unclassified(anyhow::Error),
 }
 
+/// A classification of [Error]s.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+#[non_exhaustive]
+pub enum ErrorKind {
+    /// The client is misconfigured, or its environment is incomplete.
+    Configuration,
+
+    /// A party was not authenticated, or access was denied.
+    Auth,
+
+    /// A problem communicating with Authly.
+    Network,
+
+    /// A problem encoding or decoding data.
+    Codec,
+
+    /// An unclassified problem.
+    Other,
+}
+
+impl Error {
+    /// The classification of this error.
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            Self::PrivateKeyGen
+            | Self::AuthlyCAmissingInEtc
+            | Self::AuthlyCA(_)
+            | Self::Identity(_)
+            | Self::Tls(_)
+            | Self::EnvironmentNotInferrable
+            | Self::InvalidAltNames(_)
+            | Self::InvalidPropertyAttributeLabel => ErrorKind::Configuration,
+            Self::Unauthorized(_)
+            | Self::InvalidAccessToken(_)
+            | Self::AccessDenied
+            | Self::AccessDeniedReason(_) => ErrorKind::Auth,
+            Self::Network(_) => ErrorKind::Network,
+            Self::Codec(_) => ErrorKind::Codec,
+            Self::Unclassified(_) => ErrorKind::Other,
+        }
+    }
+
+    /// Whether the error is transient, so that the failed operation may succeed if retried.
+    ///
+    /// Network and unclassified errors are retryable,
+    /// except for Authly responses with a status code indicating a permanent failure.
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            Self::Network(err) => match err.downcast_ref::<tonic::Status>() {
+                Some(status) => matches!(
+                    status.code(),
+                    tonic::Code::Unavailable | tonic::Code::ResourceExhausted
+                ),
+                None => true,
+            },
+            Self::Unclassified(_) => true,
+            _ => false,
+        }
+    }
+
+    /// Whether the error is caused by missing authentication or authorization.
+    pub fn is_auth(&self) -> bool {
+        self.kind() == ErrorKind::Auth
+    }
+}
+
 pub(crate) fn unclassified(err: impl std::error::Error + Send + Sync + 'static) -> Error {
     Error::Unclassified(anyhow::Error::from(err))
 }
@@ -76,9 +146,69 @@ pub(crate) fn tonic(err: tonic::Status) -> Error {
 }
 
 pub(crate) fn network(err: impl std::error::Error + Send + Sync + 'static) -> Error {
-    Error::Unauthorized(anyhow::Error::from(err))
+    Error::Network(anyhow::Error::from(err))
 }
 
 pub(crate) fn unauthorized(err: impl std::error::Error + Send + Sync + 'static) -> Error {
     Error::Unauthorized(anyhow::Error::from(err))
 }
+
+#[test]
+fn test_error_classification() {
+    use anyhow::anyhow;
+
+    let configuration = [
+        Error::PrivateKeyGen,
+        Error::AuthlyCAmissingInEtc,
+        Error::AuthlyCA("test"),
+        Error::Identity("test"),
+        Error::Tls("test"),
+        Error::EnvironmentNotInferrable,
+        Error::InvalidAltNames(anyhow!("test")),
+        Error::InvalidPropertyAttributeLabel,
+    ];
+    for err in configuration {
+        assert_eq!(ErrorKind::Configuration, err.kind(), "{err:?}");
+        assert!(!err.is_retryable(), "{err:?}");
+        assert!(!err.is_auth(), "{err:?}");
+    }
+
+    let auth = [
+        Error::Unauthorized(anyhow!("test")),
+        Error::InvalidAccessToken(anyhow!("test")),
+        Error::AccessDenied,
+        Error::AccessDeniedReason("test".to_string()),
+        tonic(tonic::Status::unauthenticated("test")),
+        tonic(tonic::Status::permission_denied("test")),
+    ];
+    for err in auth {
+        assert_eq!(ErrorKind::Auth, err.kind(), "{err:?}");
+        assert!(!err.is_retryable(), "{err:?}");
+        assert!(err.is_auth(), "{err:?}");
+    }
+
+    for err in [
+        Error::Network(anyhow!("test")),
+        network(std::io::Error::from(std::io::ErrorKind::ConnectionRefused)),
+        tonic(tonic::Status::unavailable("test")),
+        tonic(tonic::Status::resource_exhausted("test")),
+    ] {
+        assert_eq!(ErrorKind::Network, err.kind(), "{err:?}");
+        assert!(err.is_retryable(), "{err:?}");
+        assert!(!err.is_auth(), "{err:?}");
+    }
+
+    let permanent_network = tonic(tonic::Status::invalid_argument("test"));
+    assert_eq!(ErrorKind::Network, permanent_network.kind());
+    assert!(!permanent_network.is_retryable());
+
+    let codec = Error::Codec(anyhow!("test"));
+    assert_eq!(ErrorKind::Codec, codec.kind());
+    assert!(!codec.is_retryable());
+    assert!(!codec.is_auth());
+
+    let unclassified = Error::Unclassified(anyhow!("test"));
+    assert_eq!(ErrorKind::Other, unclassified.kind());
+    assert!(unclassified.is_retryable());
+    assert!(!unclassified.is_auth());
+}
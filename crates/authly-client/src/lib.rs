@@ -6,43 +6,62 @@
 #![warn(missing_docs)]
 
 pub use access_control::AccessControl;
-pub use authly_common::service::NamespacePropertyMapping;
-pub use builder::ClientBuilder;
+use access_control::AccessControlRequestBuilder;
+pub use authly_common::service::{NamespacePropertyMapping, PropertyMappings};
 use builder::ConnectionParamsBuilder;
-use connection::{Connection, ConnectionParams, ReconfigureStrategy};
-pub use error::Error;
-use futures_util::{stream::BoxStream, StreamExt};
+pub use builder::{ClientBuilder, InferencePaths};
+use connection::{
+    Connection, ConnectionParams, ConnectionStatus, ReconfigureStrategy, ReconnectPolicy,
+    RetryPolicy,
+};
+use environment::{Environment, SystemEnvironment};
+pub use error::{Error, ErrorKind};
+use futures_util::{stream::BoxStream, StreamExt, TryStreamExt};
 use metadata::{NamespaceMetadata, ServiceMetadata};
+use metrics::{Metrics, NoOpMetrics};
 use rcgen::{CertificateParams, DnType, ExtendedKeyUsagePurpose, KeyPair, KeyUsagePurpose};
 use rustls_pki_types::{CertificateDer, PrivateKeyDer};
 pub use token::AccessToken;
+use token::{AccessTokenCache, SessionTokenScheme};
 
-use arc_swap::ArcSwap;
+use arc_swap::{ArcSwap, ArcSwapOption};
 use tracing::info;
 
 use std::{borrow::Cow, sync::Arc, time::Duration};
 
 use anyhow::anyhow;
 use authly_common::{
-    access_token::AuthlyAccessTokenClaims,
     id::{Id128DynamicArrayConv, ServiceId},
+    policy::engine::PolicyEngine,
     proto::{
         proto_struct_to_json,
         service::{self as proto, authly_service_client::AuthlyServiceClient},
     },
 };
-use http::header::COOKIE;
+use http::header::{AUTHORIZATION, COOKIE};
+use tokio::task::JoinHandle;
 use tonic::{transport::Channel, Request};
 
 pub mod access_control;
 pub mod connection;
+pub mod environment;
 pub mod identity;
 pub mod metadata;
+pub mod metrics;
+#[cfg(feature = "axum")]
+pub mod middleware;
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
 pub mod token;
 
 mod background_worker;
 mod builder;
 mod error;
+#[cfg(test)]
+mod mock;
+
+/// Environment variable overriding the Authly URL.
+const AUTHLY_URL_VAR: &str = "AUTHLY_URL";
 
 /// File path for the root CA certificate.
 #[expect(unused)]
@@ -57,6 +76,43 @@ const IDENTITY_PATH: &str = "/etc/authly/identity/identity.pem";
 /// File path for detecting a valid kubernetes environment.
 const K8S_SA_TOKENFILE_PATH: &str = "/var/run/secrets/kubernetes.io/serviceaccount/token";
 
+/// URL of the Authly endpoint that exchanges a kubernetes service account token for a client certificate.
+const K8S_AUTH_URL: &str = "https://authly-k8s/api/v0/authenticate";
+
+/// The maximum number of concurrent access control requests made by [Client::filter_allowed].
+const FILTER_ALLOWED_CONCURRENCY: usize = 16;
+
+/// The deadline of [Client::health_check].
+const HEALTH_CHECK_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The default validity period of server certificates.
+const DEFAULT_SERVER_CERT_VALIDITY: Duration = Duration::from_secs(365 * 24 * 60 * 60);
+
+/// The default quiet period awaited after a reconfiguration before it is published by the client streams.
+const DEFAULT_RECONFIGURE_DEBOUNCE: Duration = Duration::from_millis(500);
+
+/// The maximum validity period of server certificates signed by Authly.
+const MAX_SERVER_CERT_VALIDITY: Duration = DEFAULT_SERVER_CERT_VALIDITY;
+
+/// How far back in time the validity of server certificates starts.
+const SERVER_CERT_CLOCK_SKEW: Duration = Duration::from_secs(5 * 60);
+
+/// How long a replaced JWT decoding key is still accepted after a CA rotation.
+const JWT_DECODING_KEY_GRACE_PERIOD: Duration = Duration::from_secs(5 * 60);
+
+/// How long before its expiry [Client::access_token_stream] refreshes an access token.
+///
+/// Short-lived tokens are refreshed halfway through their remaining lifetime instead.
+const ACCESS_TOKEN_REFRESH_MARGIN: Duration = Duration::from_secs(30);
+
+/// The shortest delay before [Client::access_token_stream] refreshes an access token.
+///
+/// Avoids refreshing in a tight loop when tokens are issued already expired, e.g. because of clock skew.
+const ACCESS_TOKEN_MIN_REFRESH_DELAY: Duration = Duration::from_secs(1);
+
+/// Base settings applied to each [reqwest::ClientBuilder] made by the client.
+type ReqwestBase = Arc<dyn Fn(reqwest::ClientBuilder) -> reqwest::ClientBuilder + Send + Sync>;
+
 /// The authly client handle.
 #[derive(Clone)]
 pub struct Client {
@@ -71,21 +127,70 @@ struct ClientState {
     /// How to reconfigure the connection
     reconfigure: ReconfigureStrategy,
 
+    /// How to recover from a lost connection
+    reconnect_policy: ReconnectPolicy,
+
+    /// How to retry requests that failed with a transient error
+    retry_policy: RetryPolicy,
+
     /// Triggered when the client connection parameters get reconfigured
     #[allow(unused)]
     reconfigured_rx: tokio::sync::watch::Receiver<Arc<ConnectionParams>>,
 
+    /// How long reconfigurations must settle before they are published by the client streams
+    reconfigure_debounce: Duration,
+
     /// Triggered when the cache is cleared => service metadata invalidated
     metadata_invalidated_rx: tokio::sync::watch::Receiver<()>,
 
-    /// signal sent when the state is dropped
+    /// The status of the connection, updated by the background worker
+    status_rx: tokio::sync::watch::Receiver<ConnectionStatus>,
+
+    /// signal sent when the state is dropped or the client is shut down
     closed_tx: tokio::sync::watch::Sender<()>,
 
+    /// The background worker task, taken by the first [Client::shutdown]
+    worker: std::sync::Mutex<Option<JoinHandle<()>>>,
+
     /// current configuration
     configuration: ArcSwap<Configuration>,
+
+    /// The JWT decoding key that was in use before the last CA rotation
+    retired_jwt_decoding_key: ArcSwapOption<RetiredDecodingKey>,
+
+    /// Cached session token exchanges, if enabled
+    access_token_cache: Option<AccessTokenCache>,
+
+    /// How session tokens are passed to Authly
+    session_token_scheme: SessionTokenScheme,
+
+    /// Base settings applied to each [reqwest::ClientBuilder] made by the client
+    #[cfg_attr(not(feature = "reqwest_012"), allow(unused))]
+    reqwest_base: ReqwestBase,
+
+    /// The requested validity period of server certificates
+    server_cert_validity: Duration,
+
+    /// The interval of proactive server certificate rotation, if enabled
+    #[cfg_attr(not(feature = "rustls_023"), allow(unused))]
+    server_cert_rotation: Option<Duration>,
+
+    /// The expiry time of the most recently issued server certificate
+    server_cert_not_after: ArcSwapOption<time::OffsetDateTime>,
+
+    /// Receiver of metrics about requests to Authly
+    metrics: Arc<dyn Metrics>,
+}
+
+/// A JWT decoding key that has been replaced, but is still accepted for a grace period.
+struct RetiredDecodingKey {
+    key: jsonwebtoken::DecodingKey,
+    algorithm: jsonwebtoken::Algorithm,
+    retired_at: tokio::time::Instant,
 }
 
-struct Configuration {
+/// The configuration of this service, as provided by Authly.
+pub struct Configuration {
     /// service hosts
     hosts: Vec<String>,
 
@@ -94,6 +199,21 @@ struct Configuration {
     /// For that to work, the client should keep a subscription option and listen
     /// for change events and re-download the property mapping.
     resource_property_mapping: Arc<NamespacePropertyMapping>,
+
+    /// The policy engine for local access control evaluation, if Authly provided the policies.
+    policy_engine: Option<Arc<PolicyEngine>>,
+}
+
+impl Configuration {
+    /// The hosts that can be used to reach this service.
+    pub fn hosts(&self) -> &[String] {
+        &self.hosts
+    }
+
+    /// The resource properties of this service, in the form of a [NamespacePropertyMapping].
+    pub fn resource_property_mapping(&self) -> Arc<NamespacePropertyMapping> {
+        self.resource_property_mapping.clone()
+    }
 }
 
 impl Drop for ClientState {
@@ -105,22 +225,39 @@ impl Drop for ClientState {
 impl Client {
     /// Construct a new builder.
     pub fn builder() -> ClientBuilder {
-        let url = std::env::var("AUTHLY_URL")
+        let url = SystemEnvironment
+            .var(AUTHLY_URL_VAR)
             .map(Cow::Owned)
             .unwrap_or(Cow::Borrowed("https://authly"));
 
         ClientBuilder {
             inner: ConnectionParamsBuilder::new(url),
+            reconnect_policy: Default::default(),
+            retry_policy: Default::default(),
+            access_token_cache: None,
+            session_token_scheme: Default::default(),
+            reqwest_base: Arc::new(|builder| builder),
+            reconfigure_debounce: DEFAULT_RECONFIGURE_DEBOUNCE,
+            server_cert_validity: DEFAULT_SERVER_CERT_VALIDITY,
+            server_cert_rotation: None,
+            metrics: Arc::new(NoOpMetrics),
         }
     }
 
     /// Retrieve the [ServiceMetadata] about service this client identifies as.
     pub async fn metadata(&self) -> Result<ServiceMetadata, Error> {
         let proto = self
-            .current_service()
-            .get_metadata(proto::Empty::default())
-            .await
-            .map_err(error::tonic)?
+            .state
+            .retry_policy
+            .retry(|| async {
+                metrics::observe_rpc(
+                    &*self.state.metrics,
+                    "get_metadata",
+                    self.current_service().get_metadata(proto::Empty::default()),
+                )
+                .await
+            })
+            .await?
             .into_inner();
 
         Ok(ServiceMetadata {
@@ -138,6 +275,75 @@ impl Client {
         })
     }
 
+    /// The [ServiceId] this client identifies as.
+    ///
+    /// Read from the identity certificate, without making a request to Authly.
+    pub fn entity_id(&self) -> ServiceId {
+        self.state.conn.load().params.entity_id
+    }
+
+    /// Check that Authly is reachable over the current connection.
+    ///
+    /// This makes a lightweight request to Authly without side effects, and is intended for use in readiness probes.
+    /// Fails with [Error::Network] if Authly does not respond successfully within a few seconds.
+    pub async fn health_check(&self) -> Result<(), Error> {
+        tokio::time::timeout(
+            HEALTH_CHECK_TIMEOUT,
+            metrics::observe_rpc(
+                &*self.state.metrics,
+                "get_metadata",
+                self.current_service().get_metadata(proto::Empty::default()),
+            ),
+        )
+        .await
+        .map_err(|elapsed| Error::Network(elapsed.into()))?
+        .map_err(|err| Error::Network(err.into()))?;
+
+        Ok(())
+    }
+
+    /// Shut down the client's background worker, and wait for it to finish.
+    ///
+    /// Clones of this client can still be used to make requests to Authly afterwards,
+    /// but no longer receive updates from Authly.
+    /// Shutting down a client that has already been shut down completes immediately.
+    pub async fn shutdown(self) {
+        let _ = self.state.closed_tx.send(());
+
+        let worker = self.state.worker.lock().unwrap().take();
+        if let Some(worker) = worker {
+            if let Err(err) = worker.await {
+                tracing::error!(?err, "background worker failed");
+            }
+        }
+    }
+
+    /// Get the current status of the connection to Authly.
+    pub fn connection_status(&self) -> ConnectionStatus {
+        *self.state.status_rx.borrow()
+    }
+
+    /// Get a stream of [ConnectionStatus] changes.
+    ///
+    /// The first status in the stream resolves immediately, and is the current status.
+    /// Short-lived intermediate statuses may be skipped.
+    pub fn connection_status_stream(&self) -> BoxStream<'static, ConnectionStatus> {
+        let mut status_rx = self.state.status_rx.clone();
+        let initial_status = *status_rx.borrow_and_update();
+
+        let change_stream = futures_util::stream::unfold(status_rx, |mut status_rx| async move {
+            // ends when the background worker stops, e.g. after `Client::shutdown`
+            status_rx.changed().await.ok()?;
+            let status = *status_rx.borrow_and_update();
+
+            Some((status, status_rx))
+        });
+
+        futures_util::stream::iter([initial_status])
+            .chain(change_stream)
+            .boxed()
+    }
+
     /// Get a stream of [ServiceMetadata] changes.
     ///
     /// The first metadata in the stream resolves immediately, and is the current metadata.
@@ -184,6 +390,37 @@ impl Client {
         .boxed())
     }
 
+    /// Get the current [Configuration] of this service.
+    pub fn configuration(&self) -> Arc<Configuration> {
+        self.state.configuration.load_full()
+    }
+
+    /// Get a stream of [Configuration] changes.
+    ///
+    /// The first configuration in the stream resolves immediately, and is the current configuration.
+    /// A new configuration is published each time Authly asks the client to reload its cached data.
+    pub fn configuration_stream(&self) -> BoxStream<'static, Arc<Configuration>> {
+        let mut watch = self.state.metadata_invalidated_rx.clone();
+        watch.mark_unchanged();
+        let initial = self.configuration();
+
+        // the stream must not keep the client state alive
+        let state = Arc::downgrade(&self.state);
+
+        let change_stream =
+            futures_util::stream::unfold((state, watch), |(state, mut watch)| async move {
+                // ends when the background worker stops, e.g. after `Client::shutdown`
+                watch.changed().await.ok()?;
+                let configuration = state.upgrade()?.configuration.load_full();
+
+                Some((configuration, (state, watch)))
+            });
+
+        futures_util::stream::iter([initial])
+            .chain(change_stream)
+            .boxed()
+    }
+
     /// Get the current resource properties of this service, in the form of a [NamespacePropertyMapping].
     pub fn get_resource_property_mapping(&self) -> Arc<NamespacePropertyMapping> {
         self.state
@@ -193,6 +430,31 @@ impl Client {
             .clone()
     }
 
+    /// Get the current resource property mappings of a single namespace of this service, if found.
+    ///
+    /// This avoids holding on to the whole [NamespacePropertyMapping] when only one namespace is of interest.
+    pub fn resource_property_namespace(
+        &self,
+        namespace_label: &str,
+    ) -> Option<Arc<PropertyMappings>> {
+        self.state
+            .configuration
+            .load()
+            .resource_property_mapping
+            .namespace_shared(namespace_label)
+    }
+
+    /// List the namespace/property/attribute labels of this service that are assigned to the entity of the given [AccessToken].
+    ///
+    /// This does not perform any policy evaluation, it only intersects the token attributes with the [NamespacePropertyMapping].
+    /// The list is sorted.
+    pub fn token_permissions(&self, access_token: &AccessToken) -> Vec<(String, String, String)> {
+        access_control::attribute_labels(
+            &self.get_resource_property_mapping(),
+            &access_token.claims.authly.entity_attributes,
+        )
+    }
+
     /// Decode and validate an Authly [AccessToken].
     /// The access token usually represents an entity which is a user of the system.
     pub fn decode_access_token(
@@ -200,40 +462,206 @@ impl Client {
         access_token: impl Into<String>,
     ) -> Result<Arc<AccessToken>, Error> {
         let access_token = access_token.into();
-        let validation = jsonwebtoken::Validation::new(jsonwebtoken::Algorithm::ES256);
-        let token_data = jsonwebtoken::decode::<AuthlyAccessTokenClaims>(
-            &access_token,
-            &self.state.conn.load().params.jwt_decoding_key,
-            &validation,
-        )
-        .map_err(|err| Error::InvalidAccessToken(err.into()))?;
+        let params = self.state.conn.load().params.clone();
+        let validation = params
+            .token_validation
+            .as_deref()
+            .cloned()
+            .unwrap_or_else(|| jsonwebtoken::Validation::new(params.jwt_algorithm));
+
+        let claims = token::decode_claims(&access_token, &params.jwt_decoding_key, &validation)
+            .or_else(|err| {
+                // During CA rotation, tokens may still be signed by the previous key
+                match self.state.retired_jwt_decoding_key.load().as_deref() {
+                    Some(retired)
+                        if retired.retired_at.elapsed() < JWT_DECODING_KEY_GRACE_PERIOD =>
+                    {
+                        // the validation algorithms belong to the current key
+                        let mut retired_validation = validation.clone();
+                        retired_validation.algorithms = vec![retired.algorithm];
+
+                        token::decode_claims(&access_token, &retired.key, &retired_validation)
+                            .map_err(|_| err)
+                    }
+                    _ => Err(err),
+                }
+            })
+            .map_err(|err| Error::InvalidAccessToken(err.into()))?;
 
         Ok(Arc::new(AccessToken {
             token: access_token,
-            claims: token_data.claims,
+            claims,
         }))
     }
 
     /// Exchange a session token for an access token suitable for evaluating access control.
+    ///
+    /// If enabled with [ClientBuilder::with_access_token_cache], a cached access token is returned when available.
     pub async fn get_access_token(&self, session_token: &str) -> Result<Arc<AccessToken>, Error> {
-        let mut request = Request::new(proto::Empty::default());
-
-        // TODO: This should use Authorization instead of Cookie?
-        request.metadata_mut().append(
-            COOKIE.as_str(),
-            format!("session-cookie={session_token}")
-                .parse()
-                .map_err(error::unclassified)?,
-        );
+        match &self.state.access_token_cache {
+            Some(cache) => {
+                cache
+                    .get_or_fetch(session_token, || self.exchange_session_token(session_token))
+                    .await
+            }
+            None => self.exchange_session_token(session_token).await,
+        }
+    }
 
-        let proto = self
-            .current_service()
-            .get_access_token(request)
-            .await
-            .map_err(error::tonic)?
+    /// Get a stream of fresh access tokens for a session token, for acting on behalf of a user over a long-lived connection.
+    ///
+    /// The first access token in the stream resolves immediately, and is the same one returned by [Self::get_access_token].
+    /// Each subsequent token is exchanged shortly before the previous one expires.
+    /// Exchanges failing with a retryable error are retried until they succeed, with delays from the client's [RetryPolicy].
+    /// The stream ends on any other error, e.g. when Authly no longer accepts the session token.
+    pub async fn access_token_stream(
+        &self,
+        session_token: impl Into<String>,
+    ) -> Result<BoxStream<'static, Arc<AccessToken>>, Error> {
+        struct StreamState {
+            client: Client,
+            session_token: String,
+            current: Option<Arc<AccessToken>>,
+        }
+
+        let session_token = session_token.into();
+        let initial = self.get_access_token(&session_token).await?;
+
+        let state = StreamState {
+            client: self.clone(),
+            session_token,
+            current: Some(initial.clone()),
+        };
+
+        let refresh_stream = futures_util::stream::unfold(state, |mut state| async move {
+            let current = state.current.take()?;
+            tokio::time::sleep(access_token_refresh_delay(&current)).await;
+
+            let mut retry = 0;
+            let next = loop {
+                match state
+                    .client
+                    .exchange_session_token(&state.session_token)
+                    .await
+                {
+                    Ok(access_token) => break access_token,
+                    Err(err) if !err.is_retryable() => {
+                        info!(
+                            ?err,
+                            "unable to refresh access token, ending access token stream"
+                        );
+                        return None;
+                    }
+                    Err(err) => {
+                        let delay = state.client.state.retry_policy.delay(retry);
+                        info!(?err, ?delay, "unable to refresh access token, retrying");
+
+                        tokio::time::sleep(delay).await;
+                        retry += 1;
+                    }
+                }
+            };
+
+            state.current = Some(next.clone());
+            Some((next, state))
+        });
+
+        Ok(futures_util::stream::iter([initial])
+            .chain(refresh_stream)
+            .boxed())
+    }
+
+    /// Evaluate access control for a collection of items on behalf of an [AccessToken], returning the items that are allowed.
+    ///
+    /// Each item is paired with its labelled resource attributes.
+    /// The evaluations are performed concurrently, and the returned items keep their original order.
+    pub async fn filter_allowed<'a, T>(
+        &self,
+        access_token: Arc<AccessToken>,
+        items: impl IntoIterator<Item = (T, Vec<(&'a str, &'a str, &'a str)>)>,
+    ) -> Result<Vec<T>, Error> {
+        let decisions: Vec<Option<T>> = futures_util::stream::iter(items)
+            .map(|(item, attributes)| {
+                let access_token = access_token.clone();
+
+                async move {
+                    let mut request = self.access_control_request().access_token(access_token);
+                    for attribute in attributes {
+                        request = request.resource_attribute(attribute)?;
+                    }
+
+                    Ok::<_, Error>(request.evaluate().await?.then_some(item))
+                }
+            })
+            .buffered(FILTER_ALLOWED_CONCURRENCY)
+            .try_collect()
+            .await?;
+
+        Ok(decisions.into_iter().flatten().collect())
+    }
+
+    /// Evaluate a batch of access control requests in a single round trip to Authly.
+    ///
+    /// The requests must share the same access token, if any.
+    /// The returned decisions are in the same order as the requests.
+    /// If any request in the batch fails, the whole batch fails.
+    pub async fn access_control_batch(
+        &self,
+        requests: Vec<AccessControlRequestBuilder<'_>>,
+    ) -> Result<Vec<bool>, Error> {
+        let request_count = requests.len();
+        let mut batch = proto::AccessControlBatchRequest {
+            requests: Vec::with_capacity(request_count),
+        };
+        let mut access_token: Option<Arc<AccessToken>> = None;
+
+        for builder in requests {
+            let (request, request_token) = builder.into_proto();
+
+            match (&access_token, request_token) {
+                (_, None) => {}
+                (None, Some(request_token)) => {
+                    access_token = Some(request_token);
+                }
+                (Some(access_token), Some(request_token)) => {
+                    if access_token.token != request_token.token {
+                        return Err(Error::InvalidAccessToken(anyhow!(
+                            "access control batch requests must share the same access token"
+                        )));
+                    }
+                }
+            }
+
+            batch.requests.push(request);
+        }
+
+        let response = self
+            .state
+            .retry_policy
+            .retry(|| async {
+                let mut request = Request::new(batch.clone());
+                if let Some(access_token) = &access_token {
+                    access_control::append_bearer(&mut request, access_token)?;
+                }
+
+                metrics::observe_rpc(
+                    &*self.state.metrics,
+                    "access_control_batch",
+                    self.current_service().access_control_batch(request),
+                )
+                .await
+            })
+            .await?
             .into_inner();
 
-        self.decode_access_token(proto.token)
+        if response.values.len() != request_count {
+            return Err(Error::Codec(anyhow!(
+                "expected {request_count} access control values, got {}",
+                response.values.len()
+            )));
+        }
+
+        Ok(response.values.into_iter().map(|value| value > 0).collect())
     }
 
     /// Convert a clone of self into a dynamically dispatched access control object.
@@ -251,37 +679,44 @@ impl Client {
     ///
     /// The common name can be any chosen text identifying the service.
     ///
+    /// The subject alternative names of the certificate are the [hosts](Configuration::hosts) of the service,
+    /// lowercased and without trailing dots.
+    /// Fails with [Error::InvalidAltNames] when the service has no hosts.
+    ///
+    /// The requested validity period is configured with [ClientBuilder::with_server_cert_validity].
+    /// If Authly rejects the request, e.g. because of the requested validity or unknown host names,
+    /// the error from Authly is returned, including its message.
+    ///
+    /// Servers should present the full certificate chain from [Client::generate_server_tls_chain] instead,
+    /// as clients may not be able to verify the certificate on its own, e.g. when the Authly Local CA is an intermediate CA.
     pub async fn generate_server_tls_params(
         &self,
         subject_common_name: &str,
     ) -> Result<(CertificateDer<'static>, PrivateKeyDer<'static>), Error> {
-        let hosts = self.state.configuration.load().hosts.clone();
-        let params = {
-            let mut params = CertificateParams::new(hosts).map_err(|_| Error::InvalidAltNames)?;
-            params
-                .distinguished_name
-                .push(DnType::CommonName, subject_common_name);
-            params.distinguished_name.push(
-                DnType::CustomDnType(
-                    authly_common::certificate::oid::ENTITY_UNIQUE_IDENTIFIER.to_vec(),
-                ),
-                self.state.conn.load().params.entity_id.to_string(),
-            );
-            params.use_authority_key_identifier_extension = false;
-            params.key_usages.push(KeyUsagePurpose::DigitalSignature);
-            params
-                .extended_key_usages
-                .push(ExtendedKeyUsagePurpose::ServerAuth);
-
-            let now = time::OffsetDateTime::now_utc();
-            params.not_before = now;
-
-            // A default timeout that is one year.
-            // FIXME(rotation) What happens to the server after the certificate expires?
-            // No other services would then be able to connect to it, but it wouldn't itself understand that it's broken.
-            params.not_after = now.checked_add(time::Duration::days(365)).unwrap();
-            params
-        };
+        let (mut chain, private_key) = self.generate_server_tls_chain(subject_common_name).await?;
+
+        Ok((chain.swap_remove(0), private_key))
+    }
+
+    /// Generate a server certificate chain and a key pair for the service.
+    ///
+    /// Works like [Client::generate_server_tls_params], but returns the certificate chain to be presented by the server,
+    /// starting with the server certificate, followed by the CA certificates Authly includes for verifying it.
+    pub async fn generate_server_tls_chain(
+        &self,
+        subject_common_name: &str,
+    ) -> Result<(Vec<CertificateDer<'static>>, PrivateKeyDer<'static>), Error> {
+        let validity = self
+            .state
+            .server_cert_validity
+            .min(MAX_SERVER_CERT_VALIDITY);
+        let params = server_certificate_params(
+            self.state.configuration.load().hosts.clone(),
+            subject_common_name,
+            self.entity_id(),
+            validity,
+            time::OffsetDateTime::now_utc(),
+        )?;
 
         // The key pair to use for the server, and signing the Certificate Signing Request.
         // The private key is not sent to Authly.
@@ -292,24 +727,57 @@ impl Client {
             .der()
             .to_vec();
 
-        let proto = self
-            .state
-            .conn
-            .load()
-            .authly_service
-            .clone()
-            .sign_certificate(Request::new(proto::CertificateSigningRequest {
-                der: csr_der.into(),
-            }))
-            .await
-            .map_err(error::tonic)?;
+        let proto = metrics::observe_rpc(
+            &*self.state.metrics,
+            "sign_certificate",
+            self.current_service().sign_certificate(Request::new(
+                proto::CertificateSigningRequest {
+                    der: csr_der.into(),
+                    validity_seconds: validity.as_secs(),
+                },
+            )),
+        )
+        .await?;
+
+        let proto = proto.into_inner();
+        let certificate = CertificateDer::from(proto.der.to_vec());
+        let (_, x509_cert) = x509_parser::parse_x509_certificate(&certificate)
+            .map_err(|_| Error::Codec(anyhow!("invalid server certificate")))?;
+        self.state
+            .server_cert_not_after
+            .store(Some(Arc::new(x509_cert.validity().not_after.to_datetime())));
 
-        let certificate = CertificateDer::from(proto.into_inner().der.to_vec());
         let private_key = PrivateKeyDer::try_from(key_pair.serialize_der()).map_err(|err| {
             Error::Unclassified(anyhow!("could not serialize private key: {err}"))
         })?;
 
-        Ok((certificate, private_key))
+        let chain = std::iter::once(certificate)
+            .chain(
+                proto
+                    .chain
+                    .into_iter()
+                    .map(|der| CertificateDer::from(der.to_vec())),
+            )
+            .collect();
+
+        Ok((chain, private_key))
+    }
+
+    /// The expiry time of the server certificate most recently issued by [Client::generate_server_tls_params],
+    /// which is also used by [Client::rustls_server_configurer].
+    ///
+    /// Returns `None` if no server certificate has been issued.
+    pub fn current_server_cert_not_after(&self) -> Option<time::OffsetDateTime> {
+        self.state.server_cert_not_after.load().as_deref().copied()
+    }
+
+    /// The number of seconds until the current server certificate expires, suitable as a metric.
+    ///
+    /// The value is negative if the certificate has expired.
+    /// Returns `None` if no server certificate has been issued.
+    pub fn current_server_cert_expires_in_seconds(&self) -> Option<i64> {
+        self.current_server_cert_not_after()
+            .map(|not_after| (not_after - time::OffsetDateTime::now_utc()).whole_seconds())
     }
 
     /// Return a stream of [rustls::ServerConfig] values for configuring authly-verified servers.
@@ -318,8 +786,8 @@ impl Client {
     /// The config comes with `h2` and `http/1.1` ALPN protocols.
     /// This may become configurable in the future.
     ///
-    /// For now, this only renews the server certificate when absolutely required.
-    /// In the future, this may rotate server certificates automatically on a fixed (configurable) interval.
+    /// The server certificate is renewed when the client is reconfigured, e.g. after an Authly CA rotation,
+    /// and on the interval configured with [ClientBuilder::with_server_cert_rotation].
     #[cfg(feature = "rustls_023")]
     pub async fn rustls_server_configurer(
         &self,
@@ -344,18 +812,20 @@ impl Client {
                 )
                 .map_err(|_err| Error::AuthlyCA("unable to include in root cert store"))?;
 
-            let (cert, key) = client
-                .generate_server_tls_params(&subject_common_name)
+            let (chain, key) = client
+                .generate_server_tls_chain(&subject_common_name)
                 .await?;
 
-            let mut tls_config = rustls::server::ServerConfig::builder()
-                .with_client_cert_verifier(
-                    WebPkiClientVerifier::builder(root_cert_store.into())
-                        .build()
-                        .map_err(|_| Error::AuthlyCA("cannot build a WebPki client verifier"))?,
-                )
-                .with_single_cert(vec![cert], key)
-                .map_err(|_| Error::Tls("Unable to configure server"))?;
+            let mut tls_config = rustls::server::ServerConfig::builder_with_protocol_versions(
+                params.min_tls_version.rustls_protocol_versions(),
+            )
+            .with_client_cert_verifier(
+                WebPkiClientVerifier::builder(root_cert_store.into())
+                    .build()
+                    .map_err(|_| Error::AuthlyCA("cannot build a WebPki client verifier"))?,
+            )
+            .with_single_cert(chain, key)
+            .map_err(|_| Error::Tls("Unable to configure server"))?;
             tls_config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
 
             Ok(Arc::new(tls_config))
@@ -371,14 +841,23 @@ impl Client {
 
         let immediate_stream = futures_util::stream::iter([initial_tls_config]);
 
+        let rotation_interval = self.state.server_cert_rotation;
+        let debounce = self.state.reconfigure_debounce;
+
         let rotation_stream =
             futures_util::stream::unfold(reconfigured_rx, move |mut reconfigured_rx| {
                 let client = client.clone();
                 let subject_common_name = subject_common_name.clone();
 
                 async move {
-                    // wait for configuration change
-                    reconfigured_rx.changed().await.ok()?;
+                    // wait for configuration change or the next scheduled rotation
+                    tokio::select! {
+                        changed = reconfigured_rx.changed() => {
+                            changed.ok()?;
+                            settle_reconfiguration(&mut reconfigured_rx, debounce).await?;
+                        }
+                        _ = server_cert_rotation_delay(rotation_interval) => {}
+                    }
 
                     loop {
                         let params = reconfigured_rx.borrow_and_update().clone();
@@ -412,6 +891,8 @@ impl Client {
     /// communicating with other services in the Authly service mesh.
     ///
     /// The first stream item will resolve immediately.
+    /// Reconfigurations in quick succession are coalesced into one item,
+    /// see [ClientBuilder::with_reconfigure_debounce].
     pub fn connection_params_stream(
         &self,
     ) -> futures_util::stream::BoxStream<'static, Arc<ConnectionParams>> {
@@ -419,6 +900,7 @@ impl Client {
 
         let mut reconfigured_rx = self.state.reconfigured_rx.clone();
         let initial_params = reconfigured_rx.borrow_and_update().clone();
+        let debounce = self.state.reconfigure_debounce;
 
         let immediate_stream = futures_util::stream::iter([initial_params]);
 
@@ -430,6 +912,7 @@ impl Client {
                         // client dropped
                         return None;
                     };
+                    settle_reconfiguration(&mut reconfigured_rx, debounce).await?;
 
                     let params = reconfigured_rx.borrow_and_update().clone();
 
@@ -440,16 +923,54 @@ impl Client {
         immediate_stream.chain(rotation_stream).boxed()
     }
 
+    /// Generates a stream of [tonic::transport::Channel]s to a peer service in the Authly service mesh.
+    /// The channels use mutual TLS, authenticating with the Authly identity and verifying the peer with the Authly local CA.
+    ///
+    /// A new channel is produced when the client is reconfigured.
+    /// The channels connect lazily, and the first stream item will resolve immediately.
+    /// Reconfigurations that cannot produce a channel are logged and skipped.
+    #[cfg(feature = "tonic_014")]
+    pub fn tonic_channel_stream(
+        &self,
+        url: impl Into<String>,
+    ) -> Result<futures_util::stream::BoxStream<'static, Channel>, Error> {
+        use futures_util::StreamExt;
+
+        let url = url.into();
+
+        // fail early on an invalid URL or identity
+        connection::peer_channel(&self.state.conn.load().params, &url)?;
+
+        Ok(self
+            .connection_params_stream()
+            .filter_map(move |params| {
+                let channel = match connection::peer_channel(&params, &url) {
+                    Ok(channel) => Some(channel),
+                    Err(err) => {
+                        tracing::error!(?err, "could not make a tonic Channel, skipping");
+                        None
+                    }
+                };
+                futures_util::future::ready(channel)
+            })
+            .boxed())
+    }
+
     /// Generates a stream of [reqwest::ClientBuilder] preconfigured with Authly TLS paramaters.
     /// The first stream item will resolve immediately.
+    ///
+    /// Each builder starts out with the base settings given to [ClientBuilder::with_reqwest_base].
     #[cfg(feature = "reqwest_012")]
     pub fn request_client_builder_stream(
         &self,
     ) -> Result<futures_util::stream::BoxStream<'static, reqwest::ClientBuilder>, Error> {
         use futures_util::StreamExt;
 
-        fn rebuild(params: Arc<ConnectionParams>) -> Result<reqwest::ClientBuilder, Error> {
-            Ok(reqwest::Client::builder()
+        fn rebuild(
+            base: &ReqwestBase,
+            params: Arc<ConnectionParams>,
+        ) -> Result<reqwest::ClientBuilder, Error> {
+            Ok(base(reqwest::Client::builder())
                 .add_root_certificate(
                     reqwest::tls::Certificate::from_pem(&params.authly_local_ca)
                         .map_err(|_| Error::AuthlyCA("unable to parse"))?,
@@ -462,35 +983,1082 @@ impl Client {
 
         Ok(self
             .connection_params_stream()
-            .map(|params| rebuild(params).expect("could not make a reqwest Client"))
+            .map({
+                let base = self.state.reqwest_base.clone();
+                move |params| rebuild(&base, params).expect("could not make a reqwest Client")
+            })
             .boxed())
     }
 }
 
 /// Private methods
 impl Client {
+    async fn exchange_session_token(&self, session_token: &str) -> Result<Arc<AccessToken>, Error> {
+        let proto = self
+            .state
+            .retry_policy
+            .retry(|| async {
+                let mut request = Request::new(proto::Empty::default());
+
+                let metadata = request.metadata_mut();
+                match self.state.session_token_scheme {
+                    SessionTokenScheme::Cookie => metadata.append(
+                        COOKIE.as_str(),
+                        format!("session-cookie={session_token}")
+                            .parse()
+                            .map_err(error::unclassified)?,
+                    ),
+                    SessionTokenScheme::Bearer => metadata.append(
+                        AUTHORIZATION.as_str(),
+                        format!("Bearer {session_token}")
+                            .parse()
+                            .map_err(error::unclassified)?,
+                    ),
+                };
+
+                metrics::observe_rpc(
+                    &*self.state.metrics,
+                    "get_access_token",
+                    self.current_service().get_access_token(request),
+                )
+                .await
+            })
+            .await?
+            .into_inner();
+
+        self.decode_access_token(proto.token)
+    }
+
     fn current_service(&self) -> AuthlyServiceClient<Channel> {
         self.state.conn.load().authly_service.clone()
     }
 }
 
+/// Wait until the next scheduled server certificate rotation, if any.
+///
+/// The interval is randomly shortened by up to a fifth, to spread out certificate signing requests from many services.
+#[cfg(feature = "rustls_023")]
+async fn server_cert_rotation_delay(interval: Option<Duration>) {
+    match interval {
+        Some(interval) => {
+            tokio::time::sleep(interval.mul_f64(rand::random_range(0.8..=1.0))).await;
+        }
+        None => std::future::pending().await,
+    }
+}
+
+/// Wait until no further reconfiguration has happened for the `debounce` period.
+///
+/// Returns `None` if the client was dropped in the meantime.
+async fn settle_reconfiguration(
+    reconfigured_rx: &mut tokio::sync::watch::Receiver<Arc<ConnectionParams>>,
+    debounce: Duration,
+) -> Option<()> {
+    loop {
+        match tokio::time::timeout(debounce, reconfigured_rx.changed()).await {
+            Ok(changed) => changed.ok()?,
+            Err(_elapsed) => return Some(()),
+        }
+    }
+}
+
+/// How long to wait before refreshing the access token, based on its remaining lifetime.
+fn access_token_refresh_delay(access_token: &AccessToken) -> Duration {
+    let remaining = Duration::try_from(access_token.expires_at() - time::OffsetDateTime::now_utc())
+        .unwrap_or(Duration::ZERO);
+
+    (remaining - ACCESS_TOKEN_REFRESH_MARGIN.min(remaining / 2)).max(ACCESS_TOKEN_MIN_REFRESH_DELAY)
+}
+
+/// The certificate parameters of a server certificate to be signed by Authly.
+fn server_certificate_params(
+    hosts: Vec<String>,
+    subject_common_name: &str,
+    entity_id: ServiceId,
+    validity: Duration,
+    now: time::OffsetDateTime,
+) -> Result<CertificateParams, Error> {
+    let mut alt_names: Vec<String> = Vec::with_capacity(hosts.len());
+    for host in hosts {
+        let host = host.trim_end_matches('.').to_ascii_lowercase();
+        if !host.is_empty() && !alt_names.contains(&host) {
+            alt_names.push(host);
+        }
+    }
+    if alt_names.is_empty() {
+        return Err(Error::InvalidAltNames(anyhow!(
+            "no hosts are configured for the service"
+        )));
+    }
+
+    let mut params =
+        CertificateParams::new(alt_names).map_err(|err| Error::InvalidAltNames(err.into()))?;
+    params
+        .distinguished_name
+        .push(DnType::CommonName, subject_common_name);
+    params.distinguished_name.push(
+        DnType::CustomDnType(authly_common::certificate::oid::ENTITY_UNIQUE_IDENTIFIER.to_vec()),
+        entity_id.to_string(),
+    );
+    params.use_authority_key_identifier_extension = false;
+    params.key_usages.push(KeyUsagePurpose::DigitalSignature);
+    params
+        .extended_key_usages
+        .push(ExtendedKeyUsagePurpose::ServerAuth);
+
+    // backdated to tolerate clock drift between services
+    params.not_before = now - SERVER_CERT_CLOCK_SKEW;
+
+    // FIXME(rotation) What happens to the server after the certificate expires?
+    // No other services would then be able to connect to it, but it wouldn't itself understand that it's broken.
+    params.not_after = now + validity.min(MAX_SERVER_CERT_VALIDITY);
+
+    Ok(params)
+}
+
 fn id_codec_error() -> Error {
     Error::Codec(anyhow!("id decocing error"))
 }
 
 async fn get_configuration(
     mut service: AuthlyServiceClient<Channel>,
+    metrics: &dyn Metrics,
 ) -> Result<Configuration, Error> {
-    let response = service
-        .get_configuration(proto::Empty::default())
-        .await
-        .map_err(error::tonic)?
-        .into_inner();
+    let response = metrics::observe_rpc(
+        metrics,
+        "get_configuration",
+        service.get_configuration(proto::Empty::default()),
+    )
+    .await?
+    .into_inner();
 
     Ok(Configuration {
         hosts: response.hosts,
         resource_property_mapping: access_control::get_resource_property_mapping(
             response.property_mapping_namespaces,
         )?,
+        policy_engine: response.policy_set.and_then(|policy_set| {
+            // a policy set this client cannot use must not make the client unusable,
+            // access control is then evaluated remotely
+            access_control::get_policy_engine(policy_set)
+                .inspect_err(|err| {
+                    tracing::warn!(?err, "local policy evaluation unavailable");
+                })
+                .ok()
+        }),
     })
 }
+
+#[tokio::test]
+async fn test_health_check() {
+    let pongs = Arc::<std::sync::atomic::AtomicUsize>::default();
+    let healthy = mock::client(
+        mock::MockAuthly {
+            pongs: pongs.clone(),
+            ..Default::default()
+        }
+        .serve()
+        .await,
+    );
+    healthy.health_check().await.unwrap();
+
+    // the probe does not answer pings that Authly never sent
+    assert_eq!(0, pongs.load(std::sync::atomic::Ordering::SeqCst));
+
+    let unavailable = mock::client(
+        mock::MockAuthly {
+            unavailable: true,
+            ..Default::default()
+        }
+        .serve()
+        .await,
+    );
+    assert!(matches!(
+        unavailable.health_check().await,
+        Err(Error::Network(_))
+    ));
+
+    // nothing listening
+    let down_addr = {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        listener.local_addr().unwrap()
+    };
+    let down = mock::client(down_addr);
+    assert!(matches!(down.health_check().await, Err(Error::Network(_))));
+}
+
+#[tokio::test]
+async fn test_entity_id() {
+    // nothing listening, the entity ID is known without making requests
+    let addr = {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        listener.local_addr().unwrap()
+    };
+    let client = mock::client(addr);
+
+    // the entity ID in the identity certificate made by the mock
+    assert_eq!(ServiceId::from_uint(0xf001), client.entity_id());
+}
+
+#[tokio::test]
+async fn test_connection_status_stream() {
+    let (messages_available_tx, messages_available_rx) = tokio::sync::watch::channel(true);
+    let addr = mock::MockAuthly {
+        messages_available: Some(messages_available_rx),
+        ..Default::default()
+    }
+    .serve()
+    .await;
+    let client = mock::client_with_worker(
+        addr,
+        ReconnectPolicy {
+            grace_period: Duration::from_millis(500),
+            retry_delay: Duration::from_secs(60),
+        },
+    )
+    .await;
+
+    let mut status_stream = client.connection_status_stream();
+    assert_eq!(
+        Some(ConnectionStatus::Connected),
+        status_stream.next().await
+    );
+
+    // drop the message stream
+    messages_available_tx.send_replace(false);
+    assert_eq!(
+        Some(ConnectionStatus::Reconnecting),
+        status_stream.next().await
+    );
+    assert_eq!(ConnectionStatus::Reconnecting, client.connection_status());
+
+    // the message stream can't be re-opened, and a full reconnect fails too
+    assert_eq!(Some(ConnectionStatus::Failed), status_stream.next().await);
+}
+
+#[tokio::test]
+async fn test_message_stream_reopen() {
+    let (messages_available_tx, messages_available_rx) = tokio::sync::watch::channel(true);
+    let addr = mock::MockAuthly {
+        messages_available: Some(messages_available_rx),
+        ..Default::default()
+    }
+    .serve()
+    .await;
+    let client = mock::client_with_worker(
+        addr,
+        ReconnectPolicy {
+            grace_period: Duration::from_secs(60),
+            retry_delay: Duration::from_secs(60),
+        },
+    )
+    .await;
+    let mut reconfigured_rx = client.state.reconfigured_rx.clone();
+    reconfigured_rx.borrow_and_update();
+
+    let mut status_stream = client.connection_status_stream();
+    assert_eq!(
+        Some(ConnectionStatus::Connected),
+        status_stream.next().await
+    );
+
+    // drop the message stream, and make it available again within the grace period
+    messages_available_tx.send_replace(false);
+    assert_eq!(
+        Some(ConnectionStatus::Reconnecting),
+        status_stream.next().await
+    );
+    messages_available_tx.send_replace(true);
+    assert_eq!(
+        Some(ConnectionStatus::Connected),
+        status_stream.next().await
+    );
+
+    // the stream was re-opened on the current connection, without a full reconnect
+    assert!(!reconfigured_rx.has_changed().unwrap());
+    assert_eq!(0, client.state.conn.load().url_index);
+}
+
+#[tokio::test]
+async fn test_configuration_stream() {
+    let (messages_tx, _) = tokio::sync::broadcast::channel(1);
+    let mock = mock::MockAuthly {
+        messages: Some(messages_tx.clone()),
+        ..Default::default()
+    };
+    let configuration = mock.configuration.clone();
+    let client = mock::client_with_worker(mock.serve().await, ReconnectPolicy::default()).await;
+
+    let mut configuration_stream = client.configuration_stream();
+    assert_eq!(
+        ["svc.local"],
+        configuration_stream.next().await.unwrap().hosts()
+    );
+
+    configuration.lock().unwrap().hosts = vec!["svc.example".to_string()];
+    messages_tx
+        .send(proto::ServiceMessage {
+            service_message_kind: Some(proto::service_message::ServiceMessageKind::ReloadCache(
+                proto::Empty {},
+            )),
+        })
+        .unwrap();
+
+    let next = configuration_stream.next().await.unwrap();
+    assert_eq!(["svc.example"], next.hosts());
+    assert_eq!(["svc.example"], client.configuration().hosts());
+
+    // the stream does not keep the client state alive, and ends when the worker stops
+    let strong_count = Arc::strong_count(&client.state);
+    let _other_stream = client.configuration_stream();
+    assert_eq!(strong_count, Arc::strong_count(&client.state));
+
+    client.shutdown().await;
+    assert!(configuration_stream.next().await.is_none());
+}
+
+#[tokio::test]
+async fn test_resource_property_namespace() {
+    use authly_common::id::AttrId;
+
+    let read = AttrId::from_uint(0xf001);
+    let (messages_tx, _) = tokio::sync::broadcast::channel(1);
+    let mock = mock::MockAuthly {
+        messages: Some(messages_tx.clone()),
+        ..Default::default()
+    };
+    let configuration = mock.configuration.clone();
+    let client = mock::client_with_worker(mock.serve().await, ReconnectPolicy::default()).await;
+    let mut configuration_stream = client.configuration_stream();
+    configuration_stream.next().await.unwrap();
+
+    configuration.lock().unwrap().property_mapping_namespaces =
+        vec![proto::PropertyMappingNamespace {
+            label: "orders".to_string(),
+            properties: vec![proto::PropertyMapping {
+                label: "action".to_string(),
+                attributes: vec![proto::AttributeMapping {
+                    label: "read".to_string(),
+                    obj_id: read.to_array_dynamic().to_vec().into(),
+                }],
+            }],
+        }];
+    messages_tx
+        .send(proto::ServiceMessage {
+            service_message_kind: Some(proto::service_message::ServiceMessageKind::ReloadCache(
+                proto::Empty {},
+            )),
+        })
+        .unwrap();
+    configuration_stream.next().await.unwrap();
+
+    let orders = client.resource_property_namespace("orders").unwrap();
+    assert_eq!(Some(read), orders.property("action").unwrap().get("read"));
+    assert!(client.resource_property_namespace("missing").is_none());
+}
+
+#[tokio::test]
+async fn test_shutdown() {
+    let (messages_available_tx, messages_available_rx) = tokio::sync::watch::channel(true);
+    let addr = mock::MockAuthly {
+        messages_available: Some(messages_available_rx),
+        ..Default::default()
+    }
+    .serve()
+    .await;
+    let client = mock::client_with_worker(
+        addr,
+        ReconnectPolicy {
+            grace_period: Duration::from_millis(100),
+            retry_delay: Duration::from_secs(60),
+        },
+    )
+    .await;
+    let state = Arc::downgrade(&client.state);
+
+    // make the worker wait for a reconnect retry
+    let mut status_stream = client.connection_status_stream();
+    messages_available_tx.send_replace(false);
+    while status_stream.next().await != Some(ConnectionStatus::Failed) {}
+
+    tokio::time::timeout(Duration::from_secs(5), client.clone().shutdown())
+        .await
+        .unwrap();
+    assert!(client.state.worker.lock().unwrap().is_none());
+
+    // shutting down again is a no-op
+    client.clone().shutdown().await;
+
+    // the worker has exited, so the client holds the last reference to the state
+    drop(client);
+    assert!(state.upgrade().is_none());
+}
+
+#[test]
+fn test_server_certificate_params() {
+    let now = time::OffsetDateTime::from_unix_timestamp(1_700_000_000).unwrap();
+    let params = |validity| {
+        server_certificate_params(
+            vec!["svc.local".to_string()],
+            "svc",
+            ServiceId::from_uint(0xf001),
+            validity,
+            now,
+        )
+        .unwrap()
+    };
+
+    let day = Duration::from_secs(24 * 60 * 60);
+    let short_lived = params(day);
+    assert_eq!(now - SERVER_CERT_CLOCK_SKEW, short_lived.not_before);
+    assert_eq!(now + day, short_lived.not_after);
+
+    // clamped to the maximum validity
+    let long_lived = params(day * 1000);
+    assert_eq!(now + day * 365, long_lived.not_after);
+}
+
+#[test]
+fn test_server_certificate_alt_names() {
+    let now = time::OffsetDateTime::from_unix_timestamp(1_700_000_000).unwrap();
+    let params = |hosts: &[&str]| {
+        server_certificate_params(
+            hosts.iter().map(|host| host.to_string()).collect(),
+            "svc",
+            ServiceId::from_uint(0xf001),
+            Duration::from_secs(60),
+            now,
+        )
+    };
+
+    assert!(matches!(params(&[]), Err(Error::InvalidAltNames(_))));
+    assert!(matches!(params(&["."]), Err(Error::InvalidAltNames(_))));
+
+    let params = params(&["svc.local", "SVC.local.", "svc.example", "svc.local"]).unwrap();
+    assert_eq!(
+        vec![
+            rcgen::SanType::DnsName("svc.local".try_into().unwrap()),
+            rcgen::SanType::DnsName("svc.example".try_into().unwrap()),
+        ],
+        params.subject_alt_names
+    );
+}
+
+#[tokio::test]
+async fn test_server_cert_unknown_host() {
+    let addr = mock::MockAuthly {
+        known_hosts: Some(vec!["other.local".to_string()]),
+        ..Default::default()
+    }
+    .serve()
+    .await;
+    let client = mock::client(addr);
+
+    let err = client.generate_server_tls_params("svc").await.unwrap_err();
+    assert!(err.to_string().contains("svc.local"), "{err}");
+    assert!(!err.is_retryable());
+}
+
+#[tokio::test]
+async fn test_server_cert_validity_request() {
+    let sign_requests = Arc::<std::sync::Mutex<Vec<_>>>::default();
+    let addr = mock::MockAuthly {
+        sign_requests: sign_requests.clone(),
+        ..Default::default()
+    }
+    .serve()
+    .await;
+    let client = mock::client(addr);
+
+    client.generate_server_tls_params("svc").await.unwrap();
+    assert_eq!(
+        365 * 24 * 60 * 60,
+        sign_requests.lock().unwrap()[0].validity_seconds
+    );
+}
+
+#[cfg(feature = "rustls_023")]
+#[tokio::test]
+async fn test_server_cert_rotation() {
+    let sign_requests = Arc::<std::sync::Mutex<Vec<_>>>::default();
+    let addr = mock::MockAuthly {
+        sign_requests: sign_requests.clone(),
+        ..Default::default()
+    }
+    .serve()
+    .await;
+    let mut client = mock::client(addr);
+    Arc::get_mut(&mut client.state)
+        .unwrap()
+        .server_cert_rotation = Some(Duration::from_millis(50));
+
+    let configs: Vec<_> = client
+        .rustls_server_configurer("svc")
+        .await
+        .unwrap()
+        .take(3)
+        .collect()
+        .await;
+
+    assert_eq!(3, configs.len());
+    assert_eq!(3, sign_requests.lock().unwrap().len());
+}
+
+#[tokio::test]
+async fn test_server_cert_chain() {
+    let client = mock::client(mock::MockAuthly::default().serve().await);
+
+    let (chain, _) = client.generate_server_tls_chain("svc").await.unwrap();
+    assert_eq!(2, chain.len());
+
+    let (leaf, _) = client.generate_server_tls_params("svc").await.unwrap();
+    let (_, x509_leaf) = x509_parser::parse_x509_certificate(&leaf).unwrap();
+    let (_, x509_ca) = x509_parser::parse_x509_certificate(&chain[1]).unwrap();
+    assert_eq!(x509_leaf.issuer(), x509_ca.subject());
+}
+
+/// Accepts any server certificate, recording the length of the presented chain.
+#[cfg(all(test, feature = "rustls_023"))]
+#[derive(Debug)]
+struct RecordingVerifier(
+    rustls::crypto::CryptoProvider,
+    std::sync::Mutex<Option<usize>>,
+);
+
+#[cfg(all(test, feature = "rustls_023"))]
+impl RecordingVerifier {
+    fn new() -> Self {
+        Self(rustls::crypto::ring::default_provider(), Default::default())
+    }
+}
+
+#[cfg(all(test, feature = "rustls_023"))]
+impl rustls::client::danger::ServerCertVerifier for RecordingVerifier {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        _server_name: &rustls_pki_types::ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: rustls_pki_types::UnixTime,
+    ) -> Result<rustls::client::danger::ServerCertVerified, rustls::Error> {
+        *self.1.lock().unwrap() = Some(1 + intermediates.len());
+        Ok(rustls::client::danger::ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &rustls::DigitallySignedStruct,
+    ) -> Result<rustls::client::danger::HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &rustls::DigitallySignedStruct,
+    ) -> Result<rustls::client::danger::HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<rustls::SignatureScheme> {
+        self.0.signature_verification_algorithms.supported_schemes()
+    }
+}
+
+#[cfg(feature = "rustls_023")]
+#[tokio::test]
+async fn test_server_configurer_chain() {
+    use rustls::pki_types::ServerName;
+
+    let client = mock::client(mock::MockAuthly::default().serve().await);
+    let server_config = client
+        .rustls_server_configurer("svc")
+        .await
+        .unwrap()
+        .next()
+        .await
+        .unwrap();
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let acceptor = tokio_rustls::TlsAcceptor::from(server_config);
+    tokio::spawn(async move {
+        let (stream, _) = listener.accept().await.unwrap();
+        let _tls = acceptor.accept(stream).await;
+    });
+
+    let verifier = Arc::new(RecordingVerifier::new());
+    let params = client.state.conn.load().params.clone();
+    let client_config = rustls::ClientConfig::builder_with_provider(Arc::new(
+        rustls::crypto::ring::default_provider(),
+    ))
+    .with_safe_default_protocol_versions()
+    .unwrap()
+    .dangerous()
+    .with_custom_certificate_verifier(verifier.clone())
+    .with_client_auth_cert(
+        vec![params.identity.cert_der.clone()],
+        params.identity.key_der.clone_key(),
+    )
+    .unwrap();
+
+    let tcp = tokio::net::TcpStream::connect(addr).await.unwrap();
+    let _tls = tokio_rustls::TlsConnector::from(Arc::new(client_config))
+        .connect(ServerName::try_from("localhost").unwrap(), tcp)
+        .await;
+
+    assert_eq!(Some(2), *verifier.1.lock().unwrap());
+}
+
+#[cfg(feature = "rustls_023")]
+#[tokio::test]
+async fn test_server_configurer_min_tls_version() {
+    use rustls::pki_types::ServerName;
+
+    use crate::connection::TlsVersion;
+
+    let (client, senders) = mock::client_with_senders(mock::MockAuthly::default().serve().await);
+    let params = client.state.conn.load().params.clone();
+
+    // a peer only supporting TLS 1.2
+    let client_config = rustls::ClientConfig::builder_with_provider(Arc::new(
+        rustls::crypto::ring::default_provider(),
+    ))
+    .with_protocol_versions(&[&rustls::version::TLS12])
+    .unwrap()
+    .dangerous()
+    .with_custom_certificate_verifier(Arc::new(RecordingVerifier::new()))
+    .with_client_auth_cert(
+        vec![params.identity.cert_der.clone()],
+        params.identity.key_der.clone_key(),
+    )
+    .unwrap();
+
+    for (min_tls_version, accepted) in [(TlsVersion::Tls12, true), (TlsVersion::Tls13, false)] {
+        senders
+            .reconfigured_tx
+            .send_replace(Arc::new(ConnectionParams {
+                min_tls_version,
+                ..(*params).clone()
+            }));
+        let server_config = client
+            .rustls_server_configurer("svc")
+            .await
+            .unwrap()
+            .next()
+            .await
+            .unwrap();
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let acceptor = tokio_rustls::TlsAcceptor::from(server_config);
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let _tls = acceptor.accept(stream).await;
+        });
+
+        let tcp = tokio::net::TcpStream::connect(addr).await.unwrap();
+        let result = tokio_rustls::TlsConnector::from(Arc::new(client_config.clone()))
+            .connect(ServerName::try_from("localhost").unwrap(), tcp)
+            .await;
+        assert_eq!(accepted, result.is_ok(), "{min_tls_version:?}");
+    }
+}
+
+#[tokio::test]
+async fn test_server_cert_not_after() {
+    let client = mock::client(mock::MockAuthly::default().serve().await);
+    assert_eq!(None, client.current_server_cert_not_after());
+    assert_eq!(None, client.current_server_cert_expires_in_seconds());
+
+    client.generate_server_tls_params("svc").await.unwrap();
+
+    let expected = time::OffsetDateTime::now_utc() + time::Duration::days(365);
+    let not_after = client.current_server_cert_not_after().unwrap();
+    assert!((expected - not_after).abs() < time::Duration::minutes(1));
+
+    let expires_in = client.current_server_cert_expires_in_seconds().unwrap();
+    assert!((expires_in - 365 * 24 * 60 * 60).abs() < 60);
+}
+
+#[tokio::test]
+async fn test_access_token_stream() {
+    let access_tokens_issued = Arc::<std::sync::atomic::AtomicUsize>::default();
+    let addr = mock::MockAuthly {
+        access_token_ttl: Some(Duration::from_secs(2)),
+        access_tokens_issued: access_tokens_issued.clone(),
+        ..Default::default()
+    }
+    .serve()
+    .await;
+    let client = mock::client(addr);
+
+    let mut stream = client.access_token_stream("session").await.unwrap();
+
+    let first = stream.next().await.unwrap();
+    assert_eq!(
+        1,
+        access_tokens_issued.load(std::sync::atomic::Ordering::SeqCst)
+    );
+
+    let second = tokio::time::timeout(Duration::from_secs(5), stream.next())
+        .await
+        .unwrap()
+        .unwrap();
+
+    // refreshed before the previous token expired
+    assert!(!first.is_expired(time::OffsetDateTime::now_utc()));
+    assert!(second.expires_at() > first.expires_at());
+    assert_eq!(first.entity_id(), second.entity_id());
+    assert_eq!(
+        2,
+        access_tokens_issued.load(std::sync::atomic::Ordering::SeqCst)
+    );
+}
+
+#[tokio::test]
+async fn test_access_token_stream_expired_tokens() {
+    let access_tokens_issued = Arc::<std::sync::atomic::AtomicUsize>::default();
+    let addr = mock::MockAuthly {
+        // tokens expire as soon as they are issued
+        access_token_ttl: Some(Duration::ZERO),
+        access_tokens_issued: access_tokens_issued.clone(),
+        ..Default::default()
+    }
+    .serve()
+    .await;
+    let client = mock::client(addr);
+
+    let stream = client.access_token_stream("session").await.unwrap();
+    let _ = tokio::time::timeout(
+        ACCESS_TOKEN_MIN_REFRESH_DELAY * 3 / 2,
+        stream.for_each(|_| async {}),
+    )
+    .await;
+
+    // the initial token, and a single refresh
+    assert_eq!(
+        2,
+        access_tokens_issued.load(std::sync::atomic::Ordering::SeqCst)
+    );
+}
+
+#[tokio::test]
+async fn test_retired_jwt_decoding_key() {
+    use rcgen::{CertificateParams, PKCS_ED25519};
+
+    let client = mock::client(
+        mock::MockAuthly {
+            access_token_ttl: Some(Duration::from_secs(60 * 60)),
+            ..Default::default()
+        }
+        .serve()
+        .await,
+    );
+
+    let old_token = client.get_access_token("session").await.unwrap();
+    tokio::time::pause();
+
+    // rotate to a CA with a different key algorithm, with a validation override for the new algorithm
+    let new_ca = CertificateParams::new(vec![])
+        .unwrap()
+        .self_signed(&KeyPair::generate_for(&PKCS_ED25519).unwrap())
+        .unwrap();
+    let mut new_params = ConnectionParams::clone(&client.state.conn.load().params);
+    new_params.authly_local_ca = new_ca.pem().into_bytes();
+    (new_params.jwt_decoding_key, new_params.jwt_algorithm) =
+        builder::jwt_decoding_key_from_cert(&new_params.authly_local_ca).unwrap();
+    new_params.token_validation = Some(Arc::new(jsonwebtoken::Validation::new(
+        jsonwebtoken::Algorithm::EdDSA,
+    )));
+    background_worker::swap_connection(
+        &client.state,
+        Connection {
+            authly_service: client.state.conn.load().authly_service.clone(),
+            params: Arc::new(new_params),
+            url_index: 0,
+        },
+    );
+
+    // accepted within the grace period
+    client.decode_access_token(old_token.token.clone()).unwrap();
+
+    tokio::time::advance(JWT_DECODING_KEY_GRACE_PERIOD).await;
+    assert!(matches!(
+        client.decode_access_token(old_token.token.clone()),
+        Err(Error::InvalidAccessToken(_))
+    ));
+}
+
+#[tokio::test]
+async fn test_token_permissions() {
+    use authly_common::id::AttrId;
+
+    let client = mock::client(mock::MockAuthly::default().serve().await);
+    mock::use_visibility_property_mapping(&client);
+
+    let mut access_token = token::test_access_token(0);
+    Arc::get_mut(&mut access_token)
+        .unwrap()
+        .claims
+        .authly
+        .entity_attributes
+        // attribute 3 is not part of the property mapping
+        .extend([2, 1, 3].map(AttrId::from_uint));
+
+    assert_eq!(
+        vec![
+            (
+                "docs".to_string(),
+                "visibility".to_string(),
+                "private".to_string()
+            ),
+            (
+                "docs".to_string(),
+                "visibility".to_string(),
+                "public".to_string()
+            ),
+        ],
+        client.token_permissions(&access_token)
+    );
+}
+
+#[tokio::test]
+async fn test_filter_allowed() {
+    use authly_common::id::AttrId;
+
+    let client = mock::client(
+        mock::MockAuthly {
+            allowed_resource_attributes: Some(vec![AttrId::from_uint(1)]),
+            ..Default::default()
+        }
+        .serve()
+        .await,
+    );
+    mock::use_visibility_property_mapping(&client);
+
+    // more items than evaluated concurrently
+    let items = (0..50).map(|i| {
+        let visibility = if i % 3 == 0 { "public" } else { "private" };
+        (i, vec![("docs", "visibility", visibility)])
+    });
+
+    let allowed = client
+        .filter_allowed(token::test_access_token(0), items)
+        .await
+        .unwrap();
+    assert_eq!((0..50).step_by(3).collect::<Vec<_>>(), allowed);
+}
+
+#[tokio::test]
+async fn test_access_control_batch() {
+    use authly_common::id::AttrId;
+
+    let access_control_requests = Arc::<std::sync::Mutex<Vec<_>>>::default();
+    let client = mock::client(
+        mock::MockAuthly {
+            allowed_resource_attributes: Some(vec![AttrId::from_uint(1)]),
+            access_control_requests: access_control_requests.clone(),
+            ..Default::default()
+        }
+        .serve()
+        .await,
+    );
+
+    let decisions = client
+        .access_control_batch(
+            [1, 2, 3, 1]
+                .into_iter()
+                .map(|attr| {
+                    client
+                        .access_control_request()
+                        .resource_attribute_id(AttrId::from_uint(attr))
+                })
+                .collect(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(vec![true, false, false, true], decisions);
+
+    // the requests were sent in order
+    let requests = access_control_requests.lock().unwrap();
+    let sent_attrs: Vec<AttrId> = requests
+        .iter()
+        .map(|request| AttrId::try_from_bytes_dynamic(&request.resource_attributes[0]).unwrap())
+        .collect();
+    assert_eq!([1, 2, 3, 1].map(AttrId::from_uint).to_vec(), sent_attrs);
+}
+
+#[tokio::test]
+async fn test_access_control_batch_mismatched_tokens() {
+    let access_control_requests = Arc::<std::sync::Mutex<Vec<_>>>::default();
+    let client = mock::client(
+        mock::MockAuthly {
+            access_control_requests: access_control_requests.clone(),
+            ..Default::default()
+        }
+        .serve()
+        .await,
+    );
+
+    let result = client
+        .access_control_batch(vec![
+            client
+                .access_control_request()
+                .access_token(token::test_access_token(1)),
+            client.access_control_request(),
+            client
+                .access_control_request()
+                .access_token(token::test_access_token(2)),
+        ])
+        .await;
+    assert!(matches!(result, Err(Error::InvalidAccessToken(_))));
+    assert!(access_control_requests.lock().unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn test_access_control_batch_count_mismatch() {
+    let client = mock::client(
+        mock::MockAuthly {
+            truncate_batch_responses: true,
+            ..Default::default()
+        }
+        .serve()
+        .await,
+    );
+
+    let result = client
+        .access_control_batch(vec![
+            client.access_control_request(),
+            client.access_control_request(),
+        ])
+        .await;
+    assert!(matches!(result, Err(Error::Codec(_))));
+}
+
+#[tokio::test]
+async fn test_session_token_scheme() {
+    for (scheme, header, value) in [
+        (SessionTokenScheme::Cookie, COOKIE, "session-cookie=session"),
+        (SessionTokenScheme::Bearer, AUTHORIZATION, "Bearer session"),
+    ] {
+        let access_token_requests = Arc::<std::sync::Mutex<Vec<_>>>::default();
+        let addr = mock::MockAuthly {
+            access_token_ttl: Some(Duration::from_secs(60)),
+            access_token_requests: access_token_requests.clone(),
+            ..Default::default()
+        }
+        .serve()
+        .await;
+        let mut client = mock::client(addr);
+        Arc::get_mut(&mut client.state)
+            .unwrap()
+            .session_token_scheme = scheme;
+
+        client.get_access_token("session").await.unwrap();
+
+        let requests = access_token_requests.lock().unwrap();
+        let metadata = &requests[0];
+        assert_eq!(
+            Some(value),
+            metadata
+                .get(header.as_str())
+                .map(|value| value.to_str().unwrap()),
+            "{scheme:?}"
+        );
+        for other in [COOKIE, AUTHORIZATION] {
+            if other != header {
+                assert!(metadata.get(other.as_str()).is_none(), "{scheme:?}");
+            }
+        }
+    }
+}
+
+#[cfg(feature = "tonic_014")]
+#[tokio::test(start_paused = true)]
+async fn test_tonic_channel_stream_skips_invalid_params() {
+    let (client, senders) = mock::client_with_senders(mock::MockAuthly::default().serve().await);
+    let params = client.state.conn.load().params.clone();
+
+    let mut channel_stream = client.tonic_channel_stream("https://peer.local").unwrap();
+    channel_stream.next().await.unwrap();
+
+    let mut invalid_params = ConnectionParams::clone(&params);
+    invalid_params.authly_local_ca = b"invalid".to_vec();
+    senders
+        .reconfigured_tx
+        .send(Arc::new(invalid_params))
+        .unwrap();
+
+    // the invalid params are skipped instead of panicking
+    assert!(
+        tokio::time::timeout(Duration::from_secs(10), channel_stream.next())
+            .await
+            .is_err()
+    );
+
+    senders.reconfigured_tx.send(params).unwrap();
+    channel_stream.next().await.unwrap();
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_connection_params_stream_debounce() {
+    let (client, senders) = mock::client_with_senders(mock::MockAuthly::default().serve().await);
+    let params = client.state.conn.load().params.clone();
+
+    let mut params_stream = client.connection_params_stream();
+    params_stream.next().await.unwrap();
+
+    let emissions = tokio::spawn(async move {
+        let mut emissions = 0;
+        while let Ok(Some(_)) =
+            tokio::time::timeout(Duration::from_secs(10), params_stream.next()).await
+        {
+            emissions += 1;
+        }
+        emissions
+    });
+
+    for _ in 0..3 {
+        senders.reconfigured_tx.send(params.clone()).unwrap();
+        tokio::time::sleep(DEFAULT_RECONFIGURE_DEBOUNCE / 2).await;
+    }
+
+    assert_eq!(1, emissions.await.unwrap());
+}
+
+#[cfg(feature = "reqwest_012")]
+#[tokio::test]
+async fn test_reqwest_base_survives_rebuild() {
+    let (mut client, senders) =
+        mock::client_with_senders(mock::MockAuthly::default().serve().await);
+    Arc::get_mut(&mut client.state).unwrap().reqwest_base = Arc::new(|builder| {
+        builder.proxy(reqwest::Proxy::all("http://proxy.example:3128").unwrap())
+    });
+
+    let mut builder_stream = client.request_client_builder_stream().unwrap();
+    let initial = format!("{:?}", builder_stream.next().await.unwrap());
+    assert!(initial.contains("proxy.example"), "{initial}");
+
+    senders
+        .reconfigured_tx
+        .send(client.state.conn.load().params.clone())
+        .unwrap();
+
+    let rebuilt = format!("{:?}", builder_stream.next().await.unwrap());
+    assert!(rebuilt.contains("proxy.example"), "{rebuilt}");
+}
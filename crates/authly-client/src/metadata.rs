@@ -31,6 +31,20 @@ impl ServiceMetadata {
     pub fn namespaces(&self) -> &[NamespaceMetadata] {
         &self.namespaces
     }
+
+    /// Look up the metadata of the namespace with the given label.
+    pub fn namespace(&self, label: &str) -> Option<&NamespaceMetadata> {
+        self.namespaces
+            .iter()
+            .find(|namespace| namespace.label == label)
+    }
+
+    /// Get the labels of the namespaces this service has access to, in no particular order.
+    pub fn labels(&self) -> impl Iterator<Item = &str> {
+        self.namespaces
+            .iter()
+            .map(|namespace| namespace.label.as_str())
+    }
 }
 
 /// Metadata about a namespace the service has access to.
@@ -55,3 +69,37 @@ impl NamespaceMetadata {
         self.metadata
     }
 }
+
+#[test]
+fn test_namespace_lookup() {
+    let metadata = ServiceMetadata {
+        entity_id: ServiceId::from_uint(0xf001),
+        label: "svc".to_string(),
+        namespaces: vec![
+            NamespaceMetadata {
+                label: "orders".to_string(),
+                metadata: None,
+            },
+            NamespaceMetadata {
+                label: "users".to_string(),
+                metadata: Some(
+                    serde_json::json!({ "key": "value" })
+                        .as_object()
+                        .unwrap()
+                        .clone(),
+                ),
+            },
+        ],
+    };
+
+    assert_eq!(
+        vec!["orders", "users"],
+        metadata.labels().collect::<Vec<_>>()
+    );
+    assert!(metadata.namespace("orders").unwrap().metadata().is_none());
+    assert_eq!(
+        "value",
+        metadata.namespace("users").unwrap().metadata().unwrap()["key"]
+    );
+    assert!(metadata.namespace("svc").is_none());
+}
@@ -0,0 +1,95 @@
+//! Metrics hooks for requests made to Authly.
+
+use std::{
+    future::Future,
+    time::{Duration, Instant},
+};
+
+use crate::{error, Error, ErrorKind};
+
+/// A receiver of metrics about the requests the client makes to Authly.
+///
+/// Configured with [ClientBuilder::with_metrics](crate::ClientBuilder::with_metrics).
+pub trait Metrics: Send + Sync {
+    /// Called after each gRPC call to Authly completes.
+    ///
+    /// `rpc` is the snake_case name of the gRPC method, e.g. `access_control`.
+    /// Retried requests report each attempt separately.
+    fn on_rpc(&self, rpc: &'static str, duration: Duration, result: Result<(), ErrorKind>);
+}
+
+/// [Metrics] that discards all metrics, which is the default.
+#[derive(Clone, Copy, Default, Debug)]
+pub struct NoOpMetrics;
+
+impl Metrics for NoOpMetrics {
+    fn on_rpc(&self, _rpc: &'static str, _duration: Duration, _result: Result<(), ErrorKind>) {}
+}
+
+/// Await a gRPC call to Authly, reporting it to the metrics.
+pub(crate) async fn observe_rpc<T>(
+    metrics: &dyn Metrics,
+    rpc: &'static str,
+    response: impl Future<Output = Result<T, tonic::Status>>,
+) -> Result<T, Error> {
+    let started = Instant::now();
+    let result = response.await.map_err(error::tonic);
+
+    metrics.on_rpc(
+        rpc,
+        started.elapsed(),
+        result.as_ref().map(|_| ()).map_err(Error::kind),
+    );
+
+    result
+}
+
+#[tokio::test]
+async fn test_access_control_metrics() {
+    use std::sync::{Arc, Mutex};
+
+    use crate::{mock, AccessControl};
+
+    #[derive(Default)]
+    struct RecordingMetrics(Mutex<Vec<(&'static str, Result<(), ErrorKind>)>>);
+
+    impl Metrics for RecordingMetrics {
+        fn on_rpc(&self, rpc: &'static str, _duration: Duration, result: Result<(), ErrorKind>) {
+            self.0.lock().unwrap().push((rpc, result));
+        }
+    }
+
+    let metrics = Arc::new(RecordingMetrics::default());
+    let mut client = mock::client(mock::MockAuthly::default().serve().await);
+    Arc::get_mut(&mut client.state).unwrap().metrics = metrics.clone();
+
+    assert!(client.access_control_request().evaluate().await.unwrap());
+    client.health_check().await.unwrap();
+
+    let unavailable = mock::MockAuthly {
+        unavailable: true,
+        ..Default::default()
+    }
+    .serve()
+    .await;
+    let mut client = mock::client(unavailable);
+    {
+        let state = Arc::get_mut(&mut client.state).unwrap();
+        state.metrics = metrics.clone();
+        state.retry_policy = crate::RetryPolicy::never();
+    }
+    client
+        .access_control_request()
+        .evaluate()
+        .await
+        .unwrap_err();
+
+    assert_eq!(
+        vec![
+            ("access_control", Ok(())),
+            ("get_metadata", Ok(())),
+            ("access_control", Err(ErrorKind::Network)),
+        ],
+        *metrics.0.lock().unwrap()
+    );
+}
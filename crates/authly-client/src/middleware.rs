@@ -0,0 +1,255 @@
+//! Tower middleware for enforcing access control.
+
+use std::{
+    future::Future,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+};
+
+use authly_common::{id::ServiceId, mtls_server::PeerServiceEntity};
+use http::{header::AUTHORIZATION, Request, Response, StatusCode};
+use tracing::debug;
+
+use crate::{access_control::AccessControl, Error};
+
+/// A [tower::Layer] that enforces access control for each request to the wrapped service, e.g. an axum route.
+///
+/// The request is evaluated with the required resource attributes, on behalf of the subject of the request:
+/// The access token in the `Authorization` bearer header, and/or the [PeerServiceEntity] set up by the mTLS middleware.
+///
+/// Requests without any subject, or with an invalid access token, are rejected with `401 Unauthorized`.
+/// Denied requests are rejected with `403 Forbidden`.
+///
+/// # Examples
+///
+/// ```rust
+/// # use authly_client::{middleware::RequireAttributes, Client};
+/// # async fn test() -> anyhow::Result<()> {
+/// // note: Client is not properly built here.
+/// let client = Client::builder().connect().await?;
+///
+/// let require_read = RequireAttributes::new(client.into_dyn_access_control())
+///     .attribute(("my_namespace", "type", "orders"))
+///     .attribute(("my_namespace", "action", "read"));
+///
+/// // e.g. axum::Router::new().route("/orders", get(list_orders)).layer(require_read)
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone)]
+pub struct RequireAttributes {
+    access_control: Arc<dyn AccessControl + Send + Sync>,
+    attributes: Vec<(String, String, String)>,
+}
+
+impl RequireAttributes {
+    /// Create a new layer evaluating requests with the given [AccessControl] backend.
+    ///
+    /// Use [Client::into_dyn_access_control](crate::Client::into_dyn_access_control) to make the backend.
+    pub fn new(access_control: Arc<dyn AccessControl + Send + Sync>) -> Self {
+        Self {
+            access_control,
+            attributes: vec![],
+        }
+    }
+
+    /// Add a labelled resource attribute to be included in each access control request.
+    pub fn attribute(
+        mut self,
+        (namespace, property, attribute): (impl Into<String>, impl Into<String>, impl Into<String>),
+    ) -> Self {
+        self.attributes
+            .push((namespace.into(), property.into(), attribute.into()));
+        self
+    }
+
+    /// Evaluate access control, returning the rejection status code if not allowed.
+    async fn authorize(
+        &self,
+        access_token: Option<String>,
+        peer_service: Option<ServiceId>,
+    ) -> Result<(), StatusCode> {
+        if access_token.is_none() && peer_service.is_none() {
+            return Err(StatusCode::UNAUTHORIZED);
+        }
+
+        let mut request = self.access_control.access_control_request();
+
+        if let Some(access_token) = access_token {
+            let access_token = self
+                .access_control
+                .decode_access_token(access_token)
+                .map_err(|err| {
+                    debug!(?err, "invalid access token");
+                    StatusCode::UNAUTHORIZED
+                })?;
+            request = request.access_token(access_token);
+        }
+
+        if let Some(peer_service) = peer_service {
+            request = request.peer_entity_id(peer_service.upcast());
+        }
+
+        for (namespace, property, attribute) in &self.attributes {
+            request = request
+                .resource_attribute((namespace.as_str(), property.as_str(), attribute.as_str()))
+                .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+        }
+
+        match request.evaluate().await {
+            Ok(true) => Ok(()),
+            Ok(false) | Err(Error::AccessDenied | Error::AccessDeniedReason(_)) => {
+                Err(StatusCode::FORBIDDEN)
+            }
+            Err(err) if err.is_auth() => Err(StatusCode::UNAUTHORIZED),
+            Err(err) => {
+                debug!(?err, "access control evaluation failed");
+                Err(StatusCode::INTERNAL_SERVER_ERROR)
+            }
+        }
+    }
+}
+
+impl<S> tower::Layer<S> for RequireAttributes {
+    type Service = RequireAttributesService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        RequireAttributesService {
+            inner,
+            config: self.clone(),
+        }
+    }
+}
+
+/// The [tower::Service] made by the [RequireAttributes] layer.
+#[derive(Clone)]
+pub struct RequireAttributesService<S> {
+    inner: S,
+    config: RequireAttributes,
+}
+
+impl<S, ReqBody, ResBody> tower::Service<Request<ReqBody>> for RequireAttributesService<S>
+where
+    S: tower::Service<Request<ReqBody>, Response = Response<ResBody>> + Clone + Send + 'static,
+    S::Future: Send,
+    ReqBody: Send + 'static,
+    ResBody: Default,
+{
+    type Response = Response<ResBody>;
+    type Error = S::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: Request<ReqBody>) -> Self::Future {
+        // the clone is not necessarily ready, so use the ready service and leave the clone in its place
+        let clone = self.inner.clone();
+        let mut inner = std::mem::replace(&mut self.inner, clone);
+        let config = self.config.clone();
+
+        let access_token = request
+            .headers()
+            .get(AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .map(str::to_string);
+        let peer_service = request
+            .extensions()
+            .get::<PeerServiceEntity>()
+            .map(|peer| peer.0);
+
+        Box::pin(async move {
+            match config.authorize(access_token, peer_service).await {
+                Ok(()) => inner.call(request).await,
+                Err(status) => {
+                    let mut response = Response::new(ResBody::default());
+                    *response.status_mut() = status;
+                    Ok(response)
+                }
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+async fn test_status(path: &str, access_token: Option<&str>, peer: bool) -> StatusCode {
+    use authly_common::{id::AttrId, service::NamespacePropertyMapping};
+    use axum::{body::Body, routing::get, Router};
+    use tower::ServiceExt;
+
+    let read = AttrId::from_uint(1);
+    let mut property_mapping = NamespacePropertyMapping::default();
+    let action = property_mapping
+        .namespace_mut("orders".to_string())
+        .property_mut("action".to_string());
+    action.put("read".to_string(), read);
+    action.put("write".to_string(), AttrId::from_uint(2));
+
+    // allows requests for the `read` action only
+    let access_control = Arc::new(
+        crate::testing::MockAccessControl::new(property_mapping)
+            .with_verdict([read], true)
+            .with_access_token(crate::token::test_access_token(0)),
+    );
+    let require = |attribute| RequireAttributes::new(access_control.clone()).attribute(attribute);
+
+    let router = Router::new()
+        .route(
+            "/read",
+            get(|| async { "ok" }).layer(require(("orders", "action", "read"))),
+        )
+        .route(
+            "/write",
+            get(|| async { "ok" }).layer(require(("orders", "action", "write"))),
+        )
+        .route(
+            "/misconfigured",
+            get(|| async { "ok" }).layer(require(("orders", "action", "nope"))),
+        );
+
+    let mut request = Request::get(path);
+    if let Some(access_token) = access_token {
+        request = request.header(AUTHORIZATION, format!("Bearer {access_token}"));
+    }
+    if peer {
+        request = request.extension(PeerServiceEntity(ServiceId::from_uint(0xf001)));
+    }
+
+    router
+        .oneshot(request.body(Body::empty()).unwrap())
+        .await
+        .unwrap()
+        .status()
+}
+
+#[tokio::test]
+async fn test_require_attributes() {
+    assert_eq!(
+        StatusCode::OK,
+        test_status("/read", Some("token-0"), false).await
+    );
+    assert_eq!(StatusCode::OK, test_status("/read", None, true).await);
+    assert_eq!(
+        StatusCode::FORBIDDEN,
+        test_status("/write", Some("token-0"), false).await
+    );
+    assert_eq!(
+        StatusCode::FORBIDDEN,
+        test_status("/write", None, true).await
+    );
+    assert_eq!(
+        StatusCode::UNAUTHORIZED,
+        test_status("/read", Some("invalid"), false).await
+    );
+    assert_eq!(
+        StatusCode::UNAUTHORIZED,
+        test_status("/read", None, false).await
+    );
+    assert_eq!(
+        StatusCode::INTERNAL_SERVER_ERROR,
+        test_status("/misconfigured", Some("token-0"), false).await
+    );
+}
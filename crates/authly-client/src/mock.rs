@@ -0,0 +1,459 @@
+//! A mock Authly service for tests.
+
+use std::{
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex, OnceLock,
+    },
+    time::Duration,
+};
+
+use arc_swap::{ArcSwap, ArcSwapOption};
+use authly_common::{
+    access_token::{Authly, AuthlyAccessTokenClaims},
+    id::{AttrId, EntityId, Id128DynamicArrayConv, PersonaId, ServiceId},
+    policy::engine::{AccessControlParams, NoOpPolicyTracer, PolicyEngine},
+    proto::service::{
+        self as proto,
+        authly_service_client::AuthlyServiceClient,
+        authly_service_server::{AuthlyService, AuthlyServiceServer},
+    },
+};
+use futures_util::{stream::BoxStream, StreamExt};
+use rcgen::{CertificateParams, CertificateSigningRequestParams, DnType, Issuer, KeyPair, SanType};
+use rustls_pki_types::PrivateKeyDer;
+use tonic::{
+    transport::{server::TcpIncoming, Endpoint, Server},
+    Request, Response, Status,
+};
+
+use crate::{
+    background_worker::{spawn_background_worker, WorkerSenders},
+    builder::ConnectionParamsBuilder,
+    connection::{Connection, ConnectionStatus, ReconfigureStrategy, ReconnectPolicy},
+    identity::Identity,
+    Client, ClientState, Configuration, NamespacePropertyMapping,
+};
+
+/// A mock of the Authly service.
+#[derive(Default)]
+pub(crate) struct MockAuthly {
+    /// Whether the mock responds with an `unavailable` status.
+    pub unavailable: bool,
+
+    /// The received certificate signing requests.
+    pub sign_requests: Arc<Mutex<Vec<proto::CertificateSigningRequest>>>,
+
+    /// Controls whether message streams are available.
+    /// When changed to `false`, open message streams end and new ones are refused.
+    pub messages_available: Option<tokio::sync::watch::Receiver<bool>>,
+
+    /// When set, message streams forward the messages sent on this channel.
+    pub messages: Option<tokio::sync::broadcast::Sender<proto::ServiceMessage>>,
+
+    /// The configuration returned to the client.
+    pub configuration: Arc<Mutex<proto::ServiceConfiguration>>,
+
+    /// When set, certificate signing requests for other host names are rejected.
+    pub known_hosts: Option<Vec<String>>,
+
+    /// When set, access tokens are issued with this lifetime, signed by the mock CA key.
+    pub access_token_ttl: Option<Duration>,
+
+    /// The number of issued access tokens.
+    pub access_tokens_issued: Arc<AtomicUsize>,
+
+    /// When set, access control requests are denied for this reason.
+    pub denial_reason: Option<String>,
+
+    /// The metadata of received access token requests.
+    pub access_token_requests: Arc<Mutex<Vec<tonic::metadata::MetadataMap>>>,
+
+    /// The number of received pongs.
+    pub pongs: Arc<AtomicUsize>,
+
+    /// When set, access control requests are only allowed when including one of these resource attributes.
+    pub allowed_resource_attributes: Option<Vec<AttrId>>,
+
+    /// When set, access control requests are decided by evaluating this engine with the requested resource attributes.
+    pub policy_engine: Option<Arc<PolicyEngine>>,
+
+    /// Whether batch access control responses leave out the value of the last request.
+    pub truncate_batch_responses: bool,
+
+    /// The received access control requests, including those of batches.
+    pub access_control_requests: Arc<Mutex<Vec<proto::AccessControlRequest>>>,
+}
+
+/// The key of the mock Authly CA, which is also the identity of mock clients.
+fn ca_key_pair() -> &'static KeyPair {
+    static KEY_PAIR: OnceLock<KeyPair> = OnceLock::new();
+    KEY_PAIR.get_or_init(|| KeyPair::generate().unwrap())
+}
+
+impl MockAuthly {
+    /// Serve the mock on a random local port, returning its address.
+    pub async fn serve(self) -> SocketAddr {
+        let incoming = TcpIncoming::bind("127.0.0.1:0".parse().unwrap()).unwrap();
+        let addr = incoming.local_addr().unwrap();
+
+        tokio::spawn(
+            Server::builder()
+                .add_service(AuthlyServiceServer::new(self))
+                .serve_with_incoming(incoming),
+        );
+
+        addr
+    }
+
+    fn respond<T>(&self, response: T) -> Result<Response<T>, Status> {
+        if self.unavailable {
+            Err(Status::unavailable("mock unavailable"))
+        } else {
+            Ok(Response::new(response))
+        }
+    }
+
+    /// Record an access control request and decide its value.
+    fn access_control_value(
+        &self,
+        request: proto::AccessControlRequest,
+    ) -> proto::AccessControlValue {
+        if let Some(engine) = &self.policy_engine {
+            let params = AccessControlParams {
+                resource_attrs: request
+                    .resource_attributes
+                    .iter()
+                    .filter_map(|bytes| AttrId::try_from_bytes_dynamic(bytes))
+                    .collect(),
+                ..Default::default()
+            };
+            self.access_control_requests.lock().unwrap().push(request);
+
+            return match engine.eval(&params, &mut NoOpPolicyTracer) {
+                Ok(value) if value.is_allow() => proto::AccessControlValue::Allow,
+                _ => proto::AccessControlValue::Deny,
+            };
+        }
+
+        let allowed = self.denial_reason.is_none()
+            && self
+                .allowed_resource_attributes
+                .as_ref()
+                .is_none_or(|allowed| {
+                    request.resource_attributes.iter().any(|bytes| {
+                        AttrId::try_from_bytes_dynamic(bytes)
+                            .is_some_and(|attr| allowed.contains(&attr))
+                    })
+                });
+        self.access_control_requests.lock().unwrap().push(request);
+
+        if allowed {
+            proto::AccessControlValue::Allow
+        } else {
+            proto::AccessControlValue::Deny
+        }
+    }
+}
+
+#[tonic::async_trait]
+impl AuthlyService for MockAuthly {
+    async fn get_configuration(
+        &self,
+        _request: Request<proto::Empty>,
+    ) -> Result<Response<proto::ServiceConfiguration>, Status> {
+        self.respond(self.configuration.lock().unwrap().clone())
+    }
+
+    async fn get_metadata(
+        &self,
+        _request: Request<proto::Empty>,
+    ) -> Result<Response<proto::ServiceMetadata>, Status> {
+        self.respond(Default::default())
+    }
+
+    async fn get_access_token(
+        &self,
+        request: Request<proto::Empty>,
+    ) -> Result<Response<proto::AccessToken>, Status> {
+        self.access_token_requests
+            .lock()
+            .unwrap()
+            .push(request.metadata().clone());
+
+        let Some(ttl) = self.access_token_ttl else {
+            return Err(Status::unimplemented("mock"));
+        };
+        let now = time::OffsetDateTime::now_utc().unix_timestamp();
+        let entity_id: EntityId = PersonaId::from_uint(0xf002).upcast();
+
+        let token = jsonwebtoken::encode(
+            &jsonwebtoken::Header::new(jsonwebtoken::Algorithm::ES256),
+            &AuthlyAccessTokenClaims {
+                iat: now,
+                exp: now + ttl.as_secs() as i64,
+                iss: None,
+                aud: None,
+                authly: Authly {
+                    entity_id,
+                    entity_attributes: Default::default(),
+                },
+            },
+            &jsonwebtoken::EncodingKey::from_ec_der(&ca_key_pair().serialize_der()),
+        )
+        .map_err(|err| Status::internal(err.to_string()))?;
+        self.access_tokens_issued.fetch_add(1, Ordering::SeqCst);
+
+        self.respond(proto::AccessToken {
+            token,
+            entity_id: entity_id.to_array_dynamic().to_vec().into(),
+        })
+    }
+
+    async fn get_resource_property_mappings(
+        &self,
+        _request: Request<proto::Empty>,
+    ) -> Result<Response<proto::PropertyMappingsResponse>, Status> {
+        self.respond(Default::default())
+    }
+
+    async fn access_control(
+        &self,
+        request: Request<proto::AccessControlRequest>,
+    ) -> Result<Response<proto::AccessControlResponse>, Status> {
+        let value = self.access_control_value(request.into_inner());
+
+        self.respond(proto::AccessControlResponse {
+            value: value.into(),
+            denial_reason: match value {
+                proto::AccessControlValue::Deny => self.denial_reason.clone(),
+                proto::AccessControlValue::Allow => None,
+            },
+        })
+    }
+
+    async fn access_control_batch(
+        &self,
+        request: Request<proto::AccessControlBatchRequest>,
+    ) -> Result<Response<proto::AccessControlBatchResponse>, Status> {
+        let mut values: Vec<i32> = request
+            .into_inner()
+            .requests
+            .into_iter()
+            .map(|request| self.access_control_value(request).into())
+            .collect();
+        if self.truncate_batch_responses {
+            values.pop();
+        }
+
+        self.respond(proto::AccessControlBatchResponse { values })
+    }
+
+    async fn sign_certificate(
+        &self,
+        request: Request<proto::CertificateSigningRequest>,
+    ) -> Result<Response<proto::Certificate>, Status> {
+        let request = request.into_inner();
+        self.sign_requests.lock().unwrap().push(request.clone());
+
+        let mut csr = CertificateSigningRequestParams::from_der(&request.der.to_vec().into())
+            .map_err(|err| Status::invalid_argument(err.to_string()))?;
+        if let Some(known_hosts) = &self.known_hosts {
+            for san in &csr.params.subject_alt_names {
+                let SanType::DnsName(name) = san else {
+                    return Err(Status::invalid_argument("unsupported alt name"));
+                };
+                if !known_hosts.iter().any(|host| host == name.as_str()) {
+                    return Err(Status::invalid_argument(format!(
+                        "{} is not a known host name",
+                        name.as_str()
+                    )));
+                }
+            }
+        }
+        let now = time::OffsetDateTime::now_utc();
+        csr.params.not_before = now;
+        csr.params.not_after = now
+            + match request.validity_seconds {
+                0 => time::Duration::days(365),
+                seconds => time::Duration::seconds(seconds as i64),
+            };
+
+        let ca_key = KeyPair::generate().unwrap();
+        let mut ca_params = CertificateParams::new(vec![]).unwrap();
+        ca_params
+            .distinguished_name
+            .push(DnType::CommonName, "Mock Authly CA");
+        let ca_cert = ca_params.self_signed(&ca_key).unwrap();
+        let issuer = Issuer::new(ca_params, ca_key);
+        let cert = csr.signed_by(&issuer).unwrap();
+
+        self.respond(proto::Certificate {
+            der: cert.der().to_vec().into(),
+            chain: vec![ca_cert.der().to_vec().into()],
+        })
+    }
+
+    type MessagesStream = BoxStream<'static, Result<proto::ServiceMessage, Status>>;
+
+    async fn messages(
+        &self,
+        _request: Request<proto::Empty>,
+    ) -> Result<Response<Self::MessagesStream>, Status> {
+        if let Some(messages_tx) = &self.messages {
+            let stream =
+                futures_util::stream::unfold(messages_tx.subscribe(), |mut messages_rx| async {
+                    let message = messages_rx.recv().await.ok()?;
+                    Some((Ok(message), messages_rx))
+                });
+
+            return self.respond(Box::pin(stream) as Self::MessagesStream);
+        }
+
+        let Some(mut available_rx) = self.messages_available.clone() else {
+            return self.respond(Box::pin(futures_util::stream::pending()) as Self::MessagesStream);
+        };
+
+        if !*available_rx.borrow_and_update() {
+            return Err(Status::unavailable("mock messages unavailable"));
+        }
+
+        // a stream without messages, ending when messages become unavailable
+        let stream = futures_util::stream::once(async move {
+            let _ = available_rx.wait_for(|available| !available).await;
+        })
+        .filter_map(|()| futures_util::future::ready(None));
+
+        self.respond(Box::pin(stream) as Self::MessagesStream)
+    }
+
+    async fn pong(
+        &self,
+        _request: Request<proto::Empty>,
+    ) -> Result<Response<proto::Empty>, Status> {
+        self.pongs.fetch_add(1, Ordering::SeqCst);
+        self.respond(proto::Empty {})
+    }
+}
+
+/// Make a client using a plaintext connection to the given address, without a background worker.
+pub(crate) fn client(addr: SocketAddr) -> Client {
+    let (client, senders, mut closed_rx) = client_state(addr, ReconnectPolicy::default());
+
+    // keep the senders open for as long as the client, like the background worker does
+    tokio::spawn(async move {
+        let _senders = senders;
+        let _ = closed_rx.changed().await;
+    });
+
+    client
+}
+
+/// Make a client using a plaintext connection to the given address, without a background worker.
+///
+/// The returned senders can be used to simulate the background worker.
+pub(crate) fn client_with_senders(addr: SocketAddr) -> (Client, WorkerSenders) {
+    let (client, senders, _closed_rx) = client_state(addr, ReconnectPolicy::default());
+    (client, senders)
+}
+
+/// Make a client using a plaintext connection to the given address, with a running background worker.
+pub(crate) async fn client_with_worker(
+    addr: SocketAddr,
+    reconnect_policy: ReconnectPolicy,
+) -> Client {
+    let (client, senders, closed_rx) = client_state(addr, reconnect_policy);
+    let worker = spawn_background_worker(client.state.clone(), senders, closed_rx)
+        .await
+        .unwrap();
+    *client.state.worker.lock().unwrap() = Some(worker);
+
+    client
+}
+
+/// Make the client use a property mapping where `docs/visibility/public` is attribute 1,
+/// and `docs/visibility/private` is attribute 2.
+pub(crate) fn use_visibility_property_mapping(client: &Client) {
+    let mut resource_property_mapping = NamespacePropertyMapping::default();
+    let visibility = resource_property_mapping
+        .namespace_mut("docs".to_string())
+        .property_mut("visibility".to_string());
+    visibility.put("public".to_string(), AttrId::from_uint(1));
+    visibility.put("private".to_string(), AttrId::from_uint(2));
+
+    client.state.configuration.store(Arc::new(Configuration {
+        hosts: vec!["svc.local".to_string()],
+        resource_property_mapping: Arc::new(resource_property_mapping),
+        policy_engine: None,
+    }));
+}
+
+fn client_state(
+    addr: SocketAddr,
+    reconnect_policy: ReconnectPolicy,
+) -> (Client, WorkerSenders, tokio::sync::watch::Receiver<()>) {
+    let key_pair = ca_key_pair();
+    let mut cert_params = CertificateParams::new(vec![]).unwrap();
+    cert_params.distinguished_name.push(
+        DnType::CustomDnType(authly_common::certificate::oid::ENTITY_UNIQUE_IDENTIFIER.to_vec()),
+        ServiceId::from_uint(0xf001).to_string(),
+    );
+    let cert = cert_params.self_signed(key_pair).unwrap();
+
+    let mut params_builder = ConnectionParamsBuilder::new(format!("http://{addr}").into());
+    params_builder.authly_local_ca = Some(cert.pem().into_bytes());
+    params_builder.identity = Some(Identity::from_der(
+        cert.der().clone(),
+        PrivateKeyDer::Pkcs8(key_pair.serialize_der().into()),
+    ));
+    let params = params_builder.try_into_connection_params().unwrap();
+
+    let channel = Endpoint::from_shared(format!("http://{addr}"))
+        .unwrap()
+        .connect_lazy();
+
+    let (reconfigured_tx, reconfigured_rx) = tokio::sync::watch::channel(params.clone());
+    let (metadata_invalidated_tx, metadata_invalidated_rx) = tokio::sync::watch::channel(());
+    let (status_tx, status_rx) = tokio::sync::watch::channel(ConnectionStatus::Connected);
+    let (closed_tx, closed_rx) = tokio::sync::watch::channel(());
+
+    let client = Client {
+        state: Arc::new(ClientState {
+            conn: ArcSwap::new(Arc::new(Connection {
+                authly_service: AuthlyServiceClient::new(channel),
+                params: params.clone(),
+                url_index: 0,
+            })),
+            reconfigure: ReconfigureStrategy::Params(params),
+            reconnect_policy,
+            retry_policy: Default::default(),
+            reconfigured_rx,
+            reconfigure_debounce: crate::DEFAULT_RECONFIGURE_DEBOUNCE,
+            metadata_invalidated_rx,
+            status_rx,
+            closed_tx,
+            worker: Default::default(),
+            configuration: ArcSwap::new(Arc::new(Configuration {
+                hosts: vec!["svc.local".to_string()],
+                resource_property_mapping: Default::default(),
+                policy_engine: None,
+            })),
+            retired_jwt_decoding_key: ArcSwapOption::empty(),
+            access_token_cache: None,
+            session_token_scheme: Default::default(),
+            reqwest_base: Arc::new(|builder| builder),
+            server_cert_validity: crate::DEFAULT_SERVER_CERT_VALIDITY,
+            server_cert_rotation: None,
+            server_cert_not_after: ArcSwapOption::empty(),
+            metrics: Arc::new(crate::metrics::NoOpMetrics),
+        }),
+    };
+    let senders = WorkerSenders {
+        reconfigured_tx,
+        metadata_invalidated_tx,
+        status_tx,
+    };
+
+    (client, senders, closed_rx)
+}
@@ -0,0 +1,262 @@
+//! Test utilities for services using the Authly client.
+
+use std::{
+    collections::BTreeSet,
+    future::Future,
+    pin::Pin,
+    sync::{Arc, Mutex},
+};
+
+use authly_common::id::{AttrId, EntityId};
+
+use crate::{
+    access_control::{AccessControl, AccessControlRequestBuilder},
+    token::AccessToken,
+    Error, NamespacePropertyMapping,
+};
+
+/// An [AccessControl] implementation for unit tests, which returns configured verdicts without contacting Authly.
+///
+/// The verdict of a request is looked up by the exact set of its resource attributes,
+/// falling back to a default verdict. Every evaluated request is recorded for later assertions.
+///
+/// # Examples
+///
+/// ```rust
+/// # use authly_client::{AccessControl, NamespacePropertyMapping, testing::MockAccessControl};
+/// # use authly_common::id::AttrId;
+/// # async fn test() -> anyhow::Result<()> {
+/// let mut mapping = NamespacePropertyMapping::default();
+/// mapping
+///     .namespace_mut("orders".to_string())
+///     .property_mut("action".to_string())
+///     .put("read".to_string(), AttrId::from_uint(0xf001));
+///
+/// let access_control = MockAccessControl::new(mapping)
+///     .with_verdict([AttrId::from_uint(0xf001)], true);
+///
+/// assert!(access_control
+///     .access_control_request()
+///     .resource_attribute(("orders", "action", "read"))?
+///     .evaluate()
+///     .await?);
+/// assert_eq!(1, access_control.requests().len());
+/// # Ok(())
+/// # }
+/// ```
+pub struct MockAccessControl {
+    property_mapping: Arc<NamespacePropertyMapping>,
+    default_verdict: bool,
+    verdicts: Vec<(BTreeSet<AttrId>, bool)>,
+    access_tokens: Vec<Arc<AccessToken>>,
+    requests: Mutex<Vec<MockAccessControlRequest>>,
+}
+
+/// An access control request recorded by [MockAccessControl].
+#[derive(Clone)]
+pub struct MockAccessControlRequest {
+    /// The resource attributes of the request.
+    pub resource_attributes: BTreeSet<AttrId>,
+
+    /// The subject attributes of the request.
+    pub subject_attributes: BTreeSet<AttrId>,
+
+    /// The peer entity IDs of the request.
+    pub peer_entity_ids: BTreeSet<EntityId>,
+
+    /// The access token included in the request, if any.
+    pub access_token: Option<Arc<AccessToken>>,
+
+    /// The verdict returned for the request.
+    pub verdict: bool,
+}
+
+impl MockAccessControl {
+    /// Create a new mock using the given property mapping for resolving attribute labels.
+    ///
+    /// All requests are denied until configured otherwise.
+    pub fn new(property_mapping: NamespacePropertyMapping) -> Self {
+        Self {
+            property_mapping: Arc::new(property_mapping),
+            default_verdict: false,
+            verdicts: vec![],
+            access_tokens: vec![],
+            requests: Default::default(),
+        }
+    }
+
+    /// Set the verdict of requests not matching any of the resource attribute sets given to [Self::with_verdict].
+    pub fn with_default_verdict(mut self, verdict: bool) -> Self {
+        self.default_verdict = verdict;
+        self
+    }
+
+    /// Set the verdict of requests with exactly the given resource attributes.
+    pub fn with_verdict(
+        mut self,
+        resource_attributes: impl IntoIterator<Item = AttrId>,
+        verdict: bool,
+    ) -> Self {
+        let resource_attributes = resource_attributes.into_iter().collect();
+        self.verdicts
+            .retain(|(attrs, _)| *attrs != resource_attributes);
+        self.verdicts.push((resource_attributes, verdict));
+        self
+    }
+
+    /// Accept the given access token when decoding access tokens, e.g. in middleware.
+    ///
+    /// The token is matched by its encoded [AccessToken::token]. All other access tokens are rejected.
+    pub fn with_access_token(mut self, access_token: Arc<AccessToken>) -> Self {
+        self.access_tokens.push(access_token);
+        self
+    }
+
+    /// The requests evaluated so far, in order.
+    pub fn requests(&self) -> Vec<MockAccessControlRequest> {
+        self.requests.lock().unwrap().clone()
+    }
+
+    /// Forget the requests evaluated so far.
+    pub fn clear_requests(&self) {
+        self.requests.lock().unwrap().clear();
+    }
+}
+
+impl AccessControl for MockAccessControl {
+    fn access_control_request(&self) -> AccessControlRequestBuilder<'_> {
+        AccessControlRequestBuilder::new(self, self.property_mapping.clone())
+    }
+
+    fn evaluate(
+        &self,
+        builder: AccessControlRequestBuilder<'_>,
+    ) -> Pin<Box<dyn Future<Output = Result<bool, Error>> + Send + '_>> {
+        let resource_attributes: BTreeSet<AttrId> = builder.resource_attributes().collect();
+        let verdict = self
+            .verdicts
+            .iter()
+            .find(|(attrs, _)| *attrs == resource_attributes)
+            .map(|(_, verdict)| *verdict)
+            .unwrap_or(self.default_verdict);
+
+        self.requests
+            .lock()
+            .unwrap()
+            .push(MockAccessControlRequest {
+                resource_attributes,
+                subject_attributes: builder.subject_attributes().collect(),
+                peer_entity_ids: builder.peer_entity_ids().collect(),
+                access_token: builder.get_access_token().cloned(),
+                verdict,
+            });
+
+        Box::pin(async move { Ok(verdict) })
+    }
+
+    fn decode_access_token(&self, access_token: String) -> Result<Arc<AccessToken>, Error> {
+        self.access_tokens
+            .iter()
+            .find(|known| known.token == access_token)
+            .cloned()
+            .ok_or_else(|| Error::InvalidAccessToken(anyhow::anyhow!("unknown access token")))
+    }
+}
+
+#[tokio::test]
+async fn test_mock_access_control() {
+    use authly_common::id::PersonaId;
+
+    const READ: AttrId = AttrId::from_uint(0xf001);
+    const WRITE: AttrId = AttrId::from_uint(0xf002);
+    const ORDERS: AttrId = AttrId::from_uint(0xf003);
+
+    let mut mapping = NamespacePropertyMapping::default();
+    let orders = mapping.namespace_mut("orders".to_string());
+    orders
+        .property_mut("action".to_string())
+        .put("read".to_string(), READ);
+    orders
+        .property_mut("action".to_string())
+        .put("write".to_string(), WRITE);
+    orders
+        .property_mut("type".to_string())
+        .put("orders".to_string(), ORDERS);
+
+    let access_control = MockAccessControl::new(mapping)
+        .with_verdict([ORDERS, READ], true)
+        .with_verdict([ORDERS, WRITE], false)
+        .with_verdict([ORDERS, WRITE], true);
+    let peer = PersonaId::from_uint(0xf004).upcast();
+
+    // code under test only sees the trait object
+    let dyn_access_control: &(dyn AccessControl + Send + Sync) = &access_control;
+
+    assert!(dyn_access_control
+        .access_control_request()
+        .resource_attribute(("orders", "type", "orders"))
+        .unwrap()
+        .resource_attribute(("orders", "action", "read"))
+        .unwrap()
+        .peer_entity_id(peer)
+        .evaluate()
+        .await
+        .unwrap());
+    assert!(dyn_access_control
+        .access_control_request()
+        .resource_attribute(("orders", "type", "orders"))
+        .unwrap()
+        .resource_attribute(("orders", "action", "write"))
+        .unwrap()
+        .evaluate()
+        .await
+        .unwrap());
+    dyn_access_control
+        .access_control_request()
+        .resource_attribute(("orders", "action", "read"))
+        .unwrap()
+        .enforce()
+        .await
+        .unwrap_err();
+
+    let requests = access_control.requests();
+    assert_eq!(
+        vec![true, true, false],
+        requests
+            .iter()
+            .map(|request| request.verdict)
+            .collect::<Vec<_>>()
+    );
+    assert_eq!(
+        BTreeSet::from([ORDERS, READ]),
+        requests[0].resource_attributes
+    );
+    assert_eq!(BTreeSet::from([peer]), requests[0].peer_entity_ids);
+    assert!(requests[1].peer_entity_ids.is_empty());
+    assert!(requests[2].access_token.is_none());
+
+    access_control.clear_requests();
+    assert!(access_control.requests().is_empty());
+
+    let permissive =
+        MockAccessControl::new(NamespacePropertyMapping::default()).with_default_verdict(true);
+    assert!(permissive
+        .access_control_request()
+        .evaluate()
+        .await
+        .unwrap());
+
+    let access_token = crate::token::test_access_token(0);
+    let with_token = MockAccessControl::new(NamespacePropertyMapping::default())
+        .with_access_token(access_token.clone());
+    assert!(Arc::ptr_eq(
+        &access_token,
+        &with_token
+            .decode_access_token(access_token.token.clone())
+            .unwrap()
+    ));
+    assert!(matches!(
+        with_token.decode_access_token("other".to_string()),
+        Err(Error::InvalidAccessToken(_))
+    ));
+}
@@ -0,0 +1,141 @@
+//! Benchmarks of access control evaluation in the policy engine.
+//!
+//! Run with `cargo bench -p authly-common --bench policy_engine`.
+
+use std::hint::black_box;
+
+use authly_common::{
+    id::{kind::Kind, AttrId, EntityId, PolicyId, PropId},
+    policy::{
+        code::{to_bytecode, OpCode, PolicyValue},
+        engine::{AccessControlParams, EvalScratch, NoOpPolicyTracer, PolicyEngine},
+    },
+};
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+
+/// The number of roles, each with its own allow and deny policy.
+const ROLES: u128 = 100;
+
+/// The number of distinct subjects in a batch.
+const SUBJECTS: u128 = 1000;
+
+/// The number of distinct role combinations among the subjects.
+const ROLE_SETS: u128 = 10;
+
+/// The number of kinds of resources in a batch.
+const RESOURCE_KINDS: u128 = 3;
+
+const ENTITY_PROP: PropId = PropId::from_uint(1);
+
+fn role(n: u128) -> AttrId {
+    AttrId::from_uint(n)
+}
+
+fn resource_kind(n: u128) -> AttrId {
+    AttrId::from_uint(ROLES + n)
+}
+
+/// An engine where each role may access one kind of resource, unless the subject is banned.
+fn engine() -> PolicyEngine {
+    let mut engine = PolicyEngine::default();
+
+    for n in 0..ROLES {
+        let allow = PolicyId::from_uint(2 * n);
+        let deny = PolicyId::from_uint(2 * n + 1);
+
+        engine
+            .add_policy_verified(
+                allow,
+                PolicyValue::Allow,
+                to_bytecode(&[
+                    OpCode::LoadConstAttrId(role(n)),
+                    OpCode::LoadSubjectAttrs,
+                    OpCode::IdSetContains,
+                    OpCode::LoadConstAttrId(resource_kind(n % RESOURCE_KINDS)),
+                    OpCode::LoadResourceAttrs,
+                    OpCode::IdSetContains,
+                    OpCode::And,
+                    OpCode::Return,
+                ]),
+            )
+            .unwrap();
+        engine
+            .add_policy_verified(
+                deny,
+                PolicyValue::Deny,
+                to_bytecode(&[
+                    OpCode::LoadSubjectId(ENTITY_PROP),
+                    OpCode::LoadConstEntityId(EntityId::from_uint(Kind::Persona, n)),
+                    OpCode::IsEq,
+                    OpCode::Return,
+                ]),
+            )
+            .unwrap();
+
+        engine.add_trigger([role(n)], [allow, deny]);
+    }
+
+    engine
+}
+
+/// A batch of every subject against every kind of resource.
+fn batch() -> Vec<AccessControlParams> {
+    let mut batch = vec![];
+
+    for subject in 0..SUBJECTS {
+        let role_set = subject % ROLE_SETS;
+        for kind in 0..RESOURCE_KINDS {
+            let mut params = AccessControlParams::default();
+            params.subject_eids.insert(
+                ENTITY_PROP,
+                EntityId::from_uint(Kind::Persona, 1_000 + subject),
+            );
+            params
+                .subject_attrs
+                .extend((0..ROLES / ROLE_SETS).map(|n| role(role_set + n * ROLE_SETS)));
+            params.resource_attrs.insert(resource_kind(kind));
+            batch.push(params);
+        }
+    }
+
+    batch
+}
+
+fn policy_engine(c: &mut Criterion) {
+    let engine = engine();
+    let batch = batch();
+
+    let mut group = c.benchmark_group("policy_engine");
+    group.throughput(Throughput::Elements(batch.len() as u64));
+
+    group.bench_function("eval", |b| {
+        b.iter(|| {
+            for params in &batch {
+                black_box(engine.eval(black_box(params), &mut NoOpPolicyTracer)).unwrap();
+            }
+        })
+    });
+
+    let mut scratch = EvalScratch::default();
+    group.bench_function("eval_with_scratch", |b| {
+        b.iter(|| {
+            for params in &batch {
+                black_box(engine.eval_with_scratch(
+                    black_box(params),
+                    &mut scratch,
+                    &mut NoOpPolicyTracer,
+                ))
+                .unwrap();
+            }
+        })
+    });
+
+    group.bench_function("eval_batch", |b| {
+        b.iter(|| black_box(engine.eval_batch(black_box(&batch), &mut NoOpPolicyTracer)))
+    });
+
+    group.finish();
+}
+
+criterion_group!(benches, policy_engine);
+criterion_main!(benches);
@@ -16,10 +16,28 @@ pub struct AuthlyAccessTokenClaims {
     /// Expiration time
     pub exp: i64,
 
+    /// Issuer, if set by Authly.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub iss: Option<String>,
+
+    /// Audience, if set by Authly.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub aud: Option<Audience>,
+
     /// Authy claims
     pub authly: Authly,
 }
 
+/// The audience claim, which is either a single value or a list of values.
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Debug)]
+#[serde(untagged)]
+pub enum Audience {
+    /// A single audience.
+    Single(String),
+    /// Multiple audiences.
+    Multiple(Vec<String>),
+}
+
 /// The authly claim.
 #[derive(Serialize, Deserialize, Debug)]
 pub struct Authly {
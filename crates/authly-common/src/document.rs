@@ -1,15 +1,18 @@
 //! Authly document type definitions.
 
-use std::collections::BTreeMap;
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    ops::Range,
+};
 
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use toml::Spanned;
 use uuid::Uuid;
 
 use crate::{id::EntityId, property::QualifiedAttributeName};
 
 /// The deserialized representation of an authly document.
-#[derive(Deserialize)]
+#[derive(Serialize, Deserialize, PartialEq)]
 #[serde(deny_unknown_fields)]
 #[allow(missing_docs)]
 pub struct Document {
@@ -18,48 +21,80 @@ pub struct Document {
     pub authly_document: AuthlyDocument,
 
     /// Collection of settings for the local authly cluster
-    #[serde(default, rename = "local-settings")]
+    #[serde(
+        default,
+        rename = "local-settings",
+        skip_serializing_if = "Option::is_none"
+    )]
     pub local_settings: Option<BTreeMap<Spanned<String>, Spanned<String>>>,
 
-    #[serde(default)]
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
     pub entity: Vec<Entity>,
 
-    #[serde(default, rename = "service-entity")]
+    #[serde(
+        default,
+        rename = "service-entity",
+        skip_serializing_if = "Vec::is_empty"
+    )]
     pub service_entity: Vec<Entity>,
 
-    #[serde(default)]
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
     pub domain: Vec<Domain>,
 
-    #[serde(default, rename = "service-domain")]
+    #[serde(
+        default,
+        rename = "service-domain",
+        skip_serializing_if = "Vec::is_empty"
+    )]
     pub service_domain: Vec<ServiceDomain>,
 
-    #[serde(default)]
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
     pub email: Vec<Email>,
 
-    #[serde(default, rename = "password-hash")]
+    #[serde(
+        default,
+        rename = "password-hash",
+        skip_serializing_if = "Vec::is_empty"
+    )]
     pub password_hash: Vec<PasswordHash>,
 
-    #[serde(default)]
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
     pub members: Vec<Members>,
 
-    #[serde(default, rename = "entity-attribute-assignment")]
+    #[serde(
+        default,
+        rename = "entity-attribute-assignment",
+        skip_serializing_if = "Vec::is_empty"
+    )]
     pub entity_attribute_assignment: Vec<EntityAttributeAssignment>,
 
-    #[serde(default, rename = "entity-property")]
+    #[serde(
+        default,
+        rename = "entity-property",
+        skip_serializing_if = "Vec::is_empty"
+    )]
     pub entity_property: Vec<EntityProperty>,
 
-    #[serde(default, rename = "resource-property")]
+    #[serde(
+        default,
+        rename = "resource-property",
+        skip_serializing_if = "Vec::is_empty"
+    )]
     pub resource_property: Vec<ResourceProperty>,
 
-    #[serde(default)]
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
     pub policy: Vec<Policy>,
 
-    #[serde(default, rename = "policy-binding")]
+    #[serde(
+        default,
+        rename = "policy-binding",
+        skip_serializing_if = "Vec::is_empty"
+    )]
     pub policy_binding: Vec<PolicyBinding>,
 }
 
 /// The authly document header
-#[derive(Deserialize)]
+#[derive(Serialize, Deserialize, PartialEq)]
 #[serde(deny_unknown_fields)]
 pub struct AuthlyDocument {
     /// The ID of this document as an Authly authority
@@ -70,48 +105,56 @@ pub struct AuthlyDocument {
 pub type DynamicObject = serde_json::Map<String, serde_json::Value>;
 
 /// An entity definition
-#[derive(Deserialize, Debug)]
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
 #[serde(deny_unknown_fields)]
 pub struct Entity {
     /// The id of this entity.
     pub eid: Spanned<EntityId>,
 
     /// A label for the entity visible in the document namespace.
-    #[serde(default)]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
     pub label: Option<Spanned<String>>,
 
     /// Metadata about this entity.
     /// The metadata is not used by authly itself, but can be used by services which have read access to the entity.
-    #[serde(default)]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
     pub metadata: Option<Spanned<DynamicObject>>,
 
     /// Attributes bound to the entity.
-    #[serde(default)]
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
     pub attributes: Vec<Spanned<QualifiedAttributeName>>,
 
     /// List of usernames.
-    #[serde(default)]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
     pub username: Option<Spanned<String>>,
 
     /// List of email addresses.
-    #[serde(default)]
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
     pub email: Vec<Spanned<String>>,
 
     /// List of password hashes.
-    #[serde(default, rename = "password-hash")]
+    #[serde(
+        default,
+        rename = "password-hash",
+        skip_serializing_if = "Vec::is_empty"
+    )]
     pub password_hash: Vec<String>,
 
     /// A list of service hostnames
-    #[serde(default)]
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
     pub hosts: Vec<String>,
 
     /// An optional kubernetes account.
-    #[serde(default, rename = "kubernetes-account")]
+    #[serde(
+        default,
+        rename = "kubernetes-account",
+        skip_serializing_if = "Option::is_none"
+    )]
     pub kubernetes_account: Option<KubernetesAccount>,
 }
 
 /// An domain declaration
-#[derive(Deserialize, Debug)]
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
 #[serde(deny_unknown_fields)]
 pub struct Domain {
     /// A label for the entity visible in the document namespace.
@@ -119,12 +162,12 @@ pub struct Domain {
 
     /// Metadata about this domain.
     /// The metadata is not used by authly itself, but can be read and used by services.
-    #[serde(default)]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
     pub metadata: Option<Spanned<DynamicObject>>,
 }
 
 /// An association of a service and a domain the service can use.
-#[derive(Deserialize, Debug)]
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
 #[serde(deny_unknown_fields)]
 pub struct ServiceDomain {
     /// A label identifying the impliied service-entity.
@@ -135,7 +178,7 @@ pub struct ServiceDomain {
 }
 
 /// An email address assignment.
-#[derive(Deserialize, Debug)]
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
 #[serde(deny_unknown_fields)]
 pub struct Email {
     /// The label of the entity that is assigned this address.
@@ -146,7 +189,7 @@ pub struct Email {
 }
 
 /// An password hash assignment.
-#[derive(Deserialize, Debug)]
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
 #[serde(deny_unknown_fields)]
 pub struct PasswordHash {
     /// The label of the entity that is assigned this password hash.
@@ -159,7 +202,7 @@ pub struct PasswordHash {
 /// A members assignment.
 ///
 /// In the authly model, any kind of entity may have members.
-#[derive(Deserialize, Debug)]
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
 #[serde(deny_unknown_fields)]
 pub struct Members {
     /// The label of the entity that members is assigned to.
@@ -170,7 +213,7 @@ pub struct Members {
 }
 
 /// A definition of an entity property.
-#[derive(Deserialize)]
+#[derive(Serialize, Deserialize, PartialEq)]
 #[serde(deny_unknown_fields)]
 pub struct EntityProperty {
     /// The label of the namespace this property is defined inside.
@@ -180,18 +223,18 @@ pub struct EntityProperty {
     pub label: Spanned<String>,
 
     /// The list of attributes of the property.
-    #[serde(default)]
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
     pub attributes: Vec<Spanned<String>>,
 }
 
 /// A kubernetes account definition.
-#[derive(Default, Deserialize, Debug)]
+#[derive(Default, Serialize, Deserialize, PartialEq, Debug)]
 #[serde(deny_unknown_fields)]
 pub struct KubernetesAccount {
     /// The kubernetes namespace.
     ///
     /// If unspecified, it means the same namespace that Authly itself is deployed within.
-    #[serde(default)]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
     pub namespace: Option<String>,
 
     /// The account name.
@@ -199,7 +242,7 @@ pub struct KubernetesAccount {
 }
 
 /// A definition of a resource property.
-#[derive(Deserialize)]
+#[derive(Serialize, Deserialize, PartialEq)]
 #[serde(deny_unknown_fields)]
 pub struct ResourceProperty {
     /// The label of the namespace this property is defined inside.
@@ -209,30 +252,30 @@ pub struct ResourceProperty {
     pub label: Spanned<String>,
 
     /// The list of attributes of the property.
-    #[serde(default)]
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
     pub attributes: Vec<Spanned<String>>,
 }
 
 /// A policy definition.
 ///
 /// A policy must contain either an `allow` or `deny` expression.
-#[derive(Deserialize)]
+#[derive(Serialize, Deserialize, PartialEq)]
 #[serde(deny_unknown_fields)]
 pub struct Policy {
     /// The policy label.
     pub label: Spanned<String>,
 
     /// An allow expression.
-    #[serde(default)]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
     pub allow: Option<Spanned<String>>,
 
     /// An deny expression.
-    #[serde(default)]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
     pub deny: Option<Spanned<String>>,
 }
 
 /// A policy binding.
-#[derive(Deserialize)]
+#[derive(Serialize, Deserialize, PartialEq)]
 pub struct PolicyBinding {
     /// The attribute set which will trigger the policy set.
     pub attributes: Vec<Spanned<QualifiedAttributeName>>,
@@ -242,7 +285,7 @@ pub struct PolicyBinding {
 }
 
 /// An entity attribute binding, which assigns attributes to entities.
-#[derive(Deserialize)]
+#[derive(Serialize, Deserialize, PartialEq)]
 pub struct EntityAttributeAssignment {
     /// An Entity ID or label identifying the entity to assign to.
     pub entity: Spanned<String>,
@@ -256,13 +299,312 @@ impl Document {
     pub fn from_toml(toml: &str) -> anyhow::Result<Self> {
         Ok(preprocess(toml::from_str(toml)?))
     }
+
+    /// Deserialize document from `json` format.
+    ///
+    /// JSON carries no span information, so every [Spanned] value in the document gets the empty span `0..0`.
+    pub fn from_json(json: &str) -> anyhow::Result<Self> {
+        Self::from_spanless(serde_json::from_str(json)?)
+    }
+
+    /// Deserialize document from `yaml` format.
+    ///
+    /// Like [Self::from_json], every [Spanned] value in the document gets the empty span `0..0`.
+    #[cfg(feature = "document-yaml")]
+    pub fn from_yaml(yaml: &str) -> anyhow::Result<Self> {
+        Self::from_spanless(serde_norway::from_str(yaml)?)
+    }
+
+    fn from_spanless(value: serde_json::Value) -> anyhow::Result<Self> {
+        Ok(preprocess(Document::deserialize(spanless::Value(value))?))
+    }
+
+    /// Serialize document to `toml` format.
+    ///
+    /// Spans are not retained, and the output reflects the preprocessed document:
+    /// emails and password hashes are emitted as separate tables referring to the entity label.
+    pub fn to_toml(&self) -> anyhow::Result<String> {
+        Ok(toml::to_string(self)?)
+    }
+
+    /// Check that all label references within the document resolve,
+    /// and that entity ids, labels and properties are not defined more than once.
+    ///
+    /// References using an explicit [EntityId] are not checked, as the entity may be defined in another document.
+    /// Attributes are only checked when their namespace is declared in this document.
+    pub fn validate(&self) -> Result<(), Vec<DocumentError>> {
+        let entity_labels: BTreeSet<&str> = self
+            .entity
+            .iter()
+            .chain(&self.service_entity)
+            .filter_map(|entity| Some(entity.label.as_ref()?.get_ref().as_str()))
+            .collect();
+        let service_labels: BTreeSet<&str> = self
+            .service_entity
+            .iter()
+            .filter_map(|entity| Some(entity.label.as_ref()?.get_ref().as_str()))
+            .collect();
+        let domain_labels: BTreeSet<&str> = self
+            .domain
+            .iter()
+            .map(|domain| domain.label.get_ref().as_str())
+            .collect();
+        let policy_labels: BTreeSet<&str> = self
+            .policy
+            .iter()
+            .map(|policy| policy.label.get_ref().as_str())
+            .collect();
+        let entity_attributes: BTreeSet<(&str, &str, &str)> = self
+            .entity_property
+            .iter()
+            .flat_map(|property| {
+                property.attributes.iter().map(|attribute| {
+                    (
+                        property.namespace.get_ref().as_str(),
+                        property.label.get_ref().as_str(),
+                        attribute.get_ref().as_str(),
+                    )
+                })
+            })
+            .collect();
+
+        let mut errors = vec![];
+
+        check_duplicates(
+            self.entity
+                .iter()
+                .chain(&self.service_entity)
+                .map(|entity| Spanned::new(entity.eid.span(), entity.eid.get_ref().to_string())),
+            DocumentErrorDuplicate::EntityId,
+            &mut errors,
+        );
+        check_duplicates(
+            self.entity
+                .iter()
+                .chain(&self.service_entity)
+                .filter_map(|entity| entity.label.clone()),
+            DocumentErrorDuplicate::EntityLabel,
+            &mut errors,
+        );
+        check_duplicates(
+            self.policy.iter().map(|policy| policy.label.clone()),
+            DocumentErrorDuplicate::PolicyLabel,
+            &mut errors,
+        );
+        check_duplicates(
+            self.entity_property.iter().map(|property| {
+                Spanned::new(
+                    property.label.span(),
+                    format!(
+                        "{}:{}",
+                        property.namespace.get_ref(),
+                        property.label.get_ref()
+                    ),
+                )
+            }),
+            DocumentErrorDuplicate::EntityProperty,
+            &mut errors,
+        );
+        check_duplicates(
+            self.resource_property.iter().map(|property| {
+                Spanned::new(
+                    property.label.span(),
+                    format!(
+                        "{}:{}",
+                        property.namespace.get_ref(),
+                        property.label.get_ref()
+                    ),
+                )
+            }),
+            DocumentErrorDuplicate::ResourceProperty,
+            &mut errors,
+        );
+
+        let check_entity = |entity: &Spanned<String>, errors: &mut Vec<DocumentError>| {
+            if entity.get_ref().parse::<EntityId>().is_err()
+                && !entity_labels.contains(entity.get_ref().as_str())
+            {
+                errors.push(DocumentError::UnresolvedEntity(entity.clone()));
+            }
+        };
+
+        for email in &self.email {
+            check_entity(&email.entity, &mut errors);
+        }
+
+        for password_hash in &self.password_hash {
+            check_entity(&password_hash.entity, &mut errors);
+        }
+
+        for members in &self.members {
+            check_entity(&members.entity, &mut errors);
+
+            for member in &members.members {
+                check_entity(member, &mut errors);
+            }
+        }
+
+        for assignment in &self.entity_attribute_assignment {
+            check_entity(&assignment.entity, &mut errors);
+
+            for attribute in &assignment.attributes {
+                let name = attribute.get_ref();
+                let namespace = name.namespace.as_str();
+
+                if (service_labels.contains(namespace) || domain_labels.contains(namespace))
+                    && !entity_attributes.contains(&(
+                        namespace,
+                        name.property.as_str(),
+                        name.attribute.as_str(),
+                    ))
+                {
+                    errors.push(DocumentError::UnresolvedAttribute(Spanned::new(
+                        attribute.span(),
+                        name.to_string(),
+                    )));
+                }
+            }
+        }
+
+        for service_domain in &self.service_domain {
+            if !service_labels.contains(service_domain.service.get_ref().as_str()) {
+                errors.push(DocumentError::UnresolvedService(
+                    service_domain.service.clone(),
+                ));
+            }
+
+            if !domain_labels.contains(service_domain.domain.get_ref().as_str()) {
+                errors.push(DocumentError::UnresolvedDomain(
+                    service_domain.domain.clone(),
+                ));
+            }
+        }
+
+        for binding in &self.policy_binding {
+            for policy in &binding.policies {
+                if !policy_labels.contains(policy.get_ref().as_str()) {
+                    errors.push(DocumentError::UnresolvedPolicy(policy.clone()));
+                }
+            }
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+}
+
+/// A document validation error, see [Document::validate].
+///
+/// Each error holds the offending reference, spanned within the source document.
+#[derive(thiserror::Error, Clone, PartialEq, Eq, Debug)]
+pub enum DocumentError {
+    /// A reference to an entity label that is not defined.
+    #[error("unresolved entity `{}`", .0.get_ref())]
+    UnresolvedEntity(Spanned<String>),
+
+    /// A reference to a service label that is not defined.
+    #[error("unresolved service `{}`", .0.get_ref())]
+    UnresolvedService(Spanned<String>),
+
+    /// A reference to a domain label that is not defined.
+    #[error("unresolved domain `{}`", .0.get_ref())]
+    UnresolvedDomain(Spanned<String>),
+
+    /// A reference to a policy label that is not defined.
+    #[error("unresolved policy `{}`", .0.get_ref())]
+    UnresolvedPolicy(Spanned<String>),
+
+    /// A reference to an entity attribute that is not defined.
+    #[error("unresolved attribute `{}`", .0.get_ref())]
+    UnresolvedAttribute(Spanned<String>),
+
+    /// Something defined more than once.
+    #[error("duplicate {kind} `{}`", .duplicate.get_ref())]
+    Duplicate {
+        /// What kind of definition is duplicated.
+        kind: DocumentErrorDuplicate,
+
+        /// The duplicate definition.
+        duplicate: Spanned<String>,
+
+        /// The span of the first definition.
+        original: Range<usize>,
+    },
+}
+
+/// The kind of definition in [DocumentError::Duplicate].
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum DocumentErrorDuplicate {
+    /// An entity id.
+    EntityId,
+    /// An entity label.
+    EntityLabel,
+    /// A policy label.
+    PolicyLabel,
+    /// An entity property `namespace:label`.
+    EntityProperty,
+    /// A resource property `namespace:label`.
+    ResourceProperty,
+}
+
+impl std::fmt::Display for DocumentErrorDuplicate {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::EntityId => write!(f, "entity id"),
+            Self::EntityLabel => write!(f, "entity label"),
+            Self::PolicyLabel => write!(f, "policy label"),
+            Self::EntityProperty => write!(f, "entity property"),
+            Self::ResourceProperty => write!(f, "resource property"),
+        }
+    }
+}
+
+impl DocumentError {
+    /// The span of the offending reference or definition in the source document.
+    pub fn span(&self) -> Range<usize> {
+        match self {
+            Self::UnresolvedEntity(spanned)
+            | Self::UnresolvedService(spanned)
+            | Self::UnresolvedDomain(spanned)
+            | Self::UnresolvedPolicy(spanned)
+            | Self::UnresolvedAttribute(spanned)
+            | Self::Duplicate {
+                duplicate: spanned, ..
+            } => spanned.span(),
+        }
+    }
+}
+
+fn check_duplicates(
+    items: impl Iterator<Item = Spanned<String>>,
+    kind: DocumentErrorDuplicate,
+    errors: &mut Vec<DocumentError>,
+) {
+    let mut seen: BTreeMap<String, Range<usize>> = BTreeMap::new();
+
+    for item in items {
+        if let Some(original) = seen.get(item.get_ref()) {
+            errors.push(DocumentError::Duplicate {
+                kind,
+                original: original.clone(),
+                duplicate: item,
+            });
+        } else {
+            seen.insert(item.get_ref().clone(), item.span());
+        }
+    }
 }
 
 fn preprocess(mut doc: Document) -> Document {
     for user in &mut doc.entity {
+        // a generated label points at the entity's eid, so errors render at a meaningful location
+        let eid_span = user.eid.span();
         let label = user
             .label
-            .get_or_insert_with(|| Spanned::new(0..0, Uuid::new_v4().to_string()));
+            .get_or_insert_with(|| Spanned::new(eid_span, Uuid::new_v4().to_string()));
 
         for email in std::mem::take(&mut user.email) {
             doc.email.push(Email {
@@ -277,7 +619,98 @@ fn preprocess(mut doc: Document) -> Document {
                 hash: pw_hash,
             });
         }
+
+        let attributes = std::mem::take(&mut user.attributes);
+        if !attributes.is_empty() {
+            doc.entity_attribute_assignment
+                .push(EntityAttributeAssignment {
+                    entity: label.clone(),
+                    attributes,
+                });
+        }
     }
 
     doc
 }
+
+/// Deserialization of span-less formats into types containing [Spanned] values.
+mod spanless {
+    use serde::de::{
+        value::{MapDeserializer, SeqDeserializer},
+        IntoDeserializer, Visitor,
+    };
+    use serde_spanned::de::{is_spanned, SpannedDeserializer};
+
+    /// A [serde_json::Value] deserializer that gives each [super::Spanned] the empty span.
+    pub struct Value(pub serde_json::Value);
+
+    impl<'de> IntoDeserializer<'de, serde_json::Error> for Value {
+        type Deserializer = Self;
+
+        fn into_deserializer(self) -> Self::Deserializer {
+            self
+        }
+    }
+
+    impl<'de> serde::Deserializer<'de> for Value {
+        type Error = serde_json::Error;
+
+        fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+            match self.0 {
+                serde_json::Value::Array(array) => {
+                    visitor.visit_seq(SeqDeserializer::new(array.into_iter().map(Value)))
+                }
+                serde_json::Value::Object(object) => {
+                    visitor.visit_map(MapDeserializer::new(object.into_iter().map(
+                        |(key, value)| (Value(serde_json::Value::String(key)), Value(value)),
+                    )))
+                }
+                value => value.deserialize_any(visitor),
+            }
+        }
+
+        fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+            match self.0 {
+                serde_json::Value::Null => visitor.visit_none(),
+                _ => visitor.visit_some(self),
+            }
+        }
+
+        fn deserialize_newtype_struct<V: Visitor<'de>>(
+            self,
+            _name: &'static str,
+            visitor: V,
+        ) -> Result<V::Value, Self::Error> {
+            visitor.visit_newtype_struct(self)
+        }
+
+        fn deserialize_struct<V: Visitor<'de>>(
+            self,
+            name: &'static str,
+            fields: &'static [&'static str],
+            visitor: V,
+        ) -> Result<V::Value, Self::Error> {
+            if is_spanned(name) {
+                visitor.visit_map(SpannedDeserializer::new(self, 0..0))
+            } else if let serde_json::Value::Object(_) = &self.0 {
+                self.deserialize_any(visitor)
+            } else {
+                self.0.deserialize_struct(name, fields, visitor)
+            }
+        }
+
+        fn deserialize_enum<V: Visitor<'de>>(
+            self,
+            name: &'static str,
+            variants: &'static [&'static str],
+            visitor: V,
+        ) -> Result<V::Value, Self::Error> {
+            self.0.deserialize_enum(name, variants, visitor)
+        }
+
+        serde::forward_to_deserialize_any! {
+            bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string
+            bytes byte_buf unit unit_struct seq tuple tuple_struct map identifier ignored_any
+        }
+    }
+}
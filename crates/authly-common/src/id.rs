@@ -7,6 +7,7 @@ use std::{
 };
 
 use anyhow::{anyhow, Context};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
 use kind::{IdKind, Kind};
 use rand::Rng;
 use serde::{Deserialize, Serialize};
@@ -14,10 +15,22 @@ use subset::{IdKindSubset, IdKindSupersetOf};
 
 use crate::FromStrVisitor;
 
+/// The largest ID value of the reserved range.
+///
+/// IDs in the range `1..=RESERVED_MAX` are reserved for builtin objects with fixed IDs.
+/// They are never generated by [Id128::random], and are rejected when parsing string representations.
+/// The nil id (zero) is not part of the reserved range.
+pub const RESERVED_MAX: u128 = 32767;
+
 /// Authly generic 128-bit identifier
 pub struct Id128<K>([u8; 16], PhantomData<K>);
 
 impl<K> Id128<K> {
+    /// The nil id, with all bits set to zero.
+    ///
+    /// The nil id is a sentinel value only, and never identifies a real object.
+    pub const NIL: Self = Self([0; 16], PhantomData);
+
     /// Construct a new identifier from a 128-bit unsigned int.
     pub const fn from_uint(val: u128) -> Self {
         Self(val.to_be_bytes(), PhantomData)
@@ -43,7 +56,7 @@ impl<K> Id128<K> {
         loop {
             let id: u128 = rand::rng().random();
             // low IDs are reserved for builtin/fixed
-            if id > u16::MAX as u128 {
+            if id > RESERVED_MAX {
                 return Self(id.to_be_bytes(), PhantomData);
             }
         }
@@ -53,9 +66,49 @@ impl<K> Id128<K> {
     pub fn to_uint(&self) -> u128 {
         u128::from_be_bytes(self.0)
     }
+
+    /// Whether this is the sentinel [Self::NIL] id.
+    pub const fn is_nil(&self) -> bool {
+        u128::from_be_bytes(self.0) == 0
+    }
+
+    /// Construct a new identifier from a [uuid::Uuid], using the same byte order as [Self::to_raw_array].
+    #[cfg(feature = "uuid")]
+    pub const fn from_uuid(uuid: uuid::Uuid) -> Self {
+        Self(uuid.into_bytes(), PhantomData)
+    }
+
+    /// Convert to a [uuid::Uuid], using the same byte order as [Self::to_raw_array].
+    #[cfg(feature = "uuid")]
+    pub const fn to_uuid(&self) -> uuid::Uuid {
+        uuid::Uuid::from_bytes(self.0)
+    }
 }
 
 impl<K: IdKind> Id128<K> {
+    /// Format as `prefix.base64url`, a more compact alternative to the [Display] format.
+    pub fn to_base64url(&self) -> String {
+        format!("{}.{}", K::kind().prefix(), URL_SAFE_NO_PAD.encode(self.0))
+    }
+
+    /// Parse the `prefix.base64url` format produced by [Self::to_base64url].
+    pub fn from_base64url(s: &str) -> anyhow::Result<Self> {
+        let prefix = K::kind().prefix();
+        let Some(s) = s.strip_prefix(prefix) else {
+            return Err(anyhow!("unrecognized prefix, expected `{prefix}`"));
+        };
+        let s = s.strip_prefix('.').context("missing `.`")?;
+
+        Ok(Id128(decode_base64url(s)?, PhantomData))
+    }
+
+    /// Parse the 32 hex digits of the ID, without the `prefix.` of the [Display] format.
+    ///
+    /// This is useful when the kind is known from context, e.g. a database column.
+    pub fn from_hex(s: &str) -> anyhow::Result<Self> {
+        Ok(Id128(decode_hex(s)?, PhantomData))
+    }
+
     /// Infallibly convert this into a [DynamicId]
     pub fn upcast<KS: IdKindSubset + IdKindSupersetOf<K>>(self) -> DynamicId<KS> {
         DynamicId {
@@ -84,7 +137,7 @@ impl<K> Eq for Id128<K> {}
 
 impl<K> PartialOrd<Id128<K>> for Id128<K> {
     fn partial_cmp(&self, other: &Id128<K>) -> Option<std::cmp::Ordering> {
-        Some(self.0.cmp(&other.0))
+        Some(self.cmp(other))
     }
 }
 
@@ -120,7 +173,7 @@ impl<K> Debug for Id128<K> {
 
 impl<K: IdKind> Display for Id128<K> {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{}.{}", K::kind().str_prefix(), hexhex::hex(&self.0))
+        write!(f, "{}.{}", K::kind().prefix(), hexhex::hex(&self.0))
     }
 }
 
@@ -172,8 +225,31 @@ pub mod kind {
     }
 
     impl Kind {
+        /// All the kinds, in ascending order.
+        pub const fn all() -> &'static [Self] {
+            &[
+                Self::Persona,
+                Self::Group,
+                Self::Service,
+                Self::Domain,
+                Self::Policy,
+                Self::Property,
+                Self::Attribute,
+                Self::Directory,
+            ]
+        }
+
+        /// The kind with the given string prefix.
+        pub fn from_prefix(prefix: &str) -> Option<Self> {
+            Self::all()
+                .iter()
+                .copied()
+                .find(|kind| kind.prefix() == prefix)
+        }
+
+        /// The prefix of the string representation of IDs of this kind, e.g. `p` for `p.1234..`.
         #[inline]
-        pub(super) const fn str_prefix(&self) -> &'static str {
+        pub const fn prefix(&self) -> &'static str {
             match self {
                 Self::Persona => "p",
                 Self::Group => "g",
@@ -186,7 +262,8 @@ pub mod kind {
             }
         }
 
-        pub(super) const fn name(&self) -> &'static str {
+        /// A human readable name of IDs of this kind, e.g. `persona ID`.
+        pub const fn name(&self) -> &'static str {
             match self {
                 Kind::Persona => "persona ID",
                 Kind::Group => "group ID",
@@ -198,19 +275,6 @@ pub mod kind {
                 Kind::Directory => "directory ID",
             }
         }
-
-        pub(super) const fn entries() -> &'static [Self] {
-            &[
-                Self::Persona,
-                Self::Group,
-                Self::Service,
-                Self::Domain,
-                Self::Policy,
-                Self::Property,
-                Self::Attribute,
-                Self::Directory,
-            ]
-        }
     }
 
     /// Trait for static kinds of Ids.
@@ -298,8 +362,16 @@ pub mod subset {
 
     /// Describes a specific subset of Authly ID kinds.
     pub trait IdKindSubset {
+        /// The kinds contained in the subset.
+        const KINDS: &'static [Kind];
+
         /// Whether the subset contains the given [Kind].
-        fn contains(kind: Kind) -> bool;
+        ///
+        /// The const [DynamicId::new](super::DynamicId::new) only consults [Self::KINDS],
+        /// so an overriding implementation must agree with it.
+        fn contains(kind: Kind) -> bool {
+            contains_kind(Self::KINDS, kind)
+        }
 
         /// The name of this subset.
         fn name() -> &'static str;
@@ -315,9 +387,7 @@ pub mod subset {
     pub struct Any;
 
     impl IdKindSubset for Entity {
-        fn contains(kind: Kind) -> bool {
-            matches!(kind, Kind::Persona | Kind::Group | Kind::Service)
-        }
+        const KINDS: &'static [Kind] = &[Kind::Persona, Kind::Group, Kind::Service];
 
         fn name() -> &'static str {
             "Entity ID"
@@ -329,9 +399,7 @@ pub mod subset {
     impl IdKindSupersetOf<Service> for Entity {}
 
     impl IdKindSubset for Any {
-        fn contains(_kind: Kind) -> bool {
-            true
-        }
+        const KINDS: &'static [Kind] = Kind::all();
 
         fn name() -> &'static str {
             "Any ID"
@@ -340,6 +408,18 @@ pub mod subset {
 
     impl<K: IdKind> IdKindSupersetOf<K> for Any {}
     impl IdKindSupersetOf<Entity> for Any {}
+
+    /// Whether `kinds` contains `kind`, usable in const contexts.
+    pub(super) const fn contains_kind(kinds: &[Kind], kind: Kind) -> bool {
+        let mut i = 0;
+        while i < kinds.len() {
+            if kinds[i] as u8 == kind as u8 {
+                return true;
+            }
+            i += 1;
+        }
+        false
+    }
 }
 
 /// Authly Persona ID
@@ -376,9 +456,9 @@ pub struct DynamicId<KS: IdKindSubset> {
 impl<KS: IdKindSubset> DynamicId<KS> {
     /// Construct a new dynamicId.
     ///
-    /// Panics if [Kind] is not member of the KS subset.
-    pub fn new(kind: Kind, id: [u8; 16]) -> Self {
-        if !KS::contains(kind) {
+    /// Panics if [Kind] is not member of the KS subset, as listed in [IdKindSubset::KINDS].
+    pub const fn new(kind: Kind, id: [u8; 16]) -> Self {
+        if !subset::contains_kind(KS::KINDS, kind) {
             panic!("Not in subset");
         }
         Self {
@@ -388,11 +468,42 @@ impl<KS: IdKindSubset> DynamicId<KS> {
         }
     }
 
+    /// Construct a new dynamicId from a [Kind] and an unsigned integer, like [Id128::from_uint].
+    ///
+    /// Panics if [Kind] is not member of the KS subset.
+    pub const fn from_uint(kind: Kind, val: u128) -> Self {
+        Self::new(kind, val.to_be_bytes())
+    }
+
     /// The dynamic kind of this dynamic id.
     pub fn kind(&self) -> Kind {
         self.kind
     }
 
+    /// Whether the id bits are all zero, regardless of kind.
+    ///
+    /// A nil dynamic id is a sentinel value only, see [Id128::NIL].
+    pub const fn is_nil(&self) -> bool {
+        u128::from_be_bytes(self.id) == 0
+    }
+
+    /// Format as `prefix.base64url`, a more compact alternative to the [Display] format.
+    pub fn to_base64url(&self) -> String {
+        format!("{}.{}", self.kind.prefix(), URL_SAFE_NO_PAD.encode(self.id))
+    }
+
+    /// Parse the `prefix.base64url` format produced by [Self::to_base64url].
+    pub fn from_base64url(s: &str) -> anyhow::Result<Self> {
+        let (prefix, s) = s.split_once('.').context("missing `.`")?;
+        let kind = parse_dynamic_kind::<KS>(prefix)?;
+
+        Ok(DynamicId {
+            id: decode_base64url(s)?,
+            kind,
+            _subset: PhantomData,
+        })
+    }
+
     /// Infallibly upcast this into a superset [DynamicId].
     pub fn upcast<KS2: IdKindSubset + IdKindSupersetOf<KS>>(&self) -> DynamicId<KS2> {
         DynamicId {
@@ -407,6 +518,39 @@ impl<KS: IdKindSubset> DynamicId<KS> {
     pub const fn to_raw_array(self) -> [u8; 16] {
         self.id
     }
+
+    /// Construct a dynamic id from a [Kind] and a [uuid::Uuid] holding the 128 id bits.
+    ///
+    /// Fails if the kind is not member of the KS subset, or if the value is in the reserved range.
+    #[cfg(feature = "uuid")]
+    pub fn from_kind_and_uuid(kind: Kind, uuid: uuid::Uuid) -> anyhow::Result<Self> {
+        if !KS::contains(kind) {
+            return Err(anyhow!("invalid subset"));
+        }
+
+        let id = uuid.into_bytes();
+        check_reserved(&id)?;
+
+        Ok(Self {
+            kind,
+            id,
+            _subset: PhantomData,
+        })
+    }
+
+    /// Convert to a [uuid::Uuid] holding the 128 id bits.
+    ///
+    /// NB! This erases the dynamic tag! Use [Self::to_kind_and_uuid] to keep it.
+    #[cfg(feature = "uuid")]
+    pub const fn to_uuid(&self) -> uuid::Uuid {
+        uuid::Uuid::from_bytes(self.id)
+    }
+
+    /// Split this dynamic id into its [Kind] and a [uuid::Uuid] holding the 128 id bits.
+    #[cfg(feature = "uuid")]
+    pub fn to_kind_and_uuid(&self) -> (Kind, uuid::Uuid) {
+        (self.kind, uuid::Uuid::from_bytes(self.id))
+    }
 }
 
 impl<KS: IdKindSubset> Clone for DynamicId<KS> {
@@ -419,13 +563,13 @@ impl<KS: IdKindSubset> Copy for DynamicId<KS> {}
 
 impl<KS: IdKindSubset> Debug for DynamicId<KS> {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{}.{}", self.kind.str_prefix(), hexhex::hex(&self.id))
+        write!(f, "{}.{}", self.kind.prefix(), hexhex::hex(&self.id))
     }
 }
 
 impl<KS: IdKindSubset> Display for DynamicId<KS> {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{}.{}", self.kind.str_prefix(), hexhex::hex(&self.id))
+        write!(f, "{}.{}", self.kind.prefix(), hexhex::hex(&self.id))
     }
 }
 
@@ -468,22 +612,13 @@ impl<K: IdKind> FromStr for Id128<K> {
     type Err = anyhow::Error;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        let prefix = K::kind().str_prefix();
+        let prefix = K::kind().prefix();
         let Some(s) = s.strip_prefix(prefix) else {
             return Err(anyhow!("unrecognized prefix, expected `{prefix}`"));
         };
         let s = s.strip_prefix('.').context("missing `.`")?;
 
-        let hex = hexhex::decode(s).context("invalid format")?;
-        let array: [u8; 16] = hex.try_into().map_err(|_| anyhow!("invalid length"))?;
-
-        let min = 32768_u128.to_be_bytes();
-
-        if array != [0; 16] && array < min {
-            return Err(anyhow!("invalid value, too small"));
-        }
-
-        Ok(Id128(array, PhantomData))
+        Self::from_hex(s)
     }
 }
 
@@ -499,33 +634,60 @@ impl<S: IdKindSubset> FromStr for DynamicId<S> {
             return Err(anyhow!("too many dots"));
         }
 
-        let kind = Kind::entries()
-            .iter()
-            .copied()
-            .find(|kind| kind.str_prefix() == prefix)
-            .context("unrecognized prefix")?;
-
-        if !S::contains(kind) {
-            return Err(anyhow!("invalid subset"));
-        }
-
-        let hex = hexhex::decode(s).context("invalid format")?;
-        let array: [u8; 16] = hex.try_into().map_err(|_| anyhow!("invalid length"))?;
-
-        let min = 32768_u128.to_be_bytes();
-
-        if array != [0; 16] && array < min {
-            return Err(anyhow!("invalid value, too small"));
-        }
+        let kind = parse_dynamic_kind::<S>(prefix)?;
 
         Ok(DynamicId {
-            id: array,
+            id: decode_hex(s)?,
             kind,
             _subset: PhantomData,
         })
     }
 }
 
+/// Find the kind of a string prefix, which must be a member of the KS subset.
+fn parse_dynamic_kind<KS: IdKindSubset>(prefix: &str) -> anyhow::Result<Kind> {
+    let kind = Kind::from_prefix(prefix).context("unrecognized prefix")?;
+
+    if !KS::contains(kind) {
+        return Err(anyhow!("invalid subset"));
+    }
+
+    Ok(kind)
+}
+
+/// Decode the hex encoded id value.
+fn decode_hex(s: &str) -> anyhow::Result<[u8; 16]> {
+    let bytes = hexhex::decode(s).context("invalid format")?;
+    let array: [u8; 16] = bytes.try_into().map_err(|_| anyhow!("invalid length"))?;
+
+    check_reserved(&array)?;
+
+    Ok(array)
+}
+
+/// Decode the base64url encoded id value, without padding.
+fn decode_base64url(s: &str) -> anyhow::Result<[u8; 16]> {
+    let bytes = URL_SAFE_NO_PAD.decode(s).context("invalid format")?;
+    let array: [u8; 16] = bytes.try_into().map_err(|_| anyhow!("invalid length"))?;
+
+    check_reserved(&array)?;
+
+    Ok(array)
+}
+
+/// Check that the id value is not within the reserved range.
+///
+/// The all-zero id is accepted.
+fn check_reserved(array: &[u8; 16]) -> anyhow::Result<()> {
+    let value = u128::from_be_bytes(*array);
+
+    if value != 0 && value <= RESERVED_MAX {
+        return Err(anyhow!("invalid value, too small"));
+    }
+
+    Ok(())
+}
+
 impl<'de, K: IdKind> Deserialize<'de> for Id128<K> {
     fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
     where
@@ -540,11 +702,7 @@ impl<K: IdKind> Serialize for Id128<K> {
     where
         S: serde::Serializer,
     {
-        serializer.serialize_str(&format!(
-            "{}.{}",
-            K::kind().str_prefix(),
-            hexhex::hex(&self.0)
-        ))
+        serializer.serialize_str(&format!("{}.{}", K::kind().prefix(), hexhex::hex(&self.0)))
     }
 }
 
@@ -553,11 +711,7 @@ impl<KS: IdKindSubset> Serialize for DynamicId<KS> {
     where
         S: serde::Serializer,
     {
-        serializer.serialize_str(&format!(
-            "{}.{}",
-            self.kind.str_prefix(),
-            hexhex::hex(&self.id)
-        ))
+        serializer.serialize_str(&format!("{}.{}", self.kind.prefix(), hexhex::hex(&self.id)))
     }
 }
 
@@ -570,6 +724,97 @@ impl<'de, KS: IdKindSubset> Deserialize<'de> for DynamicId<KS> {
     }
 }
 
+/// Compact serialization of IDs, for use with `#[serde(with = "authly_common::id::compact")]`.
+///
+/// Binary formats, like postcard, get the raw bytes of the ID: 16 bytes for [Id128],
+/// and 17 bytes for [DynamicId], including the kind.
+/// Human readable formats, like JSON, get the usual string representation.
+pub mod compact {
+    use std::marker::PhantomData;
+
+    use serde::{de::Visitor, Deserialize, Deserializer, Serialize, Serializer};
+
+    use super::{kind::IdKind, subset::IdKindSubset, DynamicId, Id128, Id128DynamicArrayConv};
+
+    /// An ID type with a compact binary representation.
+    pub trait CompactId: Serialize + for<'de> Deserialize<'de> {
+        /// Serialize the compact binary representation.
+        fn serialize_compact<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error>;
+
+        /// Parse the compact binary representation.
+        fn from_compact(bytes: &[u8]) -> Option<Self>;
+
+        /// The name of the ID type, used in error messages.
+        fn expecting() -> &'static str;
+    }
+
+    impl<K: IdKind> CompactId for Id128<K> {
+        fn serialize_compact<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+            serializer.serialize_bytes(&self.0)
+        }
+
+        fn from_compact(bytes: &[u8]) -> Option<Self> {
+            Self::from_raw_bytes(bytes)
+        }
+
+        fn expecting() -> &'static str {
+            K::kind().name()
+        }
+    }
+
+    impl<KS: IdKindSubset> CompactId for DynamicId<KS> {
+        fn serialize_compact<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+            serializer.serialize_bytes(&self.to_array_dynamic())
+        }
+
+        fn from_compact(bytes: &[u8]) -> Option<Self> {
+            Self::try_from_bytes_dynamic(bytes)
+        }
+
+        fn expecting() -> &'static str {
+            KS::name()
+        }
+    }
+
+    /// Serialize an ID compactly in binary formats.
+    pub fn serialize<T: CompactId, S: Serializer>(
+        id: &T,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        if serializer.is_human_readable() {
+            id.serialize(serializer)
+        } else {
+            id.serialize_compact(serializer)
+        }
+    }
+
+    /// Deserialize an ID serialized with [serialize].
+    pub fn deserialize<'de, T: CompactId, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<T, D::Error> {
+        if deserializer.is_human_readable() {
+            T::deserialize(deserializer)
+        } else {
+            deserializer.deserialize_bytes(CompactVisitor(PhantomData))
+        }
+    }
+
+    struct CompactVisitor<T>(PhantomData<T>);
+
+    impl<T: CompactId> Visitor<'_> for CompactVisitor<T> {
+        type Value = T;
+
+        fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+            write!(f, "{}", T::expecting())
+        }
+
+        fn visit_bytes<E: serde::de::Error>(self, bytes: &[u8]) -> Result<Self::Value, E> {
+            T::from_compact(bytes)
+                .ok_or_else(|| E::invalid_value(serde::de::Unexpected::Bytes(bytes), &self))
+        }
+    }
+}
+
 impl<K: IdKind> Id128DynamicArrayConv for Id128<K> {
     fn try_from_array_dynamic(array: &[u8; 17]) -> Option<Self> {
         let kind_byte: u8 = K::kind().into();
@@ -670,3 +915,196 @@ fn serde() {
 
     assert_eq!(before, after);
 }
+
+#[cfg(feature = "uuid")]
+#[test]
+fn kind_and_uuid() {
+    let uuid = uuid::Uuid::parse_str("7d8b18fa-5836-4875-92a4-3eacea830b47").unwrap();
+    let eid = EntityId::from_kind_and_uuid(Kind::Persona, uuid).unwrap();
+
+    assert_eq!(
+        eid,
+        EntityId::from_str("p.7d8b18fa5836487592a43eacea830b47").unwrap()
+    );
+    assert_eq!((Kind::Persona, uuid), eid.to_kind_and_uuid());
+
+    EntityId::from_kind_and_uuid(Kind::Domain, uuid).unwrap_err();
+    EntityId::from_kind_and_uuid(Kind::Group, uuid::Uuid::from_u128(42)).unwrap_err();
+    AnyId::from_kind_and_uuid(Kind::Domain, uuid).unwrap();
+}
+
+#[cfg(feature = "uuid")]
+#[test]
+fn uuid_roundtrip() {
+    let uuid = uuid::Uuid::parse_str("7d8b18fa-5836-4875-92a4-3eacea830b47").unwrap();
+    let persona_id = PersonaId::from_uuid(uuid);
+
+    assert_eq!(
+        persona_id,
+        PersonaId::from_str("p.7d8b18fa5836487592a43eacea830b47").unwrap()
+    );
+    assert_eq!(uuid, persona_id.to_uuid());
+    assert_eq!(uuid.into_bytes(), persona_id.to_raw_array());
+    assert_eq!(uuid.as_u128(), persona_id.to_uint());
+
+    let eid: EntityId = persona_id.upcast();
+    assert_eq!(uuid, eid.to_uuid());
+    assert_eq!(uuid.into_bytes(), eid.to_raw_array());
+}
+
+#[test]
+fn base64url() {
+    let persona_id = PersonaId::from_str("p.1234abcd1234abcd1234abcd1234abcd").unwrap();
+    let encoded = persona_id.to_base64url();
+
+    assert_eq!("p.EjSrzRI0q80SNKvNEjSrzQ", encoded);
+    assert_eq!(persona_id, PersonaId::from_base64url(&encoded).unwrap());
+    assert_eq!(
+        PersonaId::from_str(&persona_id.to_string()).unwrap(),
+        PersonaId::from_base64url(&encoded).unwrap()
+    );
+
+    let eid: EntityId = persona_id.upcast();
+    assert_eq!(encoded, eid.to_base64url());
+    assert_eq!(eid, EntityId::from_base64url(&encoded).unwrap());
+    assert_eq!(
+        EntityId::from_str(&eid.to_string()).unwrap(),
+        EntityId::from_base64url(&encoded).unwrap()
+    );
+
+    // wrong kind
+    ServiceId::from_base64url(&encoded).unwrap_err();
+    DomainId::from_base64url("p.EjSrzRI0q80SNKvNEjSrzQ").unwrap_err();
+    EntityId::from_base64url("d.EjSrzRI0q80SNKvNEjSrzQ").unwrap_err();
+    // wrong length
+    PersonaId::from_base64url("p.EjSrzRI0q80SNKvNEjSr").unwrap_err();
+    EntityId::from_base64url("p.EjSrzRI0q80SNKvNEjSrzRI0").unwrap_err();
+    // padding is not accepted
+    PersonaId::from_base64url("p.EjSrzRI0q80SNKvNEjSrzQ==").unwrap_err();
+    // reserved range
+    let reserved = PersonaId::from_uint(32767).to_base64url();
+    PersonaId::from_base64url(&reserved).unwrap_err();
+    EntityId::from_base64url(&reserved).unwrap_err();
+    PersonaId::from_base64url(&PersonaId::from_uint(32768).to_base64url()).unwrap();
+}
+
+#[test]
+fn nil() {
+    assert!(PersonaId::NIL.is_nil());
+    assert_eq!(PersonaId::NIL, PersonaId::from_uint(0));
+    assert!(!PersonaId::random().is_nil());
+
+    // FromStr accepts the nil id as a special case
+    let parsed = PersonaId::from_str("p.00000000000000000000000000000000").unwrap();
+    assert!(parsed.is_nil());
+
+    let eid: EntityId = PersonaId::NIL.upcast();
+    assert!(eid.is_nil());
+    assert!(!EntityId::from_str("p.1234abcd1234abcd1234abcd1234abcd")
+        .unwrap()
+        .is_nil());
+}
+
+#[test]
+fn test_dynamic_id_from_uint() {
+    const GROUP: EntityId = EntityId::from_uint(Kind::Group, 0xab01);
+    const ATTR: AnyId = AnyId::from_uint(Kind::Attribute, 42);
+
+    assert_eq!(GroupId::from_uint(0xab01).upcast::<subset::Entity>(), GROUP);
+    assert_eq!(AttrId::from_uint(42).upcast::<subset::Any>(), ATTR);
+    assert_eq!(EntityId::new(Kind::Group, 0xab01_u128.to_be_bytes()), GROUP);
+}
+
+#[test]
+#[should_panic = "Not in subset"]
+fn test_dynamic_id_from_uint_not_in_subset() {
+    EntityId::from_uint(Kind::Attribute, 42);
+}
+
+#[test]
+fn test_compact_serde() {
+    #[derive(Serialize, Deserialize, PartialEq, Debug)]
+    struct Ids {
+        #[serde(with = "compact")]
+        persona: PersonaId,
+        #[serde(with = "compact")]
+        entity: EntityId,
+    }
+
+    let ids = Ids {
+        persona: PersonaId::from_uint(0xab01),
+        entity: EntityId::from_uint(Kind::Group, 0xab02),
+    };
+
+    let json = serde_json::to_string(&ids).unwrap();
+    assert_eq!(
+        r#"{"persona":"p.0000000000000000000000000000ab01","entity":"g.0000000000000000000000000000ab02"}"#,
+        json
+    );
+    assert_eq!(ids, serde_json::from_str(&json).unwrap());
+
+    let bytes = postcard::to_allocvec(&ids).unwrap();
+    // each ID is prefixed by its length
+    assert_eq!(1 + 16 + 1 + 17, bytes.len());
+    assert_eq!(ids, postcard::from_bytes(&bytes).unwrap());
+
+    // the kind is checked
+    let mut wrong_kind = bytes.clone();
+    wrong_kind[1 + 16 + 1] = Kind::Attribute.into();
+    assert!(postcard::from_bytes::<Ids>(&wrong_kind).is_err());
+}
+
+#[test]
+fn test_from_hex() {
+    assert_eq!(
+        PersonaId::from_str("p.1234abcd1234abcd1234abcd1234abcd").unwrap(),
+        PersonaId::from_hex("1234abcd1234abcd1234abcd1234abcd").unwrap()
+    );
+    assert_eq!(
+        PersonaId::NIL,
+        PersonaId::from_hex(&"0".repeat(32)).unwrap()
+    );
+
+    // the prefix is not accepted
+    PersonaId::from_hex("p.1234abcd1234abcd1234abcd1234abcd").unwrap_err();
+
+    // wrong length
+    PersonaId::from_hex("1234abcd1234abcd1234abcd1234ab").unwrap_err();
+    PersonaId::from_hex("1234abcd1234abcd1234abcd1234abcd12").unwrap_err();
+
+    // reserved
+    PersonaId::from_hex(&format!("{:032x}", 32767)).unwrap_err();
+    PersonaId::from_hex(&format!("{:032x}", 32768)).unwrap();
+}
+
+#[test]
+fn test_kind_reflection() {
+    for (index, kind) in Kind::all().iter().enumerate() {
+        assert_eq!(index, u8::from(*kind) as usize);
+        assert_eq!(Some(*kind), Kind::from_prefix(kind.prefix()));
+        assert!(kind.name().ends_with(" ID"));
+    }
+    assert_eq!(8, Kind::all().len());
+    assert_eq!("p", Kind::Persona.prefix());
+    assert_eq!("persona ID", Kind::Persona.name());
+    assert_eq!(None, Kind::from_prefix("x"));
+    assert_eq!(None, Kind::from_prefix(""));
+}
+
+#[test]
+fn test_reserved_range() {
+    for _ in 0..1000 {
+        assert!(PersonaId::random().to_uint() > RESERVED_MAX);
+    }
+
+    for parse in [
+        |s: &str| s.parse::<PersonaId>().map(|_| ()),
+        |s: &str| s.parse::<EntityId>().map(|_| ()),
+    ] {
+        parse(&format!("p.{:032x}", RESERVED_MAX)).unwrap_err();
+        parse(&format!("p.{:032x}", RESERVED_MAX + 1)).unwrap();
+    }
+
+    PersonaId::from_hex(&format!("{:032x}", RESERVED_MAX)).unwrap_err();
+    PersonaId::from_hex(&format!("{:032x}", RESERVED_MAX + 1)).unwrap();
+}
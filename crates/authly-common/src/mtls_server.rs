@@ -1,38 +1,173 @@
 //! Utilities for creating mTLS servers participating in an Authly service mesh.
 
+use std::sync::Arc;
+
+use fnv::FnvHashSet;
 use http::Request;
 use hyper::body::Incoming;
-use tracing::warn;
-use x509_parser::prelude::{FromDer, X509Certificate};
+use time::OffsetDateTime;
+use tracing::{info, warn};
+use x509_parser::prelude::{FromDer, X509Certificate, X509Name};
 
-use crate::{certificate::oid::ENTITY_UNIQUE_IDENTIFIER, id::ServiceId};
+use crate::{
+    certificate::oid::ENTITY_UNIQUE_IDENTIFIER,
+    id::{AttrId, ServiceId},
+};
 
 /// A [Request] extension representing the peer Authly service that connected to the local server.
 #[derive(Clone, Copy, Debug)]
 pub struct PeerServiceEntity(pub ServiceId);
 
+/// Extracts the [PeerServiceEntity] set up by [MTLSMiddleware], rejecting with `401 Unauthorized` when absent.
+///
+/// ```
+/// use authly_common::mtls_server::PeerServiceEntity;
+///
+/// async fn handler(PeerServiceEntity(peer): PeerServiceEntity) -> String {
+///     format!("hello, {peer}")
+/// }
+///
+/// let router: axum::Router = axum::Router::new().route("/", axum::routing::get(handler));
+/// ```
+#[cfg(feature = "axum")]
+impl<S: Send + Sync> axum_core::extract::FromRequestParts<S> for PeerServiceEntity {
+    type Rejection = http::StatusCode;
+
+    async fn from_request_parts(
+        parts: &mut http::request::Parts,
+        _state: &S,
+    ) -> Result<Self, Self::Rejection> {
+        parts
+            .extensions
+            .get::<PeerServiceEntity>()
+            .copied()
+            .ok_or(http::StatusCode::UNAUTHORIZED)
+    }
+}
+
+/// A [Request] extension representing the attributes of the [PeerServiceEntity].
+///
+/// Only present when [MTLSMiddleware] is configured with [MTLSMiddleware::with_attribute_resolver].
+#[derive(Clone, Debug)]
+pub struct PeerServiceAttributes(pub Arc<FnvHashSet<AttrId>>);
+
+/// A [Request] extension representing the Common Name (CN) of the peer certificate subject.
+///
+/// For Authly services this is the display name chosen by the service.
+#[derive(Clone, Debug)]
+pub struct PeerCommonName(pub String);
+
+/// A [Request] extension representing the end of the validity period of the peer certificate.
+#[derive(Clone, Copy, Debug)]
+pub struct PeerCertificateNotAfter(pub OffsetDateTime);
+
+/// A [Request] extension representing the subject of the peer certificate.
+///
+/// This extension is present for all verified peers, including those that do not carry an Authly entity ID.
+#[derive(Clone, Debug)]
+pub struct PeerCertificateSubject {
+    dn: String,
+    attributes: Vec<(String, String)>,
+}
+
+impl PeerCertificateSubject {
+    fn from_x509_name(name: &X509Name) -> Self {
+        let mut attributes = vec![];
+
+        for attr in name.iter_attributes() {
+            if let Ok(value) = attr.as_str() {
+                attributes.push((attr.attr_type().to_id_string(), value.to_string()));
+            }
+        }
+
+        Self {
+            dn: name.to_string(),
+            attributes,
+        }
+    }
+
+    /// The full distinguished name of the subject, in string format.
+    pub fn dn(&self) -> &str {
+        &self.dn
+    }
+
+    /// The string-valued attributes of the subject, as pairs of dotted OID and value.
+    pub fn attributes(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.attributes
+            .iter()
+            .map(|(oid, value)| (oid.as_str(), value.as_str()))
+    }
+
+    /// The common name (CN) of the subject, if present.
+    pub fn common_name(&self) -> Option<&str> {
+        self.attributes()
+            .find(|(oid, _)| *oid == COMMON_NAME_OID)
+            .map(|(_, value)| value)
+    }
+}
+
+impl std::fmt::Display for PeerCertificateSubject {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.dn)
+    }
+}
+
+/// The dotted OID of the x509 Common Name attribute.
+const COMMON_NAME_OID: &str = "2.5.4.3";
+
 /// A middleware for setting up mTLS with [tower_server].
-#[derive(Clone)]
-pub struct MTLSMiddleware;
+#[derive(Clone, Default)]
+pub struct MTLSMiddleware {
+    attribute_resolver: Option<Arc<AttributeResolver>>,
+}
+
+type AttributeResolver = dyn Fn(ServiceId) -> FnvHashSet<AttrId> + Send + Sync;
+
+impl MTLSMiddleware {
+    /// Create a new middleware.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Resolve the attributes of the peer service, to be attached as a [PeerServiceAttributes] extension.
+    ///
+    /// The resolver is called at most once per connection, typically backed by a cache owned by the caller.
+    pub fn with_attribute_resolver(
+        mut self,
+        resolver: impl Fn(ServiceId) -> FnvHashSet<AttrId> + Send + Sync + 'static,
+    ) -> Self {
+        self.attribute_resolver = Some(Arc::new(resolver));
+        self
+    }
+
+    /// Extract connection data from the DER encoding of the peer's certificate,
+    /// and resolve the peer attributes if configured.
+    pub fn connection_data(&self, peer_der: &[u8]) -> Option<MTLSConnectionData> {
+        let mut data = MTLSConnectionData::from_peer_certificate(peer_der)?;
+
+        if let (Some(resolver), Some(peer)) = (&self.attribute_resolver, data.peer_service_entity) {
+            data.peer_attributes = Some(Arc::new(resolver(peer)));
+        }
+
+        Some(data)
+    }
+}
 
 /// The data extracted from the TLS connection
 #[derive(Default)]
 pub struct MTLSConnectionData {
     peer_service_entity: Option<ServiceId>,
+    peer_subject: Option<PeerCertificateSubject>,
+    common_name: Option<String>,
+    not_after: Option<OffsetDateTime>,
+    peer_attributes: Option<Arc<FnvHashSet<AttrId>>>,
 }
 
 impl MTLSConnectionData {
-    /// Get the peer service entity
-    pub fn peer_service_entity(&self) -> Option<ServiceId> {
-        self.peer_service_entity
-    }
-}
-
-impl tower_server::tls::TlsConnectionMiddleware for MTLSMiddleware {
-    type Data = Option<MTLSConnectionData>;
-
-    fn data(&self, connection: &rustls::ServerConnection) -> Self::Data {
-        let peer_der = connection.peer_certificates()?.first()?;
+    /// Extract connection data from the DER encoding of the peer's certificate.
+    ///
+    /// Returns `None` if the certificate could not be parsed.
+    pub fn from_peer_certificate(peer_der: &[u8]) -> Option<Self> {
         let (_, peer_cert) = X509Certificate::from_der(peer_der).ok()?;
 
         let mut data = MTLSConnectionData::default();
@@ -55,9 +190,56 @@ impl tower_server::tls::TlsConnectionMiddleware for MTLSMiddleware {
             }
         }
 
+        let peer_subject = PeerCertificateSubject::from_x509_name(&peer_cert.subject);
+        if data.peer_service_entity.is_none() {
+            info!(subject = %peer_subject, "peer certificate has no entity ID");
+        }
+        data.common_name = peer_subject.common_name().map(str::to_string);
+        data.peer_subject = Some(peer_subject);
+        data.not_after = Some(peer_cert.validity().not_after.to_datetime());
+
         Some(data)
     }
 
+    /// Get the peer service entity
+    pub fn peer_service_entity(&self) -> Option<ServiceId> {
+        self.peer_service_entity
+    }
+
+    /// Get the subject of the peer certificate
+    pub fn peer_subject(&self) -> Option<&PeerCertificateSubject> {
+        self.peer_subject.as_ref()
+    }
+
+    /// Get the Common Name (CN) of the peer certificate subject
+    pub fn common_name(&self) -> Option<&str> {
+        self.common_name.as_deref()
+    }
+
+    /// Get the end of the validity period of the peer certificate
+    pub fn not_after(&self) -> Option<OffsetDateTime> {
+        self.not_after
+    }
+
+    /// Get the attributes of the peer service entity, if resolved by the middleware
+    pub fn peer_attributes(&self) -> Option<&FnvHashSet<AttrId>> {
+        self.peer_attributes.as_deref()
+    }
+}
+
+impl tower_server::tls::TlsConnectionMiddleware for MTLSMiddleware {
+    type Data = Option<MTLSConnectionData>;
+
+    fn data(&self, connection: &rustls::ServerConnection) -> Self::Data {
+        let peer_der = connection.peer_certificates()?.first()?;
+        let data = self.connection_data(peer_der);
+        if data.is_none() {
+            warn!("unparsable peer certificate");
+        }
+
+        data
+    }
+
     fn call(&self, req: &mut Request<Incoming>, data: &Self::Data) {
         let Some(data) = data else {
             return;
@@ -65,5 +247,20 @@ impl tower_server::tls::TlsConnectionMiddleware for MTLSMiddleware {
         if let Some(id) = data.peer_service_entity {
             req.extensions_mut().insert(PeerServiceEntity(id));
         }
+        if let Some(subject) = &data.peer_subject {
+            req.extensions_mut().insert(subject.clone());
+        }
+        if let Some(common_name) = &data.common_name {
+            req.extensions_mut()
+                .insert(PeerCommonName(common_name.clone()));
+        }
+        if let Some(not_after) = data.not_after {
+            req.extensions_mut()
+                .insert(PeerCertificateNotAfter(not_after));
+        }
+        if let Some(attributes) = &data.peer_attributes {
+            req.extensions_mut()
+                .insert(PeerServiceAttributes(attributes.clone()));
+        }
     }
 }
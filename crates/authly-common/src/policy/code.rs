@@ -1,9 +1,12 @@
 //! Code definitions for the Authly policy engine.
 
+use std::fmt::{self, Write};
+
+use byteorder::{BigEndian, ReadBytesExt};
 use int_enum::IntEnum;
 use serde::{Deserialize, Serialize};
 
-use crate::id::{AttrId, EntityId, PropId};
+use crate::id::{AttrId, EntityId, Id128DynamicArrayConv, PropId};
 
 /// The value/outcome of a policy engine evaluation.
 #[derive(Clone, Copy, Eq, PartialEq, Serialize, Deserialize, Hash, Debug)]
@@ -24,6 +27,37 @@ impl PolicyValue {
     pub fn is_allow(self) -> bool {
         matches!(self, Self::Allow)
     }
+
+    /// [Self::Allow] if both self and other are [Self::Allow], otherwise [Self::Deny].
+    pub fn and(self, other: Self) -> Self {
+        Self::from(self.is_allow() && other.is_allow())
+    }
+
+    /// [Self::Allow] if either self or other is [Self::Allow], otherwise [Self::Deny].
+    pub fn or(self, other: Self) -> Self {
+        Self::from(self.is_allow() || other.is_allow())
+    }
+
+    /// Combine values the way the engine combines the policies of a disjunctive trigger:
+    /// [Self::Allow] if any value is [Self::Allow].
+    ///
+    /// An empty iterator results in [Self::Deny].
+    pub fn fold_disjunctive(values: impl IntoIterator<Item = Self>) -> Self {
+        Self::from(values.into_iter().any(Self::is_allow))
+    }
+
+    /// Combine values the way the engine combines the policies of a conjunctive trigger:
+    /// [Self::Allow] if all values are [Self::Allow].
+    ///
+    /// An empty iterator results in [Self::Deny], as an empty group never holds.
+    pub fn fold_conjunctive(values: impl IntoIterator<Item = Self>) -> Self {
+        let mut values = values.into_iter().peekable();
+        if values.peek().is_none() {
+            return Self::Deny;
+        }
+
+        Self::from(values.all(Self::is_allow))
+    }
 }
 
 impl From<bool> for PolicyValue {
@@ -45,6 +79,8 @@ pub enum OpCode {
     LoadResourceAttrs,
     LoadConstEntityId(EntityId),
     LoadConstAttrId(AttrId),
+    LoadConstUint(u64),
+    LoadNow,
     IsEq,
     SupersetOf,
     IdSetContains,
@@ -52,6 +88,15 @@ pub enum OpCode {
     Or,
     Not,
     Return,
+    Gt,
+    Lt,
+    Gte,
+    Lte,
+    Xor,
+    LoadTrue,
+    LoadFalse,
+    LoadSubjectIdSet,
+    LoadResourceIdSet,
 }
 
 /// bytecode representation for policy engine instructions.
@@ -72,6 +117,68 @@ pub enum Bytecode {
     Or = 10,
     Not = 11,
     Return = 12,
+    LoadConstUint = 13,
+    LoadNow = 14,
+    Gt = 15,
+    Lt = 16,
+    Gte = 17,
+    Lte = 18,
+    Xor = 19,
+    LoadTrue = 20,
+    LoadFalse = 21,
+    LoadSubjectIdSet = 22,
+    LoadResourceIdSet = 23,
+}
+
+/// Renders the opcode as one line of assembly, e.g. `load_const_attr_id atr.0000..0042`.
+impl fmt::Display for OpCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::LoadSubjectId(prop_id) => write!(f, "load_subject_id {prop_id}"),
+            Self::LoadSubjectAttrs => write!(f, "load_subject_attrs"),
+            Self::LoadResourceId(prop_id) => write!(f, "load_resource_id {prop_id}"),
+            Self::LoadResourceAttrs => write!(f, "load_resource_attrs"),
+            Self::LoadConstEntityId(eid) => write!(f, "load_const_entity_id {eid}"),
+            Self::LoadConstAttrId(attr_id) => write!(f, "load_const_attr_id {attr_id}"),
+            Self::LoadConstUint(uint) => write!(f, "load_const_uint {uint}"),
+            Self::LoadNow => write!(f, "load_now"),
+            Self::IsEq => write!(f, "is_eq"),
+            Self::SupersetOf => write!(f, "superset_of"),
+            Self::IdSetContains => write!(f, "id_set_contains"),
+            Self::And => write!(f, "and"),
+            Self::Or => write!(f, "or"),
+            Self::Not => write!(f, "not"),
+            Self::Return => write!(f, "return"),
+            Self::Gt => write!(f, "gt"),
+            Self::Lt => write!(f, "lt"),
+            Self::Gte => write!(f, "gte"),
+            Self::Lte => write!(f, "lte"),
+            Self::Xor => write!(f, "xor"),
+            Self::LoadTrue => write!(f, "load_true"),
+            Self::LoadFalse => write!(f, "load_false"),
+            Self::LoadSubjectIdSet => write!(f, "load_subject_id_set"),
+            Self::LoadResourceIdSet => write!(f, "load_resource_id_set"),
+        }
+    }
+}
+
+/// Bytecode decoding error.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum DecodeError {
+    /// The program ended in the middle of an instruction.
+    Truncated,
+
+    /// An unrecognized opcode byte.
+    UnknownOpcode(u8),
+
+    /// An invalid entity kind byte.
+    InvalidKind(u8),
+}
+
+impl From<std::io::Error> for DecodeError {
+    fn from(_value: std::io::Error) -> Self {
+        DecodeError::Truncated
+    }
 }
 
 /// Convert slice of opcodes to bytecode.
@@ -103,6 +210,13 @@ pub fn to_bytecode(opcodes: &[OpCode]) -> Vec<u8> {
                 out.push(Bytecode::LoadConstAttrId as u8);
                 out.extend(prop_id.to_raw_array());
             }
+            OpCode::LoadConstUint(uint) => {
+                out.push(Bytecode::LoadConstUint as u8);
+                out.extend(uint.to_be_bytes());
+            }
+            OpCode::LoadNow => {
+                out.push(Bytecode::LoadNow as u8);
+            }
             OpCode::IsEq => {
                 out.push(Bytecode::IsEq as u8);
             }
@@ -124,8 +238,102 @@ pub fn to_bytecode(opcodes: &[OpCode]) -> Vec<u8> {
             OpCode::Return => {
                 out.push(Bytecode::Return as u8);
             }
+            OpCode::Gt => {
+                out.push(Bytecode::Gt as u8);
+            }
+            OpCode::Lt => {
+                out.push(Bytecode::Lt as u8);
+            }
+            OpCode::Gte => {
+                out.push(Bytecode::Gte as u8);
+            }
+            OpCode::Lte => {
+                out.push(Bytecode::Lte as u8);
+            }
+            OpCode::Xor => {
+                out.push(Bytecode::Xor as u8);
+            }
+            OpCode::LoadTrue => {
+                out.push(Bytecode::LoadTrue as u8);
+            }
+            OpCode::LoadFalse => {
+                out.push(Bytecode::LoadFalse as u8);
+            }
+            OpCode::LoadSubjectIdSet => {
+                out.push(Bytecode::LoadSubjectIdSet as u8);
+            }
+            OpCode::LoadResourceIdSet => {
+                out.push(Bytecode::LoadResourceIdSet as u8);
+            }
         }
     }
 
     out
 }
+
+/// Convert bytecode back to a list of opcodes.
+pub fn from_bytecode(mut pc: &[u8]) -> Result<Vec<OpCode>, DecodeError> {
+    let mut out = Vec::with_capacity(pc.len());
+
+    while let Some(code) = pc.first() {
+        pc = &pc[1..];
+
+        let Ok(code) = Bytecode::try_from(*code) else {
+            return Err(DecodeError::UnknownOpcode(*code));
+        };
+
+        out.push(match code {
+            Bytecode::LoadSubjectId => {
+                OpCode::LoadSubjectId(PropId::from_uint(pc.read_u128::<BigEndian>()?))
+            }
+            Bytecode::LoadSubjectAttrs => OpCode::LoadSubjectAttrs,
+            Bytecode::LoadResourceId => {
+                OpCode::LoadResourceId(PropId::from_uint(pc.read_u128::<BigEndian>()?))
+            }
+            Bytecode::LoadResourceAttrs => OpCode::LoadResourceAttrs,
+            Bytecode::LoadConstAttrId => {
+                OpCode::LoadConstAttrId(AttrId::from_uint(pc.read_u128::<BigEndian>()?))
+            }
+            Bytecode::LoadConstEntityId => {
+                let Some(array) = pc.get(..17) else {
+                    return Err(DecodeError::Truncated);
+                };
+                let eid = EntityId::try_from_bytes_dynamic(array)
+                    .ok_or(DecodeError::InvalidKind(array[0]))?;
+                pc = &pc[17..];
+                OpCode::LoadConstEntityId(eid)
+            }
+            Bytecode::IsEq => OpCode::IsEq,
+            Bytecode::SupersetOf => OpCode::SupersetOf,
+            Bytecode::IdSetContains => OpCode::IdSetContains,
+            Bytecode::And => OpCode::And,
+            Bytecode::Or => OpCode::Or,
+            Bytecode::Not => OpCode::Not,
+            Bytecode::Return => OpCode::Return,
+            Bytecode::LoadConstUint => OpCode::LoadConstUint(pc.read_u64::<BigEndian>()?),
+            Bytecode::LoadNow => OpCode::LoadNow,
+            Bytecode::Gt => OpCode::Gt,
+            Bytecode::Lt => OpCode::Lt,
+            Bytecode::Gte => OpCode::Gte,
+            Bytecode::Lte => OpCode::Lte,
+            Bytecode::Xor => OpCode::Xor,
+            Bytecode::LoadTrue => OpCode::LoadTrue,
+            Bytecode::LoadFalse => OpCode::LoadFalse,
+            Bytecode::LoadSubjectIdSet => OpCode::LoadSubjectIdSet,
+            Bytecode::LoadResourceIdSet => OpCode::LoadResourceIdSet,
+        });
+    }
+
+    Ok(out)
+}
+
+/// Convert bytecode to its assembly text, one instruction per line.
+pub fn disassemble(bytecode: &[u8]) -> Result<String, DecodeError> {
+    let mut out = String::new();
+
+    for opcode in from_bytecode(bytecode)? {
+        writeln!(out, "{opcode}").expect("writing to a String does not fail");
+    }
+
+    Ok(out)
+}
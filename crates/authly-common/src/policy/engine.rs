@@ -1,14 +1,41 @@
 //! Policy evaluation engine that implements a Policy Decision Point (PDP).
 
-use std::collections::BTreeSet;
+use std::{
+    collections::{hash_map::Entry, BTreeSet},
+    ops::Range,
+    time::{SystemTime, UNIX_EPOCH},
+};
 
 use byteorder::{BigEndian, ReadBytesExt};
 use fnv::{FnvHashMap, FnvHashSet};
+use serde::{Deserialize, Serialize};
 use tracing::error;
 
-use crate::id::{kind::Kind, AttrId, EntityId, PolicyId, PropId};
+use crate::id::{AttrId, EntityId, Id128DynamicArrayConv, PolicyId, PropId};
 
-use super::code::{Bytecode, PolicyValue};
+use super::{
+    code::{Bytecode, PolicyValue},
+    verify::{verify, VerifyError},
+};
+
+/// The magic prefix of a serialized [PolicyEngine].
+const ENGINE_MAGIC: &[u8; 4] = b"APEN";
+
+/// The current version of the serialized [PolicyEngine] format.
+const ENGINE_FORMAT_VERSION: u8 = 1;
+
+/// Error from deserializing a [PolicyEngine].
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum EngineDecodeError {
+    /// The data is not a serialized policy engine.
+    Magic,
+
+    /// The data was serialized using an unsupported format version.
+    Version(u8),
+
+    /// The data is malformed.
+    Format,
+}
 
 /// Evaluation error.
 #[derive(Clone, Copy, PartialEq, Eq, Debug)]
@@ -20,6 +47,40 @@ pub enum EvalError {
     Type,
 }
 
+/// The outcome of a policy engine evaluation, along with what caused it.
+#[derive(Clone, Copy, PartialEq, Eq, Serialize, Debug)]
+pub struct Decision {
+    /// The resulting value of the evaluation.
+    pub value: PolicyValue,
+
+    /// What caused the value.
+    pub cause: DecisionCause,
+}
+
+/// The cause of a [Decision].
+#[derive(Clone, Copy, PartialEq, Eq, Serialize, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum DecisionCause {
+    /// No policies were applicable, so the decision came from directly matching subject attributes with resource attributes.
+    ///
+    /// Contains the matching attribute, if any.
+    AttributeMatch(Option<AttrId>),
+
+    /// A policy evaluated to true, and decided the outcome.
+    Policy {
+        /// The ID of the deciding policy.
+        ///
+        /// When the outcome was decided by a conjunctive policy group, this is the lowest policy ID in that group.
+        policy_id: PolicyId,
+
+        /// The class of the deciding policy.
+        class: PolicyValue,
+    },
+
+    /// None of the applicable policies evaluated to true, so the outcome is the default for the applicable policy classes.
+    Default,
+}
+
 /// The parameters to an policy-based access control evaluation.
 ///
 /// The access control paramaters generall consists of attributes related to a `subject` and a `resource`.
@@ -34,11 +95,34 @@ pub struct AccessControlParams {
     /// Attributes related to the `subject`.
     pub subject_attrs: FnvHashSet<AttrId>,
 
+    /// A set of entity IDs related to the `subject`, e.g. the groups it is a member of.
+    pub subject_eid_set: FnvHashSet<EntityId>,
+
     /// Entity IDs related to the `resource`.
     pub resource_eids: FnvHashMap<PropId, EntityId>,
 
+    /// A set of entity IDs related to the `resource`.
+    pub resource_eid_set: FnvHashSet<EntityId>,
+
     /// Attributes related to the `resource`.
     pub resource_attrs: FnvHashSet<AttrId>,
+
+    /// The current time, as seconds since the unix epoch.
+    ///
+    /// If unset, the system clock is read when a policy needs the current time.
+    pub now: Option<u64>,
+}
+
+impl AccessControlParams {
+    /// Get the current time as seconds since the unix epoch, as seen by the evaluation.
+    fn now(&self) -> u64 {
+        self.now.unwrap_or_else(|| {
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs()
+        })
+    }
 }
 
 /// The state of the policy engine.
@@ -51,6 +135,51 @@ pub struct PolicyEngine {
     /// The triggers in this map are keyed by the one of the
     /// attributes that has to match the trigger.
     trigger_groups: FnvHashMap<AttrId, Vec<PolicyTrigger>>,
+
+    limits: EvalLimits,
+
+    order: EvalOrder,
+}
+
+/// The order in which the policy classes are evaluated when both allow and deny policies apply.
+///
+/// The outcome is the same in both orders: an allow policy has to hold and no deny policy may hold.
+/// This includes policies that fail to evaluate: a failure only fails the evaluation
+/// when the outcome is not already decided by the other class,
+/// i.e. by a deny policy that holds, or by the allow policies not holding.
+/// Only the amount of work differs, along with the [DecisionCause] of a deny outcome,
+/// which names a deny policy whenever one was evaluated to hold.
+#[derive(Clone, Copy, PartialEq, Eq, Default, Debug)]
+pub enum EvalOrder {
+    /// Try to prove allow first, and only evaluate the deny policies when an allow policy holds.
+    #[default]
+    AllowFirst,
+
+    /// Try to prove deny first, and only evaluate the allow policies when no deny policy holds.
+    ///
+    /// Saves work when deny policies tend to hold.
+    DenyFirst,
+}
+
+/// Resource limits applied when evaluating a single policy.
+///
+/// Evaluation fails with [EvalError::Program] when a limit is exceeded.
+#[derive(Clone, Copy, Serialize, Deserialize, Debug)]
+pub struct EvalLimits {
+    /// The maximum depth of the evaluation stack.
+    pub max_stack_depth: usize,
+
+    /// The maximum number of instructions executed.
+    pub max_instructions: usize,
+}
+
+impl Default for EvalLimits {
+    fn default() -> Self {
+        Self {
+            max_stack_depth: 256,
+            max_instructions: 4096,
+        }
+    }
 }
 
 /// The policy trigger maps a set of attributes to a set of policies.
@@ -59,8 +188,24 @@ struct PolicyTrigger {
     /// The set of attributes that has to match for this policy to trigger
     pub attr_matcher: BTreeSet<AttrId>,
 
+    /// The set of attributes of which none may be present for this policy to trigger
+    pub excluded_attrs: BTreeSet<AttrId>,
+
     /// The policy which gets triggered by this attribute matcher
     pub policy_ids: BTreeSet<PolicyId>,
+
+    /// How the triggered policies are combined
+    pub combinator: Combinator,
+}
+
+/// How the policies bound by a trigger combine into an outcome.
+#[derive(Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Debug)]
+pub enum Combinator {
+    /// Any policy that evaluates to true decides the outcome of its class.
+    Disjunctive,
+
+    /// The policies of a class only decide the outcome when all of them evaluate to true.
+    Conjunctive,
 }
 
 /// A tracer used to collect debugging information from the policy engine
@@ -74,6 +219,9 @@ pub trait PolicyTracer {
 
     /// Reports the value of policy after it has been evaluated
     fn report_policy_eval_end(&mut self, value: bool) {}
+
+    /// Reports the final decision of an evaluation
+    fn report_decision(&mut self, decision: &Decision) {}
 }
 
 /// A [PolicyTracer] that does nothing.
@@ -81,6 +229,96 @@ pub struct NoOpPolicyTracer;
 
 impl PolicyTracer for NoOpPolicyTracer {}
 
+/// An event recorded by the [RecordingTracer].
+#[derive(Clone, PartialEq, Eq, Serialize, Debug)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum TraceEvent {
+    /// The applicable policies of a class, in ascending order.
+    Applicable {
+        /// The class of the policies.
+        class: PolicyValue,
+        /// The applicable policies.
+        policies: Vec<PolicyId>,
+    },
+
+    /// A policy evaluation started.
+    PolicyEvalStart {
+        /// The evaluated policy.
+        policy_id: PolicyId,
+    },
+
+    /// A policy evaluation ended.
+    PolicyEvalEnd {
+        /// The evaluated policy.
+        policy_id: PolicyId,
+        /// The value the policy evaluated to.
+        value: bool,
+    },
+
+    /// The evaluation reached a decision.
+    Decision(Decision),
+}
+
+/// A [PolicyTracer] that records a transcript of the evaluation as a list of [TraceEvent]s.
+///
+/// The transcript can be serialized, e.g. to JSON for audit logs.
+#[derive(Default, Debug)]
+pub struct RecordingTracer {
+    events: Vec<TraceEvent>,
+    current_policy: Option<PolicyId>,
+}
+
+impl RecordingTracer {
+    /// The events recorded so far.
+    pub fn events(&self) -> &[TraceEvent] {
+        &self.events
+    }
+
+    /// Take the recorded events.
+    pub fn into_events(self) -> Vec<TraceEvent> {
+        self.events
+    }
+}
+
+impl PolicyTracer for RecordingTracer {
+    fn report_applicable(&mut self, class: PolicyValue, policies: impl Iterator<Item = PolicyId>) {
+        self.events.push(TraceEvent::Applicable {
+            class,
+            policies: policies.collect(),
+        });
+    }
+
+    fn report_policy_eval_start(&mut self, policy_id: PolicyId) {
+        self.current_policy = Some(policy_id);
+        self.events.push(TraceEvent::PolicyEvalStart { policy_id });
+    }
+
+    fn report_policy_eval_end(&mut self, value: bool) {
+        if let Some(policy_id) = self.current_policy.take() {
+            self.events
+                .push(TraceEvent::PolicyEvalEnd { policy_id, value });
+        }
+    }
+
+    fn report_decision(&mut self, decision: &Decision) {
+        self.events.push(TraceEvent::Decision(*decision));
+    }
+}
+
+/// The serialized representation of a [PolicyEngine].
+///
+/// IDs are stored as plain integers, bypassing the reserved range checks of their string representation.
+#[derive(Serialize, Deserialize)]
+struct EngineData<'a> {
+    #[serde(borrow)]
+    policies: Vec<(u128, PolicyValue, &'a [u8])>,
+    triggers: Vec<TriggerData>,
+    limits: EvalLimits,
+}
+
+/// A serialized trigger: the attribute matcher, excluded attributes, policy IDs and combinator.
+type TriggerData = (Vec<u128>, Vec<u128>, Vec<u128>, Combinator);
+
 #[derive(Debug)]
 struct Policy {
     class: PolicyValue,
@@ -88,17 +326,113 @@ struct Policy {
 }
 
 #[derive(PartialEq, Eq, Debug)]
-enum StackItem<'a> {
+enum StackItem {
     Uint(u64),
-    AttrIdSet(&'a FnvHashSet<AttrId>),
+    AttrIdSet(Side),
     EntityId(EntityId),
     AttrId(AttrId),
+    EntityIdSet(Side),
 }
 
-#[derive(Debug)]
-struct EvalCtx<'e> {
-    applicable_allow: FnvHashMap<PolicyId, &'e Policy>,
-    applicable_deny: FnvHashMap<PolicyId, &'e Policy>,
+/// The side of the access control parameters a set on the stack refers to.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum Side {
+    Subject,
+    Resource,
+}
+
+impl Side {
+    fn attrs(self, params: &AccessControlParams) -> &FnvHashSet<AttrId> {
+        match self {
+            Self::Subject => &params.subject_attrs,
+            Self::Resource => &params.resource_attrs,
+        }
+    }
+
+    fn eids(self, params: &AccessControlParams) -> &FnvHashSet<EntityId> {
+        match self {
+            Self::Subject => &params.subject_eid_set,
+            Self::Resource => &params.resource_eid_set,
+        }
+    }
+}
+
+/// Reusable buffers for access control evaluations.
+///
+/// Evaluating with a scratch through [PolicyEngine::eval_with_scratch] avoids allocating
+/// for each evaluation, once the buffers have grown to fit the policies involved.
+/// The scratch is cleared before each use and may be used with any engine.
+#[derive(Default, Debug)]
+pub struct EvalScratch {
+    eval_ctx: EvalCtx,
+    stack: Vec<StackItem>,
+}
+
+#[derive(Default, Debug)]
+struct EvalCtx {
+    applicable_allow: Applicable,
+    applicable_deny: Applicable,
+}
+
+/// The applicable policies of one class.
+///
+/// The policies are sorted once collected, so that evaluation order is deterministic.
+#[derive(Default, Debug)]
+struct Applicable {
+    /// Policies from disjunctive triggers
+    disjunctive: Vec<PolicyId>,
+
+    /// Policy groups from conjunctive triggers, as ranges into `conjunctive_ids`
+    conjunctive: Vec<Range<usize>>,
+
+    /// The policies of all the conjunctive groups
+    conjunctive_ids: Vec<PolicyId>,
+
+    /// All the applicable policies, sorted and deduplicated
+    policy_ids: Vec<PolicyId>,
+}
+
+impl EvalCtx {
+    fn clear(&mut self) {
+        self.applicable_allow.clear();
+        self.applicable_deny.clear();
+    }
+}
+
+impl Applicable {
+    fn clear(&mut self) {
+        self.disjunctive.clear();
+        self.conjunctive.clear();
+        self.conjunctive_ids.clear();
+        self.policy_ids.clear();
+    }
+
+    /// Sort and deduplicate the collected policies and groups.
+    fn normalize(&mut self) {
+        self.disjunctive.sort_unstable();
+        self.disjunctive.dedup();
+
+        let ids = &self.conjunctive_ids;
+        self.conjunctive
+            .sort_unstable_by(|a, b| ids[a.clone()].cmp(&ids[b.clone()]));
+        self.conjunctive
+            .dedup_by(|a, b| ids[a.clone()] == ids[b.clone()]);
+
+        self.policy_ids.extend(&self.disjunctive);
+        for range in &self.conjunctive {
+            self.policy_ids.extend(&self.conjunctive_ids[range.clone()]);
+        }
+        self.policy_ids.sort_unstable();
+        self.policy_ids.dedup();
+    }
+
+    fn group(&self, range: &Range<usize>) -> &[PolicyId] {
+        &self.conjunctive_ids[range.clone()]
+    }
+
+    fn is_empty(&self) -> bool {
+        self.disjunctive.is_empty() && self.conjunctive.is_empty()
+    }
 }
 
 impl PolicyEngine {
@@ -107,24 +441,216 @@ impl PolicyEngine {
         self.policies.insert(id, Policy { class, bytecode });
     }
 
+    /// Adds a new policy to the engine, after verifying its bytecode.
+    ///
+    /// The policy is not added if verification fails.
+    pub fn add_policy_verified(
+        &mut self,
+        id: PolicyId,
+        class: PolicyValue,
+        bytecode: Vec<u8>,
+    ) -> Result<(), VerifyError> {
+        verify(&bytecode)?;
+        self.add_policy(id, class, bytecode);
+        Ok(())
+    }
+
     /// Adds a new policy trigger to the engine.
     pub fn add_trigger(
         &mut self,
         attr_matcher: impl Into<BTreeSet<AttrId>>,
         policy_ids: impl Into<BTreeSet<PolicyId>>,
     ) {
-        let attr_matcher = attr_matcher.into();
-        let policy_ids = policy_ids.into();
+        self.add_trigger_with_combinator(attr_matcher, policy_ids, Combinator::Disjunctive);
+    }
+
+    /// Adds a new conjunctive policy trigger to the engine.
+    ///
+    /// The policies of each class bound by the trigger form a group, which only holds when every policy in it evaluates to true.
+    pub fn add_trigger_conjunctive(
+        &mut self,
+        attr_matcher: impl Into<BTreeSet<AttrId>>,
+        policy_ids: impl Into<BTreeSet<PolicyId>>,
+    ) {
+        self.add_trigger_with_combinator(attr_matcher, policy_ids, Combinator::Conjunctive);
+    }
 
-        if let Some(first_attr) = attr_matcher.iter().next() {
+    /// Adds a new policy trigger to the engine, using the given combinator for its policies.
+    pub fn add_trigger_with_combinator(
+        &mut self,
+        attr_matcher: impl Into<BTreeSet<AttrId>>,
+        policy_ids: impl Into<BTreeSet<PolicyId>>,
+        combinator: Combinator,
+    ) {
+        self.push_trigger(PolicyTrigger {
+            attr_matcher: attr_matcher.into(),
+            excluded_attrs: Default::default(),
+            policy_ids: policy_ids.into(),
+            combinator,
+        });
+    }
+
+    /// Adds a new policy trigger to the engine, which does not trigger when any of the excluded attributes are present.
+    ///
+    /// This expresses exceptions to a trigger without inverting its policies.
+    pub fn add_trigger_excluding(
+        &mut self,
+        attr_matcher: impl Into<BTreeSet<AttrId>>,
+        excluded_attrs: impl Into<BTreeSet<AttrId>>,
+        policy_ids: impl Into<BTreeSet<PolicyId>>,
+    ) {
+        self.push_trigger(PolicyTrigger {
+            attr_matcher: attr_matcher.into(),
+            excluded_attrs: excluded_attrs.into(),
+            policy_ids: policy_ids.into(),
+            combinator: Combinator::Disjunctive,
+        });
+    }
+
+    fn push_trigger(&mut self, trigger: PolicyTrigger) {
+        if let Some(first_attr) = trigger.attr_matcher.first() {
             self.trigger_groups
                 .entry(*first_attr)
                 .or_default()
-                .push(PolicyTrigger {
-                    attr_matcher,
-                    policy_ids,
-                });
+                .push(trigger);
+        }
+    }
+
+    /// Removes a policy from the engine, along with its references from triggers.
+    ///
+    /// Triggers that no longer reference any policy are removed.
+    /// Conjunctive triggers referencing the policy are removed entirely, since their group can no longer hold.
+    /// Returns whether the policy was present.
+    pub fn remove_policy(&mut self, id: PolicyId) -> bool {
+        let removed = self.policies.remove(&id).is_some();
+
+        self.trigger_groups.retain(|_, triggers| {
+            triggers.retain_mut(|trigger| {
+                if trigger.combinator == Combinator::Conjunctive && trigger.policy_ids.contains(&id)
+                {
+                    return false;
+                }
+                trigger.policy_ids.remove(&id);
+                !trigger.policy_ids.is_empty()
+            });
+            !triggers.is_empty()
+        });
+
+        removed
+    }
+
+    /// Removes the triggers with the given attribute matcher.
+    ///
+    /// Returns whether any trigger was removed.
+    pub fn remove_trigger(&mut self, attr_matcher: impl Into<BTreeSet<AttrId>>) -> bool {
+        let attr_matcher = attr_matcher.into();
+        let Some(first_attr) = attr_matcher.first() else {
+            return false;
+        };
+        let Some(triggers) = self.trigger_groups.get_mut(first_attr) else {
+            return false;
+        };
+
+        let len = triggers.len();
+        triggers.retain(|trigger| trigger.attr_matcher != attr_matcher);
+        let removed = triggers.len() < len;
+
+        if triggers.is_empty() {
+            self.trigger_groups.remove(first_attr);
+        }
+
+        removed
+    }
+
+    /// Set the resource limits applied to each policy evaluation
+    pub fn set_eval_limits(&mut self, limits: EvalLimits) {
+        self.limits = limits;
+    }
+
+    /// Get the resource limits applied to each policy evaluation
+    pub fn eval_limits(&self) -> &EvalLimits {
+        &self.limits
+    }
+
+    /// Set the order of evaluation of the policy classes.
+    ///
+    /// The order is a property of the evaluating host, and is not part of the serialized engine.
+    pub fn set_eval_order(&mut self, order: EvalOrder) {
+        self.order = order;
+    }
+
+    /// Get the order of evaluation of the policy classes
+    pub fn eval_order(&self) -> EvalOrder {
+        self.order
+    }
+
+    /// Serialize the engine to a versioned binary format, suitable for caching.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let data = EngineData {
+            policies: self
+                .policies
+                .iter()
+                .map(|(id, policy)| (id.to_uint(), policy.class, policy.bytecode.as_slice()))
+                .collect(),
+            triggers: self
+                .trigger_groups
+                .values()
+                .flatten()
+                .map(|trigger| {
+                    (
+                        trigger.attr_matcher.iter().map(AttrId::to_uint).collect(),
+                        trigger.excluded_attrs.iter().map(AttrId::to_uint).collect(),
+                        trigger.policy_ids.iter().map(PolicyId::to_uint).collect(),
+                        trigger.combinator,
+                    )
+                })
+                .collect(),
+            limits: self.limits,
+        };
+
+        let mut out = ENGINE_MAGIC.to_vec();
+        out.push(ENGINE_FORMAT_VERSION);
+        postcard::to_extend(&data, out).expect("serializing to a Vec does not fail")
+    }
+
+    /// Deserialize an engine previously serialized with [Self::to_bytes].
+    ///
+    /// The bytecode of every policy is verified, so that malformed data fails here instead of during evaluation.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, EngineDecodeError> {
+        let Some(bytes) = bytes.strip_prefix(ENGINE_MAGIC) else {
+            return Err(EngineDecodeError::Magic);
+        };
+        let Some((version, bytes)) = bytes.split_first() else {
+            return Err(EngineDecodeError::Format);
+        };
+        if *version != ENGINE_FORMAT_VERSION {
+            return Err(EngineDecodeError::Version(*version));
+        }
+
+        let data: EngineData =
+            postcard::from_bytes(bytes).map_err(|_| EngineDecodeError::Format)?;
+
+        let mut engine = PolicyEngine {
+            limits: data.limits,
+            ..Default::default()
+        };
+
+        for (id, class, bytecode) in data.policies {
+            engine
+                .add_policy_verified(PolicyId::from_uint(id), class, bytecode.to_vec())
+                .map_err(|_| EngineDecodeError::Format)?;
+        }
+
+        for (attr_matcher, excluded_attrs, policy_ids, combinator) in data.triggers {
+            engine.push_trigger(PolicyTrigger {
+                attr_matcher: attr_matcher.into_iter().map(AttrId::from_uint).collect(),
+                excluded_attrs: excluded_attrs.into_iter().map(AttrId::from_uint).collect(),
+                policy_ids: policy_ids.into_iter().map(PolicyId::from_uint).collect(),
+                combinator,
+            });
         }
+
+        Ok(engine)
     }
 
     /// Get the number of policies currently in the engine.
@@ -137,30 +663,154 @@ impl PolicyEngine {
         self.trigger_groups.values().map(Vec::len).sum()
     }
 
+    /// Find the policies referenced by triggers which are missing from the engine, in ascending order.
+    ///
+    /// Evaluation ignores missing policies, and conjunctive groups with a missing member never hold,
+    /// so this is useful for failing fast after loading an engine.
+    pub fn validate(&self) -> Vec<PolicyId> {
+        let missing: BTreeSet<PolicyId> = self
+            .trigger_groups
+            .values()
+            .flatten()
+            .flat_map(|trigger| &trigger.policy_ids)
+            .filter(|policy_id| !self.policies.contains_key(policy_id))
+            .copied()
+            .collect();
+
+        missing.into_iter().collect()
+    }
+
     /// Perform an access control evalution of the given parameters within this engine.
     pub fn eval(
         &self,
         params: &AccessControlParams,
         tracer: &mut impl PolicyTracer,
     ) -> Result<PolicyValue, EvalError> {
-        let mut eval_ctx = EvalCtx {
-            applicable_allow: Default::default(),
-            applicable_deny: Default::default(),
-        };
+        self.eval_with_scratch(params, &mut EvalScratch::default(), tracer)
+    }
+
+    /// Perform an access control evalution of the given parameters within this engine,
+    /// reusing the buffers of the given scratch.
+    pub fn eval_with_scratch(
+        &self,
+        params: &AccessControlParams,
+        scratch: &mut EvalScratch,
+        tracer: &mut impl PolicyTracer,
+    ) -> Result<PolicyValue, EvalError> {
+        Ok(self
+            .eval_explain_with_scratch(params, scratch, tracer)?
+            .value)
+    }
+
+    /// Perform an access control evalution of the given parameters within this engine,
+    /// explaining what caused the outcome.
+    pub fn eval_explain(
+        &self,
+        params: &AccessControlParams,
+        tracer: &mut impl PolicyTracer,
+    ) -> Result<Decision, EvalError> {
+        self.eval_explain_with_scratch(params, &mut EvalScratch::default(), tracer)
+    }
+
+    /// Perform an access control evalution of the given parameters within this engine,
+    /// explaining what caused the outcome and reusing the buffers of the given scratch.
+    pub fn eval_explain_with_scratch(
+        &self,
+        params: &AccessControlParams,
+        scratch: &mut EvalScratch,
+        tracer: &mut impl PolicyTracer,
+    ) -> Result<Decision, EvalError> {
+        self.collect(params, &mut scratch.eval_ctx);
+
+        let decision = self.decide(&scratch.eval_ctx, params, &mut scratch.stack, tracer)?;
+        tracer.report_decision(&decision);
+
+        Ok(decision)
+    }
+
+    /// Perform access control evaluations of a batch of parameters within this engine.
+    ///
+    /// The outcomes are returned in the same order as the parameters.
+    ///
+    /// The applicable policies only depend on the combined subject and resource attributes,
+    /// so they are collected once per distinct attribute set in the batch and reused for
+    /// parameters that repeat it.
+    pub fn eval_batch(
+        &self,
+        params: &[AccessControlParams],
+        tracer: &mut impl PolicyTracer,
+    ) -> Vec<Result<PolicyValue, EvalError>> {
+        let mut cache: FnvHashMap<BTreeSet<AttrId>, EvalCtx> = Default::default();
+        let mut stack = vec![];
+
+        params
+            .iter()
+            .map(|params| {
+                let attrs = params
+                    .subject_attrs
+                    .iter()
+                    .chain(&params.resource_attrs)
+                    .copied()
+                    .collect();
+                let eval_ctx = match cache.entry(attrs) {
+                    Entry::Occupied(entry) => entry.into_mut(),
+                    Entry::Vacant(entry) => {
+                        let mut eval_ctx = EvalCtx::default();
+                        self.collect(params, &mut eval_ctx);
+                        entry.insert(eval_ctx)
+                    }
+                };
+
+                let decision = self.decide(eval_ctx, params, &mut stack, tracer)?;
+                tracer.report_decision(&decision);
+
+                Ok(decision.value)
+            })
+            .collect()
+    }
+
+    /// Collect the policies applicable to the given parameters into the (cleared) context.
+    fn collect(&self, params: &AccessControlParams, eval_ctx: &mut EvalCtx) {
+        eval_ctx.clear();
 
         for attr in &params.subject_attrs {
-            self.collect_applicable(*attr, params, &mut eval_ctx)?;
+            self.collect_applicable(*attr, params, eval_ctx);
         }
 
         for attr in &params.resource_attrs {
-            self.collect_applicable(*attr, params, &mut eval_ctx)?;
+            self.collect_applicable(*attr, params, eval_ctx);
         }
 
+        eval_ctx.applicable_allow.normalize();
+        eval_ctx.applicable_deny.normalize();
+    }
+
+    fn decide(
+        &self,
+        eval_ctx: &EvalCtx,
+        params: &AccessControlParams,
+        stack: &mut Vec<StackItem>,
+        tracer: &mut impl PolicyTracer,
+    ) -> Result<Decision, EvalError> {
+        let eval = |applicable: &Applicable, stack: &mut Vec<StackItem>, tracer: &mut _| {
+            eval_applicable(
+                applicable,
+                &self.policies,
+                params,
+                &self.limits,
+                stack,
+                tracer,
+            )
+        };
+
         {
-            tracer.report_applicable(PolicyValue::Deny, eval_ctx.applicable_deny.keys().copied());
+            tracer.report_applicable(
+                PolicyValue::Deny,
+                eval_ctx.applicable_deny.policy_ids.iter().copied(),
+            );
             tracer.report_applicable(
                 PolicyValue::Allow,
-                eval_ctx.applicable_allow.keys().copied(),
+                eval_ctx.applicable_allow.policy_ids.iter().copied(),
             );
         }
 
@@ -172,121 +822,248 @@ impl PolicyEngine {
                 // idea: Fallback mode, no policies matched
                 for subj_attr in &params.subject_attrs {
                     if params.resource_attrs.contains(subj_attr) {
-                        return Ok(PolicyValue::Allow);
+                        return Ok(Decision {
+                            value: PolicyValue::Allow,
+                            cause: DecisionCause::AttributeMatch(Some(*subj_attr)),
+                        });
                     }
                 }
 
-                Ok(PolicyValue::Deny)
+                Ok(Decision {
+                    value: PolicyValue::Deny,
+                    cause: DecisionCause::AttributeMatch(None),
+                })
             }
             (true, false) => {
                 // starts in Deny state, try to prove Allow
-                let is_allow =
-                    eval_policies_disjunctive(eval_ctx.applicable_allow, params, tracer)?;
-                Ok(PolicyValue::from(is_allow))
+                let allow = eval(&eval_ctx.applicable_allow, stack, tracer)?;
+                Ok(match allow {
+                    Some(policy_id) => Decision::policy(policy_id, PolicyValue::Allow),
+                    None => Decision::default(PolicyValue::Deny),
+                })
             }
             (false, true) => {
                 // starts in Allow state, try to prove Deny
-                let is_deny = eval_policies_disjunctive(eval_ctx.applicable_deny, params, tracer)?;
-                Ok(PolicyValue::from(!is_deny))
+                let deny = eval(&eval_ctx.applicable_deny, stack, tracer)?;
+                Ok(match deny {
+                    Some(policy_id) => Decision::policy(policy_id, PolicyValue::Deny),
+                    None => Decision::default(PolicyValue::Allow),
+                })
+            }
+            (true, true) if self.order == EvalOrder::DenyFirst => {
+                // try to prove Deny, which overrides any Allow
+                let deny = eval(&eval_ctx.applicable_deny, stack, tracer);
+                if let Ok(Some(policy_id)) = deny {
+                    return Ok(Decision::policy(policy_id, PolicyValue::Deny));
+                }
+
+                // no Deny holds, try to prove Allow
+                let allow = eval(&eval_ctx.applicable_allow, stack, tracer);
+                decide_allow_deny(allow, deny)
             }
             (true, true) => {
                 // starts in Deny state, try to prove Allow
-                let is_allow =
-                    eval_policies_disjunctive(eval_ctx.applicable_allow, params, tracer)?;
-                if !is_allow {
-                    return Ok(PolicyValue::Deny);
+                let allow = eval(&eval_ctx.applicable_allow, stack, tracer);
+                if let Ok(None) = allow {
+                    return Ok(Decision::default(PolicyValue::Deny));
                 }
 
-                // moved into in Allow state, try to prove Deny
-                let is_deny = eval_policies_disjunctive(eval_ctx.applicable_deny, params, tracer)?;
-                Ok(PolicyValue::from(!is_deny))
+                // moved into in Allow state (unless Allow failed), try to prove Deny
+                let deny = eval(&eval_ctx.applicable_deny, stack, tracer);
+                decide_allow_deny(allow, deny)
             }
         }
     }
 
-    fn collect_applicable<'e>(
-        &'e self,
+    fn collect_applicable(
+        &self,
         attr: AttrId,
         params: &AccessControlParams,
-        eval_ctx: &mut EvalCtx<'e>,
-    ) -> Result<(), EvalError> {
+        eval_ctx: &mut EvalCtx,
+    ) {
         // Find all potential triggers to investigate for this attribute
         let Some(policy_triggers) = self.trigger_groups.get(&attr) else {
-            return Ok(());
+            return;
         };
 
         for policy_trigger in policy_triggers {
             if policy_trigger.attr_matcher.len() > 1 {
-                // a multi-attribute trigger: needs some post-processing
-                // to figure out if it applies
-                let mut matches: BTreeSet<AttrId> = Default::default();
-
-                for attrs in [&params.subject_attrs, &params.resource_attrs] {
-                    for attr in attrs {
-                        if policy_trigger.attr_matcher.contains(attr) {
-                            matches.insert(*attr);
-                        }
-                    }
-                }
+                // a multi-attribute trigger: applies only when every attribute
+                // is present in either the subject or the resource
+                let matches = policy_trigger.attr_matcher.iter().all(|attr| {
+                    params.subject_attrs.contains(attr) || params.resource_attrs.contains(attr)
+                });
 
-                if matches != policy_trigger.attr_matcher {
+                if !matches {
                     // not applicable
                     continue;
                 }
             }
 
-            // The trigger applies; register all its policies as applicable
-            for policy_id in policy_trigger.policy_ids.iter().copied() {
-                let Some(policy) = self.policies.get(&policy_id) else {
-                    error!(?policy_id, "policy is missing");
+            let excluded = policy_trigger.excluded_attrs.iter().any(|attr| {
+                params.subject_attrs.contains(attr) || params.resource_attrs.contains(attr)
+            });
+
+            if excluded {
+                // not applicable
+                continue;
+            }
 
-                    // internal error, which is not exposed
+            if policy_trigger.combinator == Combinator::Conjunctive {
+                if let Some(policy_id) = policy_trigger
+                    .policy_ids
+                    .iter()
+                    .find(|policy_id| !self.policies.contains_key(policy_id))
+                {
+                    error!(?policy_id, "conjunctive policy is missing");
+
+                    // fail closed: the group cannot hold without all its members
                     continue;
+                }
+            }
+
+            // The trigger applies; register all its policies as applicable
+            for (class, applicable) in [
+                (PolicyValue::Allow, &mut eval_ctx.applicable_allow),
+                (PolicyValue::Deny, &mut eval_ctx.applicable_deny),
+            ] {
+                let ids = match policy_trigger.combinator {
+                    Combinator::Disjunctive => &mut applicable.disjunctive,
+                    Combinator::Conjunctive => &mut applicable.conjunctive_ids,
                 };
+                let start = ids.len();
 
-                match policy.class {
-                    PolicyValue::Deny => {
-                        eval_ctx.applicable_deny.insert(policy_id, policy);
-                    }
-                    PolicyValue::Allow => {
-                        eval_ctx.applicable_allow.insert(policy_id, policy);
+                for policy_id in policy_trigger.policy_ids.iter().copied() {
+                    let Some(policy) = self.policies.get(&policy_id) else {
+                        error!(?policy_id, "policy is missing");
+
+                        // internal error, which is not exposed
+                        continue;
+                    };
+
+                    if policy.class == class {
+                        ids.push(policy_id);
                     }
                 }
+
+                // the trigger's policy IDs are ordered, so the group is too
+                let end = ids.len();
+                if policy_trigger.combinator == Combinator::Conjunctive && end > start {
+                    applicable.conjunctive.push(start..end);
+                }
             }
         }
+    }
+}
 
-        Ok(())
+impl Decision {
+    fn policy(policy_id: PolicyId, class: PolicyValue) -> Self {
+        Self {
+            value: class,
+            cause: DecisionCause::Policy { policy_id, class },
+        }
+    }
+
+    fn default(value: PolicyValue) -> Self {
+        Self {
+            value,
+            cause: DecisionCause::Default,
+        }
+    }
+}
+
+/// Evaluate the applicable policies of one class, map their outputs to a boolean value and return the OR function applied to those values.
+///
+/// Each conjunctive group contributes the AND function applied to the values of its members.
+/// Disjunctive policies are evaluated first, in ascending [PolicyId] order, followed by the conjunctive groups.
+/// Returns the ID of the first policy (or the lowest ID of the first group) that evaluated to true, if any.
+/// Combine the outcomes of the allow and deny policies into a decision.
+///
+/// A failing policy class only fails the evaluation when the other class does not decide the outcome on its own,
+/// so that the result does not depend on the [EvalOrder].
+fn decide_allow_deny(
+    allow: Result<Option<PolicyId>, EvalError>,
+    deny: Result<Option<PolicyId>, EvalError>,
+) -> Result<Decision, EvalError> {
+    match (allow, deny) {
+        (_, Ok(Some(policy_id))) => Ok(Decision::policy(policy_id, PolicyValue::Deny)),
+        (Ok(None), _) => Ok(Decision::default(PolicyValue::Deny)),
+        (Ok(Some(policy_id)), Ok(None)) => Ok(Decision::policy(policy_id, PolicyValue::Allow)),
+        (Err(err), _) | (_, Err(err)) => Err(err),
     }
 }
 
-/// Evaluate set of policies, map their outputs to a boolean value and return the OR function applied to those values.
-fn eval_policies_disjunctive(
-    map: FnvHashMap<PolicyId, &Policy>,
+fn eval_applicable(
+    applicable: &Applicable,
+    policies: &FnvHashMap<PolicyId, Policy>,
     params: &AccessControlParams,
+    limits: &EvalLimits,
+    stack: &mut Vec<StackItem>,
     tracer: &mut impl PolicyTracer,
-) -> Result<bool, EvalError> {
-    for (policy_id, policy) in &map {
-        tracer.report_policy_eval_start(*policy_id);
-
-        let value = eval_policy(&policy.bytecode, params)?;
+) -> Result<Option<PolicyId>, EvalError> {
+    let mut eval = |policy_id: PolicyId, tracer: &mut _| -> Result<bool, EvalError> {
+        let Some(policy) = policies.get(&policy_id) else {
+            // collected policies are always present
+            return Err(EvalError::Program);
+        };
+        eval_traced(policy_id, policy, params, limits, stack, tracer)
+    };
 
-        tracer.report_policy_eval_end(value);
+    for policy_id in &applicable.disjunctive {
+        if eval(*policy_id, tracer)? {
+            return Ok(Some(*policy_id));
+        }
+    }
 
-        if value {
-            return Ok(true);
+    'groups: for range in &applicable.conjunctive {
+        let policy_ids = applicable.group(range);
+        for policy_id in policy_ids {
+            if !eval(*policy_id, tracer)? {
+                continue 'groups;
+            }
         }
+
+        return Ok(policy_ids.first().copied());
     }
 
-    Ok(false)
+    Ok(None)
+}
+
+fn eval_traced(
+    policy_id: PolicyId,
+    policy: &Policy,
+    params: &AccessControlParams,
+    limits: &EvalLimits,
+    stack: &mut Vec<StackItem>,
+    tracer: &mut impl PolicyTracer,
+) -> Result<bool, EvalError> {
+    tracer.report_policy_eval_start(policy_id);
+
+    let value = eval_policy(&policy.bytecode, params, limits, stack)?;
+
+    tracer.report_policy_eval_end(value);
+
+    Ok(value)
 }
 
-/// Evaluate one standalone policy on the given access control parameters
-fn eval_policy(mut pc: &[u8], params: &AccessControlParams) -> Result<bool, EvalError> {
-    let mut stack: Vec<StackItem> = Vec::with_capacity(16);
+/// Evaluate one standalone policy on the given access control parameters, using the given (cleared) stack
+fn eval_policy(
+    mut pc: &[u8],
+    params: &AccessControlParams,
+    limits: &EvalLimits,
+    stack: &mut Vec<StackItem>,
+) -> Result<bool, EvalError> {
+    stack.clear();
+    let mut instructions = 0;
 
     while let Some(code) = pc.first() {
         pc = &pc[1..];
 
+        instructions += 1;
+        if instructions > limits.max_instructions || stack.len() > limits.max_stack_depth {
+            return Err(EvalError::Program);
+        }
+
         let Ok(code) = Bytecode::try_from(*code) else {
             return Err(EvalError::Program);
         };
@@ -300,7 +1077,7 @@ fn eval_policy(mut pc: &[u8], params: &AccessControlParams) -> Result<bool, Eval
                 stack.push(StackItem::EntityId(*id));
             }
             Bytecode::LoadSubjectAttrs => {
-                stack.push(StackItem::AttrIdSet(&params.subject_attrs));
+                stack.push(StackItem::AttrIdSet(Side::Subject));
             }
             Bytecode::LoadResourceId => {
                 let prop_id = PropId::from_uint(pc.read_u128::<BigEndian>()?);
@@ -310,19 +1087,41 @@ fn eval_policy(mut pc: &[u8], params: &AccessControlParams) -> Result<bool, Eval
                 stack.push(StackItem::EntityId(*id));
             }
             Bytecode::LoadResourceAttrs => {
-                stack.push(StackItem::AttrIdSet(&params.resource_attrs));
+                stack.push(StackItem::AttrIdSet(Side::Resource));
+            }
+            Bytecode::LoadSubjectIdSet => {
+                stack.push(StackItem::EntityIdSet(Side::Subject));
+            }
+            Bytecode::LoadResourceIdSet => {
+                stack.push(StackItem::EntityIdSet(Side::Resource));
             }
             Bytecode::LoadConstEntityId => {
-                let Ok(kind) = Kind::try_from(pc.read_u8()?) else {
+                let Some(array) = pc.get(..17) else {
+                    return Err(EvalError::Program);
+                };
+                let Some(eid) = EntityId::try_from_bytes_dynamic(array) else {
                     return Err(EvalError::Type);
                 };
-                let uint = pc.read_u128::<BigEndian>()?;
-                stack.push(StackItem::EntityId(EntityId::new(kind, uint.to_be_bytes())));
+                pc = &pc[17..];
+                stack.push(StackItem::EntityId(eid));
             }
             Bytecode::LoadConstAttrId => {
                 let attr_id = AttrId::from_uint(pc.read_u128::<BigEndian>()?);
                 stack.push(StackItem::AttrId(attr_id));
             }
+            Bytecode::LoadConstUint => {
+                let uint = pc.read_u64::<BigEndian>()?;
+                stack.push(StackItem::Uint(uint));
+            }
+            Bytecode::LoadNow => {
+                stack.push(StackItem::Uint(params.now()));
+            }
+            Bytecode::LoadTrue => {
+                stack.push(StackItem::Uint(1));
+            }
+            Bytecode::LoadFalse => {
+                stack.push(StackItem::Uint(0));
+            }
             Bytecode::IsEq => {
                 let Some(a) = stack.pop() else {
                     return Err(EvalError::Type);
@@ -331,10 +1130,21 @@ fn eval_policy(mut pc: &[u8], params: &AccessControlParams) -> Result<bool, Eval
                     return Err(EvalError::Type);
                 };
                 let is_eq = match (a, b) {
+                    (StackItem::Uint(a), StackItem::Uint(b)) => a == b,
                     (StackItem::AttrId(a), StackItem::AttrId(b)) => a == b,
                     (StackItem::EntityId(a), StackItem::EntityId(b)) => a == b,
-                    (StackItem::AttrIdSet(set), StackItem::AttrId(id)) => set.contains(&id),
-                    (StackItem::AttrId(id), StackItem::AttrIdSet(set)) => set.contains(&id),
+                    (StackItem::AttrIdSet(set), StackItem::AttrId(id)) => {
+                        set.attrs(params).contains(&id)
+                    }
+                    (StackItem::AttrId(id), StackItem::AttrIdSet(set)) => {
+                        set.attrs(params).contains(&id)
+                    }
+                    (StackItem::EntityIdSet(set), StackItem::EntityId(id)) => {
+                        set.eids(params).contains(&id)
+                    }
+                    (StackItem::EntityId(id), StackItem::EntityIdSet(set)) => {
+                        set.eids(params).contains(&id)
+                    }
                     _ => false,
                 };
                 stack.push(StackItem::Uint(if is_eq { 1 } else { 0 }));
@@ -346,25 +1156,37 @@ fn eval_policy(mut pc: &[u8], params: &AccessControlParams) -> Result<bool, Eval
                 let Some(StackItem::AttrIdSet(b)) = stack.pop() else {
                     return Err(EvalError::Type);
                 };
-                stack.push(StackItem::Uint(if a.is_superset(b) { 1 } else { 0 }));
+                stack.push(StackItem::Uint(
+                    if a.attrs(params).is_superset(b.attrs(params)) {
+                        1
+                    } else {
+                        0
+                    },
+                ));
             }
             Bytecode::IdSetContains => {
-                let Some(a) = stack.pop() else {
+                let Some(rhs) = stack.pop() else {
                     return Err(EvalError::Type);
                 };
-                let Some(b) = stack.pop() else {
+                let Some(lhs) = stack.pop() else {
                     return Err(EvalError::Type);
                 };
 
-                match (a, b) {
-                    (StackItem::AttrIdSet(a), StackItem::AttrId(b)) => {
-                        // BUG: Does not support u128?
-                        stack.push(StackItem::Uint(if a.contains(&b) { 1 } else { 0 }));
+                // The set and the id may appear in either order
+                let contains = match (lhs, rhs) {
+                    (StackItem::AttrIdSet(set), StackItem::AttrId(id))
+                    | (StackItem::AttrId(id), StackItem::AttrIdSet(set)) => {
+                        set.attrs(params).contains(&id)
+                    }
+                    (StackItem::EntityIdSet(set), StackItem::EntityId(id))
+                    | (StackItem::EntityId(id), StackItem::EntityIdSet(set)) => {
+                        set.eids(params).contains(&id)
                     }
                     _ => {
                         return Err(EvalError::Type);
                     }
-                }
+                };
+                stack.push(StackItem::Uint(if contains { 1 } else { 0 }));
             }
             Bytecode::And => {
                 let Some(StackItem::Uint(rhs)) = stack.pop() else {
@@ -384,6 +1206,15 @@ fn eval_policy(mut pc: &[u8], params: &AccessControlParams) -> Result<bool, Eval
                 };
                 stack.push(StackItem::Uint(if rhs > 0 || lhs > 0 { 1 } else { 0 }));
             }
+            Bytecode::Xor => {
+                let Some(StackItem::Uint(rhs)) = stack.pop() else {
+                    return Err(EvalError::Type);
+                };
+                let Some(StackItem::Uint(lhs)) = stack.pop() else {
+                    return Err(EvalError::Type);
+                };
+                stack.push(StackItem::Uint(if (rhs > 0) != (lhs > 0) { 1 } else { 0 }));
+            }
             Bytecode::Not => {
                 let Some(StackItem::Uint(val)) = stack.pop() else {
                     return Err(EvalError::Type);
@@ -396,6 +1227,42 @@ fn eval_policy(mut pc: &[u8], params: &AccessControlParams) -> Result<bool, Eval
                 };
                 return Ok(u > 0);
             }
+            Bytecode::Gt => {
+                let Some(StackItem::Uint(rhs)) = stack.pop() else {
+                    return Err(EvalError::Type);
+                };
+                let Some(StackItem::Uint(lhs)) = stack.pop() else {
+                    return Err(EvalError::Type);
+                };
+                stack.push(StackItem::Uint(if lhs > rhs { 1 } else { 0 }));
+            }
+            Bytecode::Lt => {
+                let Some(StackItem::Uint(rhs)) = stack.pop() else {
+                    return Err(EvalError::Type);
+                };
+                let Some(StackItem::Uint(lhs)) = stack.pop() else {
+                    return Err(EvalError::Type);
+                };
+                stack.push(StackItem::Uint(if lhs < rhs { 1 } else { 0 }));
+            }
+            Bytecode::Gte => {
+                let Some(StackItem::Uint(rhs)) = stack.pop() else {
+                    return Err(EvalError::Type);
+                };
+                let Some(StackItem::Uint(lhs)) = stack.pop() else {
+                    return Err(EvalError::Type);
+                };
+                stack.push(StackItem::Uint(if lhs >= rhs { 1 } else { 0 }));
+            }
+            Bytecode::Lte => {
+                let Some(StackItem::Uint(rhs)) = stack.pop() else {
+                    return Err(EvalError::Type);
+                };
+                let Some(StackItem::Uint(lhs)) = stack.pop() else {
+                    return Err(EvalError::Type);
+                };
+                stack.push(StackItem::Uint(if lhs <= rhs { 1 } else { 0 }));
+            }
         }
     }
 
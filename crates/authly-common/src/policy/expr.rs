@@ -0,0 +1,367 @@
+//! Compiler for the policy expression language used in `allow`/`deny` expressions of Authly documents.
+//!
+//! Grammar:
+//!
+//! ```text
+//! expr       := and ("||" and)*
+//! and        := unary ("&&" unary)*
+//! unary      := "!" unary | "(" expr ")" | comparison
+//! comparison := term ("==" | "contains") term
+//! term       := ("Subject" | "Resource") "." path | path
+//! path       := label (":" label)*
+//! ```
+//!
+//! `Subject.entity` accesses an entity-valued property, `Subject.namespace:property` accesses attributes,
+//! a bare `label` is an entity and a bare `namespace:property:attribute` is an attribute.
+
+use std::ops::Range;
+
+use crate::id::{AttrId, EntityId, PropId};
+
+use super::code::OpCode;
+
+/// Label resolution used by the expression compiler.
+pub trait ExprEnv {
+    /// Resolve an entity-valued property, like the `entity` of `Subject.entity`.
+    fn entity_property(&self, label: &str) -> Option<PropId>;
+
+    /// Whether the attribute-valued property `namespace:property` exists.
+    fn has_attribute_property(&self, namespace: &str, property: &str) -> bool;
+
+    /// Resolve an entity by its label.
+    fn entity(&self, label: &str) -> Option<EntityId>;
+
+    /// Resolve a qualified attribute.
+    fn attribute(&self, namespace: &str, property: &str, attribute: &str) -> Option<AttrId>;
+}
+
+/// An expression compilation error.
+///
+/// The span is a byte range within the compiled expression.
+#[derive(thiserror::Error, Clone, PartialEq, Eq, Debug)]
+#[error("{kind} at {span:?}")]
+pub struct ExprError {
+    /// The location of the error.
+    pub span: Range<usize>,
+
+    /// The kind of error.
+    pub kind: ExprErrorKind,
+}
+
+/// The kind of [ExprError].
+#[derive(thiserror::Error, Clone, PartialEq, Eq, Debug)]
+pub enum ExprErrorKind {
+    /// A character that does not start any token.
+    #[error("unexpected character")]
+    UnexpectedChar,
+
+    /// The expression ended prematurely.
+    #[error("unexpected end of expression")]
+    UnexpectedEnd,
+
+    /// A token that does not fit the grammar.
+    #[error("unexpected token")]
+    UnexpectedToken,
+
+    /// An unknown entity-valued property.
+    #[error("unknown entity property")]
+    UnknownEntityProperty,
+
+    /// An unknown attribute-valued property.
+    #[error("unknown attribute property")]
+    UnknownAttributeProperty,
+
+    /// An unknown entity label.
+    #[error("unknown entity")]
+    UnknownEntity,
+
+    /// An unknown attribute.
+    #[error("unknown attribute")]
+    UnknownAttribute,
+
+    /// An operand of the wrong type.
+    #[error("operand type mismatch")]
+    TypeMismatch,
+}
+
+/// Compile a policy expression into opcodes, ending with [OpCode::Return].
+pub fn compile(expr: &str, env: &dyn ExprEnv) -> Result<Vec<OpCode>, ExprError> {
+    let mut parser = Parser {
+        tokens: lex(expr)?,
+        pos: 0,
+        end: expr.len(),
+        env,
+        output: vec![],
+    };
+
+    parser.expr()?;
+
+    if let Some((_, span)) = parser.tokens.get(parser.pos) {
+        return Err(error(span.clone(), ExprErrorKind::UnexpectedToken));
+    }
+
+    parser.output.push(OpCode::Return);
+
+    Ok(parser.output)
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+enum Token {
+    Label(String),
+    Dot,
+    Colon,
+    Eq,
+    And,
+    Or,
+    Not,
+    LParen,
+    RParen,
+}
+
+fn lex(expr: &str) -> Result<Vec<(Token, Range<usize>)>, ExprError> {
+    let mut tokens = vec![];
+    let mut chars = expr.char_indices().peekable();
+
+    while let Some((start, c)) = chars.next() {
+        let token = match c {
+            c if c.is_whitespace() => continue,
+            '.' => Token::Dot,
+            ':' => Token::Colon,
+            '!' => Token::Not,
+            '(' => Token::LParen,
+            ')' => Token::RParen,
+            '=' | '&' | '|' => {
+                if chars.next_if(|(_, next)| *next == c).is_none() {
+                    return Err(error(start..start + 1, ExprErrorKind::UnexpectedChar));
+                }
+                tokens.push((
+                    match c {
+                        '=' => Token::Eq,
+                        '&' => Token::And,
+                        _ => Token::Or,
+                    },
+                    start..start + 2,
+                ));
+                continue;
+            }
+            c if is_label_char(c) => {
+                let mut end = start + c.len_utf8();
+                while let Some((idx, c)) = chars.next_if(|(_, c)| is_label_char(*c)) {
+                    end = idx + c.len_utf8();
+                }
+                tokens.push((Token::Label(expr[start..end].to_string()), start..end));
+                continue;
+            }
+            _ => {
+                return Err(error(
+                    start..start + c.len_utf8(),
+                    ExprErrorKind::UnexpectedChar,
+                ))
+            }
+        };
+
+        tokens.push((token, start..start + 1));
+    }
+
+    Ok(tokens)
+}
+
+fn is_label_char(c: char) -> bool {
+    c.is_alphanumeric() || matches!(c, '_' | '-' | '/')
+}
+
+/// A compiled comparison operand.
+enum Operand {
+    Entity(OpCode),
+    AttrSet(OpCode),
+    Attr(OpCode),
+}
+
+struct Parser<'e> {
+    tokens: Vec<(Token, Range<usize>)>,
+    pos: usize,
+    end: usize,
+    env: &'e dyn ExprEnv,
+    output: Vec<OpCode>,
+}
+
+impl Parser<'_> {
+    fn expr(&mut self) -> Result<(), ExprError> {
+        self.and()?;
+        while self.eat(&Token::Or) {
+            self.and()?;
+            self.output.push(OpCode::Or);
+        }
+        Ok(())
+    }
+
+    fn and(&mut self) -> Result<(), ExprError> {
+        self.unary()?;
+        while self.eat(&Token::And) {
+            self.unary()?;
+            self.output.push(OpCode::And);
+        }
+        Ok(())
+    }
+
+    fn unary(&mut self) -> Result<(), ExprError> {
+        if self.eat(&Token::Not) {
+            self.unary()?;
+            self.output.push(OpCode::Not);
+            Ok(())
+        } else if self.eat(&Token::LParen) {
+            self.expr()?;
+            self.expect(&Token::RParen)
+        } else {
+            self.comparison()
+        }
+    }
+
+    fn comparison(&mut self) -> Result<(), ExprError> {
+        let (lhs, lhs_span) = self.term()?;
+
+        let (op, op_span) = self.next()?;
+        let (rhs, rhs_span) = self.term()?;
+
+        match op {
+            Token::Eq => match (lhs, rhs) {
+                (Operand::Entity(lhs), Operand::Entity(rhs))
+                | (Operand::Attr(lhs), Operand::Attr(rhs)) => {
+                    self.output.extend([lhs, rhs, OpCode::IsEq]);
+                }
+                (Operand::Entity(_) | Operand::Attr(_), _) => {
+                    return Err(error(rhs_span, ExprErrorKind::TypeMismatch))
+                }
+                (Operand::AttrSet(_), _) => {
+                    return Err(error(lhs_span, ExprErrorKind::TypeMismatch))
+                }
+            },
+            Token::Label(label) if label == "contains" => match (lhs, rhs) {
+                (Operand::AttrSet(lhs), Operand::Attr(rhs)) => {
+                    self.output.extend([lhs, rhs, OpCode::IdSetContains]);
+                }
+                (Operand::AttrSet(_), _) => {
+                    return Err(error(rhs_span, ExprErrorKind::TypeMismatch))
+                }
+                _ => return Err(error(lhs_span, ExprErrorKind::TypeMismatch)),
+            },
+            _ => return Err(error(op_span, ExprErrorKind::UnexpectedToken)),
+        }
+
+        Ok(())
+    }
+
+    fn term(&mut self) -> Result<(Operand, Range<usize>), ExprError> {
+        let (token, start_span) = self.next()?;
+        let Token::Label(label) = token else {
+            return Err(error(start_span, ExprErrorKind::UnexpectedToken));
+        };
+
+        let subject = match label.as_str() {
+            "Subject" => Some(true),
+            "Resource" => Some(false),
+            _ => None,
+        };
+
+        let (path, path_span) = match subject {
+            Some(_) => {
+                self.expect(&Token::Dot)?;
+                self.path()?
+            }
+            None => {
+                self.pos -= 1;
+                self.path()?
+            }
+        };
+        let span = start_span.start..path_span.end;
+
+        let env = self.env;
+        let operand = match (subject, path.as_slice()) {
+            (Some(subject), [property]) => {
+                let prop_id = env
+                    .entity_property(property)
+                    .ok_or_else(|| error(path_span, ExprErrorKind::UnknownEntityProperty))?;
+                Operand::Entity(if subject {
+                    OpCode::LoadSubjectId(prop_id)
+                } else {
+                    OpCode::LoadResourceId(prop_id)
+                })
+            }
+            (Some(subject), [namespace, property]) => {
+                if !env.has_attribute_property(namespace, property) {
+                    return Err(error(path_span, ExprErrorKind::UnknownAttributeProperty));
+                }
+                Operand::AttrSet(if subject {
+                    OpCode::LoadSubjectAttrs
+                } else {
+                    OpCode::LoadResourceAttrs
+                })
+            }
+            (None, [label]) => Operand::Entity(OpCode::LoadConstEntityId(
+                env.entity(label)
+                    .ok_or_else(|| error(path_span, ExprErrorKind::UnknownEntity))?,
+            )),
+            (None, [namespace, property, attribute]) => Operand::Attr(OpCode::LoadConstAttrId(
+                env.attribute(namespace, property, attribute)
+                    .ok_or_else(|| error(path_span, ExprErrorKind::UnknownAttribute))?,
+            )),
+            _ => return Err(error(path_span, ExprErrorKind::UnexpectedToken)),
+        };
+
+        Ok((operand, span))
+    }
+
+    fn path(&mut self) -> Result<(Vec<String>, Range<usize>), ExprError> {
+        let mut segments = vec![];
+        let mut span: Option<Range<usize>> = None;
+
+        loop {
+            let (token, token_span) = self.next()?;
+            let Token::Label(label) = token else {
+                return Err(error(token_span, ExprErrorKind::UnexpectedToken));
+            };
+
+            segments.push(label);
+            span = Some(span.map_or(token_span.clone(), |span| span.start..token_span.end));
+
+            if !self.eat(&Token::Colon) {
+                break;
+            }
+        }
+
+        Ok((segments, span.unwrap_or_default()))
+    }
+
+    fn next(&mut self) -> Result<(Token, Range<usize>), ExprError> {
+        let token = self
+            .tokens
+            .get(self.pos)
+            .cloned()
+            .ok_or_else(|| error(self.end..self.end, ExprErrorKind::UnexpectedEnd))?;
+        self.pos += 1;
+        Ok(token)
+    }
+
+    fn eat(&mut self, expected: &Token) -> bool {
+        match self.tokens.get(self.pos) {
+            Some((token, _)) if token == expected => {
+                self.pos += 1;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn expect(&mut self, expected: &Token) -> Result<(), ExprError> {
+        let (token, span) = self.next()?;
+        if &token == expected {
+            Ok(())
+        } else {
+            Err(error(span, ExprErrorKind::UnexpectedToken))
+        }
+    }
+}
+
+fn error(span: Range<usize>, kind: ExprErrorKind) -> ExprError {
+    ExprError { span, kind }
+}
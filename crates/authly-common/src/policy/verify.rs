@@ -0,0 +1,104 @@
+//! Static verification of policy bytecode.
+
+use super::code::{from_bytecode, DecodeError, OpCode};
+
+/// The type of a value on the policy engine stack.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum StackType {
+    /// An unsigned integer, also used for booleans.
+    Uint,
+    /// A set of attribute IDs.
+    AttrIdSet,
+    /// An entity ID.
+    EntityId,
+    /// An attribute ID.
+    AttrId,
+    /// A set of entity IDs.
+    EntityIdSet,
+}
+
+/// Bytecode verification error.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum VerifyError {
+    /// The bytecode could not be decoded.
+    Decode(DecodeError),
+
+    /// The instruction at the given index popped an empty stack.
+    StackUnderflow(usize),
+
+    /// The instruction at the given index got operands of the wrong type.
+    Type(usize),
+
+    /// The program ends without a `Return` instruction.
+    MissingReturn,
+}
+
+impl From<DecodeError> for VerifyError {
+    fn from(value: DecodeError) -> Self {
+        Self::Decode(value)
+    }
+}
+
+/// Verify that the bytecode is well-typed and always ends in a `Return`.
+///
+/// Evaluating a verified program can still fail at runtime,
+/// e.g. when the access control parameters lack a referenced entity property.
+pub fn verify(bytecode: &[u8]) -> Result<(), VerifyError> {
+    let mut stack: Vec<StackType> = vec![];
+
+    for (idx, opcode) in from_bytecode(bytecode)?.into_iter().enumerate() {
+        let mut pop = || stack.pop().ok_or(VerifyError::StackUnderflow(idx));
+
+        let output = match opcode {
+            OpCode::LoadSubjectId(_) | OpCode::LoadResourceId(_) | OpCode::LoadConstEntityId(_) => {
+                StackType::EntityId
+            }
+            OpCode::LoadSubjectAttrs | OpCode::LoadResourceAttrs => StackType::AttrIdSet,
+            OpCode::LoadSubjectIdSet | OpCode::LoadResourceIdSet => StackType::EntityIdSet,
+            OpCode::LoadConstAttrId(_) => StackType::AttrId,
+            OpCode::LoadConstUint(_) | OpCode::LoadNow | OpCode::LoadTrue | OpCode::LoadFalse => {
+                StackType::Uint
+            }
+            OpCode::IsEq => {
+                pop()?;
+                pop()?;
+                StackType::Uint
+            }
+            OpCode::SupersetOf => match (pop()?, pop()?) {
+                (StackType::AttrIdSet, StackType::AttrIdSet) => StackType::Uint,
+                _ => return Err(VerifyError::Type(idx)),
+            },
+            OpCode::IdSetContains => match (pop()?, pop()?) {
+                (StackType::AttrIdSet, StackType::AttrId)
+                | (StackType::AttrId, StackType::AttrIdSet)
+                | (StackType::EntityIdSet, StackType::EntityId)
+                | (StackType::EntityId, StackType::EntityIdSet) => StackType::Uint,
+                _ => return Err(VerifyError::Type(idx)),
+            },
+            OpCode::And
+            | OpCode::Or
+            | OpCode::Xor
+            | OpCode::Gt
+            | OpCode::Lt
+            | OpCode::Gte
+            | OpCode::Lte => match (pop()?, pop()?) {
+                (StackType::Uint, StackType::Uint) => StackType::Uint,
+                _ => return Err(VerifyError::Type(idx)),
+            },
+            OpCode::Not => match pop()? {
+                StackType::Uint => StackType::Uint,
+                _ => return Err(VerifyError::Type(idx)),
+            },
+            OpCode::Return => {
+                return match pop()? {
+                    StackType::Uint => Ok(()),
+                    _ => Err(VerifyError::Type(idx)),
+                };
+            }
+        };
+
+        stack.push(output);
+    }
+
+    Err(VerifyError::MissingReturn)
+}
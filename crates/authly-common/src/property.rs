@@ -1,14 +1,15 @@
 //! Authly property utilities.
-use std::str::FromStr;
+use std::{fmt::Display, str::FromStr};
 
-use serde::Deserialize;
+use anyhow::anyhow;
+use serde::{Deserialize, Serialize};
 
-use crate::FromStrVisitor;
+use crate::{service::NamespacedPropertyAttribute, FromStrVisitor};
 
 /// A qualified attribute name, in the context of a service.
 ///
 /// Consists of a property and an attribute of that property.
-#[derive(Debug)]
+#[derive(PartialEq, Eq, Debug)]
 pub struct QualifiedAttributeName {
     /// The namespace
     pub namespace: String,
@@ -20,23 +21,63 @@ pub struct QualifiedAttributeName {
     pub attribute: String,
 }
 
+/// Parses the `namespace:property:attribute` form.
+///
+/// Exactly three non-empty segments are accepted. Errors report the byte position of the offending segment.
 impl FromStr for QualifiedAttributeName {
-    type Err = &'static str;
+    type Err = anyhow::Error;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        let mut segments = s.split(":");
-        let namespace = segments.next();
-        let property = segments.next();
-        let attribute = segments.next();
-
-        match (namespace, property, attribute) {
-            (Some(namespace), Some(property), Some(attribute)) => Ok(Self {
-                namespace: namespace.to_string(),
-                property: property.to_string(),
-                attribute: attribute.to_string(),
-            }),
-            _ => Err("expected qualified namespace/property/attribute triple"),
+        let mut segments = [""; 3];
+        let mut position = 0;
+
+        for (index, segment) in s.split(':').enumerate() {
+            let Some(slot) = segments.get_mut(index) else {
+                return Err(anyhow!(
+                    "unexpected segment at position {position}, expected namespace:property:attribute"
+                ));
+            };
+            if segment.is_empty() {
+                let name = ["namespace", "property", "attribute"][index];
+                return Err(anyhow!("empty {name} at position {position}"));
+            }
+
+            *slot = segment;
+            position += segment.len() + 1;
         }
+
+        let [namespace, property, attribute] = segments;
+        if attribute.is_empty() {
+            return Err(anyhow!(
+                "expected qualified namespace:property:attribute triple"
+            ));
+        }
+
+        Ok(Self {
+            namespace: namespace.to_string(),
+            property: property.to_string(),
+            attribute: attribute.to_string(),
+        })
+    }
+}
+
+impl Display for QualifiedAttributeName {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:{}:{}", self.namespace, self.property, self.attribute)
+    }
+}
+
+impl NamespacedPropertyAttribute for QualifiedAttributeName {
+    fn namespace(&self) -> &str {
+        &self.namespace
+    }
+
+    fn property(&self) -> &str {
+        &self.property
+    }
+
+    fn attribute(&self) -> &str {
+        &self.attribute
     }
 }
 
@@ -48,3 +89,12 @@ impl<'de> Deserialize<'de> for QualifiedAttributeName {
         deserializer.deserialize_str(FromStrVisitor::new("attribute name"))
     }
 }
+
+impl Serialize for QualifiedAttributeName {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        serializer.collect_str(self)
+    }
+}
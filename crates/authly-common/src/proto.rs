@@ -15,13 +15,21 @@ pub mod service {
     tonic::include_proto!("authly.service");
 }
 
+/// The largest integer magnitude a protobuf number (an `f64`) represents exactly.
+const MAX_SAFE_INTEGER: f64 = 9007199254740991.0;
+
 /// Convert a protobuf Value to a JSON value.
+///
+/// Integral numbers within the exactly representable range become JSON integers.
 pub fn proto_value_to_json(value: prost_types::Value) -> serde_json::Value {
     use prost_types::value::Kind;
     use serde_json::Value;
 
     match value.kind {
         Some(Kind::NullValue(_)) => Value::Null,
+        Some(Kind::NumberValue(n)) if n.fract() == 0.0 && n.abs() <= MAX_SAFE_INTEGER => {
+            Value::Number((n as i64).into())
+        }
         Some(Kind::NumberValue(n)) => serde_json::Number::from_f64(n)
             .map(Value::Number)
             .unwrap_or(Value::Null),
@@ -47,3 +55,36 @@ pub fn proto_struct_to_json(
 
     map
 }
+
+/// Convert a JSON value to a protobuf Value.
+///
+/// Protobuf numbers are `f64`, so integers beyond the exactly representable range lose precision.
+pub fn json_to_proto_value(value: serde_json::Value) -> prost_types::Value {
+    use prost_types::value::Kind;
+    use serde_json::Value;
+
+    let kind = match value {
+        Value::Null => Kind::NullValue(prost_types::NullValue::NullValue.into()),
+        Value::Bool(b) => Kind::BoolValue(b),
+        Value::Number(n) => Kind::NumberValue(n.as_f64().unwrap_or(f64::NAN)),
+        Value::String(s) => Kind::StringValue(s),
+        Value::Array(a) => Kind::ListValue(prost_types::ListValue {
+            values: a.into_iter().map(json_to_proto_value).collect(),
+        }),
+        Value::Object(o) => Kind::StructValue(json_to_proto_struct(o)),
+    };
+
+    prost_types::Value { kind: Some(kind) }
+}
+
+/// Convert a JSON object to a protobuf Struct.
+pub fn json_to_proto_struct(
+    map: serde_json::Map<String, serde_json::Value>,
+) -> prost_types::Struct {
+    prost_types::Struct {
+        fields: map
+            .into_iter()
+            .map(|(key, value)| (key, json_to_proto_value(value)))
+            .collect(),
+    }
+}
@@ -1,15 +1,22 @@
 //! Authly service utilities and helpers
 
-use std::collections::{hash_map, HashMap};
+use std::{
+    collections::{hash_map, BTreeSet, HashMap},
+    iter,
+    sync::{Arc, OnceLock},
+};
 
-use fnv::FnvHashSet;
+use fnv::{FnvHashMap, FnvHashSet};
 
 use crate::id::AttrId;
 
 /// A namespaced property mapping maps human-readable property and attribute labels to [AttrId]s.
 #[derive(Clone, Default)]
 pub struct NamespacePropertyMapping {
-    namespaces: HashMap<String, PropertyMappings>,
+    namespaces: HashMap<String, Arc<PropertyMappings>>,
+
+    /// Lazily built index from [AttrId] to its labels, reset on mutation
+    reverse_index: OnceLock<FnvHashMap<AttrId, (String, String, String)>>,
 }
 
 /// A property mapping maps human-readable property and attribute labels to [AttrId]s.
@@ -51,20 +58,107 @@ impl<'a> NamespacedPropertyAttribute for (&'a str, &'a str, &'a str) {
 }
 
 impl NamespacePropertyMapping {
+    /// Get the property mappings of a namespace, if found.
+    pub fn namespace(&self, namespace_label: &str) -> Option<&PropertyMappings> {
+        self.namespaces.get(namespace_label).map(Arc::as_ref)
+    }
+
+    /// Get a shared handle to the property mappings of a namespace, if found.
+    ///
+    /// The handle is cheap to clone and outlives the borrow of the whole mapping.
+    pub fn namespace_shared(&self, namespace_label: &str) -> Option<Arc<PropertyMappings>> {
+        self.namespaces.get(namespace_label).cloned()
+    }
+
     /// Get a mutable reference to the namespace
     pub fn namespace_mut(&mut self, namespace_label: String) -> &mut PropertyMappings {
-        self.namespaces.entry(namespace_label).or_default()
+        self.reverse_index.take();
+        Arc::make_mut(self.namespaces.entry(namespace_label).or_default())
+    }
+
+    /// Look up the namespace/property/attribute labels of an [AttrId], if found.
+    ///
+    /// The first lookup builds a reverse index, which makes subsequent lookups cheap.
+    pub fn reverse(&self, attr: AttrId) -> Option<(&str, &str, &str)> {
+        let reverse_index = self.reverse_index.get_or_init(|| {
+            let mut reverse_index = FnvHashMap::default();
+
+            for (namespace, properties) in &self.namespaces {
+                for (property, attributes) in &properties.properties {
+                    for (attribute, attr_id) in &attributes.attributes {
+                        reverse_index.insert(
+                            *attr_id,
+                            (namespace.clone(), property.clone(), attribute.clone()),
+                        );
+                    }
+                }
+            }
+
+            reverse_index
+        });
+
+        reverse_index
+            .get(&attr)
+            .map(|(namespace, property, attribute)| {
+                (namespace.as_str(), property.as_str(), attribute.as_str())
+            })
+    }
+
+    /// Deep-merge another mapping into this one.
+    ///
+    /// When both mappings contain the same namespace/property/attribute label triple, the value from `other` wins.
+    /// Returns the label triples that were overwritten with a different [AttrId].
+    pub fn merge(&mut self, other: NamespacePropertyMapping) -> BTreeSet<(String, String, String)> {
+        let mut overwritten = BTreeSet::new();
+
+        for (namespace, properties) in other.namespaces {
+            let ns = self.namespace_mut(namespace.clone());
+
+            for (property, attributes) in Arc::unwrap_or_clone(properties).properties {
+                let prop = ns.property_mut(property.clone());
+
+                for (attribute, attr_id) in attributes.attributes {
+                    if let Some(previous) = prop.attributes.insert(attribute.clone(), attr_id) {
+                        if previous != attr_id {
+                            overwritten.insert((namespace.clone(), property.clone(), attribute));
+                        }
+                    }
+                }
+            }
+        }
+
+        overwritten
+    }
+
+    /// Iterate over every namespace/property/attribute label triple along with its [AttrId].
+    ///
+    /// The iteration order is unspecified.
+    pub fn iter_attributes(&self) -> impl Iterator<Item = (&str, &str, &str, AttrId)> {
+        self.namespaces.iter().flat_map(|(namespace, properties)| {
+            properties
+                .properties
+                .iter()
+                .flat_map(move |(property, attributes)| {
+                    attributes
+                        .attributes
+                        .iter()
+                        .map(move |(attribute, attr_id)| {
+                            (
+                                namespace.as_str(),
+                                property.as_str(),
+                                attribute.as_str(),
+                                *attr_id,
+                            )
+                        })
+                })
+        })
     }
 
     /// Get the object ID of a single namespace/property/attribute label triple, if found.
     pub fn attribute_id(&self, attr: &impl NamespacedPropertyAttribute) -> Option<AttrId> {
-        self.namespaces
-            .get(attr.namespace())?
-            .properties
-            .get(attr.property())?
-            .attributes
+        self.namespace(attr.namespace())?
+            .property(attr.property())?
             .get(attr.attribute())
-            .cloned()
     }
 
     /// Translate the given namespace/property/attribute labels to underlying [AttrId]s.
@@ -92,6 +186,11 @@ impl NamespacePropertyMapping {
 }
 
 impl PropertyMappings {
+    /// Get the attribute mappings of a property, if found.
+    pub fn property(&self, property_label: &str) -> Option<&AttributeMappings> {
+        self.properties.get(property_label)
+    }
+
     /// Get a mutable reference to the attribute mappings of a property.
     pub fn property_mut(&mut self, property_label: String) -> &mut AttributeMappings {
         self.properties.entry(property_label).or_default()
@@ -99,6 +198,11 @@ impl PropertyMappings {
 }
 
 impl AttributeMappings {
+    /// Get the attribute id of an attribute label, if found.
+    pub fn get(&self, attribute_label: &str) -> Option<AttrId> {
+        self.attributes.get(attribute_label).copied()
+    }
+
     /// Put a new attribute id under the attribute label.
     pub fn put(&mut self, attribute_label: String, attribute_id: AttrId) {
         self.attributes
@@ -108,20 +212,30 @@ impl AttributeMappings {
 }
 
 impl IntoIterator for NamespacePropertyMapping {
-    type IntoIter = hash_map::IntoIter<String, PropertyMappings>;
+    type IntoIter = iter::Map<
+        hash_map::IntoIter<String, Arc<PropertyMappings>>,
+        fn((String, Arc<PropertyMappings>)) -> (String, PropertyMappings),
+    >;
     type Item = (String, PropertyMappings);
 
     fn into_iter(self) -> Self::IntoIter {
-        self.namespaces.into_iter()
+        self.namespaces
+            .into_iter()
+            .map(|(label, properties)| (label, Arc::unwrap_or_clone(properties)))
     }
 }
 
 impl<'a> IntoIterator for &'a NamespacePropertyMapping {
-    type IntoIter = hash_map::Iter<'a, String, PropertyMappings>;
+    type IntoIter = iter::Map<
+        hash_map::Iter<'a, String, Arc<PropertyMappings>>,
+        fn((&'a String, &'a Arc<PropertyMappings>)) -> (&'a String, &'a PropertyMappings),
+    >;
     type Item = (&'a String, &'a PropertyMappings);
 
     fn into_iter(self) -> Self::IntoIter {
-        self.namespaces.iter()
+        self.namespaces
+            .iter()
+            .map(|(label, properties)| (label, properties.as_ref()))
     }
 }
 
@@ -1,6 +1,13 @@
 #[cfg(feature = "document")]
 mod test_document;
 
+#[cfg(feature = "mtls_server")]
+mod test_mtls_server;
+
+mod test_code;
+mod test_expr;
 mod test_policies;
+mod test_proto;
+mod test_service;
 
 fn main() {}
@@ -0,0 +1,160 @@
+use authly_common::{
+    id::{kind::Kind, AttrId, EntityId, PropId},
+    policy::code::{
+        disassemble, from_bytecode, to_bytecode, Bytecode, DecodeError, OpCode, PolicyValue,
+    },
+};
+use rand::Rng;
+
+fn random_opcode(rng: &mut impl Rng) -> OpCode {
+    match rng.random_range(0..24) {
+        0 => OpCode::LoadSubjectId(PropId::from_uint(rng.random())),
+        1 => OpCode::LoadSubjectAttrs,
+        2 => OpCode::LoadResourceId(PropId::from_uint(rng.random())),
+        3 => OpCode::LoadResourceAttrs,
+        4 => {
+            let kind = [Kind::Persona, Kind::Group, Kind::Service][rng.random_range(0..3)];
+            OpCode::LoadConstEntityId(EntityId::new(kind, rng.random()))
+        }
+        5 => OpCode::LoadConstAttrId(AttrId::from_uint(rng.random())),
+        6 => OpCode::LoadConstUint(rng.random()),
+        7 => OpCode::LoadNow,
+        8 => OpCode::IsEq,
+        9 => OpCode::SupersetOf,
+        10 => OpCode::IdSetContains,
+        11 => OpCode::And,
+        12 => OpCode::Or,
+        13 => OpCode::Not,
+        14 => OpCode::Return,
+        15 => OpCode::Gt,
+        16 => OpCode::Lt,
+        17 => OpCode::Gte,
+        18 => OpCode::Lte,
+        19 => OpCode::Xor,
+        20 => OpCode::LoadTrue,
+        21 => OpCode::LoadFalse,
+        22 => OpCode::LoadSubjectIdSet,
+        _ => OpCode::LoadResourceIdSet,
+    }
+}
+
+#[test]
+fn bytecode_roundtrip() {
+    let mut rng = rand::rng();
+
+    for _ in 0..1000 {
+        let len = rng.random_range(0..32);
+        let opcodes: Vec<OpCode> = (0..len).map(|_| random_opcode(&mut rng)).collect();
+
+        assert_eq!(opcodes, from_bytecode(&to_bytecode(&opcodes)).unwrap());
+    }
+}
+
+#[test]
+fn bytecode_decode_errors() {
+    let bytecode = to_bytecode(&[
+        OpCode::LoadConstAttrId(AttrId::from_uint(42)),
+        OpCode::LoadSubjectAttrs,
+        OpCode::IdSetContains,
+        OpCode::Return,
+    ]);
+
+    assert_eq!(Err(DecodeError::Truncated), from_bytecode(&bytecode[..10]));
+    assert_eq!(
+        Err(DecodeError::UnknownOpcode(255)),
+        from_bytecode(&[bytecode.as_slice(), &[255]].concat())
+    );
+    assert_eq!(
+        Err(DecodeError::InvalidKind(Kind::Domain.into())),
+        from_bytecode(
+            &[
+                &[Bytecode::LoadConstEntityId as u8, Kind::Domain.into()],
+                &[0; 16][..]
+            ]
+            .concat()
+        )
+    );
+}
+
+#[test]
+fn test_disassemble() {
+    let bytecode = to_bytecode(&[
+        OpCode::LoadSubjectId(PropId::from_uint(0xabcd)),
+        OpCode::LoadConstEntityId(EntityId::new(Kind::Group, [1; 16])),
+        OpCode::IsEq,
+        OpCode::LoadResourceAttrs,
+        OpCode::LoadConstAttrId(AttrId::from_uint(0x42)),
+        OpCode::IdSetContains,
+        OpCode::LoadNow,
+        OpCode::LoadConstUint(1_700_000_000),
+        OpCode::Gte,
+        OpCode::And,
+        OpCode::Or,
+        OpCode::Return,
+    ]);
+
+    assert_eq!(
+        "\
+load_subject_id prp.0000000000000000000000000000abcd
+load_const_entity_id g.01010101010101010101010101010101
+is_eq
+load_resource_attrs
+load_const_attr_id atr.00000000000000000000000000000042
+id_set_contains
+load_now
+load_const_uint 1700000000
+gte
+and
+or
+return
+",
+        disassemble(&bytecode).unwrap()
+    );
+
+    assert_eq!(
+        Err(DecodeError::UnknownOpcode(255)),
+        disassemble(&[Bytecode::Return as u8, 255])
+    );
+}
+
+#[test]
+fn test_policy_value_and_or() {
+    use PolicyValue::{Allow, Deny};
+
+    for (lhs, rhs, and, or) in [
+        (Deny, Deny, Deny, Deny),
+        (Deny, Allow, Deny, Allow),
+        (Allow, Deny, Deny, Allow),
+        (Allow, Allow, Allow, Allow),
+    ] {
+        assert_eq!(and, lhs.and(rhs), "{lhs:?} and {rhs:?}");
+        assert_eq!(or, lhs.or(rhs), "{lhs:?} or {rhs:?}");
+    }
+}
+
+#[test]
+fn test_policy_value_fold() {
+    use PolicyValue::{Allow, Deny};
+
+    for (values, disjunctive, conjunctive) in [
+        (&[][..], Deny, Deny),
+        (&[Deny], Deny, Deny),
+        (&[Allow], Allow, Allow),
+        (&[Deny, Deny], Deny, Deny),
+        (&[Deny, Allow], Allow, Deny),
+        (&[Allow, Deny], Allow, Deny),
+        (&[Allow, Allow], Allow, Allow),
+        (&[Allow, Allow, Deny], Allow, Deny),
+    ] {
+        assert_eq!(
+            disjunctive,
+            PolicyValue::fold_disjunctive(values.iter().copied()),
+            "{values:?}"
+        );
+        assert_eq!(
+            conjunctive,
+            PolicyValue::fold_conjunctive(values.iter().copied()),
+            "{values:?}"
+        );
+    }
+}
@@ -1,4 +1,8 @@
-use authly_common::document::Document;
+use authly_common::{
+    document::{Document, DocumentError, DocumentErrorDuplicate},
+    id::AttrId,
+    service::NamespacePropertyMapping,
+};
 use serde_json::json;
 
 const ENTITY: &str = r#"
@@ -109,19 +113,415 @@ fn test_entity() {
     let document = Document::from_toml(toml).unwrap();
 
     assert_eq!(document.authly_document.id.span(), 24..62);
-    // BUG: The span is off:
-    assert_eq!(&toml[24..62], "\"d783648f-e6ac-4492-87f7-43d5e5805d60\"");
+    assert_eq!(
+        &toml[document.authly_document.id.span()],
+        "\"d783648f-e6ac-4492-87f7-43d5e5805d60\""
+    );
 
-    assert_eq!(document.entity[0].eid.span(), 81..117);
-    assert_eq!(&toml[81..117], "\"p.7d8b18fa5836487592a43eacea830b47\"");
+    let entity = &document.entity[0];
+    assert_eq!(entity.eid.span(), 81..117);
+    assert_eq!(
+        &toml[entity.eid.span()],
+        "\"p.7d8b18fa5836487592a43eacea830b47\""
+    );
+    assert_eq!(&toml[entity.label.as_ref().unwrap().span()], "\"me\"");
+    assert_eq!(
+        &toml[entity.username.as_ref().unwrap().span()],
+        "\"testuser\""
+    );
+
+    // preprocessing moves the email out of the entity, keeping its span
+    assert_eq!(&toml[document.email[0].entity.span()], "\"me\"");
+    assert_eq!(&toml[document.email[0].value.span()], "\"me@mail.com\"");
 
     assert_eq!(document.entity.len(), 1);
 }
 
+#[test]
+fn test_entity_inline_attributes() {
+    let toml = r#"
+[authly-document]
+id = "d783648f-e6ac-4492-87f7-43d5e5805d60"
+
+[[entity]]
+eid = "p.7d8b18fa5836487592a43eacea830b47"
+label = "me"
+attributes = ["svc:role:user", "svc:role:admin"]
+
+[[entity]]
+eid = "p.8d8b18fa5836487592a43eacea830b47"
+
+[[entity-attribute-assignment]]
+entity = "other"
+attributes = ["svc:role:user"]
+"#;
+    let document = Document::from_toml(toml).unwrap();
+
+    assert!(document
+        .entity
+        .iter()
+        .all(|entity| entity.attributes.is_empty()));
+    assert_eq!(document.entity_attribute_assignment.len(), 2);
+
+    let assignment = &document.entity_attribute_assignment[1];
+    assert_eq!(assignment.entity.get_ref(), "me");
+    assert_eq!(
+        assignment
+            .attributes
+            .iter()
+            .map(|attribute| attribute.get_ref().to_string())
+            .collect::<Vec<_>>(),
+        ["svc:role:user", "svc:role:admin"]
+    );
+    assert_eq!(&toml[assignment.attributes[1].span()], "\"svc:role:admin\"");
+}
+
 #[test]
 fn testservice_example() {
     let toml = SVC;
-    Document::from_toml(toml).unwrap();
+    let document = Document::from_toml(toml).unwrap();
+
+    let service = &document.service_entity[0];
+    assert_eq!(
+        &toml[service.eid.span()],
+        "\"s.2671d2a0bc3545e69fc666130254f8e9\""
+    );
+    assert_eq!(
+        &toml[service.attributes[1].span()],
+        "\"authly:role:get_access_token\""
+    );
+}
+
+#[test]
+fn testservice_toml_roundtrip() {
+    let document = Document::from_toml(SVC).unwrap();
+    let toml = document.to_toml().unwrap();
+    let reparsed = Document::from_toml(&toml).unwrap();
+
+    assert!(document == reparsed);
+    assert_eq!(toml, reparsed.to_toml().unwrap());
+}
+
+#[test]
+fn entity_toml_roundtrip() {
+    let document = Document::from_toml(ENTITY).unwrap();
+    let reparsed = Document::from_toml(&document.to_toml().unwrap()).unwrap();
+
+    assert!(document == reparsed);
+    assert_eq!(reparsed.email.len(), 1);
+    assert_eq!(reparsed.password_hash.len(), 1);
+}
+
+#[test]
+fn validate_examples() {
+    for toml in [ENTITY, SVC, SETTINGS, METADATA] {
+        Document::from_toml(toml).unwrap().validate().unwrap();
+    }
+}
+
+fn validation_errors(toml: &str) -> Vec<(DocumentError, &str)> {
+    Document::from_toml(toml)
+        .unwrap()
+        .validate()
+        .unwrap_err()
+        .into_iter()
+        .map(|error| {
+            let span = error.span();
+            (error, &toml[span])
+        })
+        .collect()
+}
+
+#[test]
+fn validate_dangling_policy() {
+    let toml = r#"
+[authly-document]
+id = "d783648f-e6ac-4492-87f7-43d5e5805d60"
+
+[[policy]]
+label = "allow"
+allow = "Subject.entity == Resource.entity"
+
+[[policy-binding]]
+attributes = ["svc:kind:thing"]
+policies = ["allow", "alow"]
+"#;
+    let errors = validation_errors(toml);
+
+    assert_eq!(errors.len(), 1);
+    assert!(matches!(errors[0].0, DocumentError::UnresolvedPolicy(_)));
+    assert_eq!(errors[0].1, "\"alow\"");
+    assert_eq!(errors[0].0.to_string(), "unresolved policy `alow`");
+}
+
+#[test]
+fn validate_dangling_service_domain() {
+    let toml = r#"
+[authly-document]
+id = "d783648f-e6ac-4492-87f7-43d5e5805d60"
+
+[[service-entity]]
+eid = "s.2671d2a0bc3545e69fc666130254f8e9"
+label = "svc"
+
+[[domain]]
+label = "dom"
+
+[[service-domain]]
+service = "svc"
+domain = "dom"
+
+[[service-domain]]
+service = "nosvc"
+domain = "nodom"
+"#;
+    let errors = validation_errors(toml);
+
+    assert_eq!(errors.len(), 2);
+    assert!(matches!(errors[0].0, DocumentError::UnresolvedService(_)));
+    assert_eq!(errors[0].1, "\"nosvc\"");
+    assert!(matches!(errors[1].0, DocumentError::UnresolvedDomain(_)));
+    assert_eq!(errors[1].1, "\"nodom\"");
+}
+
+#[test]
+fn validate_dangling_members() {
+    let toml = r#"
+[authly-document]
+id = "d783648f-e6ac-4492-87f7-43d5e5805d60"
+
+[[entity]]
+eid = "p.7d8b18fa5836487592a43eacea830b47"
+label = "me"
+
+[[entity]]
+eid = "g.1c2d3e4f5a6b7c8d9e0f1a2b3c4d5e6f"
+label = "group"
+
+[[members]]
+entity = "group"
+members = ["me", "p.2671d2a0bc3545e69fc666130254f8e9", "you"]
+
+[[members]]
+entity = "nogroup"
+members = ["me"]
+"#;
+    let errors = validation_errors(toml);
+
+    assert_eq!(errors.len(), 2);
+    assert!(matches!(errors[0].0, DocumentError::UnresolvedEntity(_)));
+    assert_eq!(errors[0].1, "\"you\"");
+    assert!(matches!(errors[1].0, DocumentError::UnresolvedEntity(_)));
+    assert_eq!(errors[1].1, "\"nogroup\"");
+}
+
+#[test]
+fn validate_dangling_attribute_assignment() {
+    let toml = r#"
+[authly-document]
+id = "d783648f-e6ac-4492-87f7-43d5e5805d60"
+
+[[service-entity]]
+eid = "s.2671d2a0bc3545e69fc666130254f8e9"
+label = "svc"
+
+[[entity-property]]
+namespace = "svc"
+label = "role"
+attributes = ["user"]
+
+[[entity-attribute-assignment]]
+entity = "svc"
+attributes = ["svc:role:user", "svc:role:admin", "svc:rol:user", "authly:role:authenticate"]
+
+[[entity-attribute-assignment]]
+entity = "nosvc"
+attributes = ["svc:role:user"]
+"#;
+    let errors = validation_errors(toml);
+
+    assert_eq!(errors.len(), 3);
+    assert!(matches!(errors[0].0, DocumentError::UnresolvedAttribute(_)));
+    assert_eq!(errors[0].1, "\"svc:role:admin\"");
+    assert!(matches!(errors[1].0, DocumentError::UnresolvedAttribute(_)));
+    assert_eq!(errors[1].1, "\"svc:rol:user\"");
+    assert!(matches!(errors[2].0, DocumentError::UnresolvedEntity(_)));
+    assert_eq!(errors[2].1, "\"nosvc\"");
+}
+
+#[test]
+fn validate_duplicate_eid() {
+    let toml = r#"
+[authly-document]
+id = "d783648f-e6ac-4492-87f7-43d5e5805d60"
+
+[[entity]]
+eid = "p.7d8b18fa5836487592a43eacea830b47"
+label = "me"
+
+[[service-entity]]
+eid = "p.7d8b18fa5836487592a43eacea830b47"
+label = "svc"
+"#;
+    let errors = validation_errors(toml);
+
+    assert_eq!(errors.len(), 1);
+    let DocumentError::Duplicate {
+        kind, ref original, ..
+    } = errors[0].0
+    else {
+        panic!("expected duplicate");
+    };
+    assert_eq!(kind, DocumentErrorDuplicate::EntityId);
+    assert_eq!(
+        &toml[original.clone()],
+        "\"p.7d8b18fa5836487592a43eacea830b47\""
+    );
+    assert_eq!(errors[0].1, "\"p.7d8b18fa5836487592a43eacea830b47\"");
+    assert!(errors[0].0.span().start > original.end);
+}
+
+#[test]
+fn validate_duplicate_policy_label() {
+    let toml = r#"
+[authly-document]
+id = "d783648f-e6ac-4492-87f7-43d5e5805d60"
+
+[[policy]]
+label = "allow"
+allow = "Subject.entity == Resource.entity"
+
+[[policy]]
+label = "allow"
+deny = "Subject.entity == Resource.entity"
+
+[[resource-property]]
+namespace = "svc"
+label = "kind"
+attributes = ["thing"]
+
+[[resource-property]]
+namespace = "svc"
+label = "kind"
+attributes = ["other"]
+"#;
+    let errors = validation_errors(toml);
+
+    assert_eq!(errors.len(), 2);
+    let DocumentError::Duplicate {
+        kind, ref original, ..
+    } = errors[0].0
+    else {
+        panic!("expected duplicate");
+    };
+    assert_eq!(kind, DocumentErrorDuplicate::PolicyLabel);
+    assert_eq!(&toml[original.clone()], "\"allow\"");
+    assert_eq!(errors[0].1, "\"allow\"");
+    assert_eq!(errors[0].0.to_string(), "duplicate policy label `allow`");
+
+    assert!(matches!(
+        errors[1].0,
+        DocumentError::Duplicate {
+            kind: DocumentErrorDuplicate::ResourceProperty,
+            ..
+        }
+    ));
+    assert_eq!(errors[1].1, "\"kind\"");
+}
+
+#[cfg(feature = "document-yaml")]
+const SVC_YAML: &str = r#"
+authly-document:
+  id: bc9ce588-50c3-47d1-94c1-f88b21eaf299
+
+service-entity:
+  - eid: s.2671d2a0bc3545e69fc666130254f8e9
+    label: testservice
+    attributes: ["authly:role:authenticate", "authly:role:get_access_token"]
+    kubernetes-account: { name: testservice, namespace: authly-test }
+
+entity-property:
+  - namespace: testservice
+    label: role
+    attributes: [ui/user, ui/admin]
+
+entity-attribute-assignment:
+  - entity: s.7d8b18fa5836487592a43eacea830b47
+    attributes: ["testservice:role:ui/user"]
+
+resource-property:
+  - { namespace: testservice, label: name, attributes: [ontology, storage] }
+  - { namespace: testservice, label: ontology/action, attributes: [read, deploy, stop] }
+  - { namespace: testservice, label: buckets/action, attributes: [read] }
+  - { namespace: testservice, label: bucket/action, attributes: [read, create, delete] }
+  - { namespace: testservice, label: object/action, attributes: [read, create, delete] }
+
+policy:
+  - label: allow for main service
+    allow: Subject.entity == testservice
+  - label: allow for UI user
+    allow: "Subject.testservice:role contains testservice:role:ui/user"
+  - label: allow for UI admin
+    allow: "Subject.testservice:role contains testservice:role:ui/admin"
+
+policy-binding:
+  - attributes: ["testservice:ontology/action:read"]
+    policies: [allow for main service, allow for UI user]
+  - attributes: ["testservice:ontology/action:deploy"]
+    policies: [allow for main service, allow for UI admin]
+"#;
+
+#[cfg(feature = "document-yaml")]
+#[test]
+fn testservice_yaml() {
+    let document = Document::from_yaml(SVC_YAML).unwrap();
+
+    assert!(document == Document::from_toml(SVC).unwrap());
+    assert_eq!(document.service_entity[0].eid.span(), 0..0);
+    document.validate().unwrap();
+}
+
+#[cfg(feature = "document-yaml")]
+#[test]
+fn testservice_json() {
+    let value: serde_json::Value = serde_norway::from_str(SVC_YAML).unwrap();
+    let document = Document::from_json(&value.to_string()).unwrap();
+
+    assert!(document == Document::from_toml(SVC).unwrap());
+}
+
+#[test]
+fn entity_json() {
+    let document = Document::from_json(
+        &json!({
+            "authly-document": { "id": "d783648f-e6ac-4492-87f7-43d5e5805d60" },
+            "entity": [{
+                "eid": "p.7d8b18fa5836487592a43eacea830b47",
+                "email": ["me@mail.com"],
+                "metadata": { "nested": { "list": [1, null] } },
+            }],
+            "local-settings": { "KEY0": "value0" },
+        })
+        .to_string(),
+    )
+    .unwrap();
+
+    assert_eq!(document.email.len(), 1);
+    assert_eq!(document.email[0].value.get_ref(), "me@mail.com");
+    assert_eq!(
+        document.entity[0].metadata.as_ref().unwrap().get_ref()["nested"],
+        json!({ "list": [1, null] })
+    );
+    let (key, value) = document.local_settings.unwrap().into_iter().next().unwrap();
+    assert_eq!(
+        (key.get_ref().as_str(), value.get_ref().as_str()),
+        ("KEY0", "value0")
+    );
+
+    assert!(Document::from_json(
+        r#"{ "authly-document": { "id": "d783648f-e6ac-4492-87f7-43d5e5805d60" }, "unknown": 1 }"#,
+    )
+    .is_err());
 }
 
 #[test]
@@ -148,3 +548,98 @@ fn metadata_example() {
         serde_json::Value::Object(metadata.into_inner())
     );
 }
+
+#[test]
+fn testservice_property_mapping() {
+    let document = Document::from_toml(SVC).unwrap();
+
+    let mut mapping = NamespacePropertyMapping::default();
+    let mut next_id = 0x8000;
+    for property in &document.resource_property {
+        for attribute in &property.attributes {
+            mapping
+                .namespace_mut(property.namespace.get_ref().clone())
+                .property_mut(property.label.get_ref().clone())
+                .put(attribute.get_ref().clone(), AttrId::from_uint(next_id));
+            next_id += 1;
+        }
+    }
+
+    let mut attributes: Vec<_> = mapping.iter_attributes().collect();
+    attributes.sort_by_key(|(_, _, _, attr_id)| *attr_id);
+
+    assert_eq!(
+        vec![
+            ("testservice", "name", "ontology", AttrId::from_uint(0x8000)),
+            ("testservice", "name", "storage", AttrId::from_uint(0x8001)),
+            (
+                "testservice",
+                "ontology/action",
+                "read",
+                AttrId::from_uint(0x8002)
+            ),
+            (
+                "testservice",
+                "ontology/action",
+                "deploy",
+                AttrId::from_uint(0x8003)
+            ),
+            (
+                "testservice",
+                "ontology/action",
+                "stop",
+                AttrId::from_uint(0x8004)
+            ),
+            (
+                "testservice",
+                "buckets/action",
+                "read",
+                AttrId::from_uint(0x8005)
+            ),
+            (
+                "testservice",
+                "bucket/action",
+                "read",
+                AttrId::from_uint(0x8006)
+            ),
+            (
+                "testservice",
+                "bucket/action",
+                "create",
+                AttrId::from_uint(0x8007)
+            ),
+            (
+                "testservice",
+                "bucket/action",
+                "delete",
+                AttrId::from_uint(0x8008)
+            ),
+            (
+                "testservice",
+                "object/action",
+                "read",
+                AttrId::from_uint(0x8009)
+            ),
+            (
+                "testservice",
+                "object/action",
+                "create",
+                AttrId::from_uint(0x800a)
+            ),
+            (
+                "testservice",
+                "object/action",
+                "delete",
+                AttrId::from_uint(0x800b)
+            ),
+        ],
+        attributes
+    );
+
+    for (namespace, property, attribute, attr_id) in mapping.iter_attributes() {
+        assert_eq!(
+            Some(attr_id),
+            mapping.attribute_id(&(namespace, property, attribute))
+        );
+    }
+}
@@ -0,0 +1,154 @@
+use authly_common::{
+    id::{AttrId, EntityId, PropId},
+    policy::{
+        code::{to_bytecode, OpCode},
+        expr::{compile, ExprEnv, ExprErrorKind},
+        verify::verify,
+    },
+};
+
+const ENTITY_PROP: PropId = PropId::from_uint(0xe001);
+const UI_USER: AttrId = AttrId::from_uint(0xa001);
+const UI_ADMIN: AttrId = AttrId::from_uint(0xa002);
+
+fn testservice() -> EntityId {
+    "s.2671d2a0bc3545e69fc666130254f8e9".parse().unwrap()
+}
+
+struct TestEnv;
+
+impl ExprEnv for TestEnv {
+    fn entity_property(&self, label: &str) -> Option<PropId> {
+        (label == "entity").then_some(ENTITY_PROP)
+    }
+
+    fn has_attribute_property(&self, namespace: &str, property: &str) -> bool {
+        (namespace, property) == ("testservice", "role")
+    }
+
+    fn entity(&self, label: &str) -> Option<EntityId> {
+        (label == "testservice").then(testservice)
+    }
+
+    fn attribute(&self, namespace: &str, property: &str, attribute: &str) -> Option<AttrId> {
+        match (namespace, property, attribute) {
+            ("testservice", "role", "ui/user") => Some(UI_USER),
+            ("testservice", "role", "ui/admin") => Some(UI_ADMIN),
+            _ => None,
+        }
+    }
+}
+
+fn compile_verified(expr: &str) -> Vec<OpCode> {
+    let opcodes = compile(expr, &TestEnv).unwrap();
+    verify(&to_bytecode(&opcodes)).unwrap();
+    opcodes
+}
+
+#[test]
+fn test_svc_expressions() {
+    assert_eq!(
+        compile_verified("Subject.entity == testservice"),
+        vec![
+            OpCode::LoadSubjectId(ENTITY_PROP),
+            OpCode::LoadConstEntityId(testservice()),
+            OpCode::IsEq,
+            OpCode::Return,
+        ]
+    );
+    assert_eq!(
+        compile_verified("Subject.testservice:role contains testservice:role:ui/user"),
+        vec![
+            OpCode::LoadSubjectAttrs,
+            OpCode::LoadConstAttrId(UI_USER),
+            OpCode::IdSetContains,
+            OpCode::Return,
+        ]
+    );
+    assert_eq!(
+        compile_verified("Subject.testservice:role contains testservice:role:ui/admin"),
+        vec![
+            OpCode::LoadSubjectAttrs,
+            OpCode::LoadConstAttrId(UI_ADMIN),
+            OpCode::IdSetContains,
+            OpCode::Return,
+        ]
+    );
+}
+
+#[test]
+fn test_logical_operators() {
+    assert_eq!(
+        compile_verified(
+            "Subject.entity == Resource.entity || !(Resource.testservice:role contains testservice:role:ui/user && Subject.testservice:role contains testservice:role:ui/admin)"
+        ),
+        vec![
+            OpCode::LoadSubjectId(ENTITY_PROP),
+            OpCode::LoadResourceId(ENTITY_PROP),
+            OpCode::IsEq,
+            OpCode::LoadResourceAttrs,
+            OpCode::LoadConstAttrId(UI_USER),
+            OpCode::IdSetContains,
+            OpCode::LoadSubjectAttrs,
+            OpCode::LoadConstAttrId(UI_ADMIN),
+            OpCode::IdSetContains,
+            OpCode::And,
+            OpCode::Not,
+            OpCode::Or,
+            OpCode::Return,
+        ]
+    );
+}
+
+#[test]
+fn test_errors() {
+    let cases = [
+        ("Subject.entity == ", ExprErrorKind::UnexpectedEnd, 18..18),
+        (
+            "Subject.entity = testservice",
+            ExprErrorKind::UnexpectedChar,
+            15..16,
+        ),
+        (
+            "Subject.entity == testservice)",
+            ExprErrorKind::UnexpectedToken,
+            29..30,
+        ),
+        (
+            "Subject.entity == nobody",
+            ExprErrorKind::UnknownEntity,
+            18..24,
+        ),
+        (
+            "Subject.id == testservice",
+            ExprErrorKind::UnknownEntityProperty,
+            8..10,
+        ),
+        (
+            "Subject.testservice:rol contains testservice:role:ui/user",
+            ExprErrorKind::UnknownAttributeProperty,
+            8..23,
+        ),
+        (
+            "Subject.testservice:role contains testservice:role:ui/nobody",
+            ExprErrorKind::UnknownAttribute,
+            34..60,
+        ),
+        (
+            "Subject.entity contains testservice:role:ui/user",
+            ExprErrorKind::TypeMismatch,
+            0..14,
+        ),
+        (
+            "Subject.entity == testservice:role:ui/user",
+            ExprErrorKind::TypeMismatch,
+            18..42,
+        ),
+    ];
+
+    for (expr, kind, span) in cases {
+        let error = compile(expr, &TestEnv).unwrap_err();
+        assert_eq!((expr, kind), (expr, error.kind));
+        assert_eq!((expr, span), (expr, error.span));
+    }
+}
@@ -0,0 +1,142 @@
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc,
+};
+
+use authly_common::{
+    certificate::oid::ENTITY_UNIQUE_IDENTIFIER,
+    id::{AttrId, ServiceId},
+    mtls_server::{MTLSConnectionData, MTLSMiddleware},
+};
+use fnv::FnvHashSet;
+use rcgen::{CertificateParams, DnType, KeyPair};
+use time::OffsetDateTime;
+
+const SVC: ServiceId = ServiceId::from_uint(0xf001);
+const ROLE: AttrId = AttrId::from_uint(0xa001);
+
+fn service_certificate_der(common_name: &str, entity_id: ServiceId) -> Vec<u8> {
+    let mut params = CertificateParams::new(vec![]).unwrap();
+    params
+        .distinguished_name
+        .push(DnType::CommonName, common_name);
+    params.distinguished_name.push(
+        DnType::CustomDnType(ENTITY_UNIQUE_IDENTIFIER.to_vec()),
+        entity_id.to_string(),
+    );
+    params
+        .self_signed(&KeyPair::generate().unwrap())
+        .unwrap()
+        .der()
+        .to_vec()
+}
+
+#[test]
+fn test_connection_data_from_peer_certificate() {
+    let mut params = CertificateParams::new(vec!["testservice".to_string()]).unwrap();
+    params
+        .distinguished_name
+        .push(DnType::CommonName, "Test service");
+    params.distinguished_name.push(
+        DnType::CustomDnType(ENTITY_UNIQUE_IDENTIFIER.to_vec()),
+        SVC.to_string(),
+    );
+    // 2030-01-02 03:04:05 UTC
+    let not_after = OffsetDateTime::from_unix_timestamp(1_893_553_445).unwrap();
+    params.not_after = not_after;
+
+    let cert = params.self_signed(&KeyPair::generate().unwrap()).unwrap();
+    let data = MTLSConnectionData::from_peer_certificate(cert.der()).unwrap();
+
+    assert_eq!(Some(SVC), data.peer_service_entity());
+    assert_eq!(Some("Test service"), data.common_name());
+    assert_eq!(Some(not_after), data.not_after());
+    assert!(data
+        .peer_subject()
+        .unwrap()
+        .dn()
+        .contains("CN=Test service"));
+}
+
+#[test]
+fn test_connection_data_without_entity_id() {
+    let mut params = CertificateParams::new(vec![]).unwrap();
+    params.distinguished_name.push(DnType::CommonName, "client");
+
+    let cert = params.self_signed(&KeyPair::generate().unwrap()).unwrap();
+    let data = MTLSConnectionData::from_peer_certificate(cert.der()).unwrap();
+
+    assert_eq!(None, data.peer_service_entity());
+    assert_eq!(Some("client"), data.common_name());
+
+    assert!(MTLSConnectionData::from_peer_certificate(b"garbage").is_none());
+}
+
+#[test]
+fn test_attribute_resolver() {
+    let calls = Arc::new(AtomicUsize::new(0));
+    let middleware = MTLSMiddleware::new().with_attribute_resolver({
+        let calls = calls.clone();
+        move |peer| {
+            calls.fetch_add(1, Ordering::SeqCst);
+            if peer == SVC {
+                FnvHashSet::from_iter([ROLE])
+            } else {
+                FnvHashSet::default()
+            }
+        }
+    });
+
+    let data = middleware
+        .connection_data(&service_certificate_der("svc", SVC))
+        .unwrap();
+    assert_eq!(Some(&FnvHashSet::from_iter([ROLE])), data.peer_attributes());
+    assert_eq!(1, calls.load(Ordering::SeqCst));
+
+    // without a resolver
+    let data = MTLSMiddleware::new()
+        .connection_data(&service_certificate_der("svc", SVC))
+        .unwrap();
+    assert_eq!(None, data.peer_attributes());
+    assert_eq!(1, calls.load(Ordering::SeqCst));
+
+    // the resolver is not called for peers without an entity ID
+    let mut params = CertificateParams::new(vec![]).unwrap();
+    params.distinguished_name.push(DnType::CommonName, "client");
+    let cert = params.self_signed(&KeyPair::generate().unwrap()).unwrap();
+    let data = middleware.connection_data(cert.der()).unwrap();
+    assert_eq!(None, data.peer_attributes());
+    assert_eq!(1, calls.load(Ordering::SeqCst));
+}
+
+#[cfg(feature = "axum")]
+#[tokio::test]
+async fn test_peer_service_entity_extractor() {
+    use authly_common::mtls_server::PeerServiceEntity;
+    use axum::extract::FromRequestParts;
+
+    let (mut parts, _) = axum::http::Request::builder()
+        .extension(PeerServiceEntity(SVC))
+        .body(())
+        .unwrap()
+        .into_parts();
+
+    let PeerServiceEntity(peer) = PeerServiceEntity::from_request_parts(&mut parts, &())
+        .await
+        .unwrap();
+    assert_eq!(SVC, peer);
+}
+
+#[cfg(feature = "axum")]
+#[tokio::test]
+async fn test_peer_service_entity_extractor_rejection() {
+    use authly_common::mtls_server::PeerServiceEntity;
+    use axum::extract::FromRequestParts;
+
+    let (mut parts, _) = axum::http::Request::new(()).into_parts();
+
+    let rejection = PeerServiceEntity::from_request_parts(&mut parts, &())
+        .await
+        .unwrap_err();
+    assert_eq!(axum::http::StatusCode::UNAUTHORIZED, rejection);
+}
@@ -1,8 +1,13 @@
 use authly_common::{
-    id::{AttrId, PolicyId},
+    id::{kind::Kind, AttrId, EntityId, PolicyId},
     policy::{
-        code::{to_bytecode, OpCode, PolicyValue},
-        engine::{AccessControlParams, NoOpPolicyTracer, PolicyEngine},
+        code::{to_bytecode, DecodeError, OpCode, PolicyValue},
+        engine::{
+            AccessControlParams, Decision, DecisionCause, EngineDecodeError, EvalError, EvalLimits,
+            EvalOrder, EvalScratch, NoOpPolicyTracer, PolicyEngine, PolicyTracer, RecordingTracer,
+            TraceEvent,
+        },
+        verify::VerifyError,
     },
 };
 
@@ -40,6 +45,18 @@ fn false_policy() -> Vec<u8> {
     ])
 }
 
+/// A policy loading a constant entity ID with a valid kind byte that is not an entity kind.
+fn invalid_entity_kind_policy() -> Vec<u8> {
+    let mut bytecode = to_bytecode(&[
+        OpCode::LoadConstEntityId(EntityId::from_uint(Kind::Group, 1)),
+        OpCode::LoadSubjectIdSet,
+        OpCode::IdSetContains,
+        OpCode::Return,
+    ]);
+    bytecode[1] = Kind::Attribute.into();
+    bytecode
+}
+
 fn test_engine_with_policies() -> PolicyEngine {
     let mut e = PolicyEngine::default();
     e.add_policy(POL_DENY_FALSE0, PolicyValue::Deny, false_policy());
@@ -68,6 +85,18 @@ fn eval_attrs(engine: &PolicyEngine, attrs: impl IntoIterator<Item = AttrId>) ->
     }
 }
 
+/// Evaluate a single allow policy, triggered by the `FOO` resource attribute.
+#[track_caller]
+fn eval_single(code: &[OpCode], mut params: AccessControlParams) -> PolicyValue {
+    let mut e = PolicyEngine::default();
+    e.add_policy_verified(POL_ALLOW_TRUE0, PolicyValue::Allow, to_bytecode(code))
+        .unwrap();
+    e.add_trigger([FOO], [POL_ALLOW_TRUE0]);
+
+    params.resource_attrs.insert(FOO);
+    e.eval(&params, &mut NoOpPolicyTracer).unwrap()
+}
+
 #[test_log::test]
 fn test_allow_class() {
     let mut e = test_engine_with_policies();
@@ -131,12 +160,11 @@ fn test_deny_class() {
     assert_eq!("allow", eval_attrs(&e, [QUX, BOG, EXTRA]));
 }
 
-#[test_log::test]
-fn test_allow_deny_classes() {
-    let mut e = test_engine_with_policies();
+const NO: AttrId = AttrId::from_uint(100);
+const YES: AttrId = AttrId::from_uint(200);
 
-    const NO: AttrId = AttrId::from_uint(100);
-    const YES: AttrId = AttrId::from_uint(200);
+fn test_engine_allow_deny_classes() -> PolicyEngine {
+    let mut e = test_engine_with_policies();
 
     // "NO" triggers, results in deny
     e.add_trigger([NO, FOO], [POL_ALLOW_TRUE0, POL_DENY_TRUE0]);
@@ -165,6 +193,12 @@ fn test_allow_deny_classes() {
             POL_DENY_FALSE1,
         ],
     );
+    e
+}
+
+#[test_log::test]
+fn test_allow_deny_classes() {
+    let e = test_engine_allow_deny_classes();
 
     assert_eq!("deny", eval_attrs(&e, []));
     assert_eq!("deny", eval_attrs(&e, [EXTRA]));
@@ -182,3 +216,1032 @@ fn test_allow_deny_classes() {
     assert_eq!("allow", eval_attrs(&e, [YES, BAR]));
     assert_eq!("allow", eval_attrs(&e, [YES, BAR, EXTRA]));
 }
+
+#[test_log::test]
+fn test_time_window() {
+    const OPENS: u64 = 1_700_000_000;
+    const CLOSES: u64 = 1_700_003_600;
+
+    let mut e = PolicyEngine::default();
+    e.add_policy(
+        POL_ALLOW_TRUE0,
+        PolicyValue::Allow,
+        to_bytecode(&[
+            OpCode::LoadNow,
+            OpCode::LoadConstUint(OPENS),
+            OpCode::Gt,
+            OpCode::LoadNow,
+            OpCode::LoadConstUint(CLOSES),
+            OpCode::Lt,
+            OpCode::And,
+            OpCode::Return,
+        ]),
+    );
+    e.add_trigger([FOO], [POL_ALLOW_TRUE0]);
+
+    let eval_at = |now: u64| match e.eval(
+        &AccessControlParams {
+            resource_attrs: [FOO].into_iter().collect(),
+            now: Some(now),
+            ..Default::default()
+        },
+        &mut NoOpPolicyTracer,
+    ) {
+        Ok(PolicyValue::Allow) => "allow",
+        Ok(PolicyValue::Deny) => "deny",
+        Err(err) => panic!("{err:?}"),
+    };
+
+    assert_eq!("deny", eval_at(OPENS - 1));
+    assert_eq!("deny", eval_at(OPENS));
+    assert_eq!("allow", eval_at(OPENS + 1));
+    assert_eq!("allow", eval_at(CLOSES - 1));
+    assert_eq!("deny", eval_at(CLOSES));
+    assert_eq!("deny", eval_at(CLOSES + 1));
+}
+
+#[test_log::test]
+fn test_eval_explain() {
+    let mut e = test_engine_with_policies();
+    e.add_trigger([FOO], [POL_ALLOW_TRUE0]);
+    e.add_trigger([BAR], [POL_ALLOW_FALSE0]);
+    e.add_trigger([BAZ], [POL_ALLOW_TRUE0, POL_DENY_TRUE0]);
+    e.add_trigger([QUX], [POL_DENY_FALSE0]);
+
+    let explain = |subject_attrs: &[AttrId], resource_attrs: &[AttrId]| {
+        e.eval_explain(
+            &AccessControlParams {
+                subject_attrs: subject_attrs.iter().copied().collect(),
+                resource_attrs: resource_attrs.iter().copied().collect(),
+                ..Default::default()
+            },
+            &mut NoOpPolicyTracer,
+        )
+        .unwrap()
+    };
+
+    assert_eq!(
+        Decision {
+            value: PolicyValue::Allow,
+            cause: DecisionCause::Policy {
+                policy_id: POL_ALLOW_TRUE0,
+                class: PolicyValue::Allow
+            }
+        },
+        explain(&[], &[FOO])
+    );
+    assert_eq!(
+        Decision {
+            value: PolicyValue::Deny,
+            cause: DecisionCause::Default
+        },
+        explain(&[], &[BAR])
+    );
+    assert_eq!(
+        Decision {
+            value: PolicyValue::Deny,
+            cause: DecisionCause::Policy {
+                policy_id: POL_DENY_TRUE0,
+                class: PolicyValue::Deny
+            }
+        },
+        explain(&[], &[BAZ])
+    );
+    assert_eq!(
+        Decision {
+            value: PolicyValue::Allow,
+            cause: DecisionCause::Default
+        },
+        explain(&[], &[QUX])
+    );
+    assert_eq!(
+        Decision {
+            value: PolicyValue::Allow,
+            cause: DecisionCause::AttributeMatch(Some(EXTRA))
+        },
+        explain(&[EXTRA], &[EXTRA])
+    );
+    assert_eq!(
+        Decision {
+            value: PolicyValue::Deny,
+            cause: DecisionCause::AttributeMatch(None)
+        },
+        explain(&[EXTRA], &[BOG])
+    );
+}
+
+#[test_log::test]
+fn test_id_set_contains() {
+    let high_entropy = AttrId::from_uint(0x9d2c_61f4_0b7e_43a8_e51f_c03a_7d94_b16e);
+    // differs from `high_entropy` only in the upper 64 bits
+    let high_entropy_upper = AttrId::from_uint(0x1d2c_61f4_0b7e_43a8_e51f_c03a_7d94_b16e);
+
+    let eval = |code: &[OpCode], subject_attrs: &[AttrId]| {
+        eval_single(
+            code,
+            AccessControlParams {
+                subject_attrs: subject_attrs.iter().copied().collect(),
+                ..Default::default()
+            },
+        )
+    };
+
+    let set_first = [
+        OpCode::LoadSubjectAttrs,
+        OpCode::LoadConstAttrId(high_entropy),
+        OpCode::IdSetContains,
+        OpCode::Return,
+    ];
+    let id_first = [
+        OpCode::LoadConstAttrId(high_entropy),
+        OpCode::LoadSubjectAttrs,
+        OpCode::IdSetContains,
+        OpCode::Return,
+    ];
+
+    for code in [&set_first[..], &id_first[..]] {
+        assert_eq!(PolicyValue::Allow, eval(code, &[BAR, high_entropy]));
+        assert_eq!(PolicyValue::Deny, eval(code, &[BAR, high_entropy_upper]));
+        assert_eq!(PolicyValue::Deny, eval(code, &[]));
+    }
+}
+
+#[test_log::test]
+fn test_entity_id_set() {
+    const GROUP_A: EntityId = EntityId::from_uint(Kind::Group, 1);
+    const GROUP_B: EntityId = EntityId::from_uint(Kind::Group, 2);
+    const GROUP_C: EntityId = EntityId::from_uint(Kind::Group, 3);
+    // same bits as `GROUP_A`, but a different kind
+    const PERSONA_A: EntityId = EntityId::from_uint(Kind::Persona, 1);
+
+    let eval = |code: &[OpCode], subject_eid_set: &[EntityId], resource_eid_set: &[EntityId]| {
+        eval_single(
+            code,
+            AccessControlParams {
+                subject_eid_set: subject_eid_set.iter().copied().collect(),
+                resource_eid_set: resource_eid_set.iter().copied().collect(),
+                ..Default::default()
+            },
+        )
+    };
+
+    // subject is a member of any of the groups A or B
+    let any_group = [
+        OpCode::LoadSubjectIdSet,
+        OpCode::LoadConstEntityId(GROUP_A),
+        OpCode::IdSetContains,
+        OpCode::LoadConstEntityId(GROUP_B),
+        OpCode::LoadSubjectIdSet,
+        OpCode::IdSetContains,
+        OpCode::Or,
+        OpCode::Return,
+    ];
+
+    assert_eq!(PolicyValue::Allow, eval(&any_group, &[GROUP_A], &[]));
+    assert_eq!(
+        PolicyValue::Allow,
+        eval(&any_group, &[GROUP_C, GROUP_B], &[])
+    );
+    assert_eq!(PolicyValue::Deny, eval(&any_group, &[GROUP_C], &[]));
+    assert_eq!(PolicyValue::Deny, eval(&any_group, &[PERSONA_A], &[]));
+    assert_eq!(PolicyValue::Deny, eval(&any_group, &[], &[GROUP_A]));
+
+    let resource_is_eq = [
+        OpCode::LoadResourceIdSet,
+        OpCode::LoadConstEntityId(GROUP_C),
+        OpCode::IsEq,
+        OpCode::Return,
+    ];
+
+    assert_eq!(
+        PolicyValue::Allow,
+        eval(&resource_is_eq, &[], &[GROUP_A, GROUP_C])
+    );
+    assert_eq!(
+        PolicyValue::Deny,
+        eval(&resource_is_eq, &[GROUP_C], &[GROUP_A])
+    );
+}
+
+#[test_log::test]
+fn test_eval_limits() {
+    let eval = |e: &PolicyEngine| {
+        e.eval(
+            &AccessControlParams {
+                resource_attrs: [FOO].into_iter().collect(),
+                ..Default::default()
+            },
+            &mut NoOpPolicyTracer,
+        )
+    };
+
+    let mut e = PolicyEngine::default();
+    e.add_trigger([FOO], [POL_ALLOW_TRUE0]);
+
+    let deep: Vec<_> = std::iter::repeat_with(|| OpCode::LoadSubjectAttrs)
+        .take(10_000)
+        .chain([OpCode::Return])
+        .collect();
+    e.add_policy(POL_ALLOW_TRUE0, PolicyValue::Allow, to_bytecode(&deep));
+
+    assert_eq!(Err(EvalError::Program), eval(&e));
+
+    // the stack limit applies independently of the instruction limit
+    e.set_eval_limits(EvalLimits {
+        max_stack_depth: 256,
+        max_instructions: 100_000,
+    });
+    assert_eq!(Err(EvalError::Program), eval(&e));
+
+    // a long program with a shallow stack
+    let mut long = vec![
+        OpCode::LoadConstAttrId(FOO),
+        OpCode::LoadConstAttrId(FOO),
+        OpCode::IsEq,
+    ];
+    for _ in 0..5_000 {
+        long.extend([
+            OpCode::LoadConstAttrId(FOO),
+            OpCode::LoadConstAttrId(FOO),
+            OpCode::IsEq,
+            OpCode::And,
+        ]);
+    }
+    long.push(OpCode::Return);
+    e.add_policy(POL_ALLOW_TRUE0, PolicyValue::Allow, to_bytecode(&long));
+
+    assert_eq!(Ok(PolicyValue::Allow), eval(&e));
+
+    e.set_eval_limits(EvalLimits {
+        max_stack_depth: 256,
+        max_instructions: 1000,
+    });
+    assert_eq!(Err(EvalError::Program), eval(&e));
+}
+
+#[derive(Default)]
+struct EvalOrderTracer {
+    applicable: Vec<(PolicyValue, Vec<PolicyId>)>,
+    evaluated: Vec<PolicyId>,
+}
+
+impl PolicyTracer for EvalOrderTracer {
+    fn report_applicable(&mut self, class: PolicyValue, policies: impl Iterator<Item = PolicyId>) {
+        self.applicable.push((class, policies.collect()));
+    }
+
+    fn report_policy_eval_start(&mut self, policy_id: PolicyId) {
+        self.evaluated.push(policy_id);
+    }
+}
+
+#[test_log::test]
+fn test_deterministic_eval_order() {
+    let mut e = test_engine_with_policies();
+    e.add_trigger([FOO], [POL_ALLOW_TRUE1, POL_ALLOW_FALSE1]);
+    e.add_trigger([BAR], [POL_ALLOW_TRUE0, POL_ALLOW_FALSE0]);
+    e.add_trigger([BAZ], [POL_DENY_FALSE1, POL_DENY_FALSE0]);
+
+    for _ in 0..10 {
+        let mut tracer = EvalOrderTracer::default();
+        let value = e
+            .eval(
+                &AccessControlParams {
+                    resource_attrs: [BAZ, FOO, BAR].into_iter().collect(),
+                    ..Default::default()
+                },
+                &mut tracer,
+            )
+            .unwrap();
+
+        assert_eq!(PolicyValue::Allow, value);
+        assert_eq!(
+            vec![
+                (PolicyValue::Deny, vec![POL_DENY_FALSE0, POL_DENY_FALSE1]),
+                (
+                    PolicyValue::Allow,
+                    vec![
+                        POL_ALLOW_FALSE0,
+                        POL_ALLOW_FALSE1,
+                        POL_ALLOW_TRUE0,
+                        POL_ALLOW_TRUE1
+                    ]
+                ),
+            ],
+            tracer.applicable
+        );
+        assert_eq!(
+            vec![
+                POL_ALLOW_FALSE0,
+                POL_ALLOW_FALSE1,
+                POL_ALLOW_TRUE0,
+                POL_DENY_FALSE0,
+                POL_DENY_FALSE1
+            ],
+            tracer.evaluated
+        );
+    }
+}
+
+#[test_log::test]
+fn test_add_policy_verified() {
+    let mut e = PolicyEngine::default();
+
+    e.add_policy_verified(
+        POL_ALLOW_TRUE0,
+        PolicyValue::Allow,
+        to_bytecode(&[
+            OpCode::LoadSubjectAttrs,
+            OpCode::LoadResourceAttrs,
+            OpCode::SupersetOf,
+            OpCode::LoadConstAttrId(FOO),
+            OpCode::LoadSubjectAttrs,
+            OpCode::IdSetContains,
+            OpCode::And,
+            OpCode::Return,
+        ]),
+    )
+    .unwrap();
+    assert_eq!(1, e.get_policy_count());
+
+    assert_eq!(
+        Err(VerifyError::Type(2)),
+        e.add_policy_verified(
+            POL_ALLOW_TRUE1,
+            PolicyValue::Allow,
+            to_bytecode(&[
+                OpCode::LoadSubjectAttrs,
+                OpCode::LoadConstAttrId(FOO),
+                OpCode::SupersetOf,
+                OpCode::Return,
+            ]),
+        )
+    );
+    assert_eq!(
+        Err(VerifyError::Type(1)),
+        e.add_policy_verified(
+            POL_ALLOW_TRUE1,
+            PolicyValue::Allow,
+            to_bytecode(&[OpCode::LoadSubjectAttrs, OpCode::Return]),
+        )
+    );
+    assert_eq!(
+        Err(VerifyError::StackUnderflow(1)),
+        e.add_policy_verified(
+            POL_ALLOW_TRUE1,
+            PolicyValue::Allow,
+            to_bytecode(&[OpCode::LoadConstUint(1), OpCode::And, OpCode::Return]),
+        )
+    );
+    assert_eq!(
+        Err(VerifyError::MissingReturn),
+        e.add_policy_verified(
+            POL_ALLOW_TRUE1,
+            PolicyValue::Allow,
+            to_bytecode(&[OpCode::LoadConstUint(1)]),
+        )
+    );
+    assert_eq!(1, e.get_policy_count());
+}
+
+#[test_log::test]
+fn test_invalid_entity_kind() {
+    let mut e = PolicyEngine::default();
+    e.add_trigger([FOO], [POL_ALLOW_TRUE0]);
+
+    assert_eq!(
+        Err(VerifyError::Decode(DecodeError::InvalidKind(
+            Kind::Attribute.into()
+        ))),
+        e.add_policy_verified(
+            POL_ALLOW_TRUE0,
+            PolicyValue::Allow,
+            invalid_entity_kind_policy()
+        )
+    );
+
+    // unverified bytecode fails evaluation instead of panicking
+    e.add_policy(
+        POL_ALLOW_TRUE0,
+        PolicyValue::Allow,
+        invalid_entity_kind_policy(),
+    );
+    assert_eq!(
+        Err(EvalError::Type),
+        e.eval(
+            &AccessControlParams {
+                resource_attrs: [FOO].into_iter().collect(),
+                ..Default::default()
+            },
+            &mut NoOpPolicyTracer,
+        )
+    );
+}
+
+#[test_log::test]
+fn test_engine_deserialization_verifies_policies() {
+    let mut e = PolicyEngine::default();
+    e.add_trigger([FOO], [POL_ALLOW_TRUE0]);
+    e.add_policy(
+        POL_ALLOW_TRUE0,
+        PolicyValue::Allow,
+        invalid_entity_kind_policy(),
+    );
+
+    assert_eq!(
+        Err(EngineDecodeError::Format),
+        PolicyEngine::from_bytes(&e.to_bytes()).map(|_| ())
+    );
+}
+
+#[test_log::test]
+fn test_recording_tracer() {
+    let e = test_engine_allow_deny_classes();
+    let mut tracer = RecordingTracer::default();
+
+    let decision = e
+        .eval_explain(
+            &AccessControlParams {
+                resource_attrs: [YES, BAR].into_iter().collect(),
+                ..Default::default()
+            },
+            &mut tracer,
+        )
+        .unwrap();
+
+    assert_eq!(
+        vec![
+            TraceEvent::Applicable {
+                class: PolicyValue::Deny,
+                policies: vec![POL_DENY_FALSE0, POL_DENY_FALSE1],
+            },
+            TraceEvent::Applicable {
+                class: PolicyValue::Allow,
+                policies: vec![POL_ALLOW_FALSE0, POL_ALLOW_TRUE0],
+            },
+            TraceEvent::PolicyEvalStart {
+                policy_id: POL_ALLOW_FALSE0
+            },
+            TraceEvent::PolicyEvalEnd {
+                policy_id: POL_ALLOW_FALSE0,
+                value: false
+            },
+            TraceEvent::PolicyEvalStart {
+                policy_id: POL_ALLOW_TRUE0
+            },
+            TraceEvent::PolicyEvalEnd {
+                policy_id: POL_ALLOW_TRUE0,
+                value: true
+            },
+            TraceEvent::PolicyEvalStart {
+                policy_id: POL_DENY_FALSE0
+            },
+            TraceEvent::PolicyEvalEnd {
+                policy_id: POL_DENY_FALSE0,
+                value: false
+            },
+            TraceEvent::PolicyEvalStart {
+                policy_id: POL_DENY_FALSE1
+            },
+            TraceEvent::PolicyEvalEnd {
+                policy_id: POL_DENY_FALSE1,
+                value: false
+            },
+            TraceEvent::Decision(decision),
+        ],
+        tracer.events()
+    );
+    assert_eq!(
+        DecisionCause::Policy {
+            policy_id: POL_ALLOW_TRUE0,
+            class: PolicyValue::Allow
+        },
+        decision.cause
+    );
+
+    let json = serde_json::to_value(tracer.events()).unwrap();
+    assert_eq!(
+        serde_json::json!({ "event": "policy_eval_end", "policy_id": POL_ALLOW_TRUE0, "value": true }),
+        json[5]
+    );
+}
+
+#[test_log::test]
+fn test_eval_batch() {
+    let e = test_engine_allow_deny_classes();
+
+    fn params<const S: usize, const R: usize>(
+        subject: [AttrId; S],
+        resource: [AttrId; R],
+    ) -> AccessControlParams {
+        AccessControlParams {
+            subject_attrs: subject.into_iter().collect(),
+            resource_attrs: resource.into_iter().collect(),
+            ..Default::default()
+        }
+    }
+
+    let batch = [
+        params([], []),
+        params([], [YES, BAR]),
+        params([YES], [BAR]),
+        params([], [NO, BAR]),
+        params([NO], [FOO]),
+        params([], [YES, BAR]),
+        params([EXTRA], [EXTRA]),
+        params([], [EXTRA]),
+        params([NO], [BAR]),
+    ];
+
+    let batch_values = e.eval_batch(&batch, &mut NoOpPolicyTracer);
+    let individual_values: Vec<_> = batch
+        .iter()
+        .map(|params| e.eval(params, &mut NoOpPolicyTracer))
+        .collect();
+
+    assert_eq!(individual_values, batch_values);
+    assert_eq!(
+        vec![
+            Ok(PolicyValue::Deny),
+            Ok(PolicyValue::Allow),
+            Ok(PolicyValue::Allow),
+            Ok(PolicyValue::Deny),
+            Ok(PolicyValue::Deny),
+            Ok(PolicyValue::Allow),
+            Ok(PolicyValue::Allow),
+            Ok(PolicyValue::Deny),
+            Ok(PolicyValue::Deny),
+        ],
+        batch_values
+    );
+}
+
+#[test_log::test]
+fn test_deny_first() {
+    let mut e = test_engine_allow_deny_classes();
+    e.set_eval_order(EvalOrder::DenyFirst);
+
+    let mut tracer = RecordingTracer::default();
+    let decision = e
+        .eval_explain(
+            &AccessControlParams {
+                resource_attrs: [NO, BAR].into_iter().collect(),
+                ..Default::default()
+            },
+            &mut tracer,
+        )
+        .unwrap();
+
+    assert_eq!(
+        Decision {
+            value: PolicyValue::Deny,
+            cause: DecisionCause::Policy {
+                policy_id: POL_DENY_TRUE0,
+                class: PolicyValue::Deny
+            }
+        },
+        decision
+    );
+
+    // the holding deny policy short-circuits, the allow policy is never evaluated
+    let evaluated: Vec<_> = tracer
+        .events()
+        .iter()
+        .filter_map(|event| match event {
+            TraceEvent::PolicyEvalEnd { policy_id, value } => Some((*policy_id, *value)),
+            _ => None,
+        })
+        .collect();
+    assert_eq!(
+        vec![(POL_DENY_FALSE0, false), (POL_DENY_TRUE0, true)],
+        evaluated
+    );
+
+    // the outcomes are the same in both orders
+    let allow_first = test_engine_allow_deny_classes();
+    for attrs in [
+        &[][..],
+        &[NO, FOO],
+        &[NO, BAR],
+        &[NO, BAZ],
+        &[YES, FOO],
+        &[YES, BAR],
+        &[EXTRA],
+    ] {
+        let params = AccessControlParams {
+            resource_attrs: attrs.iter().copied().collect(),
+            ..Default::default()
+        };
+        assert_eq!(
+            allow_first.eval(&params, &mut NoOpPolicyTracer),
+            e.eval(&params, &mut NoOpPolicyTracer),
+            "attrs: {attrs:?}"
+        );
+    }
+}
+
+#[test_log::test]
+fn test_eval_order_failing_policy() {
+    const POL_ALLOW_ERR: PolicyId = PolicyId::from_uint(8);
+    const POL_DENY_ERR: PolicyId = PolicyId::from_uint(9);
+
+    let mut e = test_engine_with_policies();
+    e.add_policy(
+        POL_ALLOW_ERR,
+        PolicyValue::Allow,
+        invalid_entity_kind_policy(),
+    );
+    e.add_policy(
+        POL_DENY_ERR,
+        PolicyValue::Deny,
+        invalid_entity_kind_policy(),
+    );
+
+    // failing deny policy, no allow policy holds: denied regardless of the deny policies
+    e.add_trigger([FOO], [POL_ALLOW_FALSE0, POL_DENY_ERR]);
+    // failing deny policy, an allow policy holds: undecided
+    e.add_trigger([BAR], [POL_ALLOW_TRUE0, POL_DENY_ERR]);
+    // failing allow policy, a deny policy holds: denied regardless of the allow policies
+    e.add_trigger([BAZ], [POL_ALLOW_ERR, POL_DENY_TRUE0]);
+    // failing allow policy, no deny policy holds: undecided
+    e.add_trigger([QUX], [POL_ALLOW_ERR, POL_DENY_FALSE0]);
+    // both classes failing
+    e.add_trigger([BOG], [POL_ALLOW_ERR, POL_DENY_ERR]);
+
+    for order in [EvalOrder::AllowFirst, EvalOrder::DenyFirst] {
+        e.set_eval_order(order);
+
+        let eval = |attr: AttrId| {
+            e.eval(
+                &AccessControlParams {
+                    resource_attrs: [attr].into_iter().collect(),
+                    ..Default::default()
+                },
+                &mut NoOpPolicyTracer,
+            )
+        };
+
+        assert_eq!(Ok(PolicyValue::Deny), eval(FOO), "{order:?}");
+        assert_eq!(Err(EvalError::Type), eval(BAR), "{order:?}");
+        assert_eq!(Ok(PolicyValue::Deny), eval(BAZ), "{order:?}");
+        assert_eq!(Err(EvalError::Type), eval(QUX), "{order:?}");
+        assert_eq!(Err(EvalError::Type), eval(BOG), "{order:?}");
+    }
+}
+
+#[test_log::test]
+fn test_eval_with_scratch() {
+    let mut conjunctive = test_engine_with_policies();
+    conjunctive.add_trigger([FOO], [POL_ALLOW_FALSE0]);
+    conjunctive.add_trigger_conjunctive([FOO], [POL_ALLOW_TRUE0, POL_ALLOW_TRUE1]);
+    conjunctive.add_trigger_conjunctive([BAR], [POL_ALLOW_TRUE0, POL_ALLOW_FALSE1]);
+    conjunctive.add_trigger_conjunctive([BAZ, QUX], [POL_DENY_TRUE1, POL_ALLOW_TRUE0]);
+
+    let engines = [test_engine_allow_deny_classes(), conjunctive];
+    let attr_sets: [&[AttrId]; 8] = [
+        &[],
+        &[YES, BAR],
+        &[NO, BAR],
+        &[EXTRA],
+        &[FOO],
+        &[BAR],
+        &[FOO, BAR],
+        &[BAZ, QUX],
+    ];
+
+    // one scratch shared between all evaluations, across engines
+    let mut scratch = EvalScratch::default();
+
+    for e in &engines {
+        for attrs in attr_sets {
+            let params = AccessControlParams {
+                resource_attrs: attrs.iter().copied().collect(),
+                ..Default::default()
+            };
+
+            assert_eq!(
+                e.eval_explain(&params, &mut NoOpPolicyTracer),
+                e.eval_explain_with_scratch(&params, &mut scratch, &mut NoOpPolicyTracer),
+                "attrs: {attrs:?}"
+            );
+        }
+    }
+}
+
+#[test_log::test]
+fn test_validate_dangling_trigger() {
+    let mut e = test_engine_with_policies();
+    e.add_trigger([FOO], [POL_ALLOW_TRUE0]);
+    assert_eq!(Vec::<PolicyId>::new(), e.validate());
+
+    let dangling = PolicyId::from_uint(999);
+    e.add_trigger([BAR], [dangling, POL_ALLOW_TRUE0]);
+    e.add_trigger_conjunctive([BAZ], [dangling]);
+
+    assert_eq!(vec![dangling], e.validate());
+
+    // evaluation stays tolerant of the missing policy
+    assert_eq!("allow", eval_attrs(&e, [BAR]));
+    assert_eq!("deny", eval_attrs(&e, [BAZ]));
+}
+
+#[test_log::test]
+fn test_remove_policy() {
+    let mut e = test_engine_with_policies();
+    e.add_trigger([FOO], [POL_ALLOW_TRUE0]);
+    e.add_trigger([BAR], [POL_ALLOW_TRUE0, POL_ALLOW_TRUE1]);
+
+    assert_eq!(8, e.get_policy_count());
+    assert_eq!(2, e.get_trigger_count());
+    assert_eq!("allow", eval_attrs(&e, [FOO]));
+    assert_eq!("allow", eval_attrs(&e, [BAR]));
+
+    assert!(e.remove_policy(POL_ALLOW_TRUE0));
+    assert!(!e.remove_policy(POL_ALLOW_TRUE0));
+
+    assert_eq!(7, e.get_policy_count());
+    assert_eq!(1, e.get_trigger_count());
+    assert_eq!("deny", eval_attrs(&e, [FOO]));
+    assert_eq!("allow", eval_attrs(&e, [BAR]));
+
+    assert!(e.remove_policy(POL_ALLOW_TRUE1));
+
+    assert_eq!(6, e.get_policy_count());
+    assert_eq!(0, e.get_trigger_count());
+    assert_eq!("deny", eval_attrs(&e, [BAR]));
+}
+
+#[test_log::test]
+fn test_remove_conjunctive_member() {
+    let mut e = test_engine_with_policies();
+    e.add_trigger_conjunctive([FOO], [POL_ALLOW_TRUE0, POL_ALLOW_TRUE1]);
+
+    assert_eq!("allow", eval_attrs(&e, [FOO]));
+
+    // the group does not shrink to the remaining member
+    assert!(e.remove_policy(POL_ALLOW_TRUE1));
+    assert_eq!("deny", eval_attrs(&e, [FOO]));
+    assert_eq!(0, e.get_trigger_count());
+}
+
+#[test_log::test]
+fn test_conjunctive_missing_member() {
+    let mut e = test_engine_with_policies();
+    let dangling = PolicyId::from_uint(999);
+    e.add_trigger_conjunctive([FOO], [POL_ALLOW_TRUE0, dangling]);
+    e.add_trigger_conjunctive([BAR], [POL_ALLOW_TRUE0, POL_ALLOW_TRUE1]);
+
+    // the group with a missing member does not hold
+    assert_eq!("deny", eval_attrs(&e, [FOO]));
+    assert_eq!("allow", eval_attrs(&e, [BAR]));
+}
+
+#[test_log::test]
+fn test_remove_trigger() {
+    let mut e = test_engine_with_policies();
+    e.add_trigger([FOO], [POL_ALLOW_TRUE0]);
+    e.add_trigger([FOO, BAR], [POL_ALLOW_TRUE0]);
+    e.add_trigger([FOO, BAR], [POL_DENY_TRUE0]);
+
+    assert_eq!(3, e.get_trigger_count());
+    assert_eq!("allow", eval_attrs(&e, [FOO]));
+    assert_eq!("deny", eval_attrs(&e, [FOO, BAR]));
+
+    assert!(e.remove_trigger([FOO, BAR]));
+    assert!(!e.remove_trigger([FOO, BAR]));
+    assert!(!e.remove_trigger([BAR]));
+
+    assert_eq!(1, e.get_trigger_count());
+    assert_eq!(8, e.get_policy_count());
+    assert_eq!("allow", eval_attrs(&e, [FOO, BAR]));
+
+    assert!(e.remove_trigger([FOO]));
+
+    assert_eq!(0, e.get_trigger_count());
+    assert_eq!("deny", eval_attrs(&e, [FOO]));
+}
+
+#[test_log::test]
+fn test_allow_class_conjunctive() {
+    let mut e = test_engine_with_policies();
+    e.add_trigger_conjunctive([FOO], [POL_ALLOW_TRUE0, POL_ALLOW_FALSE0]);
+    e.add_trigger_conjunctive([BAR], [POL_ALLOW_TRUE0, POL_ALLOW_TRUE1]);
+    e.add_trigger_conjunctive([BAZ, QUX], [POL_ALLOW_FALSE0, POL_ALLOW_FALSE1]);
+
+    assert_eq!("deny", eval_attrs(&e, []));
+    assert_eq!("deny", eval_attrs(&e, [EXTRA]));
+    assert_eq!("deny", eval_attrs(&e, [FOO]));
+    assert_eq!("deny", eval_attrs(&e, [FOO, EXTRA]));
+    assert_eq!("deny", eval_attrs(&e, [BAZ]));
+    assert_eq!("deny", eval_attrs(&e, [BAZ, EXTRA]));
+    assert_eq!("deny", eval_attrs(&e, [QUX]));
+    assert_eq!("deny", eval_attrs(&e, [BAZ, QUX]));
+    assert_eq!("deny", eval_attrs(&e, [FOO, BAZ, QUX]));
+
+    assert_eq!("allow", eval_attrs(&e, [BAR]));
+    assert_eq!("allow", eval_attrs(&e, [BAR, EXTRA]));
+    // one group holding is enough
+    assert_eq!("allow", eval_attrs(&e, [FOO, BAR]));
+    assert_eq!("allow", eval_attrs(&e, [BAR, BAZ, QUX]));
+}
+
+#[test_log::test]
+fn test_deny_class_conjunctive() {
+    let mut e = test_engine_with_policies();
+    e.add_trigger_conjunctive([FOO], [POL_DENY_TRUE0, POL_DENY_FALSE0]);
+    e.add_trigger_conjunctive([BAR], [POL_DENY_TRUE0, POL_DENY_TRUE1]);
+    e.add_trigger_conjunctive([BAZ, QUX], [POL_DENY_TRUE1, POL_ALLOW_TRUE0]);
+
+    assert_eq!("deny", eval_attrs(&e, []));
+    assert_eq!("deny", eval_attrs(&e, [BAR]));
+    assert_eq!("deny", eval_attrs(&e, [FOO, BAR]));
+    assert_eq!("deny", eval_attrs(&e, [BAZ, QUX]));
+
+    assert_eq!("allow", eval_attrs(&e, [FOO]));
+    assert_eq!("allow", eval_attrs(&e, [FOO, EXTRA]));
+}
+
+#[test_log::test]
+fn test_excluding_trigger() {
+    let mut e = test_engine_with_policies();
+    e.add_trigger([FOO], [POL_ALLOW_TRUE0]);
+    e.add_trigger_excluding([FOO], [EXTRA, BOG], [POL_DENY_TRUE0]);
+    e.add_trigger_excluding([BAZ, QUX], [EXTRA], [POL_ALLOW_TRUE1]);
+
+    // the deny policy suppresses the allow policy, unless excluded
+    assert_eq!("deny", eval_attrs(&e, [FOO]));
+    assert_eq!("deny", eval_attrs(&e, [FOO, BAZ]));
+    assert_eq!("allow", eval_attrs(&e, [FOO, EXTRA]));
+    assert_eq!("allow", eval_attrs(&e, [FOO, BOG]));
+
+    assert_eq!("allow", eval_attrs(&e, [BAZ, QUX]));
+    assert_eq!("deny", eval_attrs(&e, [BAZ, QUX, EXTRA]));
+    assert_eq!("deny", eval_attrs(&e, [BAZ]));
+
+    // excluded subject attributes also suppress the trigger
+    assert_eq!(
+        Ok(PolicyValue::Allow),
+        e.eval(
+            &AccessControlParams {
+                subject_attrs: [EXTRA].into_iter().collect(),
+                resource_attrs: [FOO].into_iter().collect(),
+                ..Default::default()
+            },
+            &mut NoOpPolicyTracer,
+        )
+    );
+}
+
+#[test_log::test]
+fn test_mixed_combinators() {
+    let mut e = test_engine_with_policies();
+    e.add_trigger([FOO], [POL_ALLOW_FALSE0]);
+    e.add_trigger_conjunctive([FOO], [POL_ALLOW_TRUE0, POL_ALLOW_TRUE1]);
+    e.add_trigger_conjunctive([BAR], [POL_ALLOW_TRUE0, POL_ALLOW_FALSE1]);
+
+    assert_eq!("allow", eval_attrs(&e, [FOO]));
+    assert_eq!("deny", eval_attrs(&e, [BAR]));
+    assert_eq!("allow", eval_attrs(&e, [FOO, BAR]));
+
+    let decision = e
+        .eval_explain(
+            &AccessControlParams {
+                resource_attrs: [FOO].into_iter().collect(),
+                ..Default::default()
+            },
+            &mut NoOpPolicyTracer,
+        )
+        .unwrap();
+    assert_eq!(
+        DecisionCause::Policy {
+            policy_id: POL_ALLOW_TRUE0,
+            class: PolicyValue::Allow
+        },
+        decision.cause
+    );
+}
+
+#[test_log::test]
+fn test_uint_comparison() {
+    let compare = |lhs: u64, op: OpCode, rhs: u64| {
+        eval_single(
+            &[
+                OpCode::LoadConstUint(lhs),
+                OpCode::LoadConstUint(rhs),
+                op,
+                OpCode::Return,
+            ],
+            AccessControlParams::default(),
+        )
+        .is_allow()
+    };
+
+    assert!(compare(2, OpCode::Gt, 1));
+    assert!(!compare(1, OpCode::Gt, 1));
+    assert!(!compare(0, OpCode::Gt, 1));
+
+    assert!(compare(1, OpCode::Lt, 2));
+    assert!(!compare(1, OpCode::Lt, 1));
+    assert!(!compare(1, OpCode::Lt, 0));
+
+    assert!(compare(2, OpCode::Gte, 1));
+    assert!(compare(1, OpCode::Gte, 1));
+    assert!(!compare(0, OpCode::Gte, 1));
+
+    assert!(compare(1, OpCode::Lte, 2));
+    assert!(compare(1, OpCode::Lte, 1));
+    assert!(!compare(u64::MAX, OpCode::Lte, 0));
+
+    assert!(compare(5, OpCode::IsEq, 5));
+    assert!(!compare(5, OpCode::IsEq, 6));
+}
+
+#[test_log::test]
+fn test_boolean_opcodes() {
+    let eval = |opcodes: &[OpCode]| eval_single(opcodes, AccessControlParams::default()).is_allow();
+
+    assert!(eval(&[OpCode::LoadTrue, OpCode::Return]));
+    assert!(!eval(&[OpCode::LoadFalse, OpCode::Return]));
+
+    for (lhs, rhs, expected) in [
+        (false, false, false),
+        (false, true, true),
+        (true, false, true),
+        (true, true, false),
+    ] {
+        let load = |value| {
+            if value {
+                OpCode::LoadTrue
+            } else {
+                OpCode::LoadFalse
+            }
+        };
+        assert_eq!(
+            expected,
+            eval(&[load(lhs), load(rhs), OpCode::Xor, OpCode::Return]),
+            "{lhs} xor {rhs}"
+        );
+    }
+
+    // non-zero uints are truthy
+    assert!(!eval(&[
+        OpCode::LoadConstUint(2),
+        OpCode::LoadTrue,
+        OpCode::Xor,
+        OpCode::Return
+    ]));
+}
+
+#[test_log::test]
+fn test_engine_serialization_roundtrip() {
+    let mut e = test_engine_with_policies();
+    e.add_trigger([FOO], [POL_ALLOW_FALSE0]);
+    e.add_trigger([BAR], [POL_ALLOW_TRUE0]);
+    e.add_trigger([BAZ, QUX], [POL_DENY_FALSE0, POL_DENY_TRUE0]);
+    e.add_trigger([QUX, BOG], [POL_DENY_FALSE0, POL_ALLOW_TRUE1]);
+    e.add_trigger_conjunctive([BOG], [POL_ALLOW_TRUE0, POL_ALLOW_FALSE1]);
+    e.add_trigger_excluding([FOO], [EXTRA], [POL_DENY_TRUE1]);
+    e.set_eval_limits(EvalLimits {
+        max_stack_depth: 8,
+        max_instructions: 64,
+    });
+
+    let bytes = e.to_bytes();
+    let e2 = PolicyEngine::from_bytes(&bytes).unwrap();
+
+    assert_eq!(e.get_policy_count(), e2.get_policy_count());
+    assert_eq!(e.get_trigger_count(), e2.get_trigger_count());
+    assert_eq!(8, e2.eval_limits().max_stack_depth);
+    assert_eq!(64, e2.eval_limits().max_instructions);
+
+    let all = [FOO, BAR, BAZ, QUX, BOG, EXTRA];
+    for mask in 0..(1 << all.len()) {
+        let attrs: Vec<_> = all
+            .iter()
+            .enumerate()
+            .filter(|(idx, _)| mask & (1 << idx) != 0)
+            .map(|(_, attr)| *attr)
+            .collect();
+
+        assert_eq!(
+            eval_attrs(&e, attrs.clone()),
+            eval_attrs(&e2, attrs.clone()),
+            "{attrs:?}"
+        );
+    }
+
+    assert_eq!(
+        Err(EngineDecodeError::Magic),
+        PolicyEngine::from_bytes(b"nope").map(|_| ())
+    );
+    let mut future = bytes.clone();
+    future[4] = 99;
+    assert_eq!(
+        Err(EngineDecodeError::Version(99)),
+        PolicyEngine::from_bytes(&future).map(|_| ())
+    );
+    assert_eq!(
+        Err(EngineDecodeError::Format),
+        PolicyEngine::from_bytes(&bytes[..bytes.len() - 1]).map(|_| ())
+    );
+}
@@ -0,0 +1,44 @@
+use authly_common::proto::{
+    json_to_proto_struct, json_to_proto_value, proto_struct_to_json, proto_value_to_json,
+};
+use serde_json::json;
+
+#[test]
+fn test_json_proto_roundtrip() {
+    let json = json!({
+        "name": "thing",
+        "enabled": true,
+        "missing": null,
+        "count": 42,
+        "negative": -7,
+        "ratio": 0.25,
+        "large": 9007199254740991_u64,
+        "tags": ["a", 1, false, null, [2.5, {"nested": "deep"}]],
+        "child": {
+            "empty_object": {},
+            "empty_array": [],
+            "grandchild": {
+                "value": -1.5e-3,
+            },
+        },
+    });
+    let serde_json::Value::Object(map) = json.clone() else {
+        unreachable!()
+    };
+
+    let proto = json_to_proto_struct(map);
+    assert_eq!(9, proto.fields.len());
+
+    assert_eq!(
+        json,
+        serde_json::Value::Object(proto_struct_to_json(proto.clone()))
+    );
+    assert_eq!(
+        json,
+        proto_value_to_json(json_to_proto_value(proto_value_to_json(
+            prost_types::Value {
+                kind: Some(prost_types::value::Kind::StructValue(proto)),
+            }
+        )))
+    );
+}
@@ -0,0 +1,197 @@
+use std::collections::BTreeSet;
+
+use authly_common::{
+    id::AttrId, property::QualifiedAttributeName, service::NamespacePropertyMapping,
+};
+
+const FOO: AttrId = AttrId::from_uint(0xf001);
+const BAR: AttrId = AttrId::from_uint(0xf002);
+const BAZ: AttrId = AttrId::from_uint(0xf003);
+const QUX: AttrId = AttrId::from_uint(0xf004);
+
+fn test_mapping() -> NamespacePropertyMapping {
+    let mut mapping = NamespacePropertyMapping::default();
+    let ns = mapping.namespace_mut("ns".to_string());
+    ns.property_mut("role".to_string())
+        .put("foo".to_string(), FOO);
+    ns.property_mut("role".to_string())
+        .put("bar".to_string(), BAR);
+    mapping
+        .namespace_mut("other".to_string())
+        .property_mut("action".to_string())
+        .put("baz".to_string(), BAZ);
+    mapping
+}
+
+#[test]
+fn test_reverse_lookup() {
+    let mut mapping = test_mapping();
+
+    for (labels, attr_id) in [
+        (("ns", "role", "foo"), FOO),
+        (("ns", "role", "bar"), BAR),
+        (("other", "action", "baz"), BAZ),
+    ] {
+        assert_eq!(Some(attr_id), mapping.attribute_id(&labels));
+        assert_eq!(Some(labels), mapping.reverse(attr_id));
+    }
+    assert_eq!(None, mapping.reverse(QUX));
+
+    // mutation is reflected in the reverse index
+    mapping
+        .namespace_mut("ns".to_string())
+        .property_mut("role".to_string())
+        .put("qux".to_string(), QUX);
+    assert_eq!(Some(("ns", "role", "qux")), mapping.reverse(QUX));
+}
+
+#[test]
+fn test_namespace_shared() {
+    let mut mapping = test_mapping();
+
+    let ns = mapping.namespace_shared("ns").unwrap();
+    assert!(mapping.namespace_shared("missing").is_none());
+
+    // mutating the mapping does not affect a shared namespace handle
+    mapping
+        .namespace_mut("ns".to_string())
+        .property_mut("role".to_string())
+        .put("qux".to_string(), QUX);
+    assert_eq!(None, ns.property("role").unwrap().get("qux"));
+    assert_eq!(Some(QUX), mapping.attribute_id(&("ns", "role", "qux")));
+}
+
+#[test]
+fn test_immutable_getters() {
+    let mapping = test_mapping();
+
+    let role = mapping.namespace("ns").unwrap().property("role").unwrap();
+    assert_eq!(Some(FOO), role.get("foo"));
+    assert_eq!(Some(BAR), role.get("bar"));
+    assert_eq!(None, role.get("baz"));
+
+    let action = mapping
+        .namespace("other")
+        .unwrap()
+        .property("action")
+        .unwrap();
+    assert_eq!(Some(BAZ), action.get("baz"));
+
+    assert!(mapping
+        .namespace("ns")
+        .unwrap()
+        .property("action")
+        .is_none());
+    assert!(mapping.namespace("third").is_none());
+}
+
+fn labels(attrs: &[(&str, &str, &str)]) -> BTreeSet<(String, String, String)> {
+    attrs
+        .iter()
+        .map(|(ns, prop, attr)| (ns.to_string(), prop.to_string(), attr.to_string()))
+        .collect()
+}
+
+#[test]
+fn test_merge_disjoint() {
+    let mut mapping = test_mapping();
+    let mut other = NamespacePropertyMapping::default();
+    other
+        .namespace_mut("third".to_string())
+        .property_mut("kind".to_string())
+        .put("qux".to_string(), QUX);
+
+    assert!(mapping.merge(other).is_empty());
+    assert_eq!(Some(FOO), mapping.attribute_id(&("ns", "role", "foo")));
+    assert_eq!(Some(BAZ), mapping.attribute_id(&("other", "action", "baz")));
+    assert_eq!(Some(QUX), mapping.attribute_id(&("third", "kind", "qux")));
+    assert_eq!(Some(("third", "kind", "qux")), mapping.reverse(QUX));
+}
+
+#[test]
+fn test_merge_overlapping_namespace() {
+    let mut mapping = test_mapping();
+    let mut other = NamespacePropertyMapping::default();
+    let ns = other.namespace_mut("ns".to_string());
+    ns.property_mut("role".to_string())
+        .put("qux".to_string(), QUX);
+    // identical values are not conflicts
+    ns.property_mut("role".to_string())
+        .put("foo".to_string(), FOO);
+
+    assert!(mapping.merge(other).is_empty());
+    assert_eq!(Some(FOO), mapping.attribute_id(&("ns", "role", "foo")));
+    assert_eq!(Some(BAR), mapping.attribute_id(&("ns", "role", "bar")));
+    assert_eq!(Some(QUX), mapping.attribute_id(&("ns", "role", "qux")));
+}
+
+#[test]
+fn test_merge_conflicting_attribute() {
+    let mut mapping = test_mapping();
+    let mut other = NamespacePropertyMapping::default();
+    other
+        .namespace_mut("ns".to_string())
+        .property_mut("role".to_string())
+        .put("bar".to_string(), QUX);
+
+    assert_eq!(labels(&[("ns", "role", "bar")]), mapping.merge(other));
+    assert_eq!(Some(QUX), mapping.attribute_id(&("ns", "role", "bar")));
+    assert_eq!(Some(("ns", "role", "bar")), mapping.reverse(QUX));
+    assert_eq!(None, mapping.reverse(BAR));
+}
+
+#[test]
+fn test_qualified_attribute_name() {
+    let mapping = test_mapping();
+
+    for (input, attr_id) in [
+        ("ns:role:foo", Some(FOO)),
+        ("other:action:baz", Some(BAZ)),
+        ("ns:role:baz", None),
+    ] {
+        let name: QualifiedAttributeName = input.parse().unwrap();
+        assert_eq!(input, name.to_string());
+        assert_eq!(name, name.to_string().parse().unwrap());
+        assert_eq!(attr_id, mapping.attribute_id(&name), "{input}");
+    }
+}
+
+#[test]
+fn test_qualified_attribute_name_errors() {
+    let parse = |input: &str| {
+        input
+            .parse::<QualifiedAttributeName>()
+            .map_err(|err| err.to_string())
+    };
+
+    assert_eq!(
+        QualifiedAttributeName {
+            namespace: "ns".to_string(),
+            property: "role".to_string(),
+            attribute: "ui/admin".to_string(),
+        },
+        parse("ns:role:ui/admin").unwrap()
+    );
+
+    assert_eq!(
+        Err("unexpected segment at position 12, expected namespace:property:attribute".to_string()),
+        parse("ns:role:foo:bar")
+    );
+    assert_eq!(
+        Err("empty attribute at position 8".to_string()),
+        parse("ns:role:")
+    );
+    assert_eq!(
+        Err("empty property at position 3".to_string()),
+        parse("ns::foo")
+    );
+    assert_eq!(
+        Err("empty namespace at position 0".to_string()),
+        parse(":role:foo")
+    );
+    assert_eq!(
+        Err("expected qualified namespace:property:attribute triple".to_string()),
+        parse("ns:role")
+    );
+    assert_eq!(Err("empty namespace at position 0".to_string()), parse(""));
+}